@@ -0,0 +1,947 @@
+// Arithmetic coding of quantized DCT coefficients, the alternative to
+// Huffman coding in JPEG Annex D and F.1.4. Coefficients are coded as
+// binary decisions, each with an adaptive probability estimate in a
+// statistics bin chosen from what has been coded before. The decisions
+// and bins are the same as in libjpeg, so files it writes are
+// reproduced byte for byte.
+
+use jpeg::ScanHeader;
+use jpeg::decoder::Block;
+use jpeg::marker::Marker;
+
+/// The probability estimation state machine of JPEG Table D.2. For each
+/// state: the LPS probability Qe, the next state after an MPS, the next
+/// state after an LPS, and whether an LPS switches the sense of the MPS.
+/// State 113 is not in the table; it keeps a probability of 0.5 and is
+/// used for signs and refinement bits, like in libjpeg.
+const QE_TABLE: [(u32, u8, u8, bool); 114] = [
+    (0x5a1d, 1, 1, true), (0x2586, 2, 14, false), (0x1114, 3, 16, false), (0x080b, 4, 18, false),
+    (0x03d8, 5, 20, false), (0x01da, 6, 23, false), (0x00e5, 7, 25, false), (0x006f, 8, 28, false),
+    (0x0036, 9, 30, false), (0x001a, 10, 33, false), (0x000d, 11, 35, false),
+    (0x0006, 12, 9, false), (0x0003, 13, 10, false), (0x0001, 13, 12, false),
+    (0x5a7f, 15, 15, true), (0x3f25, 16, 36, false), (0x2cf2, 17, 38, false),
+    (0x207c, 18, 39, false), (0x17b9, 19, 40, false), (0x1182, 20, 42, false),
+    (0x0cef, 21, 43, false), (0x09a1, 22, 45, false), (0x072f, 23, 46, false),
+    (0x055c, 24, 48, false), (0x0406, 25, 49, false), (0x0303, 26, 51, false),
+    (0x0240, 27, 52, false), (0x01b1, 28, 54, false), (0x0144, 29, 56, false),
+    (0x00f5, 30, 57, false), (0x00b7, 31, 59, false), (0x008a, 32, 60, false),
+    (0x0068, 33, 62, false), (0x004e, 34, 63, false), (0x003b, 35, 32, false),
+    (0x002c, 9, 33, false), (0x5ae1, 37, 37, true), (0x484c, 38, 64, false),
+    (0x3a0d, 39, 65, false), (0x2ef1, 40, 67, false), (0x261f, 41, 68, false),
+    (0x1f33, 42, 69, false), (0x19a8, 43, 70, false), (0x1518, 44, 72, false),
+    (0x1177, 45, 73, false), (0x0e74, 46, 74, false), (0x0bfb, 47, 75, false),
+    (0x09f8, 48, 77, false), (0x0861, 49, 78, false), (0x0706, 50, 79, false),
+    (0x05cd, 51, 48, false), (0x04de, 52, 50, false), (0x040f, 53, 50, false),
+    (0x0363, 54, 51, false), (0x02d4, 55, 52, false), (0x025c, 56, 53, false),
+    (0x01f8, 57, 54, false), (0x01a4, 58, 55, false), (0x0160, 59, 56, false),
+    (0x0125, 60, 57, false), (0x00f6, 61, 58, false), (0x00cb, 62, 59, false),
+    (0x00ab, 63, 61, false), (0x008f, 32, 61, false), (0x5b12, 65, 65, true),
+    (0x4d04, 66, 80, false), (0x412c, 67, 81, false), (0x37d8, 68, 82, false),
+    (0x2fe8, 69, 83, false), (0x293c, 70, 84, false), (0x2379, 71, 86, false),
+    (0x1edf, 72, 87, false), (0x1aa9, 73, 87, false), (0x174e, 74, 72, false),
+    (0x1424, 75, 72, false), (0x119c, 76, 74, false), (0x0f6b, 77, 74, false),
+    (0x0d51, 78, 75, false), (0x0bb6, 79, 77, false), (0x0a40, 48, 77, false),
+    (0x5832, 81, 80, true), (0x4d1c, 82, 88, false), (0x438e, 83, 89, false),
+    (0x3bdd, 84, 90, false), (0x34ee, 85, 91, false), (0x2eae, 86, 92, false),
+    (0x299a, 87, 93, false), (0x2516, 71, 86, false), (0x5570, 89, 88, true),
+    (0x4ca9, 90, 95, false), (0x44d9, 91, 96, false), (0x3e22, 92, 97, false),
+    (0x3824, 93, 99, false), (0x32b4, 94, 99, false), (0x2e17, 86, 93, false),
+    (0x56a8, 96, 95, true), (0x4f46, 97, 101, false), (0x47e5, 98, 102, false),
+    (0x41cf, 99, 103, false), (0x3c3d, 100, 104, false), (0x375e, 93, 99, false),
+    (0x5231, 102, 105, false), (0x4c0f, 103, 106, false), (0x4639, 104, 107, false),
+    (0x415e, 99, 103, false), (0x5627, 106, 105, true), (0x50e7, 107, 108, false),
+    (0x4b85, 103, 109, false), (0x5597, 109, 110, false), (0x504f, 107, 111, false),
+    (0x5a10, 111, 110, true), (0x5522, 109, 112, false), (0x59eb, 111, 112, true),
+    (0x5a1d, 113, 113, false),
+];
+
+/// Statistics bins of each DC table: the five difference contexts of
+/// five bins each, followed by the bins of the magnitude categories and
+/// magnitude bits. See JPEG Table F.4.
+const DC_BINS: usize = 64;
+/// Statistics bins of each AC table: three for each coefficient of the
+/// band, followed by the bins of the magnitude categories and magnitude
+/// bits of the low and high frequencies. See JPEG Table F.5.
+const AC_BINS: usize = 256;
+/// The bin with the fixed probability of state 113
+const FIXED_BIN: usize = 4 * DC_BINS + 4 * AC_BINS;
+/// The first bin of the magnitude categories of DC differences, X1 in
+/// JPEG Table F.4
+const DC_CATEGORIES: usize = 20;
+/// The first bin of the magnitude categories of AC coefficients up to
+/// and above Kx, X2 in JPEG Table F.5
+const AC_LOW_CATEGORIES: usize = 189;
+const AC_HIGH_CATEGORIES: usize = 217;
+/// How far the bins of the magnitude bits are from the bins of the
+/// magnitude categories, M2 - X2 in JPEG Tables F.4 and F.5
+const MAGNITUDE_BITS: usize = 14;
+
+/// Index of bin `offset` of DC table `table`.
+fn dc_bin(table: usize, offset: usize) -> usize {
+    table * DC_BINS + offset
+}
+
+/// Index of bin `offset` of AC table `table`.
+fn ac_bin(table: usize, offset: usize) -> usize {
+    4 * DC_BINS + table * AC_BINS + offset
+}
+
+/// The conditioning of the statistics of each arithmetic coding table,
+/// which a DAC segment may change. See JPEG F.1.4.4.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArithmeticConditioning {
+    /// The bounds `(L, U)` of the small DC difference category, for
+    /// each DC table
+    pub dc: [(u8, u8); 4],
+    /// The last coefficient `Kx` coded with the statistics of low
+    /// frequencies, for each AC table
+    pub ac: [u8; 4],
+}
+
+impl Default for ArithmeticConditioning {
+    /// The conditioning used when there is no DAC segment, see JPEG F.1.4.4.
+    fn default() -> ArithmeticConditioning {
+        ArithmeticConditioning {
+            dc: [(0, 1); 4],
+            ac: [5; 4],
+        }
+    }
+}
+
+impl ArithmeticConditioning {
+    /// Set the conditioning of the tables in the data of a DAC segment.
+    /// See JPEG B.2.4.3.
+    pub fn update(&mut self, data: &[u8]) -> Result<(), String> {
+        if !data.len().is_multiple_of(2) {
+            return Err("DAC segment has a partial table".to_string());
+        }
+        for table in data.chunks(2) {
+            let (class, destination, value) = (table[0] >> 4, (table[0] & 0x0f) as usize, table[1]);
+            if destination > 3 {
+                return Err(format!("Invalid arithmetic coding table destination {}",
+                                   destination));
+            }
+            match class {
+                0 => {
+                    let (lower, upper) = (value & 0x0f, value >> 4);
+                    if lower > upper {
+                        return Err(format!("Invalid DC conditioning L = {}, U = {}",
+                                           lower,
+                                           upper));
+                    }
+                    self.dc[destination] = (lower, upper);
+                }
+                1 => {
+                    if !(1..=63).contains(&value) {
+                        return Err(format!("Invalid AC conditioning Kx = {}", value));
+                    }
+                    self.ac[destination] = value;
+                }
+                _ => return Err(format!("Invalid arithmetic coding table class {}", class)),
+            }
+        }
+        Ok(())
+    }
+
+    /// The data of a DAC segment with the conditioning of the tables
+    /// `scan` uses, as libjpeg writes in front of each scan, or `None` if
+    /// it uses none.
+    pub fn segment_data(&self, scan: &ScanHeader) -> Option<Vec<u8>> {
+        let codes_dc = scan.start_spectral_selection == 0 &&
+                       scan.successive_approximation_bit_pos_high == 0;
+        let codes_ac = scan.end_spectral_selection > 0;
+        let mut data = Vec::new();
+        for table in 0..4 {
+            let uses = |selector: u8| scan.scan_components.iter().any(|c| {
+                (if selector == 0 { c.dc_table_selector } else { c.ac_table_selector }) ==
+                table as u8
+            });
+            if codes_dc && uses(0) {
+                let (lower, upper) = self.dc[table];
+                data.extend_from_slice(&[table as u8, upper << 4 | lower]);
+            }
+            if codes_ac && uses(1) {
+                data.extend_from_slice(&[0x10 | table as u8, self.ac[table]]);
+            }
+        }
+        if data.is_empty() {
+            None
+        } else {
+            Some(data)
+        }
+    }
+}
+
+/// The statistics bins of all tables, and what else the coding of a
+/// scan depends on.
+#[derive(Debug, Clone)]
+struct Statistics {
+    /// The state in Table D.2 of each bin in the low seven bits, and
+    /// the sense of the MPS in the high bit
+    bins: Vec<u8>,
+    /// The context of the next DC difference of each component in the
+    /// scan, which depends on the previous difference. See JPEG F.1.4.4.1.
+    dc_context: Vec<usize>,
+    conditioning: ArithmeticConditioning,
+}
+
+impl Statistics {
+    fn new(num_components: usize, conditioning: ArithmeticConditioning) -> Statistics {
+        let mut statistics = Statistics {
+            bins: vec![0; FIXED_BIN + 1],
+            dc_context: vec![0; num_components],
+            conditioning,
+        };
+        statistics.reset();
+        statistics
+    }
+
+    /// Start over, as at the start of a scan or restart interval.
+    /// See JPEG F.1.4.3.
+    fn reset(&mut self) {
+        for bin in &mut self.bins {
+            *bin = 0;
+        }
+        self.bins[FIXED_BIN] = 113;
+        for context in &mut self.dc_context {
+            *context = 0;
+        }
+    }
+
+    /// The context of the next DC difference with table `table`, after
+    /// a difference with magnitude category `category`. See JPEG
+    /// F.1.4.4.1.2.
+    fn next_dc_context(&self, table: usize, category: u32, negative: bool) -> usize {
+        let (lower, upper) = self.conditioning.dc[table];
+        let sign = if negative { 4 } else { 0 };
+        if category < (1 << lower) >> 1 {
+            0
+        } else if category > (1 << upper) >> 1 {
+            12 + sign
+        } else {
+            4 + sign
+        }
+    }
+
+    /// The first bin of the magnitude categories of AC coefficient `k`
+    /// with table `table` which are larger than 2.
+    fn ac_categories(&self, table: usize, k: usize) -> usize {
+        if k <= self.conditioning.ac[table] as usize {
+            ac_bin(table, AC_LOW_CATEGORIES)
+        } else {
+            ac_bin(table, AC_HIGH_CATEGORIES)
+        }
+    }
+
+    /// Update the probability estimate of `bin` after coding the MPS.
+    fn after_mps(&mut self, bin: usize) {
+        let state = self.bins[bin];
+        self.bins[bin] = (state & 0x80) | QE_TABLE[(state & 0x7f) as usize].1;
+    }
+
+    /// Update the probability estimate of `bin` after coding the LPS.
+    fn after_lps(&mut self, bin: usize) {
+        let state = self.bins[bin];
+        let (_, _, next_lps, switch) = QE_TABLE[(state & 0x7f) as usize];
+        let mps = if switch { (state & 0x80) ^ 0x80 } else { state & 0x80 };
+        self.bins[bin] = mps | next_lps;
+    }
+}
+
+/// The state of an `ArithmeticDecoder` between two pieces of data, so
+/// that a scan can be decoded as its data arrives.
+#[derive(Debug, Clone)]
+pub struct ArithmeticState {
+    /// The code register, with the bits of the data which are read
+    c: u32,
+    /// The size of the current interval
+    a: u32,
+    /// Number of bits in `c` which are not used yet. It counts up to `0`
+    /// while the first two bytes are read.
+    ct: i32,
+    /// The marker ending the data, once we have found it
+    marker: Option<u8>,
+    statistics: Statistics,
+}
+
+impl ArithmeticState {
+    /// The state at the start of a scan with `num_components` components.
+    pub fn new(num_components: usize, conditioning: ArithmeticConditioning) -> ArithmeticState {
+        ArithmeticState {
+            c: 0,
+            a: 0,
+            ct: -16,
+            marker: None,
+            statistics: Statistics::new(num_components, conditioning),
+        }
+    }
+}
+
+/// Decodes arithmetic coded data, the way `huffman::HuffmanDecoder`
+/// decodes Huffman coded data. See JPEG D.2.
+///
+/// `data` is the entropy coded data of a scan as it is in the file,
+/// that is with stuffed `0x00` bytes and restart markers.
+pub struct ArithmeticDecoder<'a> {
+    data: &'a [u8],
+    /// The index of next byte to read from the data stream
+    next_index: usize,
+    state: ArithmeticState,
+}
+
+impl<'a> ArithmeticDecoder<'a> {
+    /// Continue decoding from `state`, where `data` starts with the
+    /// first byte which was not read before.
+    pub fn resume(data: &'a [u8], state: ArithmeticState) -> ArithmeticDecoder<'a> {
+        ArithmeticDecoder {
+            data,
+            next_index: 0,
+            state,
+        }
+    }
+
+    /// Stop decoding, and get the state to resume from along with
+    /// the number of bytes of `data` which are read.
+    pub fn suspend(self) -> (ArithmeticState, usize) {
+        (self.state, self.next_index)
+    }
+
+    /// Index in `data` of the next byte which is not read yet.
+    pub fn next_index(&self) -> usize {
+        self.next_index
+    }
+
+    /// Number of bytes of `data` which are not read yet.
+    pub fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.next_index)
+    }
+
+    /// The marker terminating the entropy coded data, if we have read
+    /// all the data in front of it.
+    pub fn marker(&self) -> Option<u8> {
+        self.state.marker
+    }
+
+    /// Whether the data is read up to the marker which ends it. The
+    /// decoder reads ahead, so the last blocks may not be decoded yet.
+    pub fn at_end(&self) -> bool {
+        self.state.marker.is_some_and(|code| !(0xd0..0xd8).contains(&code))
+    }
+
+    /// Read a DC difference of component `i` of the scan, with DC table
+    /// `table`. See JPEG F.2.4.1.
+    pub fn next_difference(&mut self, i: usize, table: usize) -> Result<i32, String> {
+        let context = dc_bin(table, self.state.statistics.dc_context[i]);
+        if !self.decode(context) {
+            self.state.statistics.dc_context[i] = 0;
+            return Ok(0);
+        }
+        let negative = self.decode(context + 1);
+        let mut bin = context + 2 + negative as usize;
+        let mut category = 0;
+        if self.decode(bin) {
+            category = 1;
+            bin = dc_bin(table, DC_CATEGORIES);
+            while self.decode(bin) {
+                category <<= 1;
+                if category == 0x8000 {
+                    return Err("DC difference is too large".to_string());
+                }
+                bin += 1;
+            }
+        }
+        self.state.statistics.dc_context[i] =
+            self.state.statistics.next_dc_context(table, category, negative);
+        let magnitude = self.decode_magnitude(bin, category);
+        Ok(if negative { -magnitude } else { magnitude })
+    }
+
+    /// Read one bit with a fixed probability, used for refining DC
+    /// coefficients in progressive scans. See JPEG G.1.3.1.
+    pub fn next_bit(&mut self) -> bool {
+        self.decode(FIXED_BIN)
+    }
+
+    /// Read the AC coefficients in band `start..=end` of a block, with
+    /// AC table `table`. In progressive scans these are the first bits
+    /// of the coefficients. See JPEG F.2.4.2 and G.1.3.2.
+    pub fn next_ac(&mut self,
+                   block: &mut Block,
+                   table: usize,
+                   start: usize,
+                   end: usize,
+                   point_transform: u8)
+                   -> Result<(), String> {
+        let mut k = start;
+        while k <= end {
+            let mut bin = ac_bin(table, 3 * (k - 1));
+            if self.decode(bin) {
+                // End of block
+                break;
+            }
+            while !self.decode(bin + 1) {
+                bin += 3;
+                k += 1;
+                if k > end {
+                    return Err("AC coefficients run past the end of the band".to_string());
+                }
+            }
+            let negative = self.decode(FIXED_BIN);
+            bin += 2;
+            let mut category = 0;
+            if self.decode(bin) {
+                category = 1;
+                if self.decode(bin) {
+                    category = 2;
+                    bin = self.state.statistics.ac_categories(table, k);
+                    while self.decode(bin) {
+                        category <<= 1;
+                        if category == 0x8000 {
+                            return Err("AC coefficient is too large".to_string());
+                        }
+                        bin += 1;
+                    }
+                }
+            }
+            let magnitude = self.decode_magnitude(bin, category);
+            let value = if negative { -magnitude } else { magnitude };
+            block[k] = (value << point_transform) as i16;
+            k += 1;
+        }
+        Ok(())
+    }
+
+    /// Read the refinement bits of the AC coefficients in band
+    /// `start..=end` of a block in a progressive scan. See JPEG G.1.3.3.
+    pub fn next_ac_refinement(&mut self,
+                              block: &mut Block,
+                              table: usize,
+                              start: usize,
+                              end: usize,
+                              point_transform: u8)
+                              -> Result<(), String> {
+        let positive = 1 << point_transform;
+        let negative = -1 << point_transform;
+        // The end of the band in the previous scans. Past it, each
+        // coefficient may be the end of the block.
+        let previous_end = (1..end + 1).rev().find(|&k| block[k] != 0).unwrap_or(0);
+        let mut k = start;
+        while k <= end {
+            let mut bin = ac_bin(table, 3 * (k - 1));
+            if k > previous_end && self.decode(bin) {
+                break;
+            }
+            loop {
+                if block[k] != 0 {
+                    if self.decode(bin + 2) {
+                        block[k] += if block[k] < 0 { negative } else { positive };
+                    }
+                    break;
+                }
+                if self.decode(bin + 1) {
+                    block[k] = if self.decode(FIXED_BIN) { negative } else { positive };
+                    break;
+                }
+                bin += 3;
+                k += 1;
+                if k > end {
+                    return Err("AC coefficients run past the end of the band".to_string());
+                }
+            }
+            k += 1;
+        }
+        Ok(())
+    }
+
+    /// Skip the rest of the restart interval and the restart marker
+    /// after it, and reset the decoder. See JPEG F.1.4.3.
+    pub fn restart(&mut self) -> Result<(), String> {
+        // Unlike Huffman coded data, the last bytes of the interval may
+        // not be read yet.
+        if self.state.marker.is_none() {
+            self.skip_to_marker();
+        }
+        match self.state.marker {
+            Some(code) if (0xd0..0xd8).contains(&code) => self.skip_marker(),
+            Some(code) => return Err(format!("Expected a restart marker, found {:02x}", code)),
+            None => return Err("Expected a restart marker, found the end of the data".to_string()),
+        }
+        self.state.c = 0;
+        self.state.a = 0;
+        self.state.ct = -16;
+        self.state.statistics.reset();
+        Ok(())
+    }
+
+    /// Skip the data up to the next marker without decoding it, and
+    /// return whether the marker is found. If we are at a restart marker,
+    /// it is skipped first. See `HuffmanDecoder::skip_to_marker`.
+    pub fn skip_to_marker(&mut self) -> bool {
+        if self.state.marker.is_some_and(|code| (0xd0..0xd8).contains(&code)) {
+            self.skip_marker();
+        }
+        self.resynchronize()
+    }
+
+    /// Skip the data up to the next marker after corrupt data, without
+    /// skipping a restart marker we are at. The decoder is reset when
+    /// the restart marker is read. See `HuffmanDecoder::resynchronize`.
+    pub fn resynchronize(&mut self) -> bool {
+        while self.state.marker.is_none() {
+            match self.data[self.next_index..].iter().position(|&byte| byte == 0xff) {
+                Some(position) => self.next_index += position,
+                None => {
+                    self.next_index = self.data.len();
+                    return false;
+                }
+            }
+            self.find_marker();
+            if self.state.marker.is_none() {
+                match self.data[self.next_index..].iter().position(|&byte| byte != 0xff) {
+                    // A stuffed `0xff00`
+                    Some(position) => self.next_index += position + 1,
+                    // Fill bytes, which may be followed by a marker in
+                    // the next piece of data.
+                    None => return false,
+                }
+            }
+        }
+        true
+    }
+
+    /// Skip all data up to the marker which ends it, past any restart
+    /// markers, and return whether it is found.
+    pub fn skip_to_end(&mut self) -> bool {
+        while self.skip_to_marker() {
+            if !self.state.marker.is_some_and(|code| (0xd0..0xd8).contains(&code)) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Move past the marker we have found, and its fill bytes.
+    fn skip_marker(&mut self) {
+        while self.data[self.next_index] == 0xff {
+            self.next_index += 1;
+        }
+        self.next_index += 1;
+        self.state.marker = None;
+    }
+
+    /// Check whether there is a marker at `next_index`, and set `marker`
+    /// if there is. Any number of `0xff` fill bytes may precede a marker.
+    fn find_marker(&mut self) {
+        let mut index = self.next_index;
+        while index < self.data.len() && self.data[index] == 0xff {
+            index += 1;
+        }
+        if index > self.next_index && index < self.data.len() && self.data[index] != 0x00 {
+            self.state.marker = Some(self.data[index]);
+        }
+    }
+
+    /// Read the next byte of data, without its stuffed `0x00`. Once we
+    /// reach a marker or the end of the data, `0` bytes are read instead.
+    /// Unlike in Huffman coded data, the decoder may need these to decode
+    /// the last decisions. See JPEG D.2.6.
+    fn next_byte(&mut self) -> u32 {
+        if self.state.marker.is_none() {
+            self.find_marker();
+        }
+        if self.state.marker.is_some() || self.next_index >= self.data.len() {
+            return 0;
+        }
+        let byte = self.data[self.next_index];
+        self.next_index += 1;
+        if byte == 0xff {
+            // A stuffed `0xff00`, as `find_marker` found no marker here.
+            while self.next_index < self.data.len() && self.data[self.next_index] == 0xff {
+                self.next_index += 1;
+            }
+            self.next_index = (self.next_index + 1).min(self.data.len());
+        }
+        byte as u32
+    }
+
+    /// Decode a decision with the probability estimate of `bin`. See
+    /// JPEG D.2.4 and D.2.5.
+    fn decode(&mut self, bin: usize) -> bool {
+        // Renormalize, reading more data into `c`. See JPEG D.2.6.
+        while self.state.a < 0x8000 {
+            self.state.ct -= 1;
+            if self.state.ct < 0 {
+                let byte = self.next_byte();
+                self.state.c = (self.state.c << 8) | byte;
+                self.state.ct += 8;
+                if self.state.ct < 0 {
+                    // The first two bytes start the interval.
+                    self.state.ct += 1;
+                    if self.state.ct == 0 {
+                        self.state.a = 0x8000;
+                    }
+                }
+            }
+            self.state.a <<= 1;
+        }
+
+        let state = self.state.statistics.bins[bin];
+        let mps = state & 0x80 != 0;
+        let qe = QE_TABLE[(state & 0x7f) as usize].0;
+        self.state.a -= qe;
+        let lps_start = self.state.a << self.state.ct;
+        if self.state.c >= lps_start {
+            // The LPS sub-interval, unless it is the larger one, in which
+            // case the symbols are exchanged.
+            self.state.c -= lps_start;
+            let is_mps = self.state.a < qe;
+            self.state.a = qe;
+            self.estimate(bin, is_mps);
+            if is_mps { mps } else { !mps }
+        } else if self.state.a < 0x8000 {
+            let is_mps = self.state.a >= qe;
+            self.estimate(bin, is_mps);
+            if is_mps { mps } else { !mps }
+        } else {
+            mps
+        }
+    }
+
+    fn estimate(&mut self, bin: usize, is_mps: bool) {
+        if is_mps {
+            self.state.statistics.after_mps(bin);
+        } else {
+            self.state.statistics.after_lps(bin);
+        }
+    }
+
+    /// Decode the magnitude of a value of magnitude category `category`,
+    /// the last bin of which is `bin`. See JPEG Figure F.24.
+    fn decode_magnitude(&mut self, bin: usize, category: u32) -> i32 {
+        let bin = bin + MAGNITUDE_BITS;
+        let mut magnitude = category;
+        let mut bit = category >> 1;
+        while bit != 0 {
+            if self.decode(bin) {
+                magnitude |= bit;
+            }
+            bit >>= 1;
+        }
+        magnitude as i32 + 1
+    }
+}
+
+/// Arithmetic codes the coefficients of a scan, the reverse of
+/// `ArithmeticDecoder`. See JPEG D.1.
+pub struct ArithmeticEncoder {
+    data: Vec<u8>,
+    /// The code register, the base of the current interval
+    c: u32,
+    /// The size of the current interval
+    a: u32,
+    /// Number of bits to shift into `c` before the next byte is ready
+    ct: i32,
+    /// The last byte which is ready, held back as a carry may still
+    /// increase it. `None` before the first byte.
+    buffer: Option<u8>,
+    /// Number of `0xff` bytes after `buffer`, which a carry would turn
+    /// into `0x00` bytes
+    stacked: usize,
+    /// Number of `0x00` bytes which are held back, since they are left
+    /// out at the end of the data
+    zeros: usize,
+    statistics: Statistics,
+}
+
+impl ArithmeticEncoder {
+    /// An encoder for a scan with `num_components` components.
+    pub fn new(num_components: usize, conditioning: ArithmeticConditioning) -> ArithmeticEncoder {
+        ArithmeticEncoder {
+            data: Vec::new(),
+            c: 0,
+            a: 0x10000,
+            ct: 11,
+            buffer: None,
+            stacked: 0,
+            zeros: 0,
+            statistics: Statistics::new(num_components, conditioning),
+        }
+    }
+
+    /// Encode a DC difference of component `i` of the scan, with DC table
+    /// `table`. See JPEG F.1.4.1.
+    pub fn encode_difference(&mut self, i: usize, table: usize, difference: i32) {
+        let context = dc_bin(table, self.statistics.dc_context[i]);
+        if difference == 0 {
+            self.encode(context, false);
+            self.statistics.dc_context[i] = 0;
+            return;
+        }
+        self.encode(context, true);
+        let negative = difference < 0;
+        self.encode(context + 1, negative);
+        let mut bin = context + 2 + negative as usize;
+        let magnitude = difference.unsigned_abs() - 1;
+        let mut category = 0;
+        if magnitude != 0 {
+            self.encode(bin, true);
+            category = 1;
+            bin = dc_bin(table, DC_CATEGORIES);
+            while magnitude >> category != 0 {
+                self.encode(bin, true);
+                category += 1;
+                bin += 1;
+            }
+        }
+        self.encode(bin, false);
+        let category = if category == 0 { 0 } else { 1 << (category - 1) };
+        self.statistics.dc_context[i] = self.statistics.next_dc_context(table, category, negative);
+        self.encode_magnitude(bin, category, magnitude);
+    }
+
+    /// Encode one bit with a fixed probability, used for refining DC
+    /// coefficients in progressive scans. See JPEG G.1.3.1.
+    pub fn encode_bit(&mut self, bit: bool) {
+        self.encode(FIXED_BIN, bit);
+    }
+
+    /// Encode the AC coefficients in band `start..=end` of a block, with
+    /// AC table `table`. In progressive scans, these are divided by
+    /// `2^point_transform`. See JPEG F.1.4.2 and G.1.3.2.
+    pub fn encode_ac(&mut self,
+                     block: &Block,
+                     table: usize,
+                     start: usize,
+                     end: usize,
+                     point_transform: u8) {
+        let magnitude = |k: usize| (block[k] as i32).unsigned_abs() >> point_transform;
+        let last = (1..end + 1).rev().find(|&k| magnitude(k) != 0).unwrap_or(0);
+        let mut k = start;
+        while k <= last {
+            let mut bin = ac_bin(table, 3 * (k - 1));
+            self.encode(bin, false);
+            while magnitude(k) == 0 {
+                self.encode(bin + 1, false);
+                bin += 3;
+                k += 1;
+            }
+            self.encode(bin + 1, true);
+            self.encode(FIXED_BIN, block[k] < 0);
+            bin += 2;
+            let value = magnitude(k) - 1;
+            let mut category = 0;
+            if value != 0 {
+                self.encode(bin, true);
+                category = 1;
+                if value >> 1 != 0 {
+                    self.encode(bin, true);
+                    category = 2;
+                    bin = self.statistics.ac_categories(table, k);
+                    while value >> category != 0 {
+                        self.encode(bin, true);
+                        category += 1;
+                        bin += 1;
+                    }
+                }
+            }
+            self.encode(bin, false);
+            let category = if category == 0 { 0 } else { 1 << (category - 1) };
+            self.encode_magnitude(bin, category, value);
+            k += 1;
+        }
+        if k <= end {
+            // End of block
+            self.encode(ac_bin(table, 3 * (k - 1)), true);
+        }
+    }
+
+    /// Encode the bit `point_transform` of the AC coefficients in band
+    /// `start..=end` of a block in a progressive scan. See JPEG G.1.3.3.
+    pub fn encode_ac_refinement(&mut self,
+                                block: &Block,
+                                table: usize,
+                                start: usize,
+                                end: usize,
+                                point_transform: u8) {
+        let magnitude = |k: usize| (block[k] as i32).unsigned_abs() >> point_transform;
+        let last = (1..end + 1).rev().find(|&k| magnitude(k) != 0).unwrap_or(0);
+        // The end of the band in the previous scans
+        let previous_end = (1..last + 1).rev().find(|&k| magnitude(k) > 1).unwrap_or(0);
+        let mut k = start;
+        while k <= last {
+            let mut bin = ac_bin(table, 3 * (k - 1));
+            if k > previous_end {
+                self.encode(bin, false);
+            }
+            loop {
+                let value = magnitude(k);
+                if value > 1 {
+                    self.encode(bin + 2, value & 1 != 0);
+                    break;
+                }
+                if value == 1 {
+                    self.encode(bin + 1, true);
+                    self.encode(FIXED_BIN, block[k] < 0);
+                    break;
+                }
+                self.encode(bin + 1, false);
+                bin += 3;
+                k += 1;
+            }
+            k += 1;
+        }
+        if k <= end {
+            self.encode(ac_bin(table, 3 * (k - 1)), true);
+        }
+    }
+
+    /// End restart interval number `n`, counting from `0`, with its
+    /// restart marker, and reset the encoder. See JPEG F.1.4.3.
+    pub fn restart(&mut self, n: usize) {
+        self.flush();
+        self.data.push(0xff);
+        self.data.push(Marker::Restart((n % 8) as u8).code());
+        self.c = 0;
+        self.a = 0x10000;
+        self.ct = 11;
+        self.buffer = None;
+        self.stacked = 0;
+        self.zeros = 0;
+        self.statistics.reset();
+    }
+
+    /// Get the data, with the last bits flushed.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.flush();
+        self.data
+    }
+
+    /// Encode a decision with the probability estimate of `bin`. See
+    /// JPEG D.1.4 and D.1.5.
+    fn encode(&mut self, bin: usize, decision: bool) {
+        let state = self.statistics.bins[bin];
+        let mps = state & 0x80 != 0;
+        let qe = QE_TABLE[(state & 0x7f) as usize].0;
+        self.a -= qe;
+        if decision != mps {
+            // The LPS gets the larger sub-interval if the MPS has the
+            // smaller one.
+            if self.a >= qe {
+                self.c += self.a;
+                self.a = qe;
+            }
+            self.statistics.after_lps(bin);
+        } else {
+            if self.a >= 0x8000 {
+                return;
+            }
+            if self.a < qe {
+                self.c += self.a;
+                self.a = qe;
+            }
+            self.statistics.after_mps(bin);
+        }
+
+        // Renormalize, writing out the bytes of `c` which are ready.
+        // See JPEG D.1.6.
+        while self.a < 0x8000 {
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+            if self.ct == 0 {
+                let byte = self.c >> 19;
+                if byte > 0xff {
+                    self.carry();
+                    self.buffer = Some(byte as u8);
+                } else if byte == 0xff {
+                    self.stacked += 1;
+                } else {
+                    self.write_buffer();
+                    self.buffer = Some(byte as u8);
+                }
+                self.c &= 0x7ffff;
+                self.ct += 8;
+            }
+        }
+    }
+
+    /// Encode the magnitude bits of `value` below the most significant
+    /// one, `category`, the last bin of which is `bin`. See JPEG Figure
+    /// F.9.
+    fn encode_magnitude(&mut self, bin: usize, category: u32, value: u32) {
+        let bin = bin + MAGNITUDE_BITS;
+        let mut bit = category >> 1;
+        while bit != 0 {
+            self.encode(bin, value & bit != 0);
+            bit >>= 1;
+        }
+    }
+
+    /// Write the bytes which follow from the last decisions, leaving out
+    /// `0x00` bytes at the end. See JPEG D.1.8.
+    fn flush(&mut self) {
+        // The value in the interval with the most trailing zero bits
+        let rounded = (self.a - 1 + self.c) & 0xffff0000;
+        self.c = if rounded < self.c { rounded + 0x8000 } else { rounded };
+        self.c <<= self.ct;
+        if self.c & 0x8000000 != 0 {
+            self.carry();
+        } else {
+            self.write_buffer();
+        }
+        if self.c & 0x7fff800 != 0 {
+            self.write_zeros();
+            self.write_byte((self.c >> 19) as u8);
+            if self.c & 0x7f800 != 0 {
+                self.write_byte((self.c >> 11) as u8);
+            }
+        }
+    }
+
+    /// Add a carry to the buffered byte and write it. The stacked `0xff`
+    /// bytes become `0x00` bytes.
+    fn carry(&mut self) {
+        if let Some(byte) = self.buffer {
+            self.write_zeros();
+            // The byte can't be `0xff`, as those are stacked.
+            self.write_byte(byte + 1);
+        }
+        self.zeros += self.stacked;
+        self.stacked = 0;
+    }
+
+    /// Write the buffered byte and the stacked `0xff` bytes, which no
+    /// carry can change anymore.
+    fn write_buffer(&mut self) {
+        match self.buffer {
+            Some(0) => self.zeros += 1,
+            Some(byte) => {
+                self.write_zeros();
+                self.write_byte(byte);
+            }
+            None => {}
+        }
+        if self.stacked > 0 {
+            self.write_zeros();
+            for _ in 0..self.stacked {
+                self.data.extend_from_slice(&[0xff, 0x00]);
+            }
+            self.stacked = 0;
+        }
+    }
+
+    fn write_zeros(&mut self) {
+        for _ in 0..self.zeros {
+            self.data.push(0x00);
+        }
+        self.zeros = 0;
+    }
+
+    /// Write a byte, with a `0x00` stuffed after `0xff`.
+    fn write_byte(&mut self, byte: u8) {
+        self.data.push(byte);
+        if byte == 0xff {
+            self.data.push(0x00);
+        }
+    }
+}
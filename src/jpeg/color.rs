@@ -0,0 +1,166 @@
+// Conversion between YCbCr and RGB.
+//
+// JFIF images use the YCbCr of ITU-R BT.601, with all components in the
+// full range of 0 to 255. Images made from video frames may instead use
+// the luma coefficients of BT.709, or the limited range of video, where Y
+// goes from 16 to 235 and Cb and Cr from 16 to 240.
+//
+// The conversions are done in fixed point, with the whole sum rounded
+// to the nearest integer at the end. The factors have enough bits that
+// every pixel gets the same values as exact arithmetic would give it,
+// unless the exact value is halfway between two integers.
+
+/// Number of fractional bits of the fixed point coefficients
+const FRACTION_BITS: u32 = 32;
+const ONE: i64 = 1 << FRACTION_BITS;
+const HALF: i64 = ONE / 2;
+
+/// Which coefficients make the luma out of red, green and blue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Matrix {
+    /// ITU-R BT.601, as used by JFIF
+    BT601,
+    /// ITU-R BT.709, as used by HD video
+    BT709,
+}
+
+impl Matrix {
+    /// The weights of red and blue in the luma. Green has the rest.
+    fn red_and_blue(self) -> (f64, f64) {
+        match self {
+            Matrix::BT601 => (0.299, 0.114),
+            Matrix::BT709 => (0.2126, 0.0722),
+        }
+    }
+}
+
+/// The values the components of YCbCr take.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Range {
+    /// 0 to 255 for all components, as in JFIF
+    Full,
+    /// 16 to 235 for Y, and 16 to 240 for Cb and Cr, as in video
+    Limited,
+}
+
+impl Range {
+    /// The offset and scale of Y, and the scale of Cb and Cr, from full
+    /// range. See ITU-R BT.601 section 2.5.3.
+    fn scales(self) -> (i64, f64, f64) {
+        match self {
+            Range::Full => (0, 1.0, 1.0),
+            Range::Limited => (16, 219.0 / 255.0, 224.0 / 255.0),
+        }
+    }
+}
+
+/// Converts pixels between YCbCr and RGB, with the coefficients of a
+/// `Matrix` and the values of a `Range`. The default is JFIF, which is
+/// BT.601 in full range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorConversion {
+    matrix: Matrix,
+    range: Range,
+    /// Offset of Y
+    y_offset: i64,
+    /// Fixed point factors of Y, Cr for red, Cb and Cr for green, and Cb
+    /// for blue, to get RGB
+    to_rgb: [i64; 5],
+    /// Fixed point factors of red, green and blue for Y, Cb and Cr
+    to_y_cb_cr: [[i64; 3]; 3],
+    /// Fixed point factors of red, green and blue for the luma, in full
+    /// range
+    to_luma: [i64; 3],
+}
+
+impl Default for ColorConversion {
+    fn default() -> ColorConversion {
+        ColorConversion::new(Matrix::BT601, Range::Full)
+    }
+}
+
+impl ColorConversion {
+    pub fn new(matrix: Matrix, range: Range) -> ColorConversion {
+        let (kr, kb) = matrix.red_and_blue();
+        let kg = 1.0 - kr - kb;
+        let (y_offset, y_scale, c_scale) = range.scales();
+        let fix = |n: f64| (n * ONE as f64).round() as i64;
+
+        // See ITU-R BT.601 section 2.5.2, and ITU-R BT.709 section 3.
+        let to_rgb = [fix(1.0 / y_scale),
+                      fix(2.0 * (1.0 - kr) / c_scale),
+                      fix(2.0 * kb * (1.0 - kb) / kg / c_scale),
+                      fix(2.0 * kr * (1.0 - kr) / kg / c_scale),
+                      fix(2.0 * (1.0 - kb) / c_scale)];
+
+        // The factor of green is what is left of the sum of each row
+        // once the others are rounded, so that gray pixels have no
+        // chroma, and keep their value in full range.
+        let row = |r: f64, b: f64, sum: f64| {
+            let (r, b) = (fix(r), fix(b));
+            [r, fix(sum) - r - b, b]
+        };
+        let to_y_cb_cr = [row(kr * y_scale, kb * y_scale, y_scale),
+                          row(-kr / (2.0 * (1.0 - kb)) * c_scale, 0.5 * c_scale, 0.0),
+                          row(0.5 * c_scale, -kb / (2.0 * (1.0 - kr)) * c_scale, 0.0)];
+        let to_luma = row(kr, kb, 1.0);
+
+        ColorConversion {
+            matrix,
+            range,
+            y_offset,
+            to_rgb,
+            to_y_cb_cr,
+            to_luma,
+        }
+    }
+
+    pub fn matrix(&self) -> Matrix {
+        self.matrix
+    }
+
+    pub fn range(&self) -> Range {
+        self.range
+    }
+
+    /// Convert a YCbCr pixel to RGB.
+    pub fn to_rgb(&self, y: u8, cb: u8, cr: u8) -> (u8, u8, u8) {
+        let [y_factor, cr_red, cb_green, cr_green, cb_blue] = self.to_rgb;
+        let y = (y as i64 - self.y_offset) * y_factor + HALF;
+        let (cb, cr) = (cb as i64 - 128, cr as i64 - 128);
+        (descale(y + cr_red * cr),
+         descale(y - cb_green * cb - cr_green * cr),
+         descale(y + cb_blue * cb))
+    }
+
+    /// Convert the luma of a gray pixel to its value, which only changes
+    /// it in limited range.
+    pub fn gray(&self, y: u8) -> u8 {
+        descale((y as i64 - self.y_offset) * self.to_rgb[0] + HALF)
+    }
+
+    /// The luma of an RGB pixel, in full range, as `gray` gives it for
+    /// the Y of the pixel. Gray pixels keep their value.
+    pub fn luma(&self, pixel: (u8, u8, u8)) -> u8 {
+        let rgb = [pixel.0 as i64, pixel.1 as i64, pixel.2 as i64];
+        descale(self.to_luma.iter().zip(&rgb).map(|(&f, &n)| f * n).sum::<i64>() + HALF)
+    }
+
+    /// Convert an RGB pixel to YCbCr.
+    pub fn to_y_cb_cr(&self, pixel: (u8, u8, u8)) -> (u8, u8, u8) {
+        let rgb = [pixel.0 as i64, pixel.1 as i64, pixel.2 as i64];
+        let component = |factors: &[i64; 3], offset: i64| {
+            let sum = factors.iter().zip(&rgb).map(|(&f, &n)| f * n).sum::<i64>();
+            descale(sum + (offset << FRACTION_BITS) + HALF)
+        };
+        (component(&self.to_y_cb_cr[0], self.y_offset),
+         component(&self.to_y_cb_cr[1], 128),
+         component(&self.to_y_cb_cr[2], 128))
+    }
+}
+
+/// Turn a fixed point value, which has half added to it already, into
+/// a byte, rounded and clamped.
+fn descale(n: i64) -> u8 {
+    (n >> FRACTION_BITS).clamp(0, 255) as u8
+}
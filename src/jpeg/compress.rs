@@ -0,0 +1,332 @@
+// Encoding images from pixels, like `cjpeg` of libjpeg.
+//
+// The pixels are converted to YCbCr, the chroma is downsampled by
+// averaging, and each block of samples goes through the DCT and is
+// quantized with the example tables of JPEG Annex K, scaled for the
+// quality the way libjpeg scales them. `JPEGSegmentWriter` then codes the
+// coefficients with optimal Huffman tables.
+
+use std::f32::consts::PI;
+
+use jpeg::{encoder, FrameComponentHeader, FrameHeader};
+use jpeg::color::ColorConversion;
+use jpeg::decoder::{Block, CoefficientOrder, ComponentCoefficients, ZIGZAG_INDICES};
+use jpeg::marker::Marker;
+use jpeg::writer::JPEGSegmentWriter;
+
+/// Quantization table for luma, in natural order. See JPEG Table K.1.
+const LUMA_QUANTIZATION: [u16; 64] =
+    [16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69,
+     56, 14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81,
+     104, 113, 92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99];
+
+/// Quantization table for chroma, in natural order. See JPEG Table K.2.
+const CHROMA_QUANTIZATION: [u16; 64] =
+    [17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99,
+     99, 47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+     99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99];
+
+/// How much the chroma of color images is downsampled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChromaSubsampling {
+    /// Chroma at full resolution
+    YCbCr444,
+    /// Chroma at half the width
+    YCbCr422,
+    /// Chroma at half the width and half the height
+    YCbCr420,
+}
+
+impl ChromaSubsampling {
+    /// The sampling factors of the luma. The chroma has factors of `1`.
+    fn luma_sampling_factors(self) -> (u8, u8) {
+        match self {
+            ChromaSubsampling::YCbCr444 => (1, 1),
+            ChromaSubsampling::YCbCr422 => (2, 1),
+            ChromaSubsampling::YCbCr420 => (2, 2),
+        }
+    }
+}
+
+/// Encodes pixels as a JFIF file.
+#[derive(Debug, Clone)]
+pub struct JPEGEncoder {
+    /// From `1` to `100`, as in libjpeg
+    quality: u8,
+    subsampling: ChromaSubsampling,
+    progressive: bool,
+    /// How RGB is turned into YCbCr
+    color_conversion: ColorConversion,
+}
+
+impl Default for JPEGEncoder {
+    fn default() -> JPEGEncoder {
+        JPEGEncoder::new()
+    }
+}
+
+impl JPEGEncoder {
+    /// An encoder of quality 75 with 4:2:0 chroma, which is what
+    /// libjpeg does by default.
+    pub fn new() -> JPEGEncoder {
+        JPEGEncoder {
+            quality: 75,
+            subsampling: ChromaSubsampling::YCbCr420,
+            progressive: false,
+            color_conversion: ColorConversion::default(),
+        }
+    }
+
+    /// Set the quality, from `1`, the smallest files, to `100`, where
+    /// little is lost but the files are large.
+    pub fn quality(&mut self, quality: u8) -> Result<(), String> {
+        if !(1..=100).contains(&quality) {
+            return Err(format!("Quality {} is not between 1 and 100", quality));
+        }
+        self.quality = quality;
+        Ok(())
+    }
+
+    pub fn chroma_subsampling(&mut self, subsampling: ChromaSubsampling) {
+        self.subsampling = subsampling;
+    }
+
+    /// Write a progressive image, with the scans of
+    /// `JPEGSegmentWriter::make_progressive`, instead of a baseline one.
+    pub fn progressive(&mut self) {
+        self.progressive = true;
+    }
+
+    /// Convert RGB to YCbCr with `conversion`. Files other than JFIF
+    /// don't say which conversion they use, so the decoder must be told.
+    pub fn color_conversion(&mut self, conversion: ColorConversion) {
+        self.color_conversion = conversion;
+    }
+
+    /// Encode a color image of `width` by `height` pixels, line by line.
+    pub fn encode(&self,
+                  pixels: &[(u8, u8, u8)],
+                  width: usize,
+                  height: usize)
+                  -> Result<Vec<u8>, String> {
+        check_dimensions(pixels.len(), width, height)?;
+        let mut planes = (0..3).map(|_| Vec::with_capacity(pixels.len())).collect::<Vec<_>>();
+        for &pixel in pixels {
+            let (y, cb, cr) = self.color_conversion.to_y_cb_cr(pixel);
+            planes[0].push(y);
+            planes[1].push(cb);
+            planes[2].push(cr);
+        }
+        self.encode_planes(&planes, width, height)
+    }
+
+    /// Encode a gray image of `width` by `height` pixels, line by line.
+    pub fn encode_gray(&self, luma: &[u8], width: usize, height: usize) -> Result<Vec<u8>, String> {
+        check_dimensions(luma.len(), width, height)?;
+        self.encode_planes(&[luma.to_vec()], width, height)
+    }
+
+    /// Encode the full resolution samples of the components, which are
+    /// either luma or YCbCr.
+    fn encode_planes(&self,
+                     planes: &[Vec<u8>],
+                     width: usize,
+                     height: usize)
+                     -> Result<Vec<u8>, String> {
+        let (max_h, max_v) = if planes.len() == 1 {
+            (1, 1)
+        } else {
+            self.subsampling.luma_sampling_factors()
+        };
+        let frame_components = (0..planes.len())
+            .map(|i| {
+                let (h, v) = if i == 0 { (max_h, max_v) } else { (1, 1) };
+                FrameComponentHeader {
+                    component_id: i as u8 + 1,
+                    horizontal_sampling_factor: h,
+                    vertical_sampling_factor: v,
+                    quantization_selector: if i == 0 { 0 } else { 1 },
+                }
+            })
+            .collect::<Vec<_>>();
+        let frame = FrameHeader {
+            frame_type: if self.progressive {
+                Marker::ProgressiveDCT
+            } else {
+                Marker::BaselineDCT
+            },
+            sample_precision: 8,
+            num_lines: height as u16,
+            samples_per_line: width as u16,
+            image_components: planes.len() as u8,
+            frame_components,
+        };
+
+        let tables = [scaled_table(&LUMA_QUANTIZATION, self.quality),
+                      scaled_table(&CHROMA_QUANTIZATION, self.quality)];
+        let cosines = dct_cosines();
+        let components = planes.iter()
+            .zip(&frame.frame_components)
+            .map(|(samples, component)| {
+                let table = &tables[component.quantization_selector as usize];
+                component_coefficients(samples, &frame, component, table, &cosines)
+            })
+            .collect::<Vec<_>>();
+
+        // The file with everything but the entropy coded data, which the
+        // writer adds along with the Huffman tables.
+        let mut data = vec![0xff, Marker::StartOfImage.code()];
+        // JFIF 1.01, with a pixel aspect ratio of 1:1 and no thumbnail
+        push_segment(&mut data,
+                     Marker::ApplicationSegment(0),
+                     b"JFIF\0\x01\x01\x00\x00\x01\x00\x01\x00\x00");
+        let mut quantization = Vec::with_capacity(130);
+        for (id, table) in tables.iter().enumerate().take(if planes.len() == 1 { 1 } else { 2 }) {
+            quantization.push(id as u8);
+            quantization.extend(ZIGZAG_INDICES.iter().map(|&i| table[i] as u8));
+        }
+        push_segment(&mut data, Marker::QuantizationTable, &quantization);
+        push_segment(&mut data, frame.frame_type, &frame.segment_data());
+        let scans = if self.progressive {
+            encoder::progressive_scans(&frame)
+        } else {
+            encoder::sequential_scans(&frame)
+        };
+        for scan in &scans {
+            push_segment(&mut data, Marker::StartOfScan, &scan.segment_data());
+        }
+        data.extend_from_slice(&[0xff, Marker::EndOfImage.code()]);
+
+        let mut writer = JPEGSegmentWriter::new(&data)?;
+        writer.replace_coefficients_with_optimal_tables(&components)?;
+        Ok(writer.to_bytes())
+    }
+}
+
+fn check_dimensions(num_pixels: usize, width: usize, height: usize) -> Result<(), String> {
+    if width == 0 || height == 0 || width > u16::MAX as usize || height > u16::MAX as usize {
+        return Err(format!("Images of {}x{} pixels can't be encoded", width, height));
+    }
+    if num_pixels != width * height {
+        return Err(format!("{} pixels are not an image of {}x{} pixels",
+                           num_pixels,
+                           width,
+                           height));
+    }
+    Ok(())
+}
+
+fn push_segment(data: &mut Vec<u8>, marker: Marker, payload: &[u8]) {
+    let length = payload.len() + 2;
+    data.extend_from_slice(&[0xff, marker.code(), (length >> 8) as u8, length as u8]);
+    data.extend_from_slice(payload);
+}
+
+/// Scale an example table for `quality`, like `jpeg_quality_scaling` of
+/// libjpeg. Quality 50 gives the table as it is. Values are kept in
+/// 8 bits, as baseline files need.
+fn scaled_table(table: &[u16; 64], quality: u8) -> [u16; 64] {
+    let quality = quality as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+    let mut scaled = [0; 64];
+    for (scaled, &value) in scaled.iter_mut().zip(table.iter()) {
+        *scaled = ((value as u32 * scale + 50) / 100).clamp(1, 255) as u16;
+    }
+    scaled
+}
+
+/// `cosines[x * 8 + u]` is the DCT basis function of frequency `u` at
+/// sample `x`, with the factors which make the transform orthonormal.
+fn dct_cosines() -> [f32; 64] {
+    let mut cosines = [0f32; 64];
+    for x in 0..8 {
+        for u in 0..8 {
+            let alpha = if u == 0 { 0.5f32.sqrt() } else { 1.0 };
+            cosines[x * 8 + u] = alpha / 2.0 *
+                                 ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos();
+        }
+    }
+    cosines
+}
+
+/// Forward DCT of a block of level shifted samples, one dimension at a
+/// time. See JPEG A.3.3.
+fn forward_dct(samples: &[f32; 64], cosines: &[f32; 64]) -> [f32; 64] {
+    let mut rows = [0f32; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| samples[y * 8 + x] * cosines[x * 8 + u]).sum();
+        }
+    }
+    let mut coefficients = [0f32; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            coefficients[v * 8 + u] = (0..8).map(|y| rows[y * 8 + u] * cosines[y * 8 + v]).sum();
+        }
+    }
+    coefficients
+}
+
+/// Downsample, transform and quantize the full resolution `samples` of
+/// a component. All blocks of whole MCUs are made, with the samples past
+/// the right and bottom edges copied from the last ones.
+fn component_coefficients(samples: &[u8],
+                          frame: &FrameHeader,
+                          component: &FrameComponentHeader,
+                          table: &[u16; 64],
+                          cosines: &[f32; 64])
+                          -> ComponentCoefficients {
+    let width = frame.samples_per_line as usize;
+    let height = frame.num_lines as usize;
+    let max_h = frame.frame_components[0].horizontal_sampling_factor as usize;
+    let max_v = frame.frame_components[0].vertical_sampling_factor as usize;
+    let h = component.horizontal_sampling_factor as usize;
+    let v = component.vertical_sampling_factor as usize;
+    // Each sample of the component is the average of this many pixels.
+    let (step_x, step_y) = (max_h / h, max_v / v);
+
+    let blocks_per_line = width.div_ceil(8 * max_h) * h;
+    let num_block_rows = height.div_ceil(8 * max_v) * v;
+    let mut blocks = Vec::with_capacity(blocks_per_line * num_block_rows);
+    let mut block_samples = [0f32; 64];
+    for block_y in 0..num_block_rows {
+        for block_x in 0..blocks_per_line {
+            for (i, sample) in block_samples.iter_mut().enumerate() {
+                let x = (block_x * 8 + i % 8) * step_x;
+                let y = (block_y * 8 + i / 8) * step_y;
+                let mut sum = 0;
+                for dy in 0..step_y {
+                    let line = (y + dy).min(height - 1) * width;
+                    for dx in 0..step_x {
+                        sum += samples[line + (x + dx).min(width - 1)] as usize;
+                    }
+                }
+                *sample = sum as f32 / (step_x * step_y) as f32 - 128.0;
+            }
+            let coefficients = forward_dct(&block_samples, cosines);
+            let mut block: Block = [0; 64];
+            for ((quantized, &coefficient), &q) in block.iter_mut()
+                .zip(coefficients.iter())
+                .zip(table.iter()) {
+                *quantized = (coefficient / q as f32).round() as i16;
+            }
+            blocks.push(block);
+        }
+    }
+    ComponentCoefficients {
+        component: component.component_id,
+        horizontal_sampling_factor: component.horizontal_sampling_factor,
+        vertical_sampling_factor: component.vertical_sampling_factor,
+        quantization_id: component.quantization_selector,
+        order: CoefficientOrder::Natural,
+        quantization_table: table.to_vec(),
+        width_in_blocks: (width * h).div_ceil(max_h).div_ceil(8),
+        height_in_blocks: (height * v).div_ceil(max_v).div_ceil(8),
+        blocks_per_line,
+        blocks,
+    }
+}
@@ -1,34 +1,83 @@
-use std::iter::repeat;
-
-use itertools::Itertools;
+use std::collections::VecDeque;
+use std::mem;
+use std::iter::repeat_n;
+use std::ops::Range;
 
 use jpeg::{FrameHeader, ScanHeader};
-use jpeg::huffman;
+use jpeg::{arithmetic, huffman};
+use jpeg::color::ColorConversion;
+use jpeg::marker::Marker;
+use jpeg::pixel::{PixelBuffer, PixelFormat};
 use ::transform;
 
 type QuantizationTable = Vec<u16>;
-type Block = Vec<f32>;
-type BlockSlice = [f32];
+/// The quantized DCT coefficients of an 8x8 block. The decoder keeps
+/// them in zigzag order.
+pub type Block = [i16; 64];
 
 /// Struct to hold state of JPEG decoding.
 /// Instantiate it, and pass in AC/DC tables, quantization
-/// tables, sampling factors, data, etc. as it is available,
+/// tables, sampling factors, etc. as it is available,
 /// or updated.
 ///
-/// Call `JPEGDecoder::decode()` to start reading from `data`.
-pub struct JPEGDecoder<'a> {
-    /// Encoded image data
-    data: &'a [u8],
+/// Call `JPEGDecoder::decode_scan()` with the data of each scan,
+/// and `JPEGDecoder::decode()` to get the image when all scans are read.
+/// If the data of a scan arrives in pieces, call
+/// `JPEGDecoder::start_scan()` and then `JPEGDecoder::decode_mcus()`
+/// with each piece instead.
+pub struct JPEGDecoder {
+    /// The SOFn marker of the frame, which tells how it is coded
+    frame_type: Marker,
+    /// Bits per sample
+    sample_precision: u8,
     /// Huffman tables for AC coefficients
     huffman_ac_tables: [Option<huffman::HuffmanTable>; 4],
     /// Huffman tables for DC coefficients
     huffman_dc_tables: [Option<huffman::HuffmanTable>; 4],
+    /// Conditioning of the arithmetic coding tables
+    arithmetic_conditioning: arithmetic::ArithmeticConditioning,
     /// Quantization tables
     quantization_tables: [Option<QuantizationTable>; 4],
-    /// Fields specific for each component.
+    /// Fields specific for each component, in frame order.
     component_fields: Vec<JPEGDecoderComponentFields>,
-    /// Image dimensions
+    /// Image dimensions. The height is `0` until we have read
+    /// the DNL segment, if the frame header did not have it.
     dimensions: (usize, usize),
+    /// Number of MCUs between restart markers, or `0` if there are none
+    restart_interval: u16,
+    /// Number of MCU rows we have room for in the components. If
+    /// the height is not known, this grows as the first scan is decoded.
+    num_mcu_rows: usize,
+    /// The scan being decoded, until all its MCUs are decoded
+    scan: Option<ScanState>,
+    /// Whether to turn MCU rows into pixels as soon as they are decoded,
+    /// if the frame allows it. See `JPEGDecoder::output_rows`.
+    output_rows: bool,
+    /// Whether the components only hold one MCU row of blocks, which is
+    /// turned into pixels as soon as it is decoded.
+    streaming_rows: bool,
+    /// The MCU row at the start of the blocks of the components. Only
+    /// nonzero when `streaming_rows` is set.
+    first_mcu_row: usize,
+    /// MCU rows which are turned into pixels, and not taken yet
+    decoded_rows: VecDeque<MCURow>,
+    /// Each 8x8 block is decoded to this many samples across, so the
+    /// image is scaled by `scale / 8`. See `JPEGDecoder::scale`.
+    scale: usize,
+    /// The rectangle of the scaled image to decode, as `(x, y, width,
+    /// height)`, or `None` for all of it. See `JPEGDecoder::region`.
+    region: Option<(usize, usize, usize, usize)>,
+    /// Whether only the first component is turned into pixels.
+    /// See `JPEGDecoder::luma_only`.
+    luma_only: bool,
+    /// How YCbCr is turned into RGB
+    color_conversion: ColorConversion,
+    /// Whether corrupt entropy coded data is skipped instead of failing.
+    /// See `JPEGDecoder::lenient`.
+    lenient: bool,
+    /// What was wrong with the data, in lenient mode, until taken with
+    /// `take_warnings`
+    warnings: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -49,25 +98,307 @@ struct JPEGDecoderComponentFields {
     horizontal_sampling_factor: u8,
     /// Number of pixels for each sample in horizontal direction (?)
     vertical_sampling_factor: u8,
+    /// Decoded blocks of the component, row by row. Rows are padded
+    /// to a whole number of MCUs, as in interleaved scans.
+    blocks: Vec<Block>,
+    /// Decoded samples of the component in lossless frames, laid out
+    /// like `blocks`.
+    samples: Vec<i32>,
+    /// Point transform of the samples in lossless frames
+    point_transform: u8,
+}
+
+/// The samples of one component of a frame, at the resolution of the
+/// component. In differential frames, these are differences from the
+/// previous frame.
+#[derive(Debug, Clone)]
+pub struct ComponentSamples {
+    pub component: u8,
+    pub horizontal_sampling_factor: u8,
+    pub vertical_sampling_factor: u8,
+    pub width: usize,
+    pub height: usize,
+    pub samples: Vec<i32>,
+}
+
+/// The order of the 64 coefficients of a block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoefficientOrder {
+    /// Row by row, from the top left, as the samples of the block
+    Natural,
+    /// The zigzag order they are coded in. See JPEG Figure A.6.
+    Zigzag,
+}
+
+/// The quantized DCT coefficients of one component of a frame, before
+/// they are dequantized.
+#[derive(Debug, Clone)]
+pub struct ComponentCoefficients {
+    pub component: u8,
+    pub horizontal_sampling_factor: u8,
+    pub vertical_sampling_factor: u8,
+    /// Identifier of the quantization table of the component
+    pub quantization_id: u8,
+    /// The order of the coefficients in `blocks`
+    pub order: CoefficientOrder,
+    /// The quantization table, in the same order as the coefficients
+    pub quantization_table: Vec<u16>,
+    /// Number of blocks needed to cover the component horizontally.
+    /// See JPEG A.1.1.
+    pub width_in_blocks: usize,
+    /// Number of blocks needed to cover the component vertically
+    pub height_in_blocks: usize,
+    /// Number of blocks in each row of `blocks`. Rows are padded to a
+    /// whole number of MCUs, so this may be more than `width_in_blocks`,
+    /// and there may be more rows than `height_in_blocks`.
+    pub blocks_per_line: usize,
+    /// The blocks, row by row
+    pub blocks: Vec<Block>,
+}
+
+/// The pixels of the lines covered by one row of MCUs.
+#[derive(Debug, Clone)]
+pub struct MCURow {
+    /// The first line of the row in the image
+    pub first_line: usize,
+    /// Number of lines in the row. The last row of an image may have
+    /// fewer lines than the others.
+    pub num_lines: usize,
+    /// The pixels of the lines, left to right and top to bottom
+    pub pixels: Vec<(u8, u8, u8)>,
+}
+
+/// What a scan codes, which decides how each block is read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ScanKind {
+    /// All coefficients of the blocks, in sequential frames
+    Sequential,
+    /// The first bits of the DC coefficients, in progressive frames
+    DCFirst,
+    /// One more bit of the DC coefficients, in progressive frames
+    DCRefinement,
+    /// The first bits of a band of AC coefficients, in progressive frames
+    ACFirst,
+    /// One more bit of a band of AC coefficients, in progressive frames
+    ACRefinement,
+    /// Sample differences, in lossless frames
+    Lossless,
+}
+
+/// The most entropy coded data one MCU can take up, in bytes. An MCU has
+/// at most ten blocks, each with at most 64 codes and values of 16 bits
+/// each, which may be doubled by stuffed bytes. With this much data
+/// available, we can decode an MCU without running out of data.
+pub const MAX_MCU_SIZE: usize = 8192;
+
+/// Where the entropy decoder of a scan stopped in the data given so far.
+#[derive(Clone)]
+enum EntropyState {
+    Huffman(huffman::HuffmanState),
+    Arithmetic(arithmetic::ArithmeticState),
+}
+
+/// The entropy decoder of a scan, for Huffman or arithmetic coded frames.
+enum EntropyDecoder<'a> {
+    Huffman(huffman::HuffmanDecoder<'a>),
+    Arithmetic(arithmetic::ArithmeticDecoder<'a>),
+}
+
+impl<'a> EntropyDecoder<'a> {
+    fn resume(data: &'a [u8], state: EntropyState) -> EntropyDecoder<'a> {
+        match state {
+            EntropyState::Huffman(state) => {
+                EntropyDecoder::Huffman(huffman::HuffmanDecoder::resume(data, state))
+            }
+            EntropyState::Arithmetic(state) => {
+                EntropyDecoder::Arithmetic(arithmetic::ArithmeticDecoder::resume(data, state))
+            }
+        }
+    }
+
+    fn suspend(self) -> (EntropyState, usize) {
+        match self {
+            EntropyDecoder::Huffman(decoder) => {
+                let (state, used) = decoder.suspend();
+                (EntropyState::Huffman(state), used)
+            }
+            EntropyDecoder::Arithmetic(decoder) => {
+                let (state, used) = decoder.suspend();
+                (EntropyState::Arithmetic(state), used)
+            }
+        }
+    }
+
+    fn next_index(&self) -> usize {
+        match *self {
+            EntropyDecoder::Huffman(ref decoder) => decoder.next_index(),
+            EntropyDecoder::Arithmetic(ref decoder) => decoder.next_index(),
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        match *self {
+            EntropyDecoder::Huffman(ref decoder) => decoder.remaining(),
+            EntropyDecoder::Arithmetic(ref decoder) => decoder.remaining(),
+        }
+    }
+
+    fn at_end(&mut self) -> bool {
+        match *self {
+            EntropyDecoder::Huffman(ref mut decoder) => decoder.at_end(),
+            EntropyDecoder::Arithmetic(ref decoder) => decoder.at_end(),
+        }
+    }
+
+    fn marker(&self) -> Option<u8> {
+        match *self {
+            EntropyDecoder::Huffman(ref decoder) => decoder.marker(),
+            EntropyDecoder::Arithmetic(ref decoder) => decoder.marker(),
+        }
+    }
+
+    /// Whether the decoder has read past the end of the data. Arithmetic
+    /// decoders may do so in well formed data, so they never tell.
+    fn overrun(&self) -> bool {
+        match *self {
+            EntropyDecoder::Huffman(ref decoder) => decoder.overrun(),
+            EntropyDecoder::Arithmetic(_) => false,
+        }
+    }
+
+    fn resynchronize(&mut self) -> bool {
+        match *self {
+            EntropyDecoder::Huffman(ref mut decoder) => decoder.resynchronize(),
+            EntropyDecoder::Arithmetic(ref mut decoder) => decoder.resynchronize(),
+        }
+    }
+
+    fn restart(&mut self) -> Result<(), String> {
+        match *self {
+            EntropyDecoder::Huffman(ref mut decoder) => decoder.restart(),
+            EntropyDecoder::Arithmetic(ref mut decoder) => decoder.restart(),
+        }
+    }
+
+    fn skip_to_marker(&mut self) -> bool {
+        match *self {
+            EntropyDecoder::Huffman(ref mut decoder) => decoder.skip_to_marker(),
+            EntropyDecoder::Arithmetic(ref mut decoder) => decoder.skip_to_marker(),
+        }
+    }
+
+    fn skip_to_end(&mut self) -> bool {
+        match *self {
+            EntropyDecoder::Huffman(ref mut decoder) => decoder.skip_to_end(),
+            EntropyDecoder::Arithmetic(ref mut decoder) => decoder.skip_to_end(),
+        }
+    }
+}
+
+/// State kept while decoding a scan.
+struct ScanState {
+    /// Where the entropy decoder stopped in the data given so far
+    entropy_state: EntropyState,
+    kind: ScanKind,
+    /// Indices in `component_fields` of the components in the scan,
+    /// in scan order.
+    components: Vec<usize>,
+    /// AC and DC Huffman tables of each component in the scan
+    tables: Vec<(Option<huffman::HuffmanTable>, Option<huffman::HuffmanTable>)>,
+    /// The previous DC coefficient of each component in the scan
+    previous: Vec<i32>,
+    start_spectral_selection: usize,
+    end_spectral_selection: usize,
+    point_transform: u8,
+    /// The row where the current restart interval started, for each
+    /// component in lossless scans.
+    interval_start_row: Vec<usize>,
+    /// Whether the next sample of each component in a lossless scan is
+    /// the first one of a restart interval.
+    interval_start: Vec<bool>,
+    /// Number of MCUs decoded so far
+    mcu: usize,
+    /// Number of MCUs in the scan, or `None` if the height of the
+    /// image is not known yet.
+    num_mcus: Option<usize>,
+    /// The restart interval the decoder is in, counted from `0`
+    interval: usize,
+    /// The MCU where the data turned out to be corrupt and the error,
+    /// until we have found the marker to continue at, in lenient mode
+    corruption: Option<(usize, String)>,
+}
+
+impl ScanState {
+    /// Start a new restart interval, at block `x` of a row. `rows` is the
+    /// current row of each component in the scan.
+    fn restart(&mut self,
+               decoder: &mut EntropyDecoder,
+               x: usize,
+               rows: &[usize])
+               -> Result<(), String> {
+        // JPEG F.2.1.3.1: at the start of each restart interval the
+        // decoder is reset, and the data is aligned to a byte boundary.
+        decoder.restart()?;
+        for previous in self.previous.iter_mut() {
+            *previous = 0;
+        }
+        if self.kind == ScanKind::Lossless {
+            // Lossless predictions depend on where the interval starts.
+            if x != 0 {
+                return Err("Restart intervals must start at a new line in lossless scans"
+                    .to_string());
+            }
+            self.interval_start_row = rows.to_vec();
+            for start in self.interval_start.iter_mut() {
+                *start = true;
+            }
+        }
+        Ok(())
+    }
 }
 
-impl<'a> JPEGDecoder<'a> {
-    pub fn new(data: &'a [u8]) -> JPEGDecoder {
+impl Default for JPEGDecoder {
+    fn default() -> JPEGDecoder {
+        JPEGDecoder::new()
+    }
+}
+
+impl JPEGDecoder {
+    pub fn new() -> JPEGDecoder {
         JPEGDecoder {
-            data: data,
+            frame_type: Marker::BaselineDCT,
+            sample_precision: 8,
             huffman_ac_tables: [None, None, None, None],
             huffman_dc_tables: [None, None, None, None],
+            arithmetic_conditioning: arithmetic::ArithmeticConditioning::default(),
             quantization_tables: [None, None, None, None],
             component_fields: Vec::new(),
             dimensions: (0, 0),
+            restart_interval: 0,
+            num_mcu_rows: 0,
+            scan: None,
+            output_rows: false,
+            streaming_rows: false,
+            first_mcu_row: 0,
+            decoded_rows: VecDeque::new(),
+            scale: 8,
+            region: None,
+            luma_only: false,
+            color_conversion: ColorConversion::default(),
+            lenient: false,
+            warnings: Vec::new(),
         }
     }
 
-    pub fn dimensions(mut self, dimensions: (usize, usize)) -> JPEGDecoder<'a> {
+    pub fn dimensions(mut self, dimensions: (usize, usize)) -> JPEGDecoder {
         self.dimensions = dimensions;
         self
     }
 
+    pub fn restart_interval(&mut self, restart_interval: u16) {
+        self.restart_interval = restart_interval;
+    }
+
     pub fn huffman_ac_tables(&mut self, id: u8, table: huffman::HuffmanTable) {
         self.huffman_ac_tables[id as usize] = Some(table);
     }
@@ -76,102 +407,235 @@ impl<'a> JPEGDecoder<'a> {
         self.huffman_dc_tables[id as usize] = Some(table);
     }
 
-    pub fn quantization_table(&mut self, id: u8, table: Vec<u16>) {
-        self.quantization_tables[id as usize] = Some(table);
+    pub fn arithmetic_conditioning(&mut self, conditioning: arithmetic::ArithmeticConditioning) {
+        self.arithmetic_conditioning = conditioning;
     }
 
-    pub fn frame_header(mut self, frame_header: FrameHeader) -> JPEGDecoder<'a> {
-        for frame_component in &frame_header.frame_components {
-            // Update horiz/vert sampling factor, and quant selector.
-            let was_none = self.component_fields
-                .iter_mut()
-                .find(|cf| cf.component == frame_component.component_id)
-                .as_mut()
-                .map(|cf| {
-                    cf.horizontal_sampling_factor = frame_component.horizontal_sampling_factor;
-                    cf.vertical_sampling_factor = frame_component.vertical_sampling_factor;
-                    cf.quantization_id = frame_component.quantization_selector;
-                })
-                .is_none();
-            // Or insert a new element, if none was found.
-            if was_none {
-                self.component_fields.push({
-                    JPEGDecoderComponentFields {
-                        component: frame_component.component_id,
-                        horizontal_sampling_factor: frame_component.horizontal_sampling_factor,
-                        vertical_sampling_factor: frame_component.vertical_sampling_factor,
-                        quantization_id: frame_component.quantization_selector,
-                        dc_table_id: 0xff,
-                        ac_table_id: 0xff,
-                    }
-                });
-            }
-        }
-        self
+    pub fn quantization_table(&mut self, id: u8, table: Vec<u16>) {
+        self.quantization_tables[id as usize] = Some(table);
     }
 
-    pub fn scan_header(mut self, scan_header: ScanHeader) -> JPEGDecoder<'a> {
-        for scan_component in &scan_header.scan_components {
-            // Update horiz/vert sampling factor, and quant selector.
-            let was_none = self.component_fields
-                .iter_mut()
-                .find(|cf| cf.component == scan_component.component_id)
-                .as_mut()
-                .map(|cf| {
-                    cf.ac_table_id = scan_component.ac_table_selector;
-                    cf.dc_table_id = scan_component.dc_table_selector;
-                })
-                .is_none();
-            // Or insert a new element, if none was found.
-            if was_none {
-                self.component_fields.push({
-                    JPEGDecoderComponentFields {
-                        component: scan_component.component_id,
-                        horizontal_sampling_factor: 0xff,
-                        vertical_sampling_factor: 0xff,
-                        quantization_id: 0xff,
-                        dc_table_id: scan_component.ac_table_selector,
-                        ac_table_id: scan_component.dc_table_selector,
-                    }
-                });
-            }
-        }
-        // The order of the components is the order from scan_header.
-        // Make sure this is the case.
-        self.component_fields = scan_header.scan_components
+    pub fn frame_header(mut self, frame_header: FrameHeader) -> JPEGDecoder {
+        self.frame_type = frame_header.frame_type;
+        self.sample_precision = frame_header.sample_precision;
+        self.component_fields = frame_header.frame_components
             .iter()
-            .map(|scan_component| {
-                self.component_fields
-                    .iter()
-                    .find(|cf| cf.component == scan_component.component_id)
-                    .cloned()
-                    .unwrap()
+            .map(|frame_component| {
+                JPEGDecoderComponentFields {
+                    component: frame_component.component_id,
+                    horizontal_sampling_factor: frame_component.horizontal_sampling_factor,
+                    vertical_sampling_factor: frame_component.vertical_sampling_factor,
+                    quantization_id: frame_component.quantization_selector,
+                    dc_table_id: 0xff,
+                    ac_table_id: 0xff,
+                    blocks: Vec::new(),
+                    samples: Vec::new(),
+                    point_transform: 0,
+                }
             })
             .collect();
         self
     }
 
-    fn ac_table(&'a self, id: u8) -> &'a huffman::HuffmanTable {
-        self.huffman_ac_tables[id as usize].as_ref().unwrap()
+    /// Turn each MCU row into pixels as soon as it is decoded, and only
+    /// keep the blocks of one MCU row, so that images of any size can be
+    /// decoded in little memory. Take the rows with `take_mcu_row`.
+    ///
+    /// This is only possible when the first scan of a sequential frame
+    /// has all components, as no later scan can then change the rows.
+    /// Otherwise the frame is decoded as usual.
+    pub fn output_rows(&mut self) {
+        self.output_rows = true;
     }
 
-    fn dc_table(&'a self, id: u8) -> &'a huffman::HuffmanTable {
-        self.huffman_dc_tables[id as usize].as_ref().unwrap()
+    /// Decode the image at `scale / 8` of its size, for a `scale` from 1
+    /// to 16, with inverse DCTs of `scale` x `scale` samples instead of
+    /// upsampling. Scaling down skips most of the work of the inverse
+    /// DCTs; a `scale` of 1 only takes the DC coefficient of each block.
+    /// Only DCT frames which are not differential can be scaled.
+    pub fn scale(&mut self, scale: usize) -> Result<(), String> {
+        if !(1..=16).contains(&scale) {
+            return Err(format!("Can't scale images by {}/8", scale));
+        }
+        self.scale = scale;
+        Ok(())
     }
 
-    pub fn decode(&mut self) -> (Vec<(u8, u8, u8)>, usize) {
-        // Number of blocks in x and y direction
-        let num_blocks_x = (self.dimensions.0 + 7) / 8;
-        let num_blocks_y = (self.dimensions.1 + 7) / 8;
-        let num_blocks = num_blocks_x * num_blocks_y;
-        let num_components = self.component_fields.len();
+    /// Only turn the first component into pixels, which is the luma of
+    /// YCbCr images, and give gray pixels. The other components are not
+    /// dequantized, transformed or converted, and scans which only have
+    /// other components are skipped without decoding them. Interleaved
+    /// scans still have to be decoded in full.
+    pub fn luma_only(&mut self) {
+        self.luma_only = true;
+    }
 
-        // 2D vector, one vector for each component.
-        let mut blocks: Vec<Vec<Block>> = (0..self.component_fields.len())
-            .map(|_| vec![])
-            .collect();
-        let mut previous_dc: Vec<f32> = repeat(0.0).take(self.component_fields.len()).collect();
+    /// Convert YCbCr to RGB with `conversion` instead of the BT.601 in
+    /// full range of JFIF, for images made from video frames.
+    pub fn color_conversion(&mut self, conversion: ColorConversion) {
+        self.color_conversion = conversion;
+    }
+
+    /// Decode as much as possible of entropy coded data which is corrupt
+    /// or cut short, instead of failing. When an MCU can't be decoded,
+    /// the data is skipped up to the next marker. If it is a restart
+    /// marker, decoding continues with the restart interval it starts,
+    /// and otherwise the scan ends there. The MCUs in between are gray
+    /// in sequential scans, and keep what earlier scans decoded in
+    /// progressive ones. Take the warnings with `take_warnings`.
+    ///
+    /// Data which is cut short is only found in Huffman coded scans,
+    /// and at restart markers in arithmetic coded ones, as arithmetic
+    /// decoders may read past the end of well formed data.
+    pub fn lenient(&mut self) {
+        self.lenient = true;
+    }
+
+    /// Take what was found to be wrong with the data so far, in lenient
+    /// mode. See `JPEGDecoder::lenient`.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        mem::take(&mut self.warnings)
+    }
+
+    /// Only decode the `width` x `height` rectangle at `(x, y)` of the
+    /// image, as it is scaled. Blocks outside the rectangle are not turned
+    /// into pixels, and in DCT frames the entropy coded data is skipped
+    /// after the last MCU of the rectangle, and at restart markers when a
+    /// whole restart interval is outside it. The decoded image is then
+    /// the rectangle.
+    pub fn region(&mut self,
+                  x: usize,
+                  y: usize,
+                  width: usize,
+                  height: usize)
+                  -> Result<(), String> {
+        self.region = Some((x, y, width, height));
+        self.check_region()
+    }
+
+    /// Check that the region is inside the image, as far as its size
+    /// is known.
+    fn check_region(&self) -> Result<(), String> {
+        let (x, y, width, height) = match self.region {
+            Some(region) => region,
+            None => return Ok(()),
+        };
+        let (image_width, image_height) = self.scaled_size();
+        if width == 0 || height == 0 || x + width > image_width ||
+           (image_height != 0 && y + height > image_height) {
+            return Err(format!("Region of {}x{} pixels at ({}, {}) is not inside the image of \
+                                {}x{} pixels",
+                               width,
+                               height,
+                               x,
+                               y,
+                               image_width,
+                               image_height));
+        }
+        Ok(())
+    }
+
+    /// Width and height of the decoded image, which is the region if one
+    /// is set, or else the image scaled by `scale / 8`.
+    pub fn output_dimensions(&self) -> (usize, usize) {
+        match self.region {
+            Some((_, _, width, height)) => (width, height),
+            None => self.scaled_size(),
+        }
+    }
+
+    /// Width and height of the image scaled by `scale / 8`, rounded up.
+    fn scaled_size(&self) -> (usize, usize) {
+        let block_size = self.scaled_block_size();
+        ((self.dimensions.0 * block_size).div_ceil(self.block_size()),
+         (self.dimensions.1 * block_size).div_ceil(self.block_size()))
+    }
+
+    /// Whether MCU rows are turned into pixels as they are decoded.
+    /// Known when the first scan is started.
+    pub fn is_streaming_rows(&self) -> bool {
+        self.streaming_rows
+    }
+
+    /// Take the next MCU row which is turned into pixels, when
+    /// `is_streaming_rows` is set.
+    pub fn take_mcu_row(&mut self) -> Option<MCURow> {
+        self.decoded_rows.pop_front()
+    }
+
+    /// The height of the image was not in the frame header, and is
+    /// given by a DNL segment after the first scan. See JPEG B.2.5.
+    pub fn define_number_of_lines(&mut self, num_lines: usize) -> Result<(), String> {
+        if num_lines == 0 {
+            return Err("DNL segment defines zero lines".to_string());
+        }
+        self.dimensions.1 = num_lines;
+        let num_mcu_rows = self.mcu_rows();
+        if num_mcu_rows > self.num_mcu_rows {
+            return Err(format!("DNL segment defines {} lines, but the first scan only has {}",
+                               num_lines,
+                               self.num_mcu_rows * self.block_size() *
+                               self.max_sampling_factors().1));
+        }
+        self.allocate_mcu_rows(num_mcu_rows);
+        Ok(())
+    }
+
+    pub fn is_progressive(&self) -> bool {
+        matches!(self.frame_type,
+                 Marker::ProgressiveDCT | Marker::DifferentialProgressiveDCT |
+                 Marker::ProgressiveDCTArithmetic |
+                 Marker::DifferentialProgressiveDCTArithmetic)
+    }
+
+    pub fn is_lossless(&self) -> bool {
+        self.frame_type.is_lossless()
+    }
+
+    pub fn is_arithmetic(&self) -> bool {
+        self.frame_type.is_arithmetic_coded()
+    }
+
+    /// Whether the frame is a differential frame in a hierarchical image.
+    pub fn is_differential(&self) -> bool {
+        matches!(self.frame_type,
+                 Marker::DifferentialSequentialDCT | Marker::DifferentialProgressiveDCT |
+                 Marker::DifferentialLossless |
+                 Marker::DifferentialSequentialDCTArithmetic |
+                 Marker::DifferentialProgressiveDCTArithmetic |
+                 Marker::DifferentialLosslessArithmetic)
+    }
+
+    /// Width and height of the units the components are coded in; blocks
+    /// of 8x8 samples in DCT frames, and single samples in lossless frames.
+    fn block_size(&self) -> usize {
+        if self.is_lossless() { 1 } else { 8 }
+    }
+
+    /// Width and height of the units the components are coded in, once
+    /// they are scaled.
+    fn scaled_block_size(&self) -> usize {
+        if self.is_lossless() { 1 } else { self.scale }
+    }
 
+    /// Number of lines each MCU row has in the scaled image.
+    fn lines_per_mcu_row(&self) -> usize {
+        self.scaled_block_size() * self.max_sampling_factors().1
+    }
+
+    /// The columns and rows of MCUs which cover the region.
+    fn region_mcus(&self) -> (Range<usize>, Range<usize>) {
+        let (width, height) = self.scaled_size();
+        let (x, y, region_width, region_height) = self.region.unwrap_or((0, 0, width, height));
+        let (max_h, max_v) = self.max_sampling_factors();
+        let mcu_width = self.scaled_block_size() * max_h;
+        let mcu_height = self.scaled_block_size() * max_v;
+        (x / mcu_width..(x + region_width).div_ceil(mcu_width),
+         y / mcu_height..(y + region_height).div_ceil(mcu_height))
+    }
+
+    /// The largest horizontal and vertical sampling factors in the frame.
+    fn max_sampling_factors(&self) -> (usize, usize) {
         let max_block_hori_scale = self.component_fields
             .iter()
             .map(|c| c.horizontal_sampling_factor)
@@ -184,229 +648,1138 @@ impl<'a> JPEGDecoder<'a> {
             .max()
             .unwrap_or(1) as usize;
 
-        let block_factor = max_block_hori_scale * max_block_vert_scale;
+        (max_block_hori_scale, max_block_vert_scale)
+    }
+
+    /// Number of MCUs in each row of the image.
+    fn mcus_per_line(&self) -> usize {
+        self.dimensions.0.div_ceil(self.block_size() * self.max_sampling_factors().0)
+    }
+
+    /// Number of MCU rows in the image, or `0` if the height is not known.
+    fn mcu_rows(&self) -> usize {
+        self.dimensions.1.div_ceil(self.block_size() * self.max_sampling_factors().1)
+    }
 
-        let mut huffman_decoder = huffman::HuffmanDecoder::new(self.data);
+    /// Number of blocks in each row of a component, including the
+    /// blocks padding the last MCU.
+    fn blocks_per_line(&self, component: &JPEGDecoderComponentFields) -> usize {
+        self.mcus_per_line() * component.horizontal_sampling_factor as usize
+    }
 
-        let skip_factor = max_block_vert_scale * max_block_hori_scale;
-        let num_read_blocks = (num_blocks + skip_factor - 1) / skip_factor;
+    /// Number of blocks needed to cover the samples of a component,
+    /// horizontally and vertically. See JPEG A.1.1.
+    fn component_blocks(&self, component: &JPEGDecoderComponentFields) -> (usize, usize) {
+        let (max_h, max_v) = self.max_sampling_factors();
+        let h = component.horizontal_sampling_factor as usize;
+        let v = component.vertical_sampling_factor as usize;
+        let x_i = (self.dimensions.0 * h).div_ceil(max_h);
+        let y_i = (self.dimensions.1 * v).div_ceil(max_v);
+        (x_i.div_ceil(self.block_size()), y_i.div_ceil(self.block_size()))
+    }
 
-        // Step 1: Read encoded data
-        for _ in 0..num_read_blocks {
-            for (component_i, component) in self.component_fields.iter().enumerate() {
-                let ac_table = self.ac_table(component.ac_table_id);
-                let dc_table = self.dc_table(component.dc_table_id);
+    /// Make room for `num_mcu_rows` rows of MCUs in all components.
+    fn allocate_mcu_rows(&mut self, num_mcu_rows: usize) {
+        let mcus_per_line = self.mcus_per_line();
+        let is_lossless = self.is_lossless();
+        for component in &mut self.component_fields {
+            let num_blocks = num_mcu_rows * mcus_per_line *
+                             component.horizontal_sampling_factor as usize *
+                             component.vertical_sampling_factor as usize;
+            if is_lossless {
+                component.samples.resize(num_blocks, 0);
+            } else {
+                component.blocks.resize(num_blocks, [0; 64]);
+            }
+        }
+        self.num_mcu_rows = num_mcu_rows;
+    }
 
-                for _ in 0..(component.horizontal_sampling_factor *
-                             component.vertical_sampling_factor) {
-                    let mut decoded_block: Vec<f32> = huffman_decoder.next_block(ac_table, dc_table)
-                        .iter()
-                        .map(|&i| i as f32)
-                        .collect();
+    /// Decode the entropy coded `data` of a scan.
+    ///
+    /// If the height of the image is not known yet, the image grows one
+    /// MCU row at a time until we reach the end of the data.
+    pub fn decode_scan(&mut self, scan_header: &ScanHeader, data: &[u8]) -> Result<(), String> {
+        self.start_scan(scan_header)?;
+        self.decode_mcus(data, true)?;
+        Ok(())
+    }
+
+    /// Start decoding a scan, whose data is given to `decode_mcus`.
+    pub fn start_scan(&mut self, scan_header: &ScanHeader) -> Result<(), String> {
+        let (max_h, max_v) = self.max_sampling_factors();
+        for component in &self.component_fields {
+            if max_h % component.horizontal_sampling_factor as usize != 0 ||
+               max_v % component.vertical_sampling_factor as usize != 0 {
+                return Err("Sampling factors which are not divisors of the largest \
+                            sampling factor are not supported"
+                    .to_string());
+            }
+        }
+        let kind = self.scan_kind(scan_header)?;
 
-                    // DC correction
-                    let encoded = decoded_block[0];
-                    decoded_block[0] = encoded + previous_dc[component_i];
-                    previous_dc[component_i] = decoded_block[0];
+        let mut scan_components = Vec::with_capacity(scan_header.scan_components.len());
+        for scan_component in &scan_header.scan_components {
+            let index = match self.component_fields
+                .iter()
+                .position(|cf| cf.component == scan_component.component_id) {
+                Some(index) => index,
+                None => {
+                    return Err(format!("Scan has component {}, which is not in the frame",
+                                       scan_component.component_id))
+                }
+            };
+            let component = &mut self.component_fields[index];
+            component.ac_table_id = scan_component.ac_table_selector;
+            component.dc_table_id = scan_component.dc_table_selector;
+            if kind == ScanKind::Lossless {
+                component.point_transform = scan_header.successive_approximation_bit_pos_low;
+            }
+            scan_components.push(index);
+        }
+        if (kind == ScanKind::ACFirst || kind == ScanKind::ACRefinement) &&
+           scan_components.len() > 1 {
+            return Err("Progressive scans of AC coefficients must have one component"
+                .to_string());
+        }
 
-                    blocks[component_i].push(decoded_block);
+        // Look up the tables once, so we don't have to borrow `self` for each block.
+        // Which tables the scan needs depends on what it codes.
+        // Arithmetic coding has no tables to look up, only statistics
+        // which are kept by the decoder.
+        let needs_ac_table = !self.is_arithmetic() &&
+                             matches!(kind,
+                                      ScanKind::Sequential | ScanKind::ACFirst |
+                                      ScanKind::ACRefinement);
+        let needs_dc_table = !self.is_arithmetic() &&
+                             matches!(kind,
+                                      ScanKind::Sequential | ScanKind::DCFirst |
+                                      ScanKind::Lossless);
+        let mut tables = Vec::with_capacity(scan_components.len());
+        for &index in &scan_components {
+            let component = &self.component_fields[index];
+            let ac_table = self.huffman_ac_tables
+                .get(component.ac_table_id as usize)
+                .and_then(|t| t.clone());
+            let dc_table = self.huffman_dc_tables
+                .get(component.dc_table_id as usize)
+                .and_then(|t| t.clone());
+            if (needs_ac_table && ac_table.is_none()) || (needs_dc_table && dc_table.is_none()) {
+                return Err(format!("Missing huffman table for component {}",
+                                   component.component));
+            }
+            tables.push((ac_table, dc_table));
+        }
+        let entropy_state = if self.is_arithmetic() {
+            for &index in &scan_components {
+                let component = &self.component_fields[index];
+                if component.ac_table_id > 3 || component.dc_table_id > 3 {
+                    return Err(format!("Invalid table selector for component {}",
+                                       component.component));
                 }
             }
+            if kind == ScanKind::Lossless {
+                return Err("Decoding of arithmetic coded lossless frames is not supported"
+                    .to_string());
+            }
+            // The decoder reads ahead, so it can't find the end of the
+            // data to count the lines by.
+            if self.dimensions.1 == 0 {
+                return Err("Arithmetic coded frames with a DNL segment are not supported"
+                    .to_string());
+            }
+            EntropyState::Arithmetic(arithmetic::ArithmeticState::new(scan_components.len(),
+                                                                      self.arithmetic_conditioning))
+        } else {
+            EntropyState::Huffman(huffman::HuffmanState::default())
+        };
+
+        if self.streaming_rows {
+            return Err("Found another scan after a scan with all components of a sequential \
+                        frame"
+                .to_string());
         }
+        let num_mcus = if self.dimensions.1 == 0 {
+            None
+        } else {
+            if self.output_rows && self.num_mcu_rows == 0 && kind == ScanKind::Sequential &&
+               !self.is_differential() &&
+               scan_components.len() == self.component_fields.len() {
+                self.streaming_rows = true;
+                self.allocate_mcu_rows(1);
+            } else if self.num_mcu_rows < self.mcu_rows() {
+                let num_mcu_rows = self.mcu_rows();
+                self.allocate_mcu_rows(num_mcu_rows);
+            }
+            if scan_components.len() == 1 {
+                // A non-interleaved scan, where each MCU is a single block.
+                // Only the blocks covering the component are coded, without
+                // the padding of interleaved MCUs. See JPEG A.2.2.
+                let (num_blocks_x, num_blocks_y) =
+                    self.component_blocks(&self.component_fields[scan_components[0]]);
+                Some(num_blocks_x * num_blocks_y)
+            } else {
+                Some(self.mcus_per_line() * self.mcu_rows())
+            }
+        };
 
-        // Step 2: get color data
-        // Now all decoded blocks are in `blocks`.
-        // For each block, do dequantization, reverse zigzag, and inverse DCT.
-        let mut image_data = (0..num_components).map(|_| vec![]).collect::<Vec<_>>();
-        for (component_i, component) in self.component_fields.iter().enumerate() {
-            let quant_table = self.quantization_tables[component.quantization_id as usize]
-                .as_ref()
-                .expect(&format!("Did not find quantization table for {}",
-                                 component.quantization_id));
+        let num_components = scan_components.len();
+        self.scan = Some(ScanState {
+            entropy_state,
+            kind,
+            components: scan_components,
+            tables,
+            previous: repeat_n(0, num_components).collect(),
+            start_spectral_selection: scan_header.start_spectral_selection as usize,
+            end_spectral_selection: scan_header.end_spectral_selection as usize,
+            point_transform: scan_header.successive_approximation_bit_pos_low,
+            interval_start_row: repeat_n(0, num_components).collect(),
+            interval_start: repeat_n(true, num_components).collect(),
+            mcu: 0,
+            num_mcus,
+            interval: 0,
+            corruption: None,
+        });
+        Ok(())
+    }
 
-            let component_blocks: Vec<Vec<f32>> = blocks[component_i]
-                .iter()
-                .map(|block| {
-                    zigzag_inverse(block.iter()
-                        .zip(quant_table.iter())
-                        .map(|(&n, &q)| n * q as f32))
-                })
-                .map(|block| transform::discrete_cosine_transform_inverse(&block))
-                .collect();
+    /// Whether a scan is started, and not all of its MCUs are decoded.
+    pub fn scan_in_progress(&self) -> bool {
+        self.scan.is_some()
+    }
+
+    /// Number of lines of the image which the current scan has decoded
+    /// so far, or all lines if no scan is in progress.
+    pub fn decoded_lines(&self) -> usize {
+        let scan = match self.scan {
+            Some(ref scan) => scan,
+            None => return self.output_dimensions().1,
+        };
+        let mut num_lines = self.completed_mcu_rows(scan) * self.lines_per_mcu_row();
+        if self.dimensions.1 != 0 {
+            num_lines = num_lines.min(self.scaled_size().1);
+        }
+        match self.region {
+            Some((_, y, _, height)) => num_lines.saturating_sub(y).min(height),
+            None => num_lines,
+        }
+    }
 
+    /// Number of MCU rows a scan has decoded all its blocks in.
+    fn completed_mcu_rows(&self, scan: &ScanState) -> usize {
+        if scan.components.len() == 1 {
+            let component = &self.component_fields[scan.components[0]];
+            let (num_blocks_x, _) = self.component_blocks(component);
+            scan.mcu / num_blocks_x / component.vertical_sampling_factor as usize
+        } else {
+            scan.mcu / self.mcus_per_line()
+        }
+    }
 
-            // See JPEG A.1.1
-            let x_i = (self.dimensions.0 as f32 *
-                       (component.horizontal_sampling_factor as f32 / max_block_hori_scale as f32))
-                .ceil();
-
-            let y_i = (self.dimensions.1 as f32 *
-                       (component.vertical_sampling_factor as f32 / max_block_vert_scale as f32))
-                .ceil();
-
-            // `?_factor` are how many times each block needs to be repeated
-            // in its direction.
-            let x_factor = (self.dimensions.0 as f32 / x_i).ceil() as usize;
-            let y_factor = (self.dimensions.1 as f32 / y_i).ceil() as usize;
-            let stride = self.dimensions.0;
-
-            let num_pixels = self.dimensions.0 * self.dimensions.1;
-            let mut data = repeat(0.0)
-                .take(num_pixels)
-                .collect::<Vec<f32>>();
-            let mut block_i = 0;
-
-            let get_indices =
-                |x, y, max_x, max_y, x_factor, y_factor, max_x_factor, max_y_factor| {
-                    if max_y_factor > 1 && y_factor == 1 {
-                        if max_x_factor > 1 && x_factor == 1 {
-                            let is_upper = y & 1 == 0;
-                            if is_upper {
-                                let move_down = (x / 2) & 1 == 1;
-                                if move_down {
-                                    return (x / 2 - 1 + (x & 1), y + 1);
-                                } else {
-                                    return (x / 2 + (x & 1), y);
-                                }
-                            } else {
-                                let move_up = y > 0 && (x / 2) & 1 == 0;
-                                if move_up {
-                                    return (max_x / 2 + x / 2 - 1 + (x & 1), y);
-                                } else {
-                                    return (max_x / 2 + x / 2 + (x & 1), y - 1);
-                                }
-                            }
-                        } else {
-                            if y & 1 == 0 {
-                                return (x / 2, y + (x & 1));
-                            } else {
-                                return (x / 2 + max_x / 2, y - (x & 1));
+    /// Turn the MCU rows which `scan` has completed into pixels, and
+    /// make room for the next row. At the end of the scan, all rows
+    /// are complete.
+    fn output_mcu_rows(&mut self, scan: &ScanState, end_of_scan: bool) -> Result<(), String> {
+        let num_mcu_rows = if end_of_scan {
+            self.mcu_rows()
+        } else {
+            self.completed_mcu_rows(scan)
+        };
+        while self.first_mcu_row < num_mcu_rows {
+            let row = self.mcu_row(self.first_mcu_row)?;
+            if row.num_lines > 0 {
+                self.decoded_rows.push_back(row);
+            }
+            self.first_mcu_row += 1;
+            for component in &mut self.component_fields {
+                for block in &mut component.blocks {
+                    *block = [0; 64];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode MCUs of the current scan from the next piece of its entropy
+    /// coded `data`, and return how many bytes of `data` are used. The
+    /// bytes which are not used must be given again in the next call.
+    ///
+    /// Unless `end_of_data` is set, MCUs are only decoded when they are
+    /// sure to be complete in `data`, so it is fine to split the data
+    /// anywhere. When rows are output as they are decoded, we also stop
+    /// after each row, until it is taken with `take_mcu_row`.
+    pub fn decode_mcus(&mut self, data: &[u8], end_of_data: bool) -> Result<usize, String> {
+        let mut scan = match self.scan.take() {
+            Some(scan) => scan,
+            None => return Err("No scan is started".to_string()),
+        };
+        let complete = end_of_data || huffman::has_end_marker(data);
+        let mut decoder = EntropyDecoder::resume(data, scan.entropy_state.clone());
+        loop {
+            if scan.num_mcus.is_some_and(|num_mcus| scan.mcu >= num_mcus) {
+                break;
+            }
+            if !complete && decoder.remaining() < MAX_MCU_SIZE {
+                return Ok(self.suspend_scan(scan, decoder));
+            }
+            if scan.num_mcus.is_none() && decoder.at_end() {
+                break;
+            }
+            // After corrupt data, we first look for the marker to continue at.
+            let skippable_mcus = if scan.corruption.is_some() {
+                None
+            } else {
+                self.skippable_mcus(&scan)
+            };
+            match skippable_mcus {
+                Some(num_mcus) => {
+                    let found = if scan.num_mcus.is_some_and(|n| scan.mcu + num_mcus >= n) {
+                        decoder.skip_to_end()
+                    } else {
+                        decoder.skip_to_marker()
+                    };
+                    if !found {
+                        if complete {
+                            break;
+                        }
+                        return Ok(self.suspend_scan(scan, decoder));
+                    }
+                    scan.mcu += num_mcus;
+                }
+                None if scan.corruption.is_some() => {
+                    if !decoder.resynchronize() && !complete {
+                        return Ok(self.suspend_scan(scan, decoder));
+                    }
+                    self.skip_corrupt_mcus(&mut scan, &decoder);
+                }
+                None => {
+                    match self.decode_mcu(&mut scan, &mut decoder) {
+                        Ok(()) if decoder.overrun() => {
+                            let error = "The data ends early".to_string();
+                            if !self.lenient {
+                                return Err(format!("{} in MCU {}", error, scan.mcu));
                             }
+                            scan.corruption = Some((scan.mcu, error));
                         }
+                        Ok(()) => scan.mcu += 1,
+                        Err(error) if self.lenient => scan.corruption = Some((scan.mcu, error)),
+                        Err(error) => return Err(error),
                     }
-                    (x, y)
+                }
+            }
+            if self.streaming_rows {
+                self.output_mcu_rows(&scan, false)?;
+                if !self.decoded_rows.is_empty() {
+                    // Let the row be taken before we decode more.
+                    return Ok(self.suspend_scan(scan, decoder));
+                }
+            }
+        }
+        if self.streaming_rows {
+            self.output_mcu_rows(&scan, true)?;
+        }
+        if let EntropyDecoder::Arithmetic(ref mut decoder) = decoder {
+            // The decoder may not have read the last bytes of the data.
+            decoder.skip_to_end();
+        }
+        Ok(decoder.next_index())
+    }
+
+    /// Number of MCUs from the next MCU of `scan` on whose data can be
+    /// skipped, as they are outside the region: either the rest of the
+    /// scan, or a restart interval. `None` if the next MCU is decoded.
+    fn skippable_mcus(&self, scan: &ScanState) -> Option<usize> {
+        let num_mcus = scan.num_mcus?;
+        if self.luma_only && !scan.components.contains(&0) {
+            return Some(num_mcus - scan.mcu);
+        }
+        if self.region.is_none() || self.is_lossless() {
+            return None;
+        }
+        let (columns, rows) = self.region_mcus();
+        // The MCU column and row of each MCU of the scan. In
+        // non-interleaved scans, the MCUs are single blocks.
+        let (num_blocks_x, h, v) = if scan.components.len() == 1 {
+            let component = &self.component_fields[scan.components[0]];
+            (self.component_blocks(component).0,
+             component.horizontal_sampling_factor as usize,
+             component.vertical_sampling_factor as usize)
+        } else {
+            (self.mcus_per_line(), 1, 1)
+        };
+        let position = |mcu: usize| ((mcu % num_blocks_x) / h, (mcu / num_blocks_x) / v);
+        let in_region = |mcu: usize| {
+            let (x, y) = position(mcu);
+            columns.contains(&x) && rows.contains(&y)
+        };
+
+        let last_x = (columns.end * h).min(num_blocks_x) - 1;
+        let last_y = rows.end * v - 1;
+        if scan.mcu > last_y * num_blocks_x + last_x {
+            return Some(num_mcus - scan.mcu);
+        }
+        let restart_interval = self.restart_interval as usize;
+        if restart_interval > 0 && scan.mcu.is_multiple_of(restart_interval) {
+            let end = (scan.mcu + restart_interval).min(num_mcus);
+            if !(scan.mcu..end).any(in_region) {
+                return Some(end - scan.mcu);
+            }
+        }
+        None
+    }
+
+    /// Continue after the corrupt MCU of `scan`, once `decoder` has found
+    /// the next marker or the end of the data. If the marker is a restart
+    /// marker, we continue with the restart interval it starts, and
+    /// otherwise the scan is over. The MCUs in between are filled.
+    fn skip_corrupt_mcus(&mut self, scan: &mut ScanState, decoder: &EntropyDecoder) {
+        let (mcu, error) = match scan.corruption.take() {
+            Some(corruption) => corruption,
+            None => return,
+        };
+        let restart_interval = self.restart_interval as usize;
+        let (end, message) = match decoder.marker() {
+            Some(code) if restart_interval > 0 && (0xd0..0xd8).contains(&code) => {
+                // RSTn starts interval `i` for `(i - 1) % 8 == n`. Corrupt
+                // data may have hidden the markers in between.
+                let n = (code - 0xd0) as usize;
+                let mut interval = scan.interval + 1;
+                while (interval - 1) % 8 != n {
+                    interval += 1;
+                }
+                (interval * restart_interval,
+                 format!("continuing at RST{} with MCU {}", n, interval * restart_interval))
+            }
+            _ => (scan.num_mcus.unwrap_or(scan.mcu), "skipping the rest of the scan".to_string()),
+        };
+        let end = scan.num_mcus.map_or(end, |num_mcus| end.min(num_mcus));
+        self.warnings.push(format!("{} in MCU {}, {}", error, mcu, message));
+        for mcu in scan.mcu..end {
+            self.fill_mcu(scan, mcu);
+        }
+        scan.mcu = end;
+    }
+
+    /// Fill an MCU of `scan` which can't be decoded. Blocks are gray in
+    /// sequential scans, as are samples in lossless scans. Progressive
+    /// scans keep what the earlier scans decoded, and DC scans make the
+    /// DC coefficient gray.
+    fn fill_mcu(&mut self, scan: &ScanState, mcu: usize) {
+        if scan.components.len() == 1 {
+            let index = scan.components[0];
+            let (num_blocks_x, _) = self.component_blocks(&self.component_fields[index]);
+            self.fill_unit(scan.kind, index, mcu % num_blocks_x, mcu / num_blocks_x);
+            return;
+        }
+        let mcus_per_line = self.mcus_per_line();
+        let (mcu_x, mcu_y) = (mcu % mcus_per_line, mcu / mcus_per_line);
+        for &index in &scan.components {
+            let h = self.component_fields[index].horizontal_sampling_factor as usize;
+            let v = self.component_fields[index].vertical_sampling_factor as usize;
+            for y in 0..v {
+                for x in 0..h {
+                    self.fill_unit(scan.kind, index, mcu_x * h + x, mcu_y * v + y);
+                }
+            }
+        }
+    }
+
+    /// Fill the block, or sample, of a component at `(x, y)`, if it is in
+    /// the rows we keep. See `fill_mcu`.
+    fn fill_unit(&mut self, kind: ScanKind, index: usize, x: usize, y: usize) {
+        let differential = self.is_differential();
+        let precision = self.sample_precision;
+        let blocks_per_line = self.blocks_per_line(&self.component_fields[index]);
+        let component = &mut self.component_fields[index];
+        let first_row = self.first_mcu_row * component.vertical_sampling_factor as usize;
+        if y < first_row {
+            return;
+        }
+        let i = (y - first_row) * blocks_per_line + x;
+        match kind {
+            ScanKind::Lossless if i < component.samples.len() => {
+                component.samples[i] = if differential {
+                    0
+                } else {
+                    1 << (precision - component.point_transform - 1)
                 };
+            }
+            ScanKind::Sequential if i < component.blocks.len() => component.blocks[i] = [0; 64],
+            ScanKind::DCFirst if i < component.blocks.len() => component.blocks[i][0] = 0,
+            _ => {}
+        }
+    }
+
+    /// Keep the state of `scan` until more data is given to `decode_mcus`,
+    /// and return the number of bytes used.
+    fn suspend_scan(&mut self, scan: ScanState, decoder: EntropyDecoder) -> usize {
+        let mut scan = scan;
+        let (state, used) = decoder.suspend();
+        scan.entropy_state = state;
+        self.scan = Some(scan);
+        used
+    }
+
+    /// Decode the next MCU of a scan.
+    fn decode_mcu(&mut self,
+                  scan: &mut ScanState,
+                  decoder: &mut EntropyDecoder)
+                  -> Result<(), String> {
+        let restart_interval = self.restart_interval as usize;
+        let restart = restart_interval > 0 && scan.mcu > 0 &&
+                      scan.mcu.is_multiple_of(restart_interval);
+
+        if scan.components.len() == 1 {
+            let index = scan.components[0];
+            let (num_blocks_x, _) = self.component_blocks(&self.component_fields[index]);
+            let v = self.component_fields[index].vertical_sampling_factor as usize;
+            let block_x = scan.mcu % num_blocks_x;
+            let block_y = scan.mcu / num_blocks_x;
+            if scan.num_mcus.is_none() && block_y / v >= self.num_mcu_rows {
+                self.allocate_mcu_rows(block_y / v + 1);
+            }
+            if restart {
+                scan.restart(decoder, block_x, &[block_y])?;
+                scan.interval = scan.mcu / restart_interval;
+            }
+            return self.decode_unit(scan, decoder, 0, index, block_x, block_y);
+        }
+
+        let mcus_per_line = self.mcus_per_line();
+        let mcu_x = scan.mcu % mcus_per_line;
+        let mcu_y = scan.mcu / mcus_per_line;
+        if scan.num_mcus.is_none() && mcu_y >= self.num_mcu_rows {
+            self.allocate_mcu_rows(mcu_y + 1);
+        }
+        if restart {
+            let rows = scan.components
+                .iter()
+                .map(|&index| mcu_y * self.component_fields[index].vertical_sampling_factor as usize)
+                .collect::<Vec<_>>();
+            scan.restart(decoder, mcu_x, &rows)?;
+            scan.interval = scan.mcu / restart_interval;
+        }
+        for scan_i in 0..scan.components.len() {
+            let index = scan.components[scan_i];
+            let h = self.component_fields[index].horizontal_sampling_factor as usize;
+            let v = self.component_fields[index].vertical_sampling_factor as usize;
+            // The blocks of a component in an MCU are ordered
+            // left to right, top to bottom. See JPEG A.2.3.
+            for y in 0..v {
+                for x in 0..h {
+                    self.decode_unit(scan, decoder, scan_i, index, mcu_x * h + x, mcu_y * v + y)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Which part of the data a scan has. See JPEG G.1.1.1.1.
+    fn scan_kind(&self, scan_header: &ScanHeader) -> Result<ScanKind, String> {
+        let start = scan_header.start_spectral_selection;
+        let end = scan_header.end_spectral_selection;
+        let high = scan_header.successive_approximation_bit_pos_high;
+        if self.is_lossless() {
+            // `start` is the predictor, and `end` is not used.
+            if start > 7 {
+                return Err(format!("Invalid predictor {} in lossless scan", start));
+            }
+            let point_transform = scan_header.successive_approximation_bit_pos_low;
+            if point_transform >= self.sample_precision {
+                return Err(format!("Invalid point transform {} in lossless scan of {}-bit samples",
+                                   point_transform,
+                                   self.sample_precision));
+            }
+            return Ok(ScanKind::Lossless);
+        }
+        if !self.is_progressive() {
+            return Ok(ScanKind::Sequential);
+        }
+        if end > 63 || start > end || (start == 0 && end != 0) {
+            return Err(format!("Invalid spectral selection {}..{} in progressive scan",
+                               start,
+                               end));
+        }
+        Ok(match (start, high) {
+            (0, 0) => ScanKind::DCFirst,
+            (0, _) => ScanKind::DCRefinement,
+            (_, 0) => ScanKind::ACFirst,
+            (_, _) => ScanKind::ACRefinement,
+        })
+    }
+
+    /// Decode one block, or one sample in lossless frames, of a component
+    /// at position `(x, y)`, counted in blocks (or samples).
+    fn decode_unit(&mut self,
+                   scan: &mut ScanState,
+                   decoder: &mut EntropyDecoder,
+                   scan_i: usize,
+                   index: usize,
+                   x: usize,
+                   y: usize)
+                   -> Result<(), String> {
+        let differential = self.is_differential();
+        let precision = self.sample_precision;
+        let blocks_per_line = self.blocks_per_line(&self.component_fields[index]);
+        // Only the rows from `first_mcu_row` are kept.
+        let first_row = self.first_mcu_row *
+                        self.component_fields[index].vertical_sampling_factor as usize;
+        let component = &mut self.component_fields[index];
+        let (ref ac_table, ref dc_table) = scan.tables[scan_i];
+        let (ac_table_id, dc_table_id) = (component.ac_table_id as usize,
+                                          component.dc_table_id as usize);
 
-            for y in 0..num_blocks_y / y_factor {
-                for x in 0..num_blocks_x / x_factor {
-                    let (x_i, y_i) = get_indices(x,
-                                                 y,
-                                                 num_blocks_x,
-                                                 num_blocks_y,
-                                                 x_factor,
-                                                 y_factor,
-                                                 max_block_hori_scale,
-                                                 max_block_vert_scale);
-                    if x_i >= num_blocks_x || y_i >= num_blocks_y {
-                        // break;
+        if scan.kind == ScanKind::Lossless {
+            let predictor = scan.start_spectral_selection;
+            let point_transform = scan.point_transform;
+            let difference = match *decoder {
+                EntropyDecoder::Huffman(ref mut decoder) => {
+                    decoder.next_difference(dc_table.as_ref().unwrap())?
+                }
+                // Rejected in `start_scan`
+                EntropyDecoder::Arithmetic(_) => unreachable!(),
+            };
+            let samples = &component.samples;
+            let sample = |x: usize, y: usize| samples[y * blocks_per_line + x];
+            // JPEG H.1.2.1 and Table H.1.
+            let prediction = if differential || predictor == 0 {
+                0
+            } else if scan.interval_start[scan_i] {
+                scan.interval_start[scan_i] = false;
+                1 << (precision - point_transform - 1)
+            } else if y == scan.interval_start_row[scan_i] {
+                sample(x - 1, y)
+            } else if x == 0 {
+                sample(x, y - 1)
+            } else {
+                let a = sample(x - 1, y);
+                let b = sample(x, y - 1);
+                let c = sample(x - 1, y - 1);
+                match predictor {
+                    1 => a,
+                    2 => b,
+                    3 => c,
+                    4 => a + b - c,
+                    5 => a + ((b - c) >> 1),
+                    6 => b + ((a - c) >> 1),
+                    _ => (a + b) / 2,
+                }
+            };
+            // Calculations are modulo 2^16.
+            component.samples[y * blocks_per_line + x] = (prediction + difference) & 0xffff;
+            return Ok(());
+        }
+
+        let block = &mut component.blocks[(y - first_row) * blocks_per_line + x];
+        let (start, end) = (scan.start_spectral_selection, scan.end_spectral_selection);
+        match scan.kind {
+            ScanKind::Sequential => {
+                *block = match *decoder {
+                    EntropyDecoder::Huffman(ref mut decoder) => {
+                        decoder.next_block(ac_table.as_ref().unwrap(),
+                                           dc_table.as_ref().unwrap())?
+                    }
+                    EntropyDecoder::Arithmetic(ref mut decoder) => {
+                        let mut block = [0; 64];
+                        block[0] = decoder.next_difference(scan_i, dc_table_id)? as i16;
+                        decoder.next_ac(&mut block, ac_table_id, 1, 63, 0)?;
+                        block
                     }
-                    JPEGDecoder::fill_block_in_array(&component_blocks[block_i],
-                                                     data.as_mut_slice(),
-                                                     x_factor,
-                                                     y_factor,
-                                                     x_i,
-                                                     y_i,
-                                                     stride);
-                    block_i += 1;
+                };
+                // DC correction. Differential frames code the DC coefficient
+                // without prediction.
+                if !differential {
+                    block[0] = block[0].wrapping_add(scan.previous[scan_i] as i16);
+                    scan.previous[scan_i] = block[0] as i32;
+                }
+            }
+            ScanKind::DCFirst => {
+                let mut dc = match *decoder {
+                    EntropyDecoder::Huffman(ref mut decoder) => {
+                        decoder.next_difference(dc_table.as_ref().unwrap())?
+                    }
+                    EntropyDecoder::Arithmetic(ref mut decoder) => {
+                        decoder.next_difference(scan_i, dc_table_id)?
+                    }
+                };
+                if !differential {
+                    dc += scan.previous[scan_i];
+                    scan.previous[scan_i] = dc;
                 }
+                block[0] = (dc << scan.point_transform) as i16;
             }
+            ScanKind::DCRefinement => {
+                let bit = match *decoder {
+                    EntropyDecoder::Huffman(ref mut decoder) => decoder.next_bit(),
+                    EntropyDecoder::Arithmetic(ref mut decoder) => decoder.next_bit(),
+                };
+                if bit {
+                    block[0] |= 1 << scan.point_transform;
+                }
+            }
+            ScanKind::ACFirst => {
+                match *decoder {
+                    EntropyDecoder::Huffman(ref mut decoder) => {
+                        decoder.next_ac_first(block,
+                                           ac_table.as_ref().unwrap(),
+                                           start,
+                                           end,
+                                           scan.point_transform)?
+                    }
+                    EntropyDecoder::Arithmetic(ref mut decoder) => {
+                        decoder.next_ac(block, ac_table_id, start, end, scan.point_transform)?
+                    }
+                }
+            }
+            ScanKind::ACRefinement => {
+                match *decoder {
+                    EntropyDecoder::Huffman(ref mut decoder) => {
+                        decoder.next_ac_refinement(block,
+                                                ac_table.as_ref().unwrap(),
+                                                start,
+                                                end,
+                                                scan.point_transform)?
+                    }
+                    EntropyDecoder::Arithmetic(ref mut decoder) => {
+                        decoder.next_ac_refinement(block,
+                                                   ac_table_id,
+                                                   start,
+                                                   end,
+                                                   scan.point_transform)?
+                    }
+                }
+            }
+            ScanKind::Lossless => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Get the pixels of the image, from the blocks of all scans decoded
+    /// so far.
+    pub fn decode(&self) -> Result<Vec<(u8, u8, u8)>, String> {
+        let (width, height) = self.output_dimensions();
+        let mut image_data = Vec::with_capacity(width * height);
+        self.write_pixels(self.luma_only, |_, _, pixel| image_data.push(pixel))?;
+        Ok(image_data)
+    }
+
+    /// Write the pixels of the image to `buffer`, in the given format and
+    /// with lines `stride` bytes apart. Gray formats get the luma of the
+    /// image, as `luma_only` would.
+    pub fn decode_into(&self,
+                       buffer: &mut [u8],
+                       format: PixelFormat,
+                       stride: usize)
+                       -> Result<(), String> {
+        let mut buffer = PixelBuffer::new(buffer,
+                                          format,
+                                          stride,
+                                          self.output_dimensions(),
+                                          self.color_conversion)?;
+        self.write_pixels(self.luma_only || format.is_gray(),
+                          |x, y, pixel| buffer.put(x, y, pixel))
+    }
 
-            image_data[component_i] = data;
+    /// Give each pixel of the image to `put`, along with its position,
+    /// line by line. With `luma_only`, the pixels are gray.
+    fn write_pixels<F>(&self, luma_only: bool, mut put: F) -> Result<(), String>
+        where F: FnMut(usize, usize, (u8, u8, u8))
+    {
+        if self.streaming_rows {
+            return Err("The rows of the image are already turned into pixels".to_string());
+        }
+        if self.dimensions.1 == 0 {
+            return Err("The number of lines in the image is not known".to_string());
+        }
+        self.check_region()?;
+        if self.is_lossless() || self.is_differential() {
+            if self.scale != 8 {
+                return Err("Only DCT frames which are not differential can be scaled"
+                    .to_string());
+            }
+            let (left, top, width, height) = self.region
+                .unwrap_or((0, 0, self.dimensions.0, self.dimensions.1));
+            let mut components = self.component_samples()?;
+            if luma_only {
+                components.truncate(1);
+            }
+            return write_samples(&components,
+                                 self.dimensions,
+                                 self.sample_precision,
+                                 self.color_conversion,
+                                 |x, y, pixel| if (left..left + width).contains(&x) &&
+                                                  (top..top + height).contains(&y) {
+                                     put(x - left, y - top, pixel)
+                                 });
+        }
+        for mcu_y in self.region_mcus().1 {
+            self.write_mcu_row(mcu_y, luma_only, &mut put)?;
         }
+        Ok(())
+    }
 
-        let image_data = if num_components == 1 {
-            image_data[0]
-                .iter()
-                .map(|&b| {
-                    let u = f32_to_u8(b + 128.0);
-                    (u, u, u)
-                })
-                .collect::<Vec<(u8, u8, u8)>>()
-        } else if num_components == 3 {
-            izip!(&image_data[0], &image_data[1], &image_data[2])
-                .map(|(&y, &cb, &cr)| y_cb_cr_to_rgb(y, cb, cr))
-                .collect::<Vec<(u8, u8, u8)>>()
-        } else {
-            panic!("asd")
-        };
+    /// Get the pixels of the lines in MCU row `mcu_y`, from the blocks
+    /// of all scans decoded so far. Only for DCT frames which are not
+    /// differential.
+    pub fn mcu_row(&self, mcu_y: usize) -> Result<MCURow, String> {
+        let mut pixels = Vec::new();
+        let (first_line, num_lines) =
+            self.write_mcu_row(mcu_y, self.luma_only, |_, _, pixel| pixels.push(pixel))?;
+        Ok(MCURow {
+            first_line,
+            num_lines,
+            pixels,
+        })
+    }
 
-        // A scan must end on a byte boundary. If we are into the next byte,
-        // increment by one. Subtract `4` for the four bytes taht are
-        // shifted into `current`.
-        let bytes_read = if huffman_decoder.bits_read() > 0 {
-            huffman_decoder.next_index() + 1
+    /// Give each pixel in MCU row `mcu_y` to `put`, along with its
+    /// position, and return the first line and number of lines of the row.
+    fn write_mcu_row<F>(&self,
+                        mcu_y: usize,
+                        luma_only: bool,
+                        mut put: F)
+                        -> Result<(usize, usize), String>
+        where F: FnMut(usize, usize, (u8, u8, u8))
+    {
+        let (width, height) = self.scaled_size();
+        let num_components = self.component_fields.len();
+        if num_components != 1 && num_components != 3 {
+            return Err(format!("Images with {} components are not supported",
+                               num_components));
+        }
+        let (left, top, region_width, region_height) = self.region
+            .unwrap_or((0, 0, width, height));
+        let max_h = self.max_sampling_factors().0;
+        let lines_per_mcu_row = self.lines_per_mcu_row();
+        let mcu_row_start = mcu_y * lines_per_mcu_row;
+        let first_line = mcu_row_start.max(top);
+        let end_line = (mcu_row_start + lines_per_mcu_row)
+            .min(top + region_height)
+            .min(height)
+            .max(first_line);
+        if first_line == end_line {
+            return Ok((first_line - top, 0));
+        }
+        let output_components = if luma_only {
+            &self.component_fields[..1]
         } else {
-            huffman_decoder.next_index()
-        } - 4;
+            &self.component_fields[..]
+        };
+        let rows = output_components
+            .iter()
+            .map(|component| self.component_mcu_row(component, mcu_y))
+            .collect::<Result<Vec<_>, String>>()?;
+        // All rows are upsampled to the same stride.
+        let stride = self.mcus_per_line() * self.scale * max_h;
 
-        (image_data, bytes_read)
+        for line in first_line..end_line {
+            let start = (line - mcu_row_start) * stride + left;
+            let end = start + region_width;
+            let y = line - top;
+            if rows.len() == 1 {
+                for (x, &luma) in rows[0][start..end].iter().enumerate() {
+                    let u = self.color_conversion.gray(f32_to_u8(luma));
+                    put(x, y, (u, u, u));
+                }
+            } else {
+                for (x, (&luma, &cb, &cr)) in izip!(&rows[0][start..end],
+                                                    &rows[1][start..end],
+                                                    &rows[2][start..end])
+                    .enumerate() {
+                    let (luma, cb, cr) = (f32_to_u8(luma), f32_to_u8(cb), f32_to_u8(cr));
+                    put(x, y, self.color_conversion.to_rgb(luma, cb, cr));
+                }
+            }
+        }
+        Ok((first_line - top, end_line - first_line))
     }
 
+    /// Get the quantized DCT coefficients of each component, from the
+    /// blocks of all scans decoded so far, without turning them into
+    /// samples.
+    pub fn coefficients(&self,
+                        order: CoefficientOrder)
+                        -> Result<Vec<ComponentCoefficients>, String> {
+        if self.is_lossless() {
+            return Err("Lossless frames have no DCT coefficients".to_string());
+        }
+        if self.streaming_rows {
+            return Err("The rows of the image are already turned into pixels".to_string());
+        }
+        if self.dimensions.1 == 0 {
+            return Err("The number of lines in the image is not known".to_string());
+        }
+        let mut components = Vec::with_capacity(self.component_fields.len());
+        for component in &self.component_fields {
+            let quantization_table = self.component_quantization_table(component)?;
+            let (width_in_blocks, height_in_blocks) = self.component_blocks(component);
+            let (quantization_table, blocks) = match order {
+                CoefficientOrder::Zigzag => (quantization_table.clone(), component.blocks.clone()),
+                CoefficientOrder::Natural => {
+                    let blocks = component.blocks
+                        .iter()
+                        .map(|block| {
+                            let mut natural = [0; 64];
+                            natural.copy_from_slice(&zigzag_inverse(block.iter().cloned()));
+                            natural
+                        })
+                        .collect();
+                    (zigzag_inverse(quantization_table.iter().cloned()), blocks)
+                }
+            };
+            components.push(ComponentCoefficients {
+                component: component.component,
+                horizontal_sampling_factor: component.horizontal_sampling_factor,
+                vertical_sampling_factor: component.vertical_sampling_factor,
+                quantization_id: component.quantization_id,
+                order,
+                quantization_table,
+                width_in_blocks,
+                height_in_blocks,
+                blocks_per_line: self.blocks_per_line(component),
+                blocks,
+            });
+        }
+        Ok(components)
+    }
 
+    /// Get the samples of each component, at the resolution of the component.
+    ///
+    /// Differential frames give differences without a level shift.
+    pub fn component_samples(&self) -> Result<Vec<ComponentSamples>, String> {
+        if self.num_mcu_rows == 0 {
+            return Err("The frame has no scans".to_string());
+        }
+        let (max_h, max_v) = self.max_sampling_factors();
+        let differential = self.is_differential();
+        let max_value = (1 << self.sample_precision) - 1;
+        let mut components = Vec::with_capacity(self.component_fields.len());
+        for component in &self.component_fields {
+            let h = component.horizontal_sampling_factor as usize;
+            let v = component.vertical_sampling_factor as usize;
+            // See JPEG A.1.1
+            let width = (self.dimensions.0 * h).div_ceil(max_h);
+            let height = (self.dimensions.1 * v).div_ceil(max_v);
+            let blocks_per_line = self.blocks_per_line(component);
+            let mut samples = repeat_n(0, width * height).collect::<Vec<i32>>();
 
-    fn fill_block_in_array(block: &Vec<f32>,
-                           target: &mut [f32],
-                           x_scale: usize,
-                           y_scale: usize,
-                           x: usize,
-                           y: usize,
-                           stride: usize) {
-        // println!("x = {}", x);
-        block.into_iter()
-            .flat_map(|n| repeat(n).take(x_scale))
-            .chunks_lazy(8 * x_scale)
-            .into_iter()
-            .enumerate()
-            .map(|(line_number, line)| {
-                let start_x = x * 8 * x_scale;
-                // println!("stride: {}\tstart_x: {}", stride, start_x);
-                if stride < start_x {
-                    return;
+            if self.is_lossless() {
+                let point_transform = component.point_transform;
+                for y in 0..height {
+                    for x in 0..width {
+                        let sample = component.samples[y * blocks_per_line + x];
+                        samples[y * width + x] = (sample << point_transform) & 0xffff;
+                    }
                 }
-                let max_i = stride - start_x;
-                let start_i = y * 8 * y_scale * stride + line_number * stride + start_x;
-                for (ind, &n) in line.enumerate() {
-                    let i = ind + start_i;
-                    for j in 0..y_scale {
-                        if i + j * stride < target.len() {
-                            target[i + j * stride * 8] = n;
+            } else {
+                let quant_table = self.component_quantization_table(component)?;
+                let (num_blocks_x, num_blocks_y) = self.component_blocks(component);
+                for block_y in 0..num_blocks_y {
+                    for block_x in 0..num_blocks_x {
+                        let block = &component.blocks[block_y * blocks_per_line + block_x];
+                        let block = zigzag_inverse(block.iter()
+                            .zip(quant_table.iter())
+                            .map(|(&n, &q)| n as f32 * q as f32));
+                        let block = transform::discrete_cosine_transform_inverse(&block);
+                        for (i, &value) in block.iter().enumerate() {
+                            let x = block_x * 8 + i % 8;
+                            let y = block_y * 8 + i / 8;
+                            if x >= width || y >= height {
+                                continue;
+                            }
+                            samples[y * width + x] = if differential {
+                                value.round() as i32
+                            } else {
+                                // Level shift, see JPEG A.3.1
+                                ((value + 128.0).round() as i32).clamp(0, max_value)
+                            };
                         }
                     }
                 }
-            })
-            .last();
+            }
+            components.push(ComponentSamples {
+                component: component.component,
+                horizontal_sampling_factor: component.horizontal_sampling_factor,
+                vertical_sampling_factor: component.vertical_sampling_factor,
+                width,
+                height,
+                samples,
+            });
+        }
+        Ok(components)
+    }
 
+    fn component_quantization_table(&self,
+                                    component: &JPEGDecoderComponentFields)
+                                    -> Result<&QuantizationTable, String> {
+        match self.quantization_tables
+            .get(component.quantization_id as usize)
+            .and_then(|t| t.as_ref()) {
+            Some(table) => Ok(table),
+            None => {
+                Err(format!("Did not find quantization table for {}",
+                            component.quantization_id))
+            }
+        }
     }
-}
 
-fn f32_to_u8(n: f32) -> u8 {
-    if n < 0.0 {
-        0
-    } else if n > 255.0 {
-        255
-    } else {
-        n as u8
+    /// Dequantize and inverse transform the blocks of a component in one
+    /// MCU row, and upsample them to the full resolution of the image.
+    fn component_mcu_row(&self,
+                         component: &JPEGDecoderComponentFields,
+                         mcu_y: usize)
+                         -> Result<Vec<f32>, String> {
+        let quant_table = self.component_quantization_table(component)?;
+        let (size, x_factor, y_factor) = self.component_idct_size(component);
+        let v = component.vertical_sampling_factor as usize;
+        let blocks_per_line = self.blocks_per_line(component);
+        let stride = blocks_per_line * size * x_factor;
+
+        let h = component.horizontal_sampling_factor as usize;
+        // Only the blocks in the MCU columns of the region are needed.
+        let columns = self.region_mcus().0;
+
+        let mut data = repeat_n(0.0, stride * self.lines_per_mcu_row()).collect::<Vec<f32>>();
+        for y in 0..v {
+            let row_start = ((mcu_y - self.first_mcu_row) * v + y) * blocks_per_line;
+            for x in columns.start * h..columns.end * h {
+                let block = &component.blocks[row_start + x];
+                let block = zigzag_inverse(block.iter()
+                    .zip(quant_table.iter())
+                    .map(|(&n, &q)| n as f32 * q as f32));
+                let block = transform::discrete_cosine_transform_inverse_scaled(&block, size);
+                JPEGDecoder::fill_block_in_array(&block,
+                                                 data.as_mut_slice(),
+                                                 size,
+                                                 (x_factor, y_factor),
+                                                 (x, y),
+                                                 stride);
+            }
+        }
+        Ok(data)
+    }
+
+    /// The size of the inverse DCT of the blocks of a component, and how
+    /// many times each of its samples needs to be repeated horizontally
+    /// and vertically to reach the size of the decoded image.
+    ///
+    /// As in libjpeg, subsampled components of a scaled down image use
+    /// larger inverse DCTs, up to 8x8, to save upsampling them.
+    fn component_idct_size(&self,
+                           component: &JPEGDecoderComponentFields)
+                           -> (usize, usize, usize) {
+        let (max_h, max_v) = self.max_sampling_factors();
+        let h = component.horizontal_sampling_factor as usize;
+        let v = component.vertical_sampling_factor as usize;
+        let mut size = self.scale;
+        while size < 8 && (max_h * self.scale).is_multiple_of(h * size * 2) &&
+              (max_v * self.scale).is_multiple_of(v * size * 2) {
+            size *= 2;
+        }
+        (size, max_h * self.scale / (h * size), max_v * self.scale / (v * size))
+    }
+
+    /// Write the `size` x `size` `block` at block position `(x, y)` in
+    /// `target`, repeating each sample `x_scale` times horizontally and
+    /// `y_scale` times vertically.
+    fn fill_block_in_array(block: &[f32],
+                           target: &mut [f32],
+                           size: usize,
+                           (x_scale, y_scale): (usize, usize),
+                           (x, y): (usize, usize),
+                           stride: usize) {
+        for (line_number, line) in block.chunks(size).enumerate() {
+            let start_x = x * size * x_scale;
+            for j in 0..y_scale {
+                let start_i = (y * size * y_scale + line_number * y_scale + j) * stride + start_x;
+                for (ind, &n) in line.iter().flat_map(|n| repeat_n(n, x_scale)).enumerate() {
+                    target[start_i + ind] = n;
+                }
+            }
+        }
     }
 }
 
-fn y_cb_cr_to_rgb(y: f32, cb: f32, cr: f32) -> (u8, u8, u8) {
-    let c_red: f32 = 0.299;
-    let c_green: f32 = 0.587;
-    let c_blue: f32 = 0.114;
+/// Upsample the components to the full size of the image, and convert
+/// them to RGB with `conversion`. Samples of more than 8 bits are scaled
+/// down.
+pub fn samples_to_rgb(components: &[ComponentSamples],
+                      dimensions: (usize, usize),
+                      sample_precision: u8,
+                      conversion: ColorConversion)
+                      -> Result<Vec<(u8, u8, u8)>, String> {
+    let mut image_data = Vec::with_capacity(dimensions.0 * dimensions.1);
+    write_samples(components,
+                  dimensions,
+                  sample_precision,
+                  conversion,
+                  |_, _, pixel| image_data.push(pixel))?;
+    Ok(image_data)
+}
 
-    let r = cr * (2.0 - 2.0 * c_red) + y;
-    let b = cb * (2.0 - 2.0 * c_blue) + y;
-    let g = (y - c_blue * b - c_red * r) / c_green;
+/// Like `samples_to_rgb`, but give each pixel to `put` along with its
+/// position, line by line.
+pub fn write_samples<F>(components: &[ComponentSamples],
+                        dimensions: (usize, usize),
+                        sample_precision: u8,
+                        conversion: ColorConversion,
+                        mut put: F)
+                        -> Result<(), String>
+    where F: FnMut(usize, usize, (u8, u8, u8))
+{
+    let (width, height) = dimensions;
+    let max_h = components.iter().map(|c| c.horizontal_sampling_factor).max().unwrap_or(1);
+    let max_v = components.iter().map(|c| c.vertical_sampling_factor).max().unwrap_or(1);
+    let shift = sample_precision.saturating_sub(8);
+    let to_u8 = |sample: i32| (sample >> shift).clamp(0, 255) as u8;
 
-    (f32_to_u8(r + 128.0), f32_to_u8(g + 128.0), f32_to_u8(b + 128.0))
+    // The sample of `component` covering pixel `(x, y)`
+    let sample = |component: &ComponentSamples, x: usize, y: usize| {
+        let x_factor = (max_h / component.horizontal_sampling_factor) as usize;
+        let y_factor = (max_v / component.vertical_sampling_factor) as usize;
+        let x = (x / x_factor).min(component.width - 1);
+        let y = (y / y_factor).min(component.height - 1);
+        to_u8(component.samples[y * component.width + x])
+    };
+
+    match components.len() {
+        1 => {
+            for y in 0..height {
+                for x in 0..width {
+                    let u = conversion.gray(sample(&components[0], x, y));
+                    put(x, y, (u, u, u));
+                }
+            }
+        }
+        3 => {
+            for y in 0..height {
+                for x in 0..width {
+                    put(x,
+                        y,
+                        conversion.to_rgb(sample(&components[0], x, y),
+                                          sample(&components[1], x, y),
+                                          sample(&components[2], x, y)));
+                }
+            }
+        }
+        n => return Err(format!("Images with {} components are not supported", n)),
+    }
+    Ok(())
 }
 
-const ZIGZAG_INDICES: [usize; 64] =
+/// Level shift a sample from the inverse DCT, and round and clamp it
+/// to a byte. See JPEG A.3.1.
+fn f32_to_u8(n: f32) -> u8 {
+    (n + 128.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// The index in natural order of each coefficient in zigzag order.
+pub const ZIGZAG_INDICES: [usize; 64] =
     [0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27,
      20, 13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58,
      59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63];
-#[allow(dead_code)]
-fn zigzag<T>(vec: &[T]) -> Vec<T>
+/// Put the 64 values of a block in natural order into zigzag order.
+pub fn zigzag<T>(vec: &[T]) -> Vec<T>
     where T: Copy
 {
     if vec.len() != 64 {
@@ -428,7 +1801,7 @@ fn zigzag_inverse<I>(iter: I) -> Vec<I::Item>
           I::Item: Default,
           I::Item: Debug
 {
-    let mut res: Vec<I::Item> = repeat(Default::default()).take(64).collect();
+    let mut res: Vec<I::Item> = repeat_n(Default::default(), 64).collect();
     for (zig_index, number) in iter.enumerate() {
         let original_index = ZIGZAG_INDICES[zig_index];
         res[original_index] = number;
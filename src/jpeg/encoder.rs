@@ -0,0 +1,892 @@
+// Huffman coding of quantized DCT coefficients, the reverse of what
+// `huffman::HuffmanDecoder` does. This lets us write images whose
+// coefficients are changed or moved around, without going through
+// pixels. Arithmetic coded frames are encoded the same way, with an
+// `arithmetic::ArithmeticEncoder` in place of the Huffman codes.
+
+use jpeg::{FrameHeader, ScanComponentHeader, ScanHeader};
+use jpeg::arithmetic::{ArithmeticConditioning, ArithmeticEncoder};
+use jpeg::decoder::{zigzag, Block, CoefficientOrder, ComponentCoefficients};
+use jpeg::huffman::HuffmanTable;
+use jpeg::marker::Marker;
+
+/// The largest number of correction bits we keep for the blocks of an
+/// end-of-band run in AC refinement scans, as in libjpeg. The run is
+/// ended early rather than keeping more.
+const MAX_CORRECTION_BITS: usize = 1000;
+
+/// Writes entropy coded data a few bits at a time, with a `0x00` byte
+/// stuffed after each `0xff` byte. See JPEG F.1.2.3.
+pub struct BitWriter {
+    data: Vec<u8>,
+    /// Bits which don't fill a byte yet, in the low `num_bits` bits
+    current: u32,
+    num_bits: usize,
+}
+
+impl Default for BitWriter {
+    fn default() -> BitWriter {
+        BitWriter::new()
+    }
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter {
+            data: Vec::new(),
+            current: 0,
+            num_bits: 0,
+        }
+    }
+
+    /// Write the `length` lowest bits of `bits`, the most significant
+    /// first. `length` is at most 16.
+    pub fn write_bits(&mut self, bits: u32, length: usize) {
+        self.current = (self.current << length) | (bits & ((1 << length) - 1));
+        self.num_bits += length;
+        while self.num_bits >= 8 {
+            let byte = (self.current >> (self.num_bits - 8)) as u8;
+            self.data.push(byte);
+            if byte == 0xff {
+                self.data.push(0x00);
+            }
+            self.num_bits -= 8;
+        }
+        self.current &= (1 << self.num_bits) - 1;
+    }
+
+    /// Fill the last byte with `1` bits.
+    pub fn pad(&mut self) {
+        if self.num_bits > 0 {
+            let padding = 8 - self.num_bits;
+            self.write_bits(0xff, padding);
+        }
+    }
+
+    /// End restart interval number `n`, counting from `0`, with its
+    /// restart marker.
+    pub fn restart(&mut self, n: usize) {
+        self.pad();
+        self.data.push(0xff);
+        self.data.push(Marker::Restart((n % 8) as u8).code());
+    }
+
+    /// Get the data, with the last byte padded.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.pad();
+        self.data
+    }
+}
+
+/// Number of bits needed for the magnitude of `value`, which is the
+/// category it is coded in. See JPEG F.1.2.1.1.
+fn category(value: i32) -> usize {
+    (32 - value.unsigned_abs().leading_zeros()) as usize
+}
+
+/// The bits following the code of `value`, which has `category` bits.
+/// Negative values are one less, in two's complement. See JPEG F.1.2.1.1.
+fn value_bits(value: i32) -> u32 {
+    if value < 0 {
+        (value - 1) as u32
+    } else {
+        value as u32
+    }
+}
+
+/// The codes of a Huffman table, looked up by value.
+struct HuffmanCodes {
+    codes: Vec<Option<(u16, u8)>>,
+}
+
+impl HuffmanCodes {
+    fn new(table: &HuffmanTable) -> HuffmanCodes {
+        HuffmanCodes { codes: table.encoding_codes() }
+    }
+
+    fn write(&self, writer: &mut BitWriter, value: u8) -> Result<(), String> {
+        match self.codes[value as usize] {
+            Some((code, length)) => {
+                writer.write_bits(code as u32, length as usize);
+                Ok(())
+            }
+            None => Err(format!("The Huffman table has no code for {:#04x}", value)),
+        }
+    }
+}
+
+/// Where the symbols of a scan go.
+enum Symbols {
+    /// Written with the codes of the Huffman tables, the four DC tables
+    /// followed by the four AC tables
+    Codes(Vec<Option<HuffmanCodes>>),
+    /// Counted for each table, to make optimal tables. See JPEG K.2.
+    Frequencies(Vec<Vec<u32>>),
+    /// Not symbols at all: the coefficients are arithmetic coded instead
+    Arithmetic(ArithmeticEncoder),
+}
+
+/// What a scan codes. See `decoder::ScanKind`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ScanKind {
+    Sequential,
+    DCFirst,
+    DCRefinement,
+    ACFirst,
+    ACRefinement,
+}
+
+/// A component of the scan being encoded.
+struct ScanComponent<'a> {
+    coefficients: &'a ComponentCoefficients,
+    horizontal_sampling_factor: usize,
+    vertical_sampling_factor: usize,
+    /// Index of the DC table in `Symbols`
+    dc_table: usize,
+    /// Index of the AC table in `Symbols`
+    ac_table: usize,
+    /// The DC coefficient of the previous block, after the point transform
+    previous: i32,
+}
+
+/// State kept while encoding a scan.
+struct ScanEncoder<'a> {
+    writer: BitWriter,
+    symbols: Symbols,
+    kind: ScanKind,
+    components: Vec<ScanComponent<'a>>,
+    interleaved: bool,
+    /// MCUs in each line of the scan, which are single blocks if it isn't
+    /// interleaved
+    mcus_per_line: usize,
+    num_mcus: usize,
+    restart_interval: usize,
+    start_spectral_selection: usize,
+    end_spectral_selection: usize,
+    point_transform: u8,
+    /// Number of blocks in the current end-of-band run, in progressive
+    /// AC scans. See JPEG G.1.2.2.
+    eob_run: u32,
+    /// Correction bits of the blocks in the end-of-band run, which follow
+    /// its code, in AC refinement scans. See JPEG G.1.2.3.
+    correction_bits: Vec<u8>,
+}
+
+/// Encode the entropy coded data of a scan of `frame`, from the
+/// coefficients of its components and the Huffman tables the scan
+/// header selects. Only Huffman coded sequential and progressive DCT
+/// frames can be encoded.
+pub fn encode_scan(frame: &FrameHeader,
+                   scan: &ScanHeader,
+                   components: &[ComponentCoefficients],
+                   dc_tables: &[Option<HuffmanTable>],
+                   ac_tables: &[Option<HuffmanTable>],
+                   restart_interval: u16)
+                   -> Result<Vec<u8>, String> {
+    let mut codes = Vec::with_capacity(8);
+    for tables in &[dc_tables, ac_tables] {
+        codes.extend((0..4).map(|i| tables.get(i).and_then(|t| t.as_ref()).map(HuffmanCodes::new)));
+    }
+    let mut encoder = ScanEncoder::new(frame,
+                                       scan,
+                                       components,
+                                       Symbols::Codes(codes),
+                                       restart_interval)?;
+    encoder.encode()?;
+    Ok(encoder.writer.finish())
+}
+
+/// Encode the entropy coded data of a scan of an arithmetic coded
+/// `frame`, from the coefficients of its components and the conditioning
+/// of the tables the scan header selects. Only sequential and progressive
+/// DCT frames can be encoded.
+pub fn encode_arithmetic_scan(frame: &FrameHeader,
+                              scan: &ScanHeader,
+                              components: &[ComponentCoefficients],
+                              conditioning: &ArithmeticConditioning,
+                              restart_interval: u16)
+                              -> Result<Vec<u8>, String> {
+    let coder = ArithmeticEncoder::new(scan.scan_components.len(), *conditioning);
+    let mut encoder = ScanEncoder::new(frame,
+                                       scan,
+                                       components,
+                                       Symbols::Arithmetic(coder),
+                                       restart_interval)?;
+    encoder.encode()?;
+    match encoder.symbols {
+        Symbols::Arithmetic(coder) => Ok(coder.finish()),
+        _ => unreachable!(),
+    }
+}
+
+/// Most components an interleaved scan can have. See JPEG B.2.3.
+const MAX_COMPONENTS_IN_SCAN: usize = 4;
+
+/// The scans of a sequential frame, which is one interleaved scan of all
+/// components, or a scan for each if there are too many of them. The
+/// first component uses Huffman tables `0` and the others tables `1`,
+/// as baseline frames have only two of each.
+pub fn sequential_scans(frame: &FrameHeader) -> Vec<ScanHeader> {
+    let components = (0..frame.frame_components.len()).collect::<Vec<_>>();
+    if components.len() <= MAX_COMPONENTS_IN_SCAN {
+        vec![scan_header(frame, &components, (0, 63), (0, 0))]
+    } else {
+        components.iter().map(|&i| scan_header(frame, &[i], (0, 63), (0, 0))).collect()
+    }
+}
+
+/// The scans of a progressive frame. The DC coefficients and the low
+/// frequencies come first, and the last bits of everything last. This
+/// is the script of `jpeg_simple_progression` in libjpeg.
+pub fn progressive_scans(frame: &FrameHeader) -> Vec<ScanHeader> {
+    let num_components = frame.frame_components.len();
+    let dc_scans = |point_transform: (u8, u8)| {
+        let components = (0..num_components).collect::<Vec<_>>();
+        if num_components <= MAX_COMPONENTS_IN_SCAN {
+            vec![scan_header(frame, &components, (0, 0), point_transform)]
+        } else {
+            components.iter()
+                .map(|&i| scan_header(frame, &[i], (0, 0), point_transform))
+                .collect()
+        }
+    };
+    let ac_scan = |i: usize, band: (u8, u8), point_transform: (u8, u8)| {
+        scan_header(frame, &[i], band, point_transform)
+    };
+
+    let mut scans = Vec::new();
+    if num_components == 3 {
+        // Luma gets out first, and chroma, which is small, gets few scans.
+        scans.extend(dc_scans((0, 1)));
+        scans.push(ac_scan(0, (1, 5), (0, 2)));
+        scans.push(ac_scan(2, (1, 63), (0, 1)));
+        scans.push(ac_scan(1, (1, 63), (0, 1)));
+        scans.push(ac_scan(0, (6, 63), (0, 2)));
+        scans.push(ac_scan(0, (1, 63), (2, 1)));
+        scans.extend(dc_scans((1, 0)));
+        scans.push(ac_scan(2, (1, 63), (1, 0)));
+        scans.push(ac_scan(1, (1, 63), (1, 0)));
+        scans.push(ac_scan(0, (1, 63), (1, 0)));
+    } else {
+        scans.extend(dc_scans((0, 1)));
+        scans.extend((0..num_components).map(|i| ac_scan(i, (1, 5), (0, 2))));
+        scans.extend((0..num_components).map(|i| ac_scan(i, (6, 63), (0, 2))));
+        scans.extend((0..num_components).map(|i| ac_scan(i, (1, 63), (2, 1))));
+        scans.extend(dc_scans((1, 0)));
+        scans.extend((0..num_components).map(|i| ac_scan(i, (1, 63), (1, 0))));
+    }
+    scans
+}
+
+/// The header of a scan of the frame components with indices
+/// `components`, coding the coefficients in the spectral selection
+/// `band`, with successive approximation bit positions `point_transform`
+/// as (high, low). Selectors of tables the scan doesn't use are `0`.
+fn scan_header(frame: &FrameHeader,
+               components: &[usize],
+               band: (u8, u8),
+               point_transform: (u8, u8))
+               -> ScanHeader {
+    let progressive = band != (0, 63);
+    let scan_components = components.iter()
+        .map(|&i| {
+            let table = if i == 0 { 0 } else { 1 };
+            let (dc_table, ac_table) = match (progressive, band.0, point_transform.0) {
+                (false, _, _) => (table, table),
+                (true, 0, 0) => (table, 0),
+                (true, 0, _) => (0, 0),
+                (true, _, _) => (0, table),
+            };
+            ScanComponentHeader {
+                component_id: frame.frame_components[i].component_id,
+                dc_table_selector: dc_table,
+                ac_table_selector: ac_table,
+            }
+        })
+        .collect::<Vec<_>>();
+    ScanHeader {
+        num_components: scan_components.len() as u8,
+        scan_components,
+        start_spectral_selection: band.0,
+        end_spectral_selection: band.1,
+        successive_approximation_bit_pos_high: point_transform.0,
+        successive_approximation_bit_pos_low: point_transform.1,
+    }
+}
+
+/// A Huffman table as it is written in a DHT segment. See JPEG B.2.4.2.
+#[derive(Debug, Clone)]
+pub struct HuffmanTableSpecification {
+    /// `0` for DC tables and `1` for AC tables
+    pub class: u8,
+    pub destination: u8,
+    /// Number of codes of each length, from 1 to 16 bits
+    pub code_lengths: [u8; 16],
+    /// The values, in the order of their codes
+    pub values: Vec<u8>,
+}
+
+impl HuffmanTableSpecification {
+    /// Read the tables of a DHT segment. See JPEG B.2.4.2.
+    pub fn parse(data: &[u8]) -> Result<Vec<HuffmanTableSpecification>, String> {
+        let mut specifications = Vec::new();
+        let mut index = 0;
+        while index < data.len() {
+            if data.len() < index + 17 {
+                return Err("DHT segment is too short".to_string());
+            }
+            let class = data[index] >> 4;
+            let destination = data[index] & 0x0f;
+            if class > 1 || destination > 3 {
+                return Err(format!("Invalid Huffman table class {} or destination {}",
+                                   class,
+                                   destination));
+            }
+            // There are `code_lengths[i]` codes of length `i + 1`.
+            let mut code_lengths = [0; 16];
+            code_lengths.copy_from_slice(&data[index + 1..index + 17]);
+            index += 17;
+
+            let number_of_codes = code_lengths.iter().map(|&n| n as usize).sum::<usize>();
+            if data.len() < index + number_of_codes {
+                return Err("DHT segment is too short".to_string());
+            }
+            specifications.push(HuffmanTableSpecification {
+                class,
+                destination,
+                code_lengths,
+                values: data[index..index + number_of_codes].to_vec(),
+            });
+            index += number_of_codes;
+        }
+        Ok(specifications)
+    }
+
+    pub fn table(&self) -> HuffmanTable {
+        HuffmanTable::from_size_data_tables(&self.code_lengths, &self.values)
+    }
+
+    /// The data of a DHT segment defining only this table.
+    pub fn segment_data(&self) -> Vec<u8> {
+        let mut data = vec![self.class << 4 | self.destination];
+        data.extend_from_slice(&self.code_lengths);
+        data.extend_from_slice(&self.values);
+        data
+    }
+}
+
+/// Make the Huffman tables which code a scan in the fewest bits, for the
+/// tables the scan header selects. The scan can then be encoded with
+/// `encode_scan`, after the tables are written in a DHT segment.
+pub fn optimal_huffman_tables(frame: &FrameHeader,
+                              scan: &ScanHeader,
+                              components: &[ComponentCoefficients],
+                              restart_interval: u16)
+                              -> Result<Vec<HuffmanTableSpecification>, String> {
+    let mut encoder = ScanEncoder::new(frame,
+                                       scan,
+                                       components,
+                                       Symbols::Frequencies(vec![vec![0; 256]; 8]),
+                                       restart_interval)?;
+    encoder.encode()?;
+    let frequencies = match encoder.symbols {
+        Symbols::Frequencies(frequencies) => frequencies,
+        _ => unreachable!(),
+    };
+    // The tables in the order of the components using them, like libjpeg
+    let mut tables: Vec<usize> = Vec::new();
+    for component in &encoder.components {
+        for &table in &[component.dc_table, component.ac_table] {
+            if !tables.contains(&table) && frequencies[table].iter().any(|&n| n > 0) {
+                tables.push(table);
+            }
+        }
+    }
+    Ok(tables.into_iter()
+        .map(|table| {
+            let (code_lengths, values) = optimal_code_lengths(&frequencies[table]);
+            HuffmanTableSpecification {
+                class: (table / 4) as u8,
+                destination: (table % 4) as u8,
+                code_lengths,
+                values,
+            }
+        })
+        .collect())
+}
+
+/// Find the number of codes of each length, and the values in the order
+/// of their codes, for a Huffman table coding values with the given
+/// frequencies in the fewest bits. Codes are at most 16 bits, and none
+/// consists of only `1` bits. This is the procedure of JPEG K.2, done
+/// the same way as libjpeg.
+fn optimal_code_lengths(frequencies: &[u32]) -> ([u8; 16], Vec<u8>) {
+    // Value 256 is reserved, so that no real code is all `1` bits.
+    let mut frequency = frequencies.iter().map(|&n| n as u64).collect::<Vec<_>>();
+    frequency.push(1);
+    let mut code_size = vec![0usize; 257];
+    // The next value in the same branch of the tree, see JPEG Figure K.1
+    let mut others: Vec<Option<usize>> = vec![None; 257];
+
+    // The least frequent value, preferring the largest one
+    let least_frequent = |frequency: &[u64], except: Option<usize>| {
+        let mut found: Option<usize> = None;
+        for (i, &n) in frequency.iter().enumerate() {
+            if n > 0 && Some(i) != except && found.is_none_or(|f| n <= frequency[f]) {
+                found = Some(i);
+            }
+        }
+        found
+    };
+    loop {
+        let c1 = least_frequent(&frequency, None).unwrap();
+        let c2 = match least_frequent(&frequency, Some(c1)) {
+            Some(c2) => c2,
+            None => break,
+        };
+        frequency[c1] += frequency[c2];
+        frequency[c2] = 0;
+        let mut c = c1;
+        code_size[c] += 1;
+        while let Some(next) = others[c] {
+            c = next;
+            code_size[c] += 1;
+        }
+        others[c] = Some(c2);
+        let mut c = c2;
+        code_size[c] += 1;
+        while let Some(next) = others[c] {
+            c = next;
+            code_size[c] += 1;
+        }
+    }
+
+    // Number of codes of each length, and then the lengths limited to 16
+    // bits. See JPEG Figure K.3.
+    let max_size = code_size.iter().cloned().max().unwrap_or(0);
+    let mut bits = vec![0u32; max_size.max(16) + 1];
+    for &size in code_size.iter().filter(|&&size| size > 0) {
+        bits[size] += 1;
+    }
+    for i in (17..bits.len()).rev() {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+    }
+    // Take away the code of the reserved value, which is one of the
+    // longest
+    let longest = (1..17).rev().find(|&i| bits[i] > 0).unwrap();
+    bits[longest] -= 1;
+
+    let mut code_lengths = [0u8; 16];
+    for (length, &n) in code_lengths.iter_mut().zip(&bits[1..17]) {
+        *length = n as u8;
+    }
+    // Values with shorter codes come first, see JPEG Figure K.4.
+    let mut values = Vec::new();
+    for size in 1..max_size + 1 {
+        values.extend((0..256).filter(|&i| code_size[i] == size).map(|i| i as u8));
+    }
+    (code_lengths, values)
+}
+
+impl<'a> ScanEncoder<'a> {
+    fn new(frame: &FrameHeader,
+           scan: &ScanHeader,
+           components: &'a [ComponentCoefficients],
+           symbols: Symbols,
+           restart_interval: u16)
+           -> Result<ScanEncoder<'a>, String> {
+        let progressive = match frame.frame_type {
+            Marker::BaselineDCT |
+            Marker::ExtendedSequentialDCT |
+            Marker::ExtendedSequentialDCTArithmetic => false,
+            Marker::ProgressiveDCT | Marker::ProgressiveDCTArithmetic => true,
+            frame_type => {
+                return Err(format!("Encoding of {:?} frames is not supported", frame_type))
+            }
+        };
+        let arithmetic = matches!(symbols, Symbols::Arithmetic(_));
+        if arithmetic != frame.frame_type.is_arithmetic_coded() {
+            return Err(format!("{:?} frames can't be coded with {} coding",
+                               frame.frame_type,
+                               if arithmetic { "arithmetic" } else { "Huffman" }));
+        }
+        let kind = match (progressive,
+                          scan.start_spectral_selection == 0,
+                          scan.successive_approximation_bit_pos_high == 0) {
+            (false, _, _) => ScanKind::Sequential,
+            (true, true, true) => ScanKind::DCFirst,
+            (true, true, false) => ScanKind::DCRefinement,
+            (true, false, true) => ScanKind::ACFirst,
+            (true, false, false) => ScanKind::ACRefinement,
+        };
+
+        let max_h = frame.frame_components
+            .iter()
+            .map(|c| c.horizontal_sampling_factor as usize)
+            .max()
+            .unwrap_or(1);
+        let max_v = frame.frame_components
+            .iter()
+            .map(|c| c.vertical_sampling_factor as usize)
+            .max()
+            .unwrap_or(1);
+        let width = frame.samples_per_line as usize;
+        let height = frame.num_lines as usize;
+        let interleaved = scan.scan_components.len() > 1;
+        let (mut mcus_per_line, mut mcu_rows) = (width.div_ceil(8 * max_h),
+                                                 height.div_ceil(8 * max_v));
+
+        let mut scan_components = Vec::with_capacity(scan.scan_components.len());
+        for scan_component in &scan.scan_components {
+            let id = scan_component.component_id;
+            let frame_component = frame.frame_components.iter().find(|c| c.component_id == id);
+            let coefficients = components.iter().find(|c| c.component == id);
+            let (frame_component, coefficients) = match (frame_component, coefficients) {
+                (Some(frame_component), Some(coefficients)) => (frame_component, coefficients),
+                _ => return Err(format!("No coefficients for component {} of the scan", id)),
+            };
+            let h = frame_component.horizontal_sampling_factor as usize;
+            let v = frame_component.vertical_sampling_factor as usize;
+            // The blocks the scan codes must all be there. See JPEG A.2.
+            let (num_blocks_x, num_blocks_y) = if interleaved {
+                (mcus_per_line * h, mcu_rows * v)
+            } else {
+                // Each MCU is one block, and only the blocks covering the
+                // component are coded. See JPEG A.2.2.
+                mcus_per_line = (width * h).div_ceil(max_h).div_ceil(8);
+                mcu_rows = (height * v).div_ceil(max_v).div_ceil(8);
+                (mcus_per_line, mcu_rows)
+            };
+            if coefficients.horizontal_sampling_factor as usize != h ||
+               coefficients.vertical_sampling_factor as usize != v ||
+               coefficients.blocks_per_line < num_blocks_x ||
+               coefficients.blocks.len() < coefficients.blocks_per_line * num_blocks_y {
+                return Err(format!("The coefficients of component {} don't match the frame",
+                                   id));
+            }
+            let dc_table = (scan_component.dc_table_selector & 3) as usize;
+            let ac_table = 4 + (scan_component.ac_table_selector & 3) as usize;
+            if let Symbols::Codes(ref codes) = symbols {
+                let needs_dc = matches!(kind, ScanKind::Sequential | ScanKind::DCFirst);
+                let needs_ac = matches!(kind,
+                                        ScanKind::Sequential | ScanKind::ACFirst |
+                                        ScanKind::ACRefinement);
+                if (needs_dc && codes[dc_table].is_none()) ||
+                   (needs_ac && codes[ac_table].is_none()) {
+                    return Err(format!("Missing huffman table for component {}", id));
+                }
+            }
+            scan_components.push(ScanComponent {
+                coefficients,
+                horizontal_sampling_factor: h,
+                vertical_sampling_factor: v,
+                dc_table,
+                ac_table,
+                previous: 0,
+            });
+        }
+        if scan_components.is_empty() {
+            return Err("Scan has no components".to_string());
+        }
+
+        Ok(ScanEncoder {
+            writer: BitWriter::new(),
+            symbols,
+            kind,
+            components: scan_components,
+            interleaved,
+            mcus_per_line,
+            num_mcus: mcus_per_line * mcu_rows,
+            restart_interval: restart_interval as usize,
+            start_spectral_selection: scan.start_spectral_selection as usize,
+            end_spectral_selection: (scan.end_spectral_selection as usize).min(63),
+            point_transform: scan.successive_approximation_bit_pos_low,
+            eob_run: 0,
+            correction_bits: Vec::new(),
+        })
+    }
+
+    /// Encode all MCUs of the scan.
+    fn encode(&mut self) -> Result<(), String> {
+        for mcu in 0..self.num_mcus {
+            if self.restart_interval > 0 && mcu > 0 && mcu.is_multiple_of(self.restart_interval) {
+                self.restart(mcu / self.restart_interval - 1)?;
+            }
+            let (mcu_x, mcu_y) = (mcu % self.mcus_per_line, mcu / self.mcus_per_line);
+            if !self.interleaved {
+                self.encode_block(0, mcu_x, mcu_y)?;
+                continue;
+            }
+            for i in 0..self.components.len() {
+                let h = self.components[i].horizontal_sampling_factor;
+                let v = self.components[i].vertical_sampling_factor;
+                // Blocks in an MCU are ordered left to right, top to
+                // bottom. See JPEG A.2.3.
+                for y in 0..v {
+                    for x in 0..h {
+                        self.encode_block(i, mcu_x * h + x, mcu_y * v + y)?;
+                    }
+                }
+            }
+        }
+        self.write_eob_run()
+    }
+
+    /// Write the code of `value` from table `table`, or count it.
+    fn write_symbol(&mut self, table: usize, value: u8) -> Result<(), String> {
+        match self.symbols {
+            Symbols::Codes(ref codes) => {
+                codes[table].as_ref().unwrap().write(&mut self.writer, value)
+            }
+            Symbols::Frequencies(ref mut frequencies) => {
+                frequencies[table][value as usize] += 1;
+                Ok(())
+            }
+            Symbols::Arithmetic(_) => unreachable!(),
+        }
+    }
+
+    /// End restart interval `n`, and reset the state the next interval
+    /// starts with. See JPEG F.1.2.3 and F.1.4.3.
+    fn restart(&mut self, n: usize) -> Result<(), String> {
+        self.write_eob_run()?;
+        match self.symbols {
+            Symbols::Arithmetic(ref mut coder) => coder.restart(n),
+            _ => self.writer.restart(n),
+        }
+        for component in &mut self.components {
+            component.previous = 0;
+        }
+        Ok(())
+    }
+
+    /// Encode the block at `(x, y)` of component `i` of the scan.
+    fn encode_block(&mut self, i: usize, x: usize, y: usize) -> Result<(), String> {
+        let block = {
+            let coefficients = self.components[i].coefficients;
+            let block = &coefficients.blocks[y * coefficients.blocks_per_line + x];
+            match coefficients.order {
+                CoefficientOrder::Zigzag => *block,
+                CoefficientOrder::Natural => {
+                    let mut zigzagged: Block = [0; 64];
+                    zigzagged.copy_from_slice(&zigzag(&block[..]));
+                    zigzagged
+                }
+            }
+        };
+        if let Symbols::Arithmetic(_) = self.symbols {
+            self.encode_arithmetic_block(i, &block);
+            return Ok(());
+        }
+        match self.kind {
+            ScanKind::Sequential => {
+                self.encode_dc(i, block[0] as i32)?;
+                self.encode_ac(i, &block)
+            }
+            ScanKind::DCFirst => self.encode_dc(i, block[0] as i32 >> self.point_transform),
+            ScanKind::DCRefinement => {
+                let bit = (block[0] as i32 >> self.point_transform) & 1;
+                self.writer.write_bits(bit as u32, 1);
+                Ok(())
+            }
+            ScanKind::ACFirst => self.encode_ac_first(i, &block),
+            ScanKind::ACRefinement => self.encode_ac_refinement(i, &block),
+        }
+    }
+
+    /// Arithmetic code a block, which is in zigzag order.
+    fn encode_arithmetic_block(&mut self, i: usize, block: &Block) {
+        let dc_table = self.components[i].dc_table;
+        let ac_table = self.components[i].ac_table - 4;
+        let (start, end) = (self.start_spectral_selection, self.end_spectral_selection);
+        let point_transform = self.point_transform;
+        let difference = match self.kind {
+            ScanKind::Sequential => self.dc_difference(i, block[0] as i32),
+            ScanKind::DCFirst => self.dc_difference(i, block[0] as i32 >> point_transform),
+            _ => 0,
+        };
+        let coder = match self.symbols {
+            Symbols::Arithmetic(ref mut coder) => coder,
+            _ => unreachable!(),
+        };
+        match self.kind {
+            ScanKind::Sequential => {
+                coder.encode_difference(i, dc_table, difference);
+                coder.encode_ac(block, ac_table, 1, 63, 0);
+            }
+            ScanKind::DCFirst => coder.encode_difference(i, dc_table, difference),
+            ScanKind::DCRefinement => {
+                coder.encode_bit((block[0] as i32 >> point_transform) & 1 == 1)
+            }
+            ScanKind::ACFirst => coder.encode_ac(block, ac_table, start, end, point_transform),
+            ScanKind::ACRefinement => {
+                coder.encode_ac_refinement(block, ac_table, start, end, point_transform)
+            }
+        }
+    }
+
+    /// The difference of a DC coefficient from the previous one of the
+    /// component, which it is coded as. See JPEG F.1.1.5.1.
+    fn dc_difference(&mut self, i: usize, value: i32) -> i32 {
+        let component = &mut self.components[i];
+        let difference = value - component.previous;
+        component.previous = value;
+        difference
+    }
+
+    /// Encode a DC coefficient as the difference from the previous one.
+    /// See JPEG F.1.2.1.
+    fn encode_dc(&mut self, i: usize, value: i32) -> Result<(), String> {
+        let difference = self.dc_difference(i, value);
+        let size = category(difference);
+        let table = self.components[i].dc_table;
+        self.write_symbol(table, size as u8)?;
+        self.writer.write_bits(value_bits(difference), size);
+        Ok(())
+    }
+
+    /// Encode the AC coefficients of a block in a sequential scan, as runs
+    /// of zeros followed by a value. See JPEG F.1.2.2.
+    fn encode_ac(&mut self, i: usize, block: &Block) -> Result<(), String> {
+        let table = self.components[i].ac_table;
+        let mut run = 0;
+        for &value in &block[1..] {
+            if value == 0 {
+                run += 1;
+                continue;
+            }
+            while run > 15 {
+                self.write_symbol(table, 0xf0)?;
+                run -= 16;
+            }
+            let value = value as i32;
+            let size = category(value);
+            self.write_symbol(table, (run << 4 | size) as u8)?;
+            self.writer.write_bits(value_bits(value), size);
+            run = 0;
+        }
+        if run > 0 {
+            self.write_symbol(table, 0x00)?;
+        }
+        Ok(())
+    }
+
+    /// Encode the first bits of a band of AC coefficients in a progressive
+    /// scan. Blocks without any are put in end-of-band runs. See JPEG
+    /// G.1.2.2.
+    fn encode_ac_first(&mut self, i: usize, block: &Block) -> Result<(), String> {
+        let table = self.components[i].ac_table;
+        let mut run = 0;
+        let band = &block[self.start_spectral_selection..self.end_spectral_selection + 1];
+        for &coefficient in band {
+            // The point transform divides the magnitude, see JPEG G.1.2.2.
+            let magnitude = (coefficient as i32).abs() >> self.point_transform;
+            if magnitude == 0 {
+                run += 1;
+                continue;
+            }
+            let value = if coefficient < 0 { -magnitude } else { magnitude };
+            self.write_eob_run()?;
+            while run > 15 {
+                self.write_symbol(table, 0xf0)?;
+                run -= 16;
+            }
+            let size = category(value);
+            self.write_symbol(table, (run << 4 | size) as u8)?;
+            self.writer.write_bits(value_bits(value), size);
+            run = 0;
+        }
+        if run > 0 {
+            self.eob_run += 1;
+            if self.eob_run == 0x7fff {
+                self.write_eob_run()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode one more bit of a band of AC coefficients in a progressive
+    /// scan. Coefficients which were zero are coded like in the first
+    /// scan, and the others get a correction bit. See JPEG G.1.2.3.
+    fn encode_ac_refinement(&mut self, i: usize, block: &Block) -> Result<(), String> {
+        let start = self.start_spectral_selection;
+        let end = self.end_spectral_selection;
+        let magnitudes = block.iter()
+            .map(|&value| (value as i32).abs() >> self.point_transform)
+            .collect::<Vec<_>>();
+        // The last coefficient which becomes nonzero in this scan. Zero
+        // runs before it can't be ended by an end-of-band.
+        let last_new = (start..end + 1).rev().find(|&k| magnitudes[k] == 1).unwrap_or(0);
+
+        let table = self.components[i].ac_table;
+        let mut run = 0;
+        let mut block_bits = Vec::new();
+        for k in start..end + 1 {
+            let magnitude = magnitudes[k];
+            if magnitude == 0 {
+                run += 1;
+                continue;
+            }
+            while run > 15 && k <= last_new {
+                self.write_eob_run()?;
+                self.write_symbol(table, 0xf0)?;
+                run -= 16;
+                self.write_correction_bits(&block_bits);
+                block_bits.clear();
+            }
+            if magnitude > 1 {
+                // A coefficient which was nonzero already
+                block_bits.push((magnitude & 1) as u8);
+                continue;
+            }
+            self.write_eob_run()?;
+            self.write_symbol(table, (run << 4 | 1) as u8)?;
+            self.writer.write_bits(if block[k] < 0 { 0 } else { 1 }, 1);
+            self.write_correction_bits(&block_bits);
+            block_bits.clear();
+            run = 0;
+        }
+        if run > 0 || !block_bits.is_empty() {
+            self.eob_run += 1;
+            self.correction_bits.extend_from_slice(&block_bits);
+            if self.eob_run == 0x7fff ||
+               self.correction_bits.len() > MAX_CORRECTION_BITS - 64 + 1 {
+                self.write_eob_run()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_correction_bits(&mut self, bits: &[u8]) {
+        for &bit in bits {
+            self.writer.write_bits(bit as u32, 1);
+        }
+    }
+
+    /// Write the end-of-band run, if there is one, followed by the
+    /// correction bits of its blocks.
+    fn write_eob_run(&mut self) -> Result<(), String> {
+        if self.eob_run == 0 {
+            return Ok(());
+        }
+        // The run is coded as the number of bits of its length, followed
+        // by all but the most significant bit. See JPEG Table G.1.
+        let size = category(self.eob_run as i32) - 1;
+        let table = self.components[0].ac_table;
+        self.write_symbol(table, (size << 4) as u8)?;
+        self.writer.write_bits(self.eob_run, size);
+        self.eob_run = 0;
+        let bits = std::mem::take(&mut self.correction_bits);
+        self.write_correction_bits(&bits);
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+// EXIF metadata, stored in APP1.
+//
+// The segment starts with `EXIF_IDENTIFIER`, followed by a TIFF
+// structure. IFD0 describes the main image, and points to the EXIF
+// and GPS sub-IFDs. IFD1, which follows IFD0, describes the thumbnail.
+
+use jpeg::tiff::{self, IFDEntry, TIFFReader};
+
+/// Identifier at the start of an APP1 segment containing EXIF data.
+pub const EXIF_IDENTIFIER: &[u8] = b"Exif\0\0";
+
+/// Tag in IFD0 pointing to the GPS IFD.
+pub const GPS_INFO_TAG: u16 = 0x8825;
+
+/// Tag in IFD0 pointing to the EXIF IFD.
+pub const EXIF_IFD_TAG: u16 = 0x8769;
+
+/// Tag in the EXIF IFD pointing to the interoperability IFD.
+pub const INTEROPERABILITY_IFD_TAG: u16 = 0xa005;
+
+/// Tag in IFD0 telling how the image should be turned when shown, where
+/// `1` means as it is.
+pub const ORIENTATION_TAG: u16 = 0x0112;
+
+/// Tags in IFD1 giving the offset and length of a JPEG thumbnail.
+pub const THUMBNAIL_OFFSET_TAG: u16 = 0x0201;
+pub const THUMBNAIL_LENGTH_TAG: u16 = 0x0202;
+
+/// Remove all GPS information from the data of an EXIF APP1 segment.
+///
+/// The GPS IFD, as well as all values it points to, are zeroed out,
+/// and the pointer to it is removed from IFD0. Nothing is moved around,
+/// so all other offsets in the segment stay valid.
+///
+/// Returns `true` if there was any GPS information.
+pub fn remove_gps(segment: &mut [u8]) -> Result<bool, String> {
+    if !segment.starts_with(EXIF_IDENTIFIER) {
+        return Err("APP1 segment is not an EXIF segment".to_string());
+    }
+    let data = &mut segment[EXIF_IDENTIFIER.len()..];
+
+    let (byte_order, ifd0_offset, ifd0, gps_offset, gps_ifd) = {
+        let reader = TIFFReader::new(data)?;
+        let ifd0_offset = reader.first_ifd_offset()? as usize;
+        let ifd0 = reader.read_ifd(ifd0_offset)?;
+        let gps_offset = match ifd0.entry(GPS_INFO_TAG) {
+            Some(entry) => reader.entry_u32(entry)? as usize,
+            None => return Ok(false),
+        };
+        let gps_ifd = reader.read_ifd(gps_offset)?;
+        (reader.byte_order(), ifd0_offset, ifd0, gps_offset, gps_ifd)
+    };
+
+    // Zero out the values stored outside of the GPS IFD, and then the IFD itself.
+    for entry in gps_ifd.entries.iter().filter(|e| e.value_length() > 4) {
+        let end = entry.value_offset + entry.value_length();
+        if end <= data.len() {
+            zero(&mut data[entry.value_offset..end]);
+        }
+    }
+    let gps_end = gps_offset + 2 + gps_ifd.entries.len() * 12 + 4;
+    zero(&mut data[gps_offset..gps_end]);
+
+    // Remove the pointer entry from IFD0, by moving the following entries,
+    // as well as the next IFD offset, one entry up.
+    let num_entries = ifd0.entries.len();
+    let removed = ifd0.entries.iter().position(|e| e.tag == GPS_INFO_TAG).unwrap();
+    let entries_start = ifd0_offset + 2;
+    let entries_end = entries_start + num_entries * 12 + 4;
+    let removed_start = entries_start + removed * 12;
+    for i in removed_start..entries_end - 12 {
+        data[i] = data[i + 12];
+    }
+    zero(&mut data[entries_end - 12..entries_end]);
+    tiff::write_u16(data, ifd0_offset, num_entries as u16 - 1, byte_order);
+
+    Ok(true)
+}
+
+/// Set the orientation in the data of an EXIF APP1 segment to `1`, for
+/// an image which has been turned the way it should be shown.
+///
+/// Returns `true` if the orientation was something else.
+pub fn reset_orientation(segment: &mut [u8]) -> Result<bool, String> {
+    if !segment.starts_with(EXIF_IDENTIFIER) {
+        return Err("APP1 segment is not an EXIF segment".to_string());
+    }
+    let data = &mut segment[EXIF_IDENTIFIER.len()..];
+
+    let (byte_order, entry) = {
+        let reader = TIFFReader::new(data)?;
+        let ifd0 = reader.read_ifd(reader.first_ifd_offset()? as usize)?;
+        let entry = match ifd0.entry(ORIENTATION_TAG) {
+            Some(entry) => entry.clone(),
+            None => return Ok(false),
+        };
+        if reader.entry_u32(&entry)? == 1 {
+            return Ok(false);
+        }
+        (reader.byte_order(), entry)
+    };
+    // The orientation is a SHORT, see EXIF 4.6.4.
+    if entry.field_type != 3 {
+        return Err(format!("EXIF orientation has type {} instead of SHORT", entry.field_type));
+    }
+    tiff::write_u16(data, entry.value_offset, 1, byte_order);
+    Ok(true)
+}
+
+/// Get the JPEG thumbnail in the data of an EXIF APP1 segment, if it
+/// has one. See EXIF 4.5.5.
+pub fn thumbnail(segment: &[u8]) -> Result<Option<&[u8]>, String> {
+    if !segment.starts_with(EXIF_IDENTIFIER) {
+        return Err("APP1 segment is not an EXIF segment".to_string());
+    }
+    let reader = TIFFReader::new(&segment[EXIF_IDENTIFIER.len()..])?;
+    let ifd0 = reader.read_ifd(reader.first_ifd_offset()? as usize)?;
+    if ifd0.next_ifd_offset == 0 {
+        return Ok(None);
+    }
+    let ifd1 = reader.read_ifd(ifd0.next_ifd_offset as usize)?;
+    let (offset, length) = match (ifd1.entry(THUMBNAIL_OFFSET_TAG),
+                                  ifd1.entry(THUMBNAIL_LENGTH_TAG)) {
+        (Some(offset), Some(length)) => {
+            (reader.entry_u32(offset)? as usize, reader.entry_u32(length)? as usize)
+        }
+        _ => return Ok(None),
+    };
+    let data = reader.data();
+    if offset.checked_add(length).is_none_or(|end| end > data.len()) {
+        return Err("EXIF thumbnail is out of bounds".to_string());
+    }
+    Ok(Some(&data[offset..offset + length]))
+}
+
+/// The IFDs of an EXIF segment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExifIFD {
+    /// The main image
+    IFD0,
+    /// The thumbnail
+    IFD1,
+    Exif,
+    GPS,
+    Interoperability,
+}
+
+/// The value of an EXIF field, as read from its TIFF type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExifValue {
+    /// ASCII, without the terminating zero
+    Text(String),
+    /// BYTE, SHORT, LONG and their signed kinds
+    Integers(Vec<i64>),
+    /// RATIONAL and SRATIONAL, as numerator and denominator
+    Rationals(Vec<(i64, i64)>),
+    /// UNDEFINED, floating point and unknown types
+    Bytes(Vec<u8>),
+}
+
+/// A field of an EXIF segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExifField {
+    pub ifd: ExifIFD,
+    pub tag: u16,
+    pub value: ExifValue,
+}
+
+impl ExifField {
+    /// The name of the tag in the EXIF standard, for common tags.
+    pub fn name(&self) -> Option<&'static str> {
+        let name = match (self.ifd, self.tag) {
+            (ExifIFD::GPS, 0x0000) => "GPSVersionID",
+            (ExifIFD::GPS, 0x0001) => "GPSLatitudeRef",
+            (ExifIFD::GPS, 0x0002) => "GPSLatitude",
+            (ExifIFD::GPS, 0x0003) => "GPSLongitudeRef",
+            (ExifIFD::GPS, 0x0004) => "GPSLongitude",
+            (ExifIFD::GPS, 0x0005) => "GPSAltitudeRef",
+            (ExifIFD::GPS, 0x0006) => "GPSAltitude",
+            (ExifIFD::GPS, 0x0007) => "GPSTimeStamp",
+            (ExifIFD::GPS, 0x001d) => "GPSDateStamp",
+            (ExifIFD::GPS, _) => return None,
+            (ExifIFD::Interoperability, 0x0001) => "InteroperabilityIndex",
+            (ExifIFD::Interoperability, 0x0002) => "InteroperabilityVersion",
+            (ExifIFD::Interoperability, _) => return None,
+            (_, 0x0100) => "ImageWidth",
+            (_, 0x0101) => "ImageLength",
+            (_, 0x0103) => "Compression",
+            (_, 0x010e) => "ImageDescription",
+            (_, 0x010f) => "Make",
+            (_, 0x0110) => "Model",
+            (_, ORIENTATION_TAG) => "Orientation",
+            (_, 0x011a) => "XResolution",
+            (_, 0x011b) => "YResolution",
+            (_, 0x0128) => "ResolutionUnit",
+            (_, 0x0131) => "Software",
+            (_, 0x0132) => "DateTime",
+            (_, 0x013b) => "Artist",
+            (_, THUMBNAIL_OFFSET_TAG) => "JPEGInterchangeFormat",
+            (_, THUMBNAIL_LENGTH_TAG) => "JPEGInterchangeFormatLength",
+            (_, 0x0213) => "YCbCrPositioning",
+            (_, 0x8298) => "Copyright",
+            (_, EXIF_IFD_TAG) => "ExifIFDPointer",
+            (_, GPS_INFO_TAG) => "GPSInfoIFDPointer",
+            (_, 0x829a) => "ExposureTime",
+            (_, 0x829d) => "FNumber",
+            (_, 0x8822) => "ExposureProgram",
+            (_, 0x8827) => "PhotographicSensitivity",
+            (_, 0x9000) => "ExifVersion",
+            (_, 0x9003) => "DateTimeOriginal",
+            (_, 0x9004) => "DateTimeDigitized",
+            (_, 0x9101) => "ComponentsConfiguration",
+            (_, 0x9201) => "ShutterSpeedValue",
+            (_, 0x9202) => "ApertureValue",
+            (_, 0x9204) => "ExposureBiasValue",
+            (_, 0x9207) => "MeteringMode",
+            (_, 0x9209) => "Flash",
+            (_, 0x920a) => "FocalLength",
+            (_, 0x927c) => "MakerNote",
+            (_, 0x9286) => "UserComment",
+            (_, 0xa000) => "FlashpixVersion",
+            (_, 0xa001) => "ColorSpace",
+            (_, 0xa002) => "PixelXDimension",
+            (_, 0xa003) => "PixelYDimension",
+            (_, INTEROPERABILITY_IFD_TAG) => "InteroperabilityIFDPointer",
+            (_, 0xa402) => "ExposureMode",
+            (_, 0xa403) => "WhiteBalance",
+            (_, 0xa406) => "SceneCaptureType",
+            (_, 0xa434) => "LensModel",
+            _ => return None,
+        };
+        Some(name)
+    }
+}
+
+/// Read every field of the data of an EXIF APP1 segment, in IFD0 and
+/// the IFDs it leads to.
+pub fn fields(segment: &[u8]) -> Result<Vec<ExifField>, String> {
+    if !segment.starts_with(EXIF_IDENTIFIER) {
+        return Err("APP1 segment is not an EXIF segment".to_string());
+    }
+    let reader = TIFFReader::new(&segment[EXIF_IDENTIFIER.len()..])?;
+    let mut fields = Vec::new();
+    let mut ifds = vec![(ExifIFD::IFD0, reader.first_ifd_offset()?)];
+    let mut visited = Vec::new();
+    while let Some((ifd_kind, offset)) = ifds.pop() {
+        // Broken files may have IFDs pointing to each other.
+        if offset == 0 || visited.contains(&offset) {
+            continue;
+        }
+        visited.push(offset);
+        let ifd = reader.read_ifd(offset as usize)?;
+        if ifd_kind == ExifIFD::IFD0 {
+            ifds.push((ExifIFD::IFD1, ifd.next_ifd_offset));
+        }
+        for entry in &ifd.entries {
+            let pointed_to = match (ifd_kind, entry.tag) {
+                (ExifIFD::IFD0, EXIF_IFD_TAG) => Some(ExifIFD::Exif),
+                (ExifIFD::IFD0, GPS_INFO_TAG) => Some(ExifIFD::GPS),
+                (ExifIFD::Exif, INTEROPERABILITY_IFD_TAG) => Some(ExifIFD::Interoperability),
+                _ => None,
+            };
+            if let Some(pointed_to) = pointed_to {
+                ifds.push((pointed_to, reader.entry_u32(entry)?));
+            }
+            fields.push(ExifField {
+                ifd: ifd_kind,
+                tag: entry.tag,
+                value: value(&reader, entry)?,
+            });
+        }
+    }
+    Ok(fields)
+}
+
+/// Read the value of `entry` by its TIFF type.
+fn value(reader: &TIFFReader, entry: &IFDEntry) -> Result<ExifValue, String> {
+    let bytes = reader.entry_bytes(entry)?;
+    let count = entry.count as usize;
+    let offset = entry.value_offset;
+    let mut integers = Vec::new();
+    let mut rationals = Vec::new();
+    match entry.field_type {
+        2 => {
+            let text = bytes.split(|&b| b == 0).next().unwrap_or(&[]);
+            return Ok(ExifValue::Text(String::from_utf8_lossy(text).into_owned()));
+        }
+        1 => integers.extend(bytes.iter().map(|&b| b as i64)),
+        6 => integers.extend(bytes.iter().map(|&b| b as i8 as i64)),
+        3 => {
+            for i in 0..count {
+                integers.push(reader.u16_at(offset + 2 * i)? as i64);
+            }
+        }
+        8 => {
+            for i in 0..count {
+                integers.push(reader.u16_at(offset + 2 * i)? as i16 as i64);
+            }
+        }
+        4 => {
+            for i in 0..count {
+                integers.push(reader.u32_at(offset + 4 * i)? as i64);
+            }
+        }
+        9 => {
+            for i in 0..count {
+                integers.push(reader.u32_at(offset + 4 * i)? as i32 as i64);
+            }
+        }
+        5 => {
+            for i in 0..count {
+                rationals.push((reader.u32_at(offset + 8 * i)? as i64,
+                                reader.u32_at(offset + 8 * i + 4)? as i64));
+            }
+        }
+        10 => {
+            for i in 0..count {
+                rationals.push((reader.u32_at(offset + 8 * i)? as i32 as i64,
+                                reader.u32_at(offset + 8 * i + 4)? as i32 as i64));
+            }
+        }
+        _ => return Ok(ExifValue::Bytes(bytes.to_vec())),
+    }
+    if matches!(entry.field_type, 5 | 10) {
+        Ok(ExifValue::Rationals(rationals))
+    } else {
+        Ok(ExifValue::Integers(integers))
+    }
+}
+
+fn zero(data: &mut [u8]) {
+    for b in data.iter_mut() {
+        *b = 0;
+    }
+}
@@ -0,0 +1,237 @@
+// Hierarchical images, see JPEG Annex J.
+//
+// A hierarchical image is a sequence of frames of increasing resolution.
+// The size and components of the final image are given by the DHP segment.
+// The first frame of each component is coded like any other image, while
+// the following differential frames (SOF5 - SOF7) code the difference
+// from the reconstruction so far. If an EXP segment precedes a frame, the
+// reconstruction is first upsampled by two horizontally and/or vertically.
+
+use jpeg::FrameHeader;
+use jpeg::color::ColorConversion;
+use jpeg::decoder::{self, ComponentSamples, JPEGDecoder};
+use jpeg::pixel::{PixelBuffer, PixelFormat};
+
+pub struct HierarchicalDecoder {
+    /// The DHP segment, with the size and components of the final image
+    frame_header: FrameHeader,
+    /// Upsampling given by the last EXP segment, which is not used yet
+    expand: (bool, bool),
+    /// Upsampling of the references for the current frame
+    frame_expand: (bool, bool),
+    /// The reconstruction of each component so far, in DHP order
+    components: Vec<Option<ComponentSamples>>,
+    /// Whether only the first component of the final image is output
+    luma_only: bool,
+    /// How YCbCr is turned into RGB
+    color_conversion: ColorConversion,
+}
+
+impl HierarchicalDecoder {
+    pub fn new(frame_header: FrameHeader) -> HierarchicalDecoder {
+        let num_components = frame_header.frame_components.len();
+        HierarchicalDecoder {
+            frame_header,
+            expand: (false, false),
+            frame_expand: (false, false),
+            components: (0..num_components).map(|_| None).collect(),
+            luma_only: false,
+            color_conversion: ColorConversion::default(),
+        }
+    }
+
+    pub fn frame_header(&self) -> &FrameHeader {
+        &self.frame_header
+    }
+
+    /// Only output the first component of the final image, as gray
+    /// pixels. The frames are still all decoded, as later frames of the
+    /// first component may refer to them.
+    pub fn luma_only(&mut self) {
+        self.luma_only = true;
+    }
+
+    /// Convert YCbCr to RGB with `conversion`. See
+    /// `JPEGDecoder::color_conversion`.
+    pub fn color_conversion(&mut self, conversion: ColorConversion) {
+        self.color_conversion = conversion;
+    }
+
+    /// Upsample the references of the next frame. See JPEG B.3.3.
+    pub fn expand(&mut self, horizontal: bool, vertical: bool) {
+        self.expand = (horizontal, vertical);
+    }
+
+    /// Call when a new frame starts, before its scans are decoded.
+    pub fn start_frame(&mut self) {
+        self.frame_expand = self.expand;
+        self.expand = (false, false);
+    }
+
+    /// Add a frame whose scans are all decoded to the reconstruction.
+    pub fn add_frame(&mut self, decoder: &JPEGDecoder) -> Result<(), String> {
+        let max_value = (1 << self.frame_header.sample_precision) - 1;
+        for samples in decoder.component_samples()? {
+            let index = match self.frame_header
+                .frame_components
+                .iter()
+                .position(|c| c.component_id == samples.component) {
+                Some(index) => index,
+                None => {
+                    return Err(format!("Frame has component {}, which is not in the DHP \
+                                        segment",
+                                       samples.component))
+                }
+            };
+            let frame_component = &self.frame_header.frame_components[index];
+
+            let mut reconstruction = if decoder.is_differential() {
+                let reference = match self.components[index].take() {
+                    Some(reference) => reference,
+                    None => {
+                        return Err(format!("Differential frame for component {}, which has \
+                                            no previous frame",
+                                           samples.component))
+                    }
+                };
+                let reference = upsample(reference, self.frame_expand);
+                if reference.width < samples.width || reference.height < samples.height {
+                    return Err(format!("Reference of component {} is {}x{}, but the \
+                                        differential frame is {}x{}",
+                                       samples.component,
+                                       reference.width,
+                                       reference.height,
+                                       samples.width,
+                                       samples.height));
+                }
+                // JPEG J.2.1 and J.2.2: add the differences to the reference.
+                let mut reconstruction = samples.clone();
+                for y in 0..samples.height {
+                    for x in 0..samples.width {
+                        let i = y * samples.width + x;
+                        let reference = reference.samples[y * reference.width + x];
+                        reconstruction.samples[i] = if decoder.is_lossless() {
+                            // Calculations are modulo 2^16 in lossless frames
+                            (reference + samples.samples[i]) & 0xffff
+                        } else {
+                            (reference + samples.samples[i]).clamp(0, max_value)
+                        };
+                    }
+                }
+                reconstruction
+            } else {
+                samples
+            };
+            reconstruction.horizontal_sampling_factor = frame_component.horizontal_sampling_factor;
+            reconstruction.vertical_sampling_factor = frame_component.vertical_sampling_factor;
+            self.components[index] = Some(reconstruction);
+        }
+        Ok(())
+    }
+
+    /// Get the pixels of the final image.
+    pub fn image_data(&self) -> Result<Vec<(u8, u8, u8)>, String> {
+        decoder::samples_to_rgb(&self.final_components(self.luma_only)?,
+                                self.dimensions(),
+                                self.frame_header.sample_precision,
+                                self.color_conversion)
+    }
+
+    /// Write the pixels of the final image to `buffer`, in the given
+    /// format and with lines `stride` bytes apart. Gray formats get the
+    /// luma of the image, as `luma_only` would.
+    pub fn decode_into(&self,
+                       buffer: &mut [u8],
+                       format: PixelFormat,
+                       stride: usize)
+                       -> Result<(), String> {
+        let mut buffer = PixelBuffer::new(buffer,
+                                          format,
+                                          stride,
+                                          self.dimensions(),
+                                          self.color_conversion)?;
+        decoder::write_samples(&self.final_components(self.luma_only || format.is_gray())?,
+                               self.dimensions(),
+                               self.frame_header.sample_precision,
+                               self.color_conversion,
+                               |x, y, pixel| buffer.put(x, y, pixel))
+    }
+
+    fn dimensions(&self) -> (usize, usize) {
+        (self.frame_header.samples_per_line as usize, self.frame_header.num_lines as usize)
+    }
+
+    /// The reconstruction of all components, or only the first with
+    /// `luma_only`, when all frames are added.
+    fn final_components(&self, luma_only: bool) -> Result<Vec<ComponentSamples>, String> {
+        let num_components = if luma_only { 1 } else { self.components.len() };
+        let frame_components = &self.frame_header.frame_components;
+        let max_h = frame_components.iter().map(|c| c.horizontal_sampling_factor).max();
+        let max_v = frame_components.iter().map(|c| c.vertical_sampling_factor).max();
+        let (max_h, max_v) = (max_h.unwrap_or(1) as usize, max_v.unwrap_or(1) as usize);
+        let (width, height) = self.dimensions();
+        let mut components = Vec::with_capacity(num_components);
+        for (component, frame_component) in self.components
+            .iter()
+            .zip(frame_components.iter())
+            .take(num_components) {
+            let component = match *component {
+                Some(ref component) => component,
+                None => {
+                    return Err(format!("No frame has component {}",
+                                       frame_component.component_id))
+                }
+            };
+            // The last frame of each component must have the size of the
+            // final image. See JPEG A.1.1.
+            let expected =
+                ((width * frame_component.horizontal_sampling_factor as usize).div_ceil(max_h),
+                 (height * frame_component.vertical_sampling_factor as usize).div_ceil(max_v));
+            if (component.width, component.height) != expected {
+                return Err(format!("Component {} is {}x{}, but the DHP segment makes it {}x{}",
+                                   component.component,
+                                   component.width,
+                                   component.height,
+                                   expected.0,
+                                   expected.1));
+            }
+            components.push(component.clone());
+        }
+        Ok(components)
+    }
+}
+
+/// Upsample a reference component by a factor of two in the given
+/// directions, using the filter in JPEG J.1.1.2.
+fn upsample(component: ComponentSamples, expand: (bool, bool)) -> ComponentSamples {
+    let mut component = component;
+    if expand.0 {
+        let width = component.width * 2;
+        let mut samples = Vec::with_capacity(width * component.height);
+        for line in component.samples.chunks(component.width) {
+            for (x, &a) in line.iter().enumerate() {
+                // The last sample is repeated at the right edge.
+                let b = line.get(x + 1).cloned().unwrap_or(a);
+                samples.push(a);
+                samples.push((a + b) >> 1);
+            }
+        }
+        component.width = width;
+        component.samples = samples;
+    }
+    if expand.1 {
+        let height = component.height * 2;
+        let mut samples = Vec::with_capacity(component.width * height);
+        for y in 0..component.height {
+            let line = &component.samples[y * component.width..(y + 1) * component.width];
+            let next_y = (y + 1).min(component.height - 1);
+            let next_line = &component.samples[next_y * component.width..
+                                               (next_y + 1) * component.width];
+            samples.extend_from_slice(line);
+            samples.extend(line.iter().zip(next_line.iter()).map(|(&a, &b)| (a + b) >> 1));
+        }
+        component.height = height;
+        component.samples = samples;
+    }
+    component
+}
@@ -1,9 +1,4 @@
-use std::iter::repeat;
-use std::cmp::min;
-
-// Selects i bits, from msb to lsb.
-const BIT_MASKS: [u16; 17] = [0x0, 0x8000, 0xC000, 0xE000, 0xF000, 0xF800, 0xFC00, 0xFE00, 0xFF00,
-                              0xFF80, 0xFFC0, 0xFFE0, 0xFFF0, 0xFFF8, 0xFFFC, 0xFFFE, 0xFFFF];
+use std::iter::repeat_n;
 
 // TODO: Naming in this file is so bad..
 // size_table? table? code_vecs? bah..
@@ -24,6 +19,12 @@ pub struct HuffmanCode {
 pub struct HuffmanTable {
     /// A list of all codes in the table, sorted on code length
     codes: Vec<HuffmanCode>,
+    /// The smallest code of each length
+    min_code: [u16; 17],
+    /// The largest code of each length, or `-1` if there are none
+    max_code: [i32; 17],
+    /// Index in `codes` of the first code of each length
+    value_pointer: [usize; 17],
 }
 
 impl HuffmanTable {
@@ -37,7 +38,7 @@ impl HuffmanTable {
     pub fn from_size_data_tables(size_data: &[u8], data_table: &[u8]) -> HuffmanTable {
         // id -> code length
         let code_lengths: Vec<u8> = (0..16)
-            .flat_map(|i| repeat(i as u8 + 1).take(size_data[i] as usize))
+            .flat_map(|i| repeat_n(i as u8 + 1, size_data[i] as usize))
             .collect();
         // id -> 0b10101
         let code_table: Vec<u16> = HuffmanTable::make_code_table(&code_lengths);
@@ -47,14 +48,32 @@ impl HuffmanTable {
             .zip(code_table.iter())
             .map(|((&value, &length), &code)| {
                 HuffmanCode {
-                    length: length,
-                    code: code,
-                    value: value,
+                    length,
+                    code,
+                    value,
                 }
             })
             .collect();
 
-        HuffmanTable { codes: codes }
+        // Lookup tables for decoding, see JPEG F.2.2.3 and Figure F.16.
+        let mut min_code = [0; 17];
+        let mut max_code = [-1; 17];
+        let mut value_pointer = [0; 17];
+        for (i, code) in codes.iter().enumerate().rev() {
+            let length = code.length as usize;
+            if max_code[length] == -1 {
+                max_code[length] = code.code as i32;
+            }
+            min_code[length] = code.code;
+            value_pointer[length] = i;
+        }
+
+        HuffmanTable {
+            codes,
+            min_code,
+            max_code,
+            value_pointer,
+        }
     }
 
     pub fn codes_of_length(&self, len: usize) -> &[HuffmanCode] {
@@ -75,6 +94,16 @@ impl HuffmanTable {
         &self.codes[a..b]
     }
 
+    /// The code and code length of each value, for encoding, or `None`
+    /// for values which have no code.
+    pub fn encoding_codes(&self) -> Vec<Option<(u16, u8)>> {
+        let mut codes = vec![None; 256];
+        for code in &self.codes {
+            codes[code.value as usize] = Some((code.code, code.length));
+        }
+        codes
+    }
+
     /// Take a size table, and return a `Vec<u16>` of codes,
     /// such that code `i` has the value `vec[i]`.
     fn make_code_table(sizes: &[u8]) -> Vec<u16> {
@@ -100,77 +129,313 @@ impl HuffmanTable {
 
 impl Clone for HuffmanTable {
     fn clone(&self) -> HuffmanTable {
-        HuffmanTable { codes: self.codes.iter().cloned().collect() }
+        HuffmanTable {
+            codes: self.codes.to_vec(),
+            min_code: self.min_code,
+            max_code: self.max_code,
+            value_pointer: self.value_pointer,
+        }
     }
 }
 
+/// Whether `data` has a marker which ends entropy coded data, that is
+/// any marker except the restart markers.
+pub fn has_end_marker(data: &[u8]) -> bool {
+    data.windows(2).any(|bytes| {
+        bytes[0] == 0xff && bytes[1] != 0x00 && bytes[1] != 0xff &&
+        !(0xd0..0xd8).contains(&bytes[1])
+    })
+}
+
+/// The state of a `HuffmanDecoder` between two pieces of data, so that
+/// a scan can be decoded as its data arrives.
+#[derive(Debug, Clone, Default)]
+pub struct HuffmanState {
+    current: u32,
+    num_bits: usize,
+    marker: Option<u8>,
+    padding_bits: usize,
+    eob_run: u32,
+    overrun: bool,
+}
+
 /// Struct used to handle state when decoding image blocks
 /// encoded with huffman coding.
+///
+/// `data` is the entropy coded data of a scan as it is in the file,
+/// that is with stuffed `0x00` bytes and restart markers.
 pub struct HuffmanDecoder<'a> {
     /// Data stream
     data: &'a [u8],
     /// The index of next byte to read from the data stream
     next_index: usize,
-    /// Number of bits read and shifted out of `current`.
-    bits_read: usize,
-    /// The bits we act on.
-    /// As codes might be 16 bits, we always need 16 readable
-    /// bits in `current`. For simplicity, we'll keep 25-32
-    /// readable bits in `current`.
+    /// The bits we act on. The next bit to read is the msb.
+    /// As codes might be 16 bits, and are followed by up to 16 more
+    /// bits, we keep at least 25 readable bits in `current`.
     current: u32,
+    /// Number of readable bits in `current`
+    num_bits: usize,
+    /// The marker ending the data, once we have found it.
+    /// `next_index` then points to its first `0xff` byte.
+    marker: Option<u8>,
+    /// Number of `0` bits put at the end of `current` after the data ended
+    padding_bits: usize,
+    /// Number of blocks left in an end-of-band run, in progressive AC scans.
+    /// See JPEG G.1.2.2.
+    eob_run: u32,
+    /// Whether more bits were read than the data has, which only happens
+    /// when it is cut short or corrupt. See `HuffmanDecoder::overrun`.
+    overrun: bool,
 }
 
 impl<'a> HuffmanDecoder<'a> {
     pub fn new(data: &'a [u8]) -> HuffmanDecoder<'a> {
-        // TODO: Revisit this: is it weird to read from `data` in
-        // the constructor?
-        let current = ((data[0] as u32) << 24) | ((data[1] as u32) << 16) |
-                      ((data[2] as u32) << 8) | (data[3] as u32);
         HuffmanDecoder {
-            data: data,
-            next_index: 4,
-            bits_read: 0,
-            current: current,
+            data,
+            next_index: 0,
+            current: 0,
+            num_bits: 0,
+            marker: None,
+            padding_bits: 0,
+            eob_run: 0,
+            overrun: false,
+        }
+    }
+
+    /// Continue decoding from `state`, where `data` starts with the
+    /// first byte which was not read before.
+    pub fn resume(data: &'a [u8], state: HuffmanState) -> HuffmanDecoder<'a> {
+        HuffmanDecoder {
+            data,
+            next_index: 0,
+            current: state.current,
+            num_bits: state.num_bits,
+            marker: state.marker,
+            padding_bits: state.padding_bits,
+            eob_run: state.eob_run,
+            overrun: state.overrun,
         }
     }
 
-    pub fn bits_read(&self) -> usize {
-        self.bits_read
+    /// Stop decoding, and get the state to resume from along with
+    /// the number of bytes of `data` which are read.
+    pub fn suspend(self) -> (HuffmanState, usize) {
+        let state = HuffmanState {
+            current: self.current,
+            num_bits: self.num_bits,
+            marker: self.marker,
+            padding_bits: self.padding_bits,
+            eob_run: self.eob_run,
+            overrun: self.overrun,
+        };
+        (state, self.next_index)
     }
 
+    /// Index in `data` of the next byte which is not read yet.
     pub fn next_index(&self) -> usize {
         self.next_index
     }
 
-    /// Read the next 8x8 block
-    pub fn next_block(&mut self, ac_table: &HuffmanTable, dc_table: &HuffmanTable) -> Vec<i16> {
+    /// Number of bytes of `data` which are not read yet.
+    pub fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.next_index)
+    }
+
+    /// The marker terminating the entropy coded data, if we have read
+    /// all the data in front of it.
+    pub fn marker(&self) -> Option<u8> {
+        self.marker
+    }
+
+    /// Whether the decoder has read past the marker ending the data, or
+    /// past the end of the data, since the last restart. The `0` bits
+    /// it read there are not part of the image, so the data is cut short
+    /// or corrupt.
+    pub fn overrun(&self) -> bool {
+        self.overrun
+    }
+
+    /// Whether all data before the next marker is used, except for the
+    /// `1` bits padding the last byte. Restart markers don't count, as
+    /// more data follows them.
+    pub fn at_end(&mut self) -> bool {
+        self.fill();
+        self.num_bits - self.padding_bits < 8 &&
+        match self.marker {
+            Some(code) => !(0xd0..0xd8).contains(&code),
+            None => true,
+        }
+    }
+
+    /// Read a DC difference, or a sample difference in lossless frames.
+    pub fn next_difference(&mut self, dc_table: &HuffmanTable) -> Result<i32, String> {
+        let num_bits = self.next_code(dc_table)? as usize;
+        match num_bits {
+            // See Table H.2; the largest difference has no additional bits.
+            16 => Ok(32768),
+            0..=15 => {
+                let num = self.read_n_bits(num_bits);
+                Ok(HuffmanDecoder::value_correction(num, num_bits) as i32)
+            }
+            _ => Err(format!("Difference with {} bits is too large", num_bits)),
+        }
+    }
+
+    /// Read one bit, used for refining DC coefficients in progressive scans.
+    pub fn next_bit(&mut self) -> bool {
+        self.read_n_bits(1) == 1
+    }
+
+    /// Read the first AC coefficients in band `start..=end` of a block in
+    /// a progressive scan. See JPEG G.1.2.2.
+    pub fn next_ac_first(&mut self,
+                         block: &mut [i16; 64],
+                         ac_table: &HuffmanTable,
+                         start: usize,
+                         end: usize,
+                         point_transform: u8)
+                         -> Result<(), String> {
+        if self.eob_run > 0 {
+            self.eob_run -= 1;
+            return Ok(());
+        }
+        let mut index = start;
+        while index <= end {
+            let next_code = self.next_code(ac_table)?;
+            let run = (next_code >> 4) as usize;
+            let num_bits = (next_code & 0xf) as usize;
+            if num_bits != 0 {
+                index += run;
+                if index > end {
+                    return Err("AC coefficients run past the end of the band".to_string());
+                }
+                let num = self.read_n_bits(num_bits);
+                block[index] = HuffmanDecoder::value_correction(num, num_bits) << point_transform;
+                index += 1;
+            } else if run == 15 {
+                index += 16;
+            } else {
+                // This block and the next `eob_run` blocks have no more
+                // coefficients in this band.
+                self.eob_run = (1 << run) - 1;
+                if run > 0 {
+                    self.eob_run += self.read_n_bits(run) as u32;
+                }
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the refinement bits of the AC coefficients in band `start..=end`
+    /// of a block in a progressive scan. See JPEG G.1.2.3.
+    pub fn next_ac_refinement(&mut self,
+                              block: &mut [i16; 64],
+                              ac_table: &HuffmanTable,
+                              start: usize,
+                              end: usize,
+                              point_transform: u8)
+                              -> Result<(), String> {
+        let positive = 1 << point_transform;
+        let negative = -1 << point_transform;
+        let mut index = start;
+
+        if self.eob_run == 0 {
+            while index <= end {
+                let next_code = self.next_code(ac_table)?;
+                let mut run = (next_code >> 4) as i32;
+                let num_bits = next_code & 0xf;
+                let mut value = 0;
+                if num_bits != 0 {
+                    // Coefficients which become nonzero in this scan have
+                    // a magnitude of 1, so we only need the sign.
+                    if num_bits != 1 {
+                        return Err(format!("Refined AC coefficient with {} bits", num_bits));
+                    }
+                    value = if self.next_bit() { positive } else { negative };
+                } else if run != 15 {
+                    self.eob_run = 1 << run;
+                    if run > 0 {
+                        self.eob_run += self.read_n_bits(run as usize) as u32;
+                    }
+                    break;
+                }
+
+                // Skip `run` zero coefficients, refining all nonzero
+                // coefficients we pass on the way.
+                while index <= end {
+                    if block[index] != 0 {
+                        self.refine(&mut block[index], positive, negative);
+                    } else {
+                        if run == 0 {
+                            break;
+                        }
+                        run -= 1;
+                    }
+                    index += 1;
+                }
+                if value != 0 {
+                    if index > end {
+                        return Err("AC coefficients run past the end of the band".to_string());
+                    }
+                    block[index] = value;
+                }
+                index += 1;
+            }
+        }
+
+        if self.eob_run > 0 {
+            // The rest of the band only has refinement bits for the
+            // coefficients which are already nonzero.
+            while index <= end {
+                if block[index] != 0 {
+                    self.refine(&mut block[index], positive, negative);
+                }
+                index += 1;
+            }
+            self.eob_run -= 1;
+        }
+        Ok(())
+    }
+
+    /// Add a refinement bit to a nonzero coefficient, away from zero.
+    fn refine(&mut self, coefficient: &mut i16, positive: i16, negative: i16) {
+        if self.next_bit() && *coefficient & positive == 0 {
+            if *coefficient >= 0 {
+                *coefficient += positive;
+            } else {
+                *coefficient += negative;
+            }
+        }
+    }
+
+    /// Read the next 8x8 block, with the coefficients in zigzag order.
+    /// The DC coefficient is the difference from the previous block.
+    pub fn next_block(&mut self,
+                      ac_table: &HuffmanTable,
+                      dc_table: &HuffmanTable)
+                      -> Result<[i16; 64], String> {
+        let mut block = [0; 64];
         // First we read the DC coefficient, which is encoded as
         // `(num_bits)(value)`, where `value` is _not_ huffman encoded,
         // but `num_bits` is.
-        // TODO: remove `expect`
-        let num_bits = self.next_code(dc_table);
-        if num_bits.is_none() {
-            println!("DC lookup fail");
-            println!("current_16 = {:016b}", (self.current >> 16) & 0xffff);
+        let num_bits = self.next_code(dc_table)? as usize;
+        if num_bits > 11 {
+            return Err(format!("DC coefficient with {} bits is too large", num_bits));
         }
-        let num_bits = num_bits.unwrap() as usize;
-        let dc_coef = HuffmanDecoder::value_correction(self.read_n_bits(num_bits), num_bits);
-
-        let mut block: Vec<i16> = vec![dc_coef];
+        block[0] = HuffmanDecoder::value_correction(self.read_n_bits(num_bits), num_bits);
 
-        while block.len() < 64 {
-            let next_code = self.next_code(ac_table).expect("ILLEGAL STATE!");
+        let mut index = 1;
+        while index < 64 {
+            let next_code = self.next_code(ac_table)?;
             match next_code {
                 0x00 => {
-                    // End. Fill rest of `block` with `0`
-                    let block_len = block.len();
-                    block.extend(repeat(0).take(64 - block_len));
+                    // End. The rest of `block` is `0`.
                     break;
                 }
                 0xf0 => {
-                    // Push 16 `0`s
-                    let to_push = min(16, 64 - block.len());
-                    block.extend(repeat(0).take(to_push));
+                    // Skip 16 `0`s
+                    index += 16;
                     continue;
                 }
                 _ => {}
@@ -182,16 +447,102 @@ impl<'a> HuffmanDecoder<'a> {
             // The tuple is huffman encoded. `code` is not.
             let prepending_zeroes = ((next_code & 0xf0) >> 4) as usize;
             let num_bits = (next_code & 0xf) as usize;
+            index += prepending_zeroes;
+            if index > 63 {
+                return Err("AC coefficients run past the end of the block".to_string());
+            }
             let num = self.read_n_bits(num_bits);
-            let number = HuffmanDecoder::value_correction(num, num_bits);
-            let zeroes_to_push = min(prepending_zeroes, 64 - block.len() - 1);
-            block.extend(repeat(0).take(zeroes_to_push));
-            block.push(number);
+            block[index] = HuffmanDecoder::value_correction(num, num_bits);
+            index += 1;
         }
 
-        assert!(block.len() == 64);
+        Ok(block)
+    }
+
+    /// Skip to the next byte boundary, and past the restart marker
+    /// which should be there.
+    pub fn restart(&mut self) -> Result<(), String> {
+        // We only read whole bytes, so the bits left in `current` are
+        // the padding of the last byte of the interval.
+        self.current = 0;
+        self.num_bits = 0;
+        self.padding_bits = 0;
+        self.eob_run = 0;
+        self.overrun = false;
+        if self.marker.is_none() {
+            self.find_marker();
+        }
+        match self.marker {
+            Some(code) if (0xd0..0xd8).contains(&code) => {
+                self.skip_marker();
+                Ok(())
+            }
+            Some(code) => Err(format!("Expected a restart marker, found {:02x}", code)),
+            None => Err("Expected a restart marker, found the end of the data".to_string()),
+        }
+    }
 
-        block
+    /// Skip the data up to the next marker without decoding it, and
+    /// return whether the marker is found. If we are at a restart marker,
+    /// it is skipped first. If no marker is found, all data which can't
+    /// be part of one is used, and more is needed.
+    pub fn skip_to_marker(&mut self) -> bool {
+        if self.marker.is_some_and(|code| (0xd0..0xd8).contains(&code)) {
+            self.skip_marker();
+        }
+        self.resynchronize()
+    }
+
+    /// Drop the bits which are read, and skip the data up to the next
+    /// marker, after corrupt data. Unlike `skip_to_marker`, a restart
+    /// marker we are at is not skipped, as decoding continues there.
+    pub fn resynchronize(&mut self) -> bool {
+        self.current = 0;
+        self.num_bits = 0;
+        self.padding_bits = 0;
+        self.eob_run = 0;
+        self.overrun = false;
+        while self.marker.is_none() {
+            match self.data[self.next_index..].iter().position(|&byte| byte == 0xff) {
+                Some(position) => self.next_index += position,
+                None => {
+                    self.next_index = self.data.len();
+                    return false;
+                }
+            }
+            self.find_marker();
+            if self.marker.is_none() {
+                match self.data[self.next_index..].iter().position(|&byte| byte != 0xff) {
+                    // A stuffed `0xff00`
+                    Some(position) => self.next_index += position + 1,
+                    // Fill bytes, which may be followed by a marker in
+                    // the next piece of data.
+                    None => return false,
+                }
+            }
+        }
+        true
+    }
+
+    /// Skip all data up to the marker which ends it, past any restart
+    /// markers, and return whether it is found. See `skip_to_marker`.
+    pub fn skip_to_end(&mut self) -> bool {
+        while self.skip_to_marker() {
+            if !self.marker.is_some_and(|code| (0xd0..0xd8).contains(&code)) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Move past the marker we have found, and its fill bytes.
+    fn skip_marker(&mut self) {
+        while self.data[self.next_index] == 0xff {
+            self.next_index += 1;
+        }
+        // Skip the marker code as well.
+        self.next_index += 1;
+        self.marker = None;
     }
 
     /// Read `n` bits from `current`
@@ -200,56 +551,78 @@ impl<'a> HuffmanDecoder<'a> {
             return 0;
         }
         assert!(n <= 16, "Should not read more than 16 bits at a time!");
-        let mask = BIT_MASKS[n];
-        let current_16 = (self.current >> 16) as u16;
-        let number = ((current_16 & mask) >> (16 - n)) as u16;
-        self.shift_and_fix_current(n);
+        self.fill();
+        let number = (self.current >> (32 - n)) as u16;
+        self.consume(n);
         number
     }
 
     /// Get the next code from `current` in the supplied table.
-    fn next_code(&mut self, table: &HuffmanTable) -> Option<u8> {
-        (2..17)
-            .flat_map(|len| {
-                let mask = BIT_MASKS[len];
-                let current_16 = (self.current >> 16) as u16;
-                let bits = ((current_16 & mask) >> (16 - len)) as u16;
-
-                table.codes_of_length(len).iter()
-                    // Find the code ID of length `len`, and code bits `bits`
-                    .find(|&code| code.code == bits)
-                    .map(|code| {
-                        self.shift_and_fix_current(len);
-                        code.value
-                    })
-            })
-            .nth(0)
+    fn next_code(&mut self, table: &HuffmanTable) -> Result<u8, String> {
+        // This is the DECODE procedure in JPEG F.2.2.3: a code of length
+        // `len` is valid if it is not larger than the largest code of
+        // that length.
+        self.fill();
+        for len in 1..17 {
+            let bits = (self.current >> (32 - len)) as i32;
+            if bits <= table.max_code[len] {
+                self.consume(len);
+                let index = table.value_pointer[len] + (bits as usize) -
+                            table.min_code[len] as usize;
+                return Ok(table.codes[index].value);
+            }
+        }
+        Err(format!("Invalid huffman code at byte {}", self.next_index))
     }
 
-    /// Shift out `len` bits from `current`, and extend with new data
-    /// from `self.data` if appropriate
-    fn shift_and_fix_current(&mut self, len: usize) {
-        if len == 0 {
-            return;
+    /// Shift out `len` bits from `current`.
+    fn consume(&mut self, len: usize) {
+        // Padding bits are at the end of `current`, so they are only
+        // used when the real bits are used up.
+        let real_bits = self.num_bits - self.padding_bits;
+        if len > real_bits {
+            self.padding_bits -= len - real_bits;
+            self.overrun = true;
         }
         self.current <<= len;
-        self.bits_read += len;
-        while self.bits_read >= 8 {
-            self.bits_read -= 8;
-            let next_num = {
-                if self.next_index >= self.data.len() {
-                    // We might need to shift in additional data
-                    // when we are at the end. Assuming the file
-                    // is well formed, these values will not be read,
-                    // and is only fill data, in order to avoid
-                    // out-of-range indexing.
-                    0xaa
-                } else {
-                    self.data[self.next_index]
-                }
-            } as u32;
-            self.current |= next_num << self.bits_read;
-            self.next_index += 1;
+        self.num_bits -= len;
+    }
+
+    /// Extend `current` with new data from `self.data`, so that it has at
+    /// least 25 bits. When we reach a marker or the end of the data, `0`s
+    /// are shifted in instead. Assuming the data is well formed, these
+    /// are never used, and `overrun` tells if they are.
+    fn fill(&mut self) {
+        while self.num_bits <= 24 {
+            if self.marker.is_none() {
+                self.find_marker();
+            }
+            let byte = if self.marker.is_some() || self.next_index >= self.data.len() {
+                self.padding_bits += 8;
+                0
+            } else if self.data[self.next_index] == 0xff {
+                // A stuffed `0xff00`, as `find_marker` found no marker here,
+                // unless the data ends with the `0xff`.
+                self.next_index = (self.next_index + 2).min(self.data.len());
+                0xff
+            } else {
+                self.next_index += 1;
+                self.data[self.next_index - 1]
+            };
+            self.current |= (byte as u32) << (24 - self.num_bits);
+            self.num_bits += 8;
+        }
+    }
+
+    /// Check whether there is a marker at `next_index`, and set `marker`
+    /// if there is. Any number of `0xff` fill bytes may precede a marker.
+    fn find_marker(&mut self) {
+        let mut index = self.next_index;
+        while index < self.data.len() && self.data[index] == 0xff {
+            index += 1;
+        }
+        if index > self.next_index && index < self.data.len() && self.data[index] != 0x00 {
+            self.marker = Some(self.data[index]);
         }
     }
 
@@ -258,10 +631,10 @@ impl<'a> HuffmanDecoder<'a> {
         if len == 0 {
             return 0;
         }
-        let val = val as i16;
-        let base: i16 = 1 << (len - 1);
+        let val = val as i32;
+        let base: i32 = 1 << (len - 1);
         if val < base {
-            -2 * base + 1 + val
+            (-2 * base + 1 + val) as i16
         } else {
             val as i16
         }
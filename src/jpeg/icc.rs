@@ -0,0 +1,49 @@
+// ICC color profiles, stored in APP2.
+//
+// A profile may be larger than a segment, so it is split into chunks.
+// Each segment starts with `ICC_IDENTIFIER`, followed by the number of
+// its chunk, counting from 1, and the number of chunks. See ICC.1 B.4.
+
+/// Identifier at the start of an APP2 segment containing a chunk of an
+/// ICC profile.
+pub const ICC_IDENTIFIER: &[u8] = b"ICC_PROFILE\0";
+
+/// Join the chunks of an ICC profile from the data of the APP2 segments
+/// which start with `ICC_IDENTIFIER`, in any order. Returns `None` if
+/// there are none.
+pub fn join_chunks<'a, I>(segments: I) -> Result<Option<Vec<u8>>, String>
+    where I: IntoIterator<Item = &'a [u8]>
+{
+    let header_length = ICC_IDENTIFIER.len() + 2;
+    let mut chunks: Vec<Option<&[u8]>> = Vec::new();
+    for segment in segments {
+        if !segment.starts_with(ICC_IDENTIFIER) || segment.len() < header_length {
+            return Err("APP2 segment is not a chunk of an ICC profile".to_string());
+        }
+        let (number, count) = (segment[ICC_IDENTIFIER.len()] as usize,
+                               segment[ICC_IDENTIFIER.len() + 1] as usize);
+        if chunks.is_empty() {
+            chunks = vec![None; count];
+        }
+        if count != chunks.len() || number == 0 || number > count {
+            return Err(format!("ICC profile chunk {} of {} doesn't fit the other chunks",
+                               number,
+                               count));
+        }
+        if chunks[number - 1].is_some() {
+            return Err(format!("ICC profile chunk {} appears twice", number));
+        }
+        chunks[number - 1] = Some(&segment[header_length..]);
+    }
+    if chunks.is_empty() {
+        return Ok(None);
+    }
+    let mut profile = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        match *chunk {
+            Some(chunk) => profile.extend_from_slice(chunk),
+            None => return Err(format!("ICC profile chunk {} is missing", i + 1)),
+        }
+    }
+    Ok(Some(profile))
+}
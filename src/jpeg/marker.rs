@@ -0,0 +1,347 @@
+// Low level reading of the marker structure of a JPEG file, without
+// interpreting any of the segments. See JPEG B.1.1.
+
+/// All markers defined in Table B.1 of the JPEG standard.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Marker {
+    // Start of frame markers, non-differential, Huffman coding
+    BaselineDCT,
+    ExtendedSequentialDCT,
+    ProgressiveDCT,
+    Lossless,
+    // Start of frame markers, differential, Huffman coding
+    DifferentialSequentialDCT,
+    DifferentialProgressiveDCT,
+    DifferentialLossless,
+    // Start of frame markers, non-differential, arithmetic coding
+    ExtendedSequentialDCTArithmetic,
+    ProgressiveDCTArithmetic,
+    LosslessArithmetic,
+    // Start of frame markers, differential, arithmetic coding
+    DifferentialSequentialDCTArithmetic,
+    DifferentialProgressiveDCTArithmetic,
+    DifferentialLosslessArithmetic,
+    /// JPG, reserved for JPEG extensions
+    ReservedJPEGExtension,
+    DefineHuffmanTable,
+    DefineArithmeticConditioning,
+    /// RSTn, with `n` in `0..8`
+    Restart(u8),
+    StartOfImage,
+    EndOfImage,
+    StartOfScan,
+    QuantizationTable,
+    DefineNumberOfLines,
+    RestartIntervalDefinition,
+    DefineHierarchicalProgression,
+    ExpandReferenceComponents,
+    /// APPn, with `n` in `0..16`
+    ApplicationSegment(u8),
+    /// JPGn, with `n` in `0..14`
+    JPEGExtension(u8),
+    Comment,
+    /// TEM, for temporary private use in arithmetic coding
+    Temporary,
+    /// RES, reserved markers `0x02` - `0xbf`.
+    Reserved(u8),
+}
+
+impl Marker {
+    /// The marker with the given code, which is the byte following `0xff`.
+    pub fn from_code(code: u8) -> Marker {
+        use self::Marker::*;
+        match code {
+            0xc0 => BaselineDCT,
+            0xc1 => ExtendedSequentialDCT,
+            0xc2 => ProgressiveDCT,
+            0xc3 => Lossless,
+            0xc4 => DefineHuffmanTable,
+            0xc5 => DifferentialSequentialDCT,
+            0xc6 => DifferentialProgressiveDCT,
+            0xc7 => DifferentialLossless,
+            0xc8 => ReservedJPEGExtension,
+            0xc9 => ExtendedSequentialDCTArithmetic,
+            0xca => ProgressiveDCTArithmetic,
+            0xcb => LosslessArithmetic,
+            0xcc => DefineArithmeticConditioning,
+            0xcd => DifferentialSequentialDCTArithmetic,
+            0xce => DifferentialProgressiveDCTArithmetic,
+            0xcf => DifferentialLosslessArithmetic,
+            0xd0..=0xd7 => Restart(code - 0xd0),
+            0xd8 => StartOfImage,
+            0xd9 => EndOfImage,
+            0xda => StartOfScan,
+            0xdb => QuantizationTable,
+            0xdc => DefineNumberOfLines,
+            0xdd => RestartIntervalDefinition,
+            0xde => DefineHierarchicalProgression,
+            0xdf => ExpandReferenceComponents,
+            0xe0..=0xef => ApplicationSegment(code - 0xe0),
+            0xf0..=0xfd => JPEGExtension(code - 0xf0),
+            0xfe => Comment,
+            0x01 => Temporary,
+            // `0x00` and `0xff` are not markers, but stuffing and fill bytes.
+            // `MarkerReader` never returns them.
+            _ => Reserved(code),
+        }
+    }
+
+    /// The byte following `0xff` for this marker.
+    pub fn code(&self) -> u8 {
+        use self::Marker::*;
+        match *self {
+            BaselineDCT => 0xc0,
+            ExtendedSequentialDCT => 0xc1,
+            ProgressiveDCT => 0xc2,
+            Lossless => 0xc3,
+            DefineHuffmanTable => 0xc4,
+            DifferentialSequentialDCT => 0xc5,
+            DifferentialProgressiveDCT => 0xc6,
+            DifferentialLossless => 0xc7,
+            ReservedJPEGExtension => 0xc8,
+            ExtendedSequentialDCTArithmetic => 0xc9,
+            ProgressiveDCTArithmetic => 0xca,
+            LosslessArithmetic => 0xcb,
+            DefineArithmeticConditioning => 0xcc,
+            DifferentialSequentialDCTArithmetic => 0xcd,
+            DifferentialProgressiveDCTArithmetic => 0xce,
+            DifferentialLosslessArithmetic => 0xcf,
+            Restart(n) => 0xd0 + n,
+            StartOfImage => 0xd8,
+            EndOfImage => 0xd9,
+            StartOfScan => 0xda,
+            QuantizationTable => 0xdb,
+            DefineNumberOfLines => 0xdc,
+            RestartIntervalDefinition => 0xdd,
+            DefineHierarchicalProgression => 0xde,
+            ExpandReferenceComponents => 0xdf,
+            ApplicationSegment(n) => 0xe0 + n,
+            JPEGExtension(n) => 0xf0 + n,
+            Comment => 0xfe,
+            Temporary => 0x01,
+            Reserved(code) => code,
+        }
+    }
+
+    /// The abbreviation of the marker in Table B.1, like `SOF0` or `APP1`.
+    pub fn name(&self) -> String {
+        use self::Marker::*;
+        match *self {
+            DefineHuffmanTable => "DHT".to_string(),
+            ReservedJPEGExtension => "JPG".to_string(),
+            DefineArithmeticConditioning => "DAC".to_string(),
+            Restart(n) => format!("RST{}", n),
+            StartOfImage => "SOI".to_string(),
+            EndOfImage => "EOI".to_string(),
+            StartOfScan => "SOS".to_string(),
+            QuantizationTable => "DQT".to_string(),
+            DefineNumberOfLines => "DNL".to_string(),
+            RestartIntervalDefinition => "DRI".to_string(),
+            DefineHierarchicalProgression => "DHP".to_string(),
+            ExpandReferenceComponents => "EXP".to_string(),
+            ApplicationSegment(n) => format!("APP{}", n),
+            JPEGExtension(n) => format!("JPG{}", n),
+            Comment => "COM".to_string(),
+            Temporary => "TEM".to_string(),
+            Reserved(code) => format!("RES{:02x}", code),
+            // The frame markers are numbered by their code.
+            marker => format!("SOF{}", marker.code() - 0xc0),
+        }
+    }
+
+    /// Whether the marker is followed by two length bytes and a segment.
+    /// Only TEM, RSTn, SOI and EOI stand alone.
+    pub fn has_length(&self) -> bool {
+        !matches!(*self,
+                  Marker::Temporary | Marker::Restart(_) | Marker::StartOfImage |
+                  Marker::EndOfImage)
+    }
+
+    /// Whether this is one of the SOFn markers.
+    pub fn is_start_of_frame(&self) -> bool {
+        matches!(self.code(),
+                 0xc0..=0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf)
+    }
+
+    /// Whether this is one of the SOFn markers of arithmetic coded frames.
+    pub fn is_arithmetic_coded(&self) -> bool {
+        matches!(self.code(), 0xc9..=0xcb | 0xcd..=0xcf)
+    }
+
+    /// Whether this is one of the SOFn markers of lossless frames.
+    pub fn is_lossless(&self) -> bool {
+        matches!(self.code(), 0xc3 | 0xc7 | 0xcb | 0xcf)
+    }
+}
+
+/// The kind of data in a `MarkerSegment`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SegmentKind {
+    Marker(Marker),
+    /// `0xff` fill bytes in front of a marker.
+    Fill,
+    /// Entropy coded data in a scan, including stuffed `0x00` bytes.
+    /// Restart markers split the data into several spans.
+    EntropyCoded,
+    /// Everything after the EOI marker.
+    Trailer,
+}
+
+/// A piece of a JPEG file, as returned by `MarkerReader`.
+#[derive(Debug, Clone)]
+pub struct MarkerSegment<'a> {
+    pub kind: SegmentKind,
+    /// Offset of the first byte of the segment in the file. For markers,
+    /// this is the offset of the `0xff` byte.
+    pub offset: usize,
+    /// For markers with a length, the data following the length bytes.
+    /// Empty for markers without one, like SOI, EOI and RSTn.
+    /// For all other kinds, all bytes of the segment.
+    pub payload: &'a [u8],
+}
+
+impl<'a> MarkerSegment<'a> {
+    /// Number of bytes the segment takes up in the file.
+    pub fn encoded_length(&self) -> usize {
+        match self.kind {
+            SegmentKind::Marker(marker) if !marker.has_length() => 2,
+            SegmentKind::Marker(_) => 4 + self.payload.len(),
+            _ => self.payload.len(),
+        }
+    }
+
+    /// Offset of the first byte after the segment.
+    pub fn end(&self) -> usize {
+        self.offset + self.encoded_length()
+    }
+}
+
+/// Iterator over all markers, fill bytes and entropy coded data in a file.
+///
+/// No segments are interpreted, except that the reader knows that
+/// entropy coded data follows SOS and RSTn markers, and that everything
+/// after EOI is trailing data. If the file is malformed, the iterator
+/// yields one error and then stops.
+pub struct MarkerReader<'a> {
+    data: &'a [u8],
+    /// Index of the next byte to read
+    position: usize,
+    /// Whether we are in a scan, so that non-marker bytes are entropy coded data
+    in_scan: bool,
+    /// Whether we have passed the EOI marker
+    after_end: bool,
+    /// Set when we encounter an error
+    done: bool,
+}
+
+impl<'a> MarkerReader<'a> {
+    pub fn new(data: &'a [u8]) -> MarkerReader<'a> {
+        MarkerReader {
+            data,
+            position: 0,
+            in_scan: false,
+            after_end: false,
+            done: false,
+        }
+    }
+
+    /// Index of the next byte to be read.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    fn error(&mut self, message: String) -> Option<Result<MarkerSegment<'a>, String>> {
+        self.done = true;
+        Some(Err(message))
+    }
+
+    fn segment(&mut self,
+               kind: SegmentKind,
+               offset: usize,
+               end: usize,
+               payload: &'a [u8])
+               -> Option<Result<MarkerSegment<'a>, String>> {
+        self.position = end;
+        Some(Ok(MarkerSegment {
+            kind,
+            offset,
+            payload,
+        }))
+    }
+}
+
+impl<'a> Iterator for MarkerReader<'a> {
+    type Item = Result<MarkerSegment<'a>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.data;
+        let start = self.position;
+        if self.done || start >= data.len() {
+            return None;
+        }
+        if self.after_end {
+            // Everything after EOI is trailing data.
+            return self.segment(SegmentKind::Trailer, start, data.len(), &data[start..]);
+        }
+
+        let is_marker_at = |i: usize| {
+            data[i] == 0xff && i + 1 < data.len() && data[i + 1] != 0x00
+        };
+
+        if !is_marker_at(start) {
+            if !self.in_scan {
+                return self.error(format!("Expected a marker at byte {}, found {:02x}",
+                                          start,
+                                          data[start]));
+            }
+            // Entropy coded data lasts until the next marker.
+            let mut end = start;
+            while end < data.len() && !is_marker_at(end) {
+                end += 1;
+            }
+            return self.segment(SegmentKind::EntropyCoded, start, end, &data[start..end]);
+        }
+
+        // Any number of 0xff bytes may precede a marker.
+        let mut code_index = start + 1;
+        while code_index < data.len() && data[code_index] == 0xff {
+            code_index += 1;
+        }
+        if code_index > start + 1 {
+            let end = code_index - 1;
+            return self.segment(SegmentKind::Fill, start, end, &data[start..end]);
+        }
+        if code_index >= data.len() {
+            return self.error(format!("File ends in the middle of a marker at byte {}", start));
+        }
+
+        let marker = Marker::from_code(data[code_index]);
+        if !marker.has_length() {
+            match marker {
+                Marker::StartOfImage => self.in_scan = false,
+                Marker::EndOfImage => {
+                    self.in_scan = false;
+                    self.after_end = true;
+                }
+                _ => {}
+            }
+            return self.segment(SegmentKind::Marker(marker), start, start + 2, &[]);
+        }
+
+        if start + 4 > data.len() {
+            return self.error(format!("{:?} marker at byte {} has no length", marker, start));
+        }
+        let length = ((data[start + 2] as usize) << 8) | data[start + 3] as usize;
+        if length < 2 || start + 2 + length > data.len() {
+            return self.error(format!("{:?} marker at byte {} has invalid length {}",
+                                      marker,
+                                      start,
+                                      length));
+        }
+        // Entropy coded data follows SOS, and ends at the next marker which
+        // is not RSTn.
+        self.in_scan = marker == Marker::StartOfScan;
+        let end = start + 2 + length;
+        self.segment(SegmentKind::Marker(marker), start, end, &data[start + 4..end])
+    }
+}
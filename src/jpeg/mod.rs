@@ -1,10 +1,35 @@
 pub mod huffman;
 pub mod decoder;
+pub mod tiff;
+pub mod mpf;
+pub mod exif;
+pub mod icc;
+pub mod writer;
+pub mod marker;
+pub mod hierarchical;
+pub mod stream;
+pub mod pixel;
+pub mod encoder;
+pub mod transform;
+pub mod arithmetic;
+pub mod color;
+pub mod compress;
+pub mod report;
+pub mod validate;
 
-use jpeg::decoder::JPEGDecoder;
+use jpeg::color::ColorConversion;
+use jpeg::decoder::{CoefficientOrder, ComponentCoefficients, JPEGDecoder, MCURow};
+use jpeg::hierarchical::HierarchicalDecoder;
+use jpeg::marker::{Marker, MarkerReader, MarkerSegment, SegmentKind};
+use jpeg::pixel::{PixelBuffer, PixelFormat};
 
 use std::str;
 
+/// Comments are stored as raw bytes, but are usually text.
+fn parse_comment(data: &[u8]) -> Option<String> {
+    str::from_utf8(data).map(|s| s.to_string()).ok()
+}
+
 // TODO: move this?
 fn u8s_to_u16(bytes: &[u8]) -> u16 {
     let msb = bytes[0] as u16;
@@ -23,9 +48,9 @@ pub enum JFIFUnits {
 impl JFIFUnits {
     pub fn from_u8(byte: u8) -> JFIFUnits {
         match byte {
-            1 => JFIFUnits::NoUnits,
-            2 => JFIFUnits::DotsPerInch,
-            3 => JFIFUnits::DotsPerCm,
+            0 => JFIFUnits::NoUnits,
+            1 => JFIFUnits::DotsPerInch,
+            2 => JFIFUnits::DotsPerCm,
             _ => JFIFUnits::Unknown(byte),
         }
     }
@@ -77,6 +102,8 @@ pub struct JPEGImage {
     huffman_dc_tables: [Option<huffman::HuffmanTable>; 4],
     /// Quantization tables
     quantization_tables: [Option<Vec<u16>>; 4],
+    /// Conditioning of the arithmetic coding tables
+    arithmetic_conditioning: arithmetic::ArithmeticConditioning,
     /// Frame header data
     frame_header: Option<FrameHeader>,
     scan_headers: Option<Vec<ScanHeader>>,
@@ -84,22 +111,100 @@ pub struct JPEGImage {
     /// NOTE: only support 8-bit precision
     /// TODO: Add support for other precisions
     image_data: Option<Vec<(u8, u8, u8)>>,
+    /// Number of MCUs in each restart interval, or `0` if restart
+    /// markers are not used.
+    restart_interval: u16,
+    /// Multi-Picture Format index, if the file has one
+    mpf_index: Option<mpf::MPFIndex>,
+    /// The bytes the image was parsed from. Embedded images,
+    /// like the ones in the MPF index, are read from this.
+    data: Vec<u8>,
+    /// What was wrong with the file. See `warnings`.
+    warnings: Vec<String>,
+    /// How YCbCr was turned into RGB, and how the luma is found again
+    /// for gray formats in `decode_into`
+    color_conversion: ColorConversion,
 }
 
 #[derive(Debug, Clone)]
 pub struct FrameHeader {
+    /// The SOFn marker of the frame, which tells how it is coded
+    pub frame_type: Marker,
     /// Bits per sample of each component in the frame
-    sample_precision: u8,
+    pub sample_precision: u8,
     /// The maximum number of lines in the source image
-    num_lines: u16,
+    pub num_lines: u16,
     /// The maximum number of samples per line in the source image
-    samples_per_line: u16,
+    pub samples_per_line: u16,
     /// Number of image components in the frame
-    image_components: u8,
+    pub image_components: u8,
     /// Headers for each component
     pub frame_components: Vec<FrameComponentHeader>,
 }
 
+impl FrameHeader {
+    /// Parse the data of a SOFn or DHP segment. See JPEG B.2.2.
+    pub fn parse(frame_type: Marker, data: &[u8]) -> Result<FrameHeader, String> {
+        if data.len() < 6 {
+            return Err(format!("{:?} segment is too short", frame_type));
+        }
+        let sample_precision = data[0];
+        let num_lines = u8s_to_u16(&data[1..]);
+        let samples_per_line = u8s_to_u16(&data[3..]);
+        let image_components = data[5];
+        if data.len() < 6 + 3 * image_components as usize {
+            return Err(format!("{:?} segment is too short for {} components",
+                               frame_type,
+                               image_components));
+        }
+
+        let mut frame_components = Vec::with_capacity(image_components as usize);
+        for component in data[6..].chunks(3).take(image_components as usize) {
+            let component_id = component[0];
+            let horizontal_sampling_factor = (component[1] & 0xf0) >> 4;
+            let vertical_sampling_factor = component[1] & 0x0f;
+            if !(1..=4).contains(&horizontal_sampling_factor) ||
+               !(1..=4).contains(&vertical_sampling_factor) {
+                return Err(format!("Invalid sampling factors {}x{} for component {}",
+                                   horizontal_sampling_factor,
+                                   vertical_sampling_factor,
+                                   component_id));
+            }
+            frame_components.push(FrameComponentHeader {
+                component_id,
+                horizontal_sampling_factor,
+                vertical_sampling_factor,
+                quantization_selector: component[2],
+            });
+        }
+        Ok(FrameHeader {
+            frame_type,
+            sample_precision,
+            num_lines,
+            samples_per_line,
+            image_components,
+            frame_components,
+        })
+    }
+
+    /// The data of the SOFn segment of this header.
+    pub fn segment_data(&self) -> Vec<u8> {
+        let mut data = vec![self.sample_precision,
+                            (self.num_lines >> 8) as u8,
+                            self.num_lines as u8,
+                            (self.samples_per_line >> 8) as u8,
+                            self.samples_per_line as u8,
+                            self.frame_components.len() as u8];
+        for component in &self.frame_components {
+            data.extend_from_slice(&[component.component_id,
+                                     component.horizontal_sampling_factor << 4 |
+                                     component.vertical_sampling_factor,
+                                     component.quantization_selector]);
+        }
+        data
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct FrameComponentHeader {
     /// Component id
@@ -115,17 +220,60 @@ pub struct FrameComponentHeader {
 #[derive(Debug, Clone)]
 pub struct ScanHeader {
     /// Number of components in the scan.
-    num_components: u8,
+    pub num_components: u8,
     /// Headers for each component
     pub scan_components: Vec<ScanComponentHeader>,
     /// (?) Should be zero for seq. DCT
-    start_spectral_selection: u8,
+    pub start_spectral_selection: u8,
     /// (?) Should be 63 for seq. DCT
-    end_spectral_selection: u8,
+    pub end_spectral_selection: u8,
     /// Something something point transform
-    successive_approximation_bit_pos_high: u8,
+    pub successive_approximation_bit_pos_high: u8,
     /// Something something point transform
-    successive_approximation_bit_pos_low: u8,
+    pub successive_approximation_bit_pos_low: u8,
+}
+
+impl ScanHeader {
+    /// Read the data of a SOS segment. See JPEG B.2.3.
+    pub fn parse(data: &[u8]) -> Result<ScanHeader, String> {
+        if data.is_empty() || data.len() < 4 + 2 * data[0] as usize {
+            return Err("SOS segment is too short".to_string());
+        }
+        let num_components = data[0];
+        let mut i = 0;
+        let mut scan_components = Vec::new();
+        for _ in 0..num_components {
+            scan_components.push(ScanComponentHeader {
+                component_id: data[i + 1],
+                dc_table_selector: (data[i + 2] & 0xf0) >> 4,
+                ac_table_selector: data[i + 2] & 0x0f,
+            });
+            i += 2;
+        }
+        Ok(ScanHeader {
+            num_components,
+            scan_components,
+            start_spectral_selection: data[i + 1],
+            end_spectral_selection: data[i + 2],
+            successive_approximation_bit_pos_high: (data[i + 3] & 0xf0) >> 4,
+            successive_approximation_bit_pos_low: data[i + 3] & 0x0f,
+        })
+    }
+
+    /// The data of the SOS segment of this header.
+    pub fn segment_data(&self) -> Vec<u8> {
+        let mut data = vec![self.scan_components.len() as u8];
+        for component in &self.scan_components {
+            data.extend_from_slice(&[component.component_id,
+                                     component.dc_table_selector << 4 |
+                                     component.ac_table_selector]);
+        }
+        data.extend_from_slice(&[self.start_spectral_selection,
+                                 self.end_spectral_selection,
+                                 self.successive_approximation_bit_pos_high << 4 |
+                                 self.successive_approximation_bit_pos_low]);
+        data
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -138,49 +286,562 @@ pub struct ScanComponentHeader {
     pub ac_table_selector: u8,
 }
 
-#[derive(Debug, PartialEq)]
-enum Marker {
-    // TODO: fill in the rest of these
-    StartOfScan,
-    DefineHuffmanTable,
-    Comment,
-    QuantizationTable,
-    BaselineDCT,
-    RestartIntervalDefinition,
-    ApplicationSegment0,
-    ApplicationSegment12,
-    ApplicationSegment14,
-    StartOfImage,
-    EndOfImage,
+/// Builds a `JPEGImage` from its segments, in the order they are in the
+/// file. This is shared by `JPEGImage::parse`, which has the whole file,
+/// and the decoders in `stream`, which read it a piece at a time.
+struct ImageBuilder {
+    image: JPEGImage,
+    /// Created when we find the frame header, and given the data
+    /// of each scan.
+    decoder: Option<JPEGDecoder>,
+    /// Created when we find the DHP segment of a hierarchical image,
+    /// and given each frame when it is done.
+    hierarchy: Option<HierarchicalDecoder>,
+    /// Whether to turn MCU rows into pixels as they are decoded.
+    /// See `JPEGDecoder::output_rows`.
+    output_rows: bool,
+    /// The image is decoded at `scale / 8` of its size.
+    /// See `JPEGDecoder::scale`.
+    scale: usize,
+    /// The rectangle of the image to decode, as `(x, y, width, height)`.
+    /// See `JPEGDecoder::region`.
+    region: Option<(usize, usize, usize, usize)>,
+    /// Whether only the luma is decoded. See `JPEGDecoder::luma_only`.
+    luma_only: bool,
+    /// How YCbCr is turned into RGB.
+    /// See `JPEGDecoder::color_conversion`.
+    color_conversion: ColorConversion,
+    /// Whether corrupt data is skipped with a warning.
+    /// See `JPEGDecoder::lenient`.
+    lenient: bool,
 }
 
-fn bytes_to_marker(data: &[u8]) -> Option<Marker> {
-    if data[0] != 0xff {
-        return None;
-    }
-    let mut n = data[1];
-    if n == 0 {
-        n = data[2];
-    }
-    use self::Marker::*;
-    let marker = match n {
-        0xc0 => BaselineDCT,
-        0xc4 => DefineHuffmanTable,
-        0xd8 => StartOfImage,
-        0xd9 => EndOfImage,
-        0xda => StartOfScan,
-        0xdb => QuantizationTable,
-        0xdd => RestartIntervalDefinition,
-        0xe0 => ApplicationSegment0,
-        0xec => ApplicationSegment12,
-        0xee => ApplicationSegment14,
-        0xfe => Comment,
-        _ => return None,
-    };
-    Some(marker)
+impl ImageBuilder {
+    fn new() -> ImageBuilder {
+        ImageBuilder {
+            image: JPEGImage::new(),
+            decoder: None,
+            hierarchy: None,
+            output_rows: false,
+            scale: 8,
+            region: None,
+            luma_only: false,
+            color_conversion: ColorConversion::default(),
+            lenient: false,
+        }
+    }
+
+    /// Read the `data` of a marker segment, not counting the length bytes.
+    /// `offset` is the position of `data` in the file.
+    ///
+    /// After a SOS segment, give the entropy coded data to `scan_data`.
+    fn segment(&mut self, marker: Marker, data: &[u8], offset: usize) -> Result<(), String> {
+        match marker {
+            Marker::Comment => {
+                self.image.comment = parse_comment(data);
+            }
+            Marker::QuantizationTable => {
+                for (_, identifier, table) in parse_quantization_tables(data)? {
+                    self.image.quantization_tables[identifier as usize] = Some(table);
+                }
+            }
+            Marker::BaselineDCT |
+            Marker::ExtendedSequentialDCT |
+            Marker::ProgressiveDCT |
+            Marker::Lossless |
+            Marker::DifferentialSequentialDCT |
+            Marker::DifferentialProgressiveDCT |
+            Marker::DifferentialLossless |
+            Marker::ExtendedSequentialDCTArithmetic |
+            Marker::ProgressiveDCTArithmetic |
+            Marker::LosslessArithmetic |
+            Marker::DifferentialSequentialDCTArithmetic |
+            Marker::DifferentialProgressiveDCTArithmetic |
+            Marker::DifferentialLosslessArithmetic => {
+                // JPEG B.2.2
+                // All frame types share the same header, but we only know
+                // how to decode some of them. This is checked in the scan.
+                let frame_header = FrameHeader::parse(marker, data)?;
+                let (samples_per_line, num_lines) = (frame_header.samples_per_line,
+                                                     frame_header.num_lines);
+                match self.hierarchy {
+                    Some(ref mut hierarchy) => {
+                        // The previous frame is done, as a new one starts.
+                        if let Some(previous) = self.decoder.take() {
+                            hierarchy.add_frame(&previous)?;
+                        }
+                        hierarchy.start_frame();
+                    }
+                    None => {
+                        if self.decoder.is_some() {
+                            return Err("Found several frames in an image which is not \
+                                        hierarchical"
+                                .to_string());
+                        }
+                        self.image.dimensions = (samples_per_line, num_lines);
+                    }
+                }
+                // If `num_lines` is zero, the height is given
+                // by a DNL segment after the first scan.
+                let mut decoder = JPEGDecoder::new()
+                    .frame_header(frame_header.clone())
+                    .dimensions((samples_per_line as usize, num_lines as usize));
+                if self.output_rows && self.hierarchy.is_none() {
+                    decoder.output_rows();
+                }
+                if self.scale != 8 {
+                    if self.hierarchy.is_some() || decoder.is_lossless() {
+                        return Err("Only DCT frames which are not hierarchical can be scaled"
+                            .to_string());
+                    }
+                    decoder.scale(self.scale)?;
+                    self.image.dimensions = scaled_dimensions(&decoder)?;
+                }
+                if self.luma_only && self.hierarchy.is_none() {
+                    decoder.luma_only();
+                }
+                decoder.color_conversion(self.color_conversion);
+                if self.lenient {
+                    decoder.lenient();
+                }
+                self.decoder = Some(decoder);
+                if self.region.is_some() {
+                    self.apply_region()?;
+                }
+                self.image.frame_header = Some(frame_header);
+            }
+            Marker::DefineHuffmanTable => {
+                for (table_class, table_dest_id, huffman_table) in parse_huffman_tables(data)? {
+                    // DC = 0, AC = 1
+                    if table_class == 0 {
+                        self.image.huffman_dc_tables[table_dest_id as usize] =
+                            Some(huffman_table);
+                    } else {
+                        self.image.huffman_ac_tables[table_dest_id as usize] =
+                            Some(huffman_table);
+                    }
+                }
+            }
+            Marker::StartOfScan => {
+                let scan_header = ScanHeader::parse(data)?;
+
+                let frame_header = match self.image.frame_header {
+                    Some(ref frame_header) => frame_header,
+                    None => return Err("Found a scan before the frame header".to_string()),
+                };
+                match frame_header.frame_type {
+                    Marker::BaselineDCT |
+                    Marker::ExtendedSequentialDCT |
+                    Marker::ProgressiveDCT |
+                    Marker::Lossless |
+                    Marker::ExtendedSequentialDCTArithmetic |
+                    Marker::ProgressiveDCTArithmetic => {}
+                    Marker::DifferentialSequentialDCT |
+                    Marker::DifferentialProgressiveDCT |
+                    Marker::DifferentialLossless |
+                    Marker::DifferentialSequentialDCTArithmetic |
+                    Marker::DifferentialProgressiveDCTArithmetic if self.hierarchy.is_some() => {}
+                    frame_type => {
+                        return Err(format!("Decoding of {:?} frames is not supported",
+                                           frame_type))
+                    }
+                }
+                let is_lossless = matches!(frame_header.frame_type,
+                                           Marker::Lossless | Marker::DifferentialLossless);
+                if (is_lossless && !(2..=16).contains(&frame_header.sample_precision)) ||
+                   (!is_lossless && frame_header.sample_precision != 8) {
+                    return Err(format!("Decoding of {}-bit samples is not supported",
+                                       frame_header.sample_precision));
+                }
+
+                let jpeg_decoder = self.decoder.as_mut().unwrap();
+                jpeg_decoder.restart_interval(self.image.restart_interval);
+
+                // Add tables to `jpeg_decoder`. They may have changed
+                // since the last scan.
+                for (i, table) in self.image.huffman_ac_tables.iter().enumerate() {
+                    if let Some(ref table) = *table {
+                        jpeg_decoder.huffman_ac_tables(i as u8, table.clone());
+                    }
+                }
+
+                for (i, table) in self.image.huffman_dc_tables.iter().enumerate() {
+                    if let Some(ref table) = *table {
+                        jpeg_decoder.huffman_dc_tables(i as u8, table.clone());
+                    }
+                }
+
+                for (i, table) in self.image.quantization_tables.iter().enumerate() {
+                    if let Some(ref table) = *table {
+                        jpeg_decoder.quantization_table(i as u8, table.clone());
+                    }
+                }
+                jpeg_decoder.arithmetic_conditioning(self.image.arithmetic_conditioning);
+
+                // The entropy coded data is given to `scan_data`. The
+                // header is only kept once the scan starts, as `finish`
+                // decodes the image if there are any.
+                jpeg_decoder.start_scan(&scan_header)?;
+                self.image.scan_headers.get_or_insert_with(Vec::new).push(scan_header);
+            }
+            Marker::RestartIntervalDefinition => {
+                // JPEG B.2.4.4
+                if data.len() < 2 {
+                    return Err("Restart interval definition is too short".to_string());
+                }
+                self.image.restart_interval = u8s_to_u16(data);
+            }
+            Marker::DefineNumberOfLines => {
+                // JPEG B.2.5
+                // Only allowed after the first scan of a frame which
+                // has zero lines in its header.
+                if data.len() < 2 {
+                    return Err("DNL segment is too short".to_string());
+                }
+                let num_lines = u8s_to_u16(data);
+                match (self.image.frame_header.as_mut(), self.decoder.as_mut()) {
+                    (Some(ref mut frame_header), Some(ref mut decoder))
+                        if frame_header.num_lines == 0 => {
+                        decoder.define_number_of_lines(num_lines as usize)?;
+                        frame_header.num_lines = num_lines;
+                        self.image.dimensions = scaled_dimensions(decoder)?;
+                    }
+                    _ => {
+                        return Err("DNL segment in a frame which has its number of \
+                                    lines"
+                            .to_string())
+                    }
+                }
+            }
+            Marker::DefineHierarchicalProgression => {
+                // JPEG B.3.2
+                // Same as a frame header, but for the final image.
+                if self.hierarchy.is_some() || self.decoder.is_some() {
+                    return Err("DHP segment must come before all frames".to_string());
+                }
+                let frame_header = FrameHeader::parse(marker, data)?;
+                self.image.dimensions = (frame_header.samples_per_line, frame_header.num_lines);
+                let mut hierarchy = HierarchicalDecoder::new(frame_header);
+                if self.luma_only {
+                    hierarchy.luma_only();
+                }
+                hierarchy.color_conversion(self.color_conversion);
+                self.hierarchy = Some(hierarchy);
+            }
+            Marker::ExpandReferenceComponents => {
+                // JPEG B.3.3
+                if data.is_empty() {
+                    return Err("EXP segment is too short".to_string());
+                }
+                let horizontal = (data[0] & 0xf0) >> 4;
+                let vertical = data[0] & 0x0f;
+                if horizontal > 1 || vertical > 1 {
+                    return Err(format!("Invalid expansion {}x{} in EXP segment",
+                                       horizontal,
+                                       vertical));
+                }
+                match self.hierarchy {
+                    Some(ref mut hierarchy) => hierarchy.expand(horizontal == 1, vertical == 1),
+                    None => {
+                        return Err("EXP segment in an image which is not hierarchical"
+                            .to_string())
+                    }
+                }
+            }
+            Marker::DefineArithmeticConditioning => {
+                // JPEG B.2.4.3
+                self.image.arithmetic_conditioning.update(data)?;
+            }
+            Marker::ApplicationSegment(0) => {
+                // JFIF puts stuff here.
+                //
+                //
+                //  X’FF’, APP0, length, identifier, version, units,
+                //  Xdensity, Ydensity, Xthumbnail, Ythumbnail, (RGB)n
+
+                if data.starts_with(b"JFIF\0") && data.len() >= 14 {
+                    self.image.version = JFIFVersion::from_bytes(data[5], data[6]);
+                    self.image.units = JFIFUnits::from_u8(data[7]);
+
+                    let x_density = u8s_to_u16(&data[8..]);
+                    let y_density = u8s_to_u16(&data[10..]);
+                    self.image.pixel_density = (x_density, y_density);
+
+                    self.image.thumbnail_dimensions = (data[12], data[13]);
+                }
+            }
+            Marker::ApplicationSegment(2) => {
+                // APP2 is used by both ICC profiles and the Multi-Picture
+                // Format. We only care about the latter for now.
+                // The primary image doesn't need the index, so a broken one
+                // is only a warning.
+                if data.starts_with(mpf::MPF_IDENTIFIER) && self.image.mpf_index.is_none() {
+                    match mpf::MPFIndex::parse(data, offset) {
+                        Ok(index) => self.image.mpf_index = Some(index),
+                        Err(error) => self.warn(format!("Ignoring the MPF index: {}", error)),
+                    }
+                }
+            }
+            Marker::ApplicationSegment(_) |
+            Marker::JPEGExtension(_) |
+            Marker::ReservedJPEGExtension |
+            Marker::Reserved(_) => {
+                // Application specific data, like EXIF and XMP in APP1, or
+                // markers we don't know about. None of these are needed
+                // for decoding, and since they have a length we can skip them.
+                // See `writer::JPEGSegmentWriter` for editing metadata.
+            }
+            // Already handled
+            Marker::StartOfImage |
+            Marker::EndOfImage |
+            Marker::Restart(_) |
+            Marker::Temporary => {}
+        }
+        Ok(())
+    }
+
+    /// Whether the last scan still needs entropy coded data.
+    fn scan_in_progress(&self) -> bool {
+        self.decoder.as_ref().is_some_and(|decoder| decoder.scan_in_progress())
+    }
+
+    /// Decode the entropy coded data of the last scan, and return how
+    /// many bytes are used. See `JPEGDecoder::decode_mcus`.
+    fn scan_data(&mut self, data: &[u8], end_of_data: bool) -> Result<usize, String> {
+        let decoder = match self.decoder {
+            Some(ref mut decoder) => decoder,
+            None => return Err("Found entropy coded data before the frame header".to_string()),
+        };
+        let used = decoder.decode_mcus(data, end_of_data)?;
+        let scan = self.image.scan_headers.as_ref().map_or(0, |headers| headers.len());
+        for warning in decoder.take_warnings() {
+            self.image.warnings.push(format!("Scan {}: {}", scan, warning));
+        }
+        Ok(used)
+    }
+
+    /// Whether the frame header is read.
+    fn header_ready(&self) -> bool {
+        self.image.frame_header.is_some()
+    }
+
+    /// Number of lines the last scan has decoded so far.
+    fn decoded_lines(&self) -> usize {
+        self.decoder.as_ref().map_or(0, |decoder| decoder.decoded_lines())
+    }
+
+    /// Get the pixels of the image from the scans decoded so far.
+    fn preview(&self) -> Result<Vec<(u8, u8, u8)>, String> {
+        if self.hierarchy.is_some() {
+            return Err("Previews of hierarchical images are not supported".to_string());
+        }
+        match self.decoder {
+            Some(ref decoder) => decoder.decode(),
+            None => Err("The frame header is not read yet".to_string()),
+        }
+    }
+
+    /// Decode the image at `scale / 8` of its size. Only before the
+    /// frame header is read.
+    fn scale(&mut self, scale: usize) -> Result<(), String> {
+        if !(1..=16).contains(&scale) {
+            return Err(format!("Can't scale images by {}/8", scale));
+        }
+        self.scale = scale;
+        Ok(())
+    }
+
+    /// Only decode the luma of the image. Only before the frame header
+    /// is read.
+    fn luma_only(&mut self) {
+        self.luma_only = true;
+    }
+
+    /// Convert YCbCr to RGB with `conversion`. Only before the frame
+    /// header is read.
+    fn color_conversion(&mut self, conversion: ColorConversion) {
+        self.color_conversion = conversion;
+        self.image.color_conversion = conversion;
+    }
+
+    /// Skip corrupt data with a warning instead of failing. Only before
+    /// the frame header is read.
+    fn lenient(&mut self) {
+        self.lenient = true;
+    }
+
+    /// Record a problem which lenient mode gets past, or which doesn't
+    /// matter for decoding.
+    fn warn(&mut self, warning: String) {
+        self.image.warnings.push(warning);
+    }
+
+    /// Only decode a rectangle of the image, in the coordinates of the
+    /// scaled image. Data is only skipped in scans which start after this.
+    fn region(&mut self, x: usize, y: usize, width: usize, height: usize) -> Result<(), String> {
+        self.region = Some((x, y, width, height));
+        if self.decoder.is_some() {
+            self.apply_region()?;
+        }
+        Ok(())
+    }
+
+    fn apply_region(&mut self) -> Result<(), String> {
+        let (x, y, width, height) = match self.region {
+            Some(region) => region,
+            None => return Ok(()),
+        };
+        if self.hierarchy.is_some() {
+            return Err("Regions of hierarchical images can't be decoded".to_string());
+        }
+        if let Some(ref mut decoder) = self.decoder {
+            decoder.region(x, y, width, height)?;
+            self.image.dimensions = scaled_dimensions(decoder)?;
+        }
+        Ok(())
+    }
+
+    /// Turn MCU rows into pixels as they are decoded, if the image allows it.
+    fn output_rows(&mut self) {
+        self.output_rows = true;
+        if let (Some(ref mut decoder), None) = (self.decoder.as_mut(), self.hierarchy.as_ref()) {
+            decoder.output_rows();
+        }
+    }
+
+    /// Take the next MCU row which is turned into pixels, if the decoder
+    /// outputs rows as they are decoded.
+    fn take_mcu_row(&mut self) -> Option<MCURow> {
+        self.decoder.as_mut().and_then(|decoder| decoder.take_mcu_row())
+    }
+
+    /// Whether the decoder outputs rows as they are decoded, instead of
+    /// keeping the whole image.
+    fn is_streaming_rows(&self) -> bool {
+        self.decoder.as_ref().is_some_and(|decoder| decoder.is_streaming_rows())
+    }
+
+    /// Get the image, when all segments are read.
+    fn finish(self) -> Result<JPEGImage, String> {
+        let mut image = self.image;
+        if let Some(mut hierarchy) = self.hierarchy {
+            if let Some(decoder) = self.decoder {
+                match hierarchy.add_frame(&decoder) {
+                    // The frames before it make up the image.
+                    Err(error) if self.lenient => image.warnings.push(error),
+                    result => result?,
+                }
+                image.image_data = Some(hierarchy.image_data()?);
+            }
+        } else if let Some(decoder) = self.decoder {
+            // If the rows are already output, the image has no pixels.
+            if image.scan_headers.is_some() && !decoder.is_streaming_rows() {
+                image.image_data = Some(decoder.decode()?);
+            }
+        }
+        Ok(image)
+    }
+
+    /// Like `finish`, but get the quantized DCT coefficients of the image
+    /// instead of its pixels. See `JPEGDecoder::coefficients`.
+    fn finish_coefficients(self,
+                           order: CoefficientOrder)
+                           -> Result<(JPEGImage, Vec<ComponentCoefficients>), String> {
+        if self.hierarchy.is_some() {
+            return Err("Coefficients of hierarchical images are not supported".to_string());
+        }
+        match self.decoder {
+            Some(ref decoder) if self.image.scan_headers.is_some() => {
+                let coefficients = decoder.coefficients(order)?;
+                Ok((self.image, coefficients))
+            }
+            _ => Err("The image has no scans".to_string()),
+        }
+    }
+
+    /// Like `finish`, but write the pixels to `buffer` instead of
+    /// keeping them in the image. See `JPEGDecoder::decode_into`.
+    fn finish_into(self,
+                   buffer: &mut [u8],
+                   format: PixelFormat,
+                   stride: usize)
+                   -> Result<JPEGImage, String> {
+        if let Some(mut hierarchy) = self.hierarchy {
+            if let Some(decoder) = self.decoder {
+                hierarchy.add_frame(&decoder)?;
+                hierarchy.decode_into(buffer, format, stride)?;
+                return Ok(self.image);
+            }
+        } else if let Some(decoder) = self.decoder {
+            if self.image.scan_headers.is_some() {
+                decoder.decode_into(buffer, format, stride)?;
+                return Ok(self.image);
+            }
+        }
+        Err("The image has no scans".to_string())
+    }
+}
+
+/// Read the tables of a DHT segment, as the class (`0` for DC and `1`
+/// for AC), identifier and table of each. See JPEG B.2.4.2.
+fn parse_huffman_tables(data: &[u8]) -> Result<Vec<(u8, u8, huffman::HuffmanTable)>, String> {
+    Ok(encoder::HuffmanTableSpecification::parse(data)?
+        .iter()
+        .map(|specification| {
+            (specification.class, specification.destination, specification.table())
+        })
+        .collect())
+}
+
+/// Read the tables of a DQT segment, as the precision (`0` for 8 bits
+/// and `1` for 16 bits), identifier and values of each. The values are
+/// in zigzag order, as in the segment. See JPEG B.2.4.1.
+fn parse_quantization_tables(data: &[u8]) -> Result<Vec<(u8, u8, Vec<u16>)>, String> {
+    let mut tables = Vec::new();
+    let mut index = 0;
+    while index < data.len() {
+        let precision = data[index] >> 4;
+        let identifier = data[index] & 0x0f;
+        // Although precision == 0 is "guaranteed" by the standard,
+        // images with 16-bit precision to exist.
+        let value_size = match precision {
+            0 => 1,
+            1 => 2,
+            _ => {
+                return Err(format!("Unknown precision of quantization table: {}", precision))
+            }
+        };
+        if identifier > 3 {
+            return Err(format!("Invalid quantization table destination {}", identifier));
+        }
+        let end = index + 1 + 64 * value_size;
+        if data.len() < end {
+            return Err("DQT segment is too short".to_string());
+        }
+        let table = data[index + 1..end]
+            .chunks(value_size)
+            .map(|value| if value_size == 1 {
+                value[0] as u16
+            } else {
+                u8s_to_u16(value)
+            })
+            .collect();
+        tables.push((precision, identifier, table));
+        index = end;
+    }
+    Ok(tables)
+}
+
+/// The size of the image `decoder` gives, scaled or cut to the region,
+/// for the dimensions of a `JPEGImage`.
+fn scaled_dimensions(decoder: &JPEGDecoder) -> Result<JPEGDimensions, String> {
+    let (width, height) = decoder.output_dimensions();
+    if width > u16::MAX as usize || height > u16::MAX as usize {
+        return Err(format!("The scaled image of {}x{} pixels is too large", width, height));
+    }
+    Ok((width as u16, height as u16))
 }
 
-#[allow(unused_variables)]
 impl JPEGImage {
     fn new() -> JPEGImage {
         JPEGImage {
@@ -193,286 +854,216 @@ impl JPEGImage {
             huffman_ac_tables: [None, None, None, None],
             huffman_dc_tables: [None, None, None, None],
             quantization_tables: [None, None, None, None],
+            arithmetic_conditioning: arithmetic::ArithmeticConditioning::default(),
             frame_header: None,
             scan_headers: None,
             image_data: None,
+            restart_interval: 0,
+            mpf_index: None,
+            data: Vec::new(),
+            warnings: Vec::new(),
+            color_conversion: ColorConversion::default(),
         }
     }
 
     pub fn parse(vec: Vec<u8>) -> Result<JPEGImage, String> {
-        let mut image = JPEGImage::new();
-
-        let mut i = 0;
-        while i < vec.len() {
-            if let Some(marker) = bytes_to_marker(&vec[i..]) {
-                if marker == Marker::EndOfImage || marker == Marker::StartOfImage {
-                    // These markers doesn't have length bytes, so they must be
-                    // handled separately, in order to to avoid out-of-bounds indexes,
-                    // or reading nonsense lengths.
-                    i += 2;
-                    continue;
-                }
+        let mut builder = ImageBuilder::new();
+        let mut reader = MarkerReader::new(&vec).peekable();
+        let mut end_of_image = false;
+        while let Some(segment) = reader.next() {
+            let segment = segment?;
+            let marker = match segment.kind {
+                SegmentKind::Marker(marker) => marker,
+                // Entropy coded data is read along with its scan header below.
+                SegmentKind::Fill | SegmentKind::EntropyCoded => continue,
+                // Anything after EOI is not part of the image. Cameras put
+                // embedded images here (see `Marker::ApplicationSegment(2)`).
+                SegmentKind::Trailer => break,
+            };
+            if marker == Marker::EndOfImage {
+                end_of_image = true;
+                break;
+            }
+            if !marker.has_length() {
+                // SOI, TEM and stray RSTn markers have no data.
+                continue;
+            }
+            // The data starts after the marker and the length bytes.
+            builder.segment(marker, segment.payload, segment.offset + 4)?;
 
-                // NOTE: this does not count the length bytes anymore!
-                // TODO: Maybe do count them? In order to make it less confusing
-                let data_length = (u8s_to_u16(&vec[i + 2..]) - 2) as usize;
-                i += 4;
-
-                match marker {
-                    Marker::Comment => {
-                        let comment = str::from_utf8(&vec[i..i + data_length])
-                            .map(|s| s.to_string())
-                            .ok();
-                        image.comment = comment;
+            if marker == Marker::StartOfScan {
+                // The entropy coded data follows the header, and lasts until
+                // the first marker which is not a restart marker.
+                let scan_start = segment.end();
+                let mut scan_end = scan_start;
+                while let Some(&Ok(MarkerSegment { kind, .. })) = reader.peek() {
+                    match kind {
+                        SegmentKind::EntropyCoded |
+                        SegmentKind::Fill |
+                        SegmentKind::Marker(Marker::Restart(_)) => {}
+                        _ => break,
                     }
-                    Marker::QuantizationTable => {
-                        // JPEG B.2.4.1
-                        let mut index = i;
-                        while index < i + data_length {
-                            let precision = (vec[index] & 0xf0) >> 4;
-                            let identifier = vec[index] & 0x0f;
-                            // Although precision == 0 is "guaranteed" by the standard,
-                            // images with 16-bit precision to exist.
-                            if precision == 0 {
-                                // 8-bit
-                                let table: Vec<u16> = vec[index + 1..index + 65]
-                                    .iter()
-                                    .map(|b| *b as u16)
-                                    .collect();
-
-                                image.quantization_tables[identifier as usize] = Some(table);
-                                index += 65; // 64 entries + one header byte
-                            } else if precision == 1 {
-                                let bytes = &vec[index + 1..index + 129];
-                                let mut i = 0;
-                                let mut table = Vec::with_capacity(64);
-                                while i < 128 {
-                                    let msb = bytes[i] as u16;
-                                    let lsb = bytes[i + 1] as u16;
-                                    table.push(msb << 8 | lsb);
-                                    i += 2;
-                                }
-                                image.quantization_tables[identifier as usize] = Some(table);
-                                index += 129;
-                            } else {
-                                panic!("Unknown precision of quantization table: {}", precision);
-                            }
-                        }
+                    if let Some(Ok(next)) = reader.next() {
+                        scan_end = next.end();
                     }
-                    Marker::BaselineDCT => {
-                        // JPEG B.2.2
-                        let sample_precision = vec[i];
-                        let num_lines = u8s_to_u16(&vec[i + 1..]);
-                        let samples_per_line = u8s_to_u16(&vec[i + 3..]);
-                        let image_components = vec[i + 5];
-
-                        let mut frame_components = Vec::with_capacity(image_components as usize);
-                        let mut index = i + 6;
-                        for component in 0..image_components {
-                            let component_id = vec[index];
-                            let horizontal_sampling_factor = (vec[index + 1] & 0xf0) >> 4;
-                            let vertical_sampling_factor = vec[index + 1] & 0x0f;
-                            assert!(horizontal_sampling_factor > 0 &&
-                                    horizontal_sampling_factor < 3);
-                            assert!(vertical_sampling_factor > 0 && vertical_sampling_factor < 3);
-                            let quantization_selector = vec[index + 2];
-
-                            frame_components.push(FrameComponentHeader {
-                                component_id: component_id,
-                                horizontal_sampling_factor: horizontal_sampling_factor,
-                                vertical_sampling_factor: vertical_sampling_factor,
-                                quantization_selector: quantization_selector,
-                            });
-                            index += 3;
-                        }
-                        let frame_header = FrameHeader {
-                            sample_precision: sample_precision,
-                            num_lines: num_lines,
-                            samples_per_line: samples_per_line,
-                            image_components: image_components,
-                            frame_components: frame_components,
-                        };
-                        image.dimensions = (samples_per_line, num_lines);
-                        image.frame_header = Some(frame_header);
+                }
+                builder.scan_data(&vec[scan_start..scan_end], true)?;
+            }
+        }
+        if !end_of_image {
+            return Err("The file ends without an EOI marker".to_string());
+        }
+        let mut image = builder.finish()?;
+        image.data = vec;
+        Ok(image)
+    }
 
-                    }
-                    Marker::DefineHuffmanTable => {
-                        // JPEG B.2.4.2
-
-                        // Head of data for each table
-                        let mut huffman_index = i;
-                        // End of segment
-                        let segment_end = i + data_length;
-
-                        while huffman_index < segment_end {
-                            let table_class = (vec[huffman_index] & 0xf0) >> 4;
-                            let table_dest_id = vec[huffman_index] & 0x0f;
-                            huffman_index += 1;
-
-                            // There are `size_area[i]` number of codes of length `i + 1`.
-                            let size_area: &[u8] = &vec[huffman_index..huffman_index + 16];
-                            huffman_index += 16;
-
-                            // TODO: replace with `.sum` as of Rust 1.11
-                            let number_of_codes = size_area.iter()
-                                .fold(0, |a, b| a + (*b as usize));
-
-                            // Code `i` has value `data_area[i]`
-                            let data_area: &[u8] = &vec[huffman_index..huffman_index +
-                                                                       number_of_codes];
-                            huffman_index += number_of_codes;
-
-                            let huffman_table =
-                                huffman::HuffmanTable::from_size_data_tables(size_area, data_area);
-                            // DC = 0, AC = 1
-                            if table_class == 0 {
-                                image.huffman_dc_tables[table_dest_id as usize] =
-                                    Some(huffman_table);
-                            } else {
-                                image.huffman_ac_tables[table_dest_id as usize] =
-                                    Some(huffman_table);
-                            }
-                        }
-                    }
-                    Marker::StartOfScan => {
-                        // JPEG B.2.3
-                        let num_components = vec[i];
-                        let mut scan_components = Vec::new();
-                        for component in 0..num_components {
-                            scan_components.push(ScanComponentHeader {
-                                component_id: vec[i + 1],
-                                dc_table_selector: (vec[i + 2] & 0xf0) >> 4,
-                                ac_table_selector: vec[i + 2] & 0x0f,
-                            });
-                            i += 2;
-                        }
+    pub fn width(&self) -> usize {
+        self.dimensions.0 as usize
+    }
 
-                        // TODO: Do we want to put the scan header in `FrameHeader`?
-                        // We don't need it for simple decoding, but it might be useful
-                        // if we want to print info (eg, all headers) for an image.
-                        let scan_header = ScanHeader {
-                            num_components: num_components,
-                            scan_components: scan_components,
-                            start_spectral_selection: vec[i + 1],
-                            end_spectral_selection: vec[i + 2],
-                            successive_approximation_bit_pos_high: (vec[i + 3] & 0xf0) >> 4,
-                            successive_approximation_bit_pos_low: vec[i + 3] & 0x0f,
-                        };
-                        // Register read data
-                        i += 4;
-
-                        if image.scan_headers.is_none() {
-                            image.scan_headers = Some(Vec::new());
-                        }
-                        image.scan_headers
-                            .as_mut()
-                            .map(|v| v.push(scan_header.clone()));
-
-                        // Copy data, and replace 0xff00 with 0xff.
-                        let mut bytes_skipped = 0;
-                        let mut encoded_data = Vec::new();
-                        {
-                            let mut i = i;
-                            while i < vec.len() {
-                                encoded_data.push(vec[i]);
-                                if vec[i] == 0xff && vec[i + 1] == 0x00 {
-                                    // Skip the 0x00 part here.
-                                    i += 1;
-                                    bytes_skipped += 1;
-                                }
-                                i += 1;
-                            }
-                        }
+    pub fn height(&self) -> usize {
+        self.dimensions.1 as usize
+    }
 
+    pub fn image_data(&self) -> Option<&Vec<(u8, u8, u8)>> {
+        self.image_data.as_ref()
+    }
 
-                        let frame_header = image.frame_header.clone().unwrap();
-                        let mut jpeg_decoder = JPEGDecoder::new(encoded_data.as_slice())
-                            .frame_header(frame_header)
-                            // need `.clone()`, or we hit some LLVM bug??
-                            .scan_header(scan_header.clone())
-                            .dimensions((image.dimensions.0 as usize,
-                                         image.dimensions.1 as usize));
+    /// Read the image in `data`, and get the quantized DCT coefficients
+    /// of each of its components instead of its pixels, in the given
+    /// order. The image which is returned has no pixels.
+    pub fn read_coefficients(data: &[u8],
+                             order: CoefficientOrder)
+                             -> Result<(JPEGImage, Vec<ComponentCoefficients>), String> {
+        stream::JPEGStreamDecoder::from_buf_read(data).read_coefficients(order)
+    }
 
-                        // Add tables to `jpeg_decoder`
-                        for (i, table) in image.huffman_ac_tables.iter().enumerate() {
-                            if let Some(ref table) = *table {
-                                jpeg_decoder.huffman_ac_tables(i as u8, table.clone());
-                            }
-                        }
+    /// Decode only the `width` x `height` rectangle at `(x, y)` of the
+    /// image in `data`, which is much faster than decoding all of it for
+    /// small rectangles of large images. The image which is returned is
+    /// the rectangle. See `stream::JPEGStreamDecoder::decode_region`.
+    pub fn decode_region(data: &[u8],
+                         x: usize,
+                         y: usize,
+                         width: usize,
+                         height: usize)
+                         -> Result<JPEGImage, String> {
+        stream::JPEGStreamDecoder::from_buf_read(data).decode_region(x, y, width, height)
+    }
 
-                        for (i, table) in image.huffman_dc_tables.iter().enumerate() {
-                            if let Some(ref table) = *table {
-                                jpeg_decoder.huffman_dc_tables(i as u8, table.clone());
-                            }
-                        }
+    /// Decode only the luma of the image in `data`, and get it as one
+    /// byte per pixel, line by line. This saves most of the work for the
+    /// chroma of color images. The image which is returned has no
+    /// pixels. See `stream::JPEGStreamDecoder::decode_luma`.
+    pub fn decode_luma(data: &[u8]) -> Result<(JPEGImage, Vec<u8>), String> {
+        stream::JPEGStreamDecoder::from_buf_read(data).decode_luma()
+    }
 
-                        for (i, table) in image.quantization_tables.iter().enumerate() {
-                            if let Some(ref table) = *table {
-                                jpeg_decoder.quantization_table(i as u8, table.clone());
-                            }
-                        }
+    /// Decode as much as possible of an image which may be truncated or
+    /// corrupt. What could not be decoded is gray, and is described by
+    /// `warnings`. See `stream::JPEGStreamDecoder::lenient`.
+    pub fn decode_lenient(data: &[u8]) -> Result<JPEGImage, String> {
+        let mut decoder = stream::JPEGStreamDecoder::from_buf_read(data);
+        decoder.lenient()?;
+        decoder.decode()
+    }
 
-                        let (image_data, bytes_read) = jpeg_decoder.decode();
-                        image.image_data = Some(image_data);
-                        return Ok(image);
+    /// Write the pixels of the image to `buffer`, in the given format and
+    /// with lines `stride` bytes apart. To decode into the buffer without
+    /// keeping the pixels in the image first, see
+    /// `stream::JPEGStreamDecoder::decode_into`.
+    pub fn decode_into(&self,
+                       buffer: &mut [u8],
+                       format: PixelFormat,
+                       stride: usize)
+                       -> Result<(), String> {
+        let image_data = match self.image_data {
+            Some(ref image_data) => image_data,
+            None => return Err("The image has no pixels".to_string()),
+        };
+        let width = self.width();
+        let mut buffer = PixelBuffer::new(buffer,
+                                          format,
+                                          stride,
+                                          (width, self.height()),
+                                          self.color_conversion)?;
+        for (i, &pixel) in image_data.iter().enumerate() {
+            buffer.put(i % width, i / width, pixel);
+        }
+        Ok(())
+    }
 
-                        // Since we are calculating how much data there is in this segment,
-                        // we update `i` manually, and `continue` the `while` loop.
-                        i += bytes_read + bytes_skipped;
-                        continue;
-                    }
-                    Marker::RestartIntervalDefinition => {
-                        // JPEG B.2.4.4
-                        // TODO: support this
-                        panic!("got to restart interval def")
-                    }
-                    Marker::ApplicationSegment0 => {
-                        // JFIF puts stuff here.
-                        //
-                        //
-                        //  X’FF’, APP0, length, identifier, version, units,
-                        //  Xdensity, Ydensity, Xthumbnail, Ythumbnail, (RGB)n
+    pub fn version(&self) -> &JFIFVersion {
+        &self.version
+    }
 
-                        let identifier = &vec[i..i + 6];
-                        let version = JFIFVersion::from_bytes(vec[7], vec[8]);
-                        let units = JFIFUnits::from_u8(vec[13]);
+    pub fn units(&self) -> &JFIFUnits {
+        &self.units
+    }
 
-                        let x_density = u8s_to_u16(&vec[10..12]);
-                        let y_density = u8s_to_u16(&vec[12..14]);
+    pub fn pixel_density(&self) -> (u16, u16) {
+        self.pixel_density
+    }
 
-                        let thumbnail_dimensions = (vec[14], vec[15]);
-                    }
-                    Marker::ApplicationSegment12 => {
-                        panic!("got {:?}", marker);
-                    }
-                    Marker::ApplicationSegment14 => {
-                        panic!("got {:?}", marker);
-                    }
-                    // Already handled
-                    Marker::StartOfImage |
-                    Marker::EndOfImage => {}
-                }
-                i += data_length;
-            } else {
-                panic!("Unhandled byte marker: {:02x} {:02x} (i={}/{})",
-                       vec[i],
-                       vec[i + 1],
-                       i,
-                       vec.len());
-            }
-        }
-        Ok(image)
+    pub fn thumbnail_dimensions(&self) -> ThumbnailDimensions {
+        self.thumbnail_dimensions
     }
 
-    pub fn width(&self) -> usize {
-        self.dimensions.0 as usize
+    pub fn restart_interval(&self) -> u16 {
+        self.restart_interval
     }
 
-    pub fn height(&self) -> usize {
-        self.dimensions.1 as usize
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
     }
 
-    pub fn image_data(&self) -> Option<&Vec<(u8, u8, u8)>> {
-        self.image_data.as_ref()
+    /// What was wrong with the file, like data which ends early or
+    /// corrupt MCUs, when it is decoded in lenient mode. In strict mode,
+    /// only problems with metadata the image doesn't need, like a broken
+    /// MPF index, are warnings.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// The Multi-Picture Format index of the image, if present.
+    pub fn mpf_index(&self) -> Option<&mpf::MPFIndex> {
+        self.mpf_index.as_ref()
+    }
+
+    /// Number of images listed in the MPF index, including the primary image.
+    pub fn num_embedded_images(&self) -> usize {
+        self.mpf_index.as_ref().map_or(0, |index| index.entries.len())
+    }
+
+    /// Get the raw bytes of the `n`th image in the MPF index.
+    pub fn embedded_image_data(&self, n: usize) -> Result<&[u8], String> {
+        let entry = match self.mpf_index.as_ref().and_then(|index| index.entries.get(n)) {
+            Some(entry) => entry,
+            None => return Err(format!("No embedded image with index {}", n)),
+        };
+        if self.data.is_empty() {
+            return Err("Embedded images are not available in images decoded from a stream"
+                .to_string());
+        }
+        // The primary image has no size of its own in some files,
+        // so use the entire file.
+        let end = if entry.offset == 0 && entry.size == 0 {
+            self.data.len()
+        } else {
+            entry.offset + entry.size
+        };
+        if end > self.data.len() {
+            return Err(format!("Embedded image {} ends at byte {}, but the file only has {}",
+                               n,
+                               end,
+                               self.data.len()));
+        }
+        Ok(&self.data[entry.offset..end])
+    }
+
+    /// Parse and decode the `n`th image in the MPF index.
+    pub fn embedded_image(&self, n: usize) -> Result<JPEGImage, String> {
+        self.embedded_image_data(n).and_then(|data| JPEGImage::parse(data.to_vec()))
     }
 }
@@ -0,0 +1,138 @@
+// Multi-Picture Format (CIPA DC-007).
+//
+// Cameras store additional images (large previews, stereo pairs,
+// disparity maps, ...) after the EOI marker of the primary image. The
+// MP Index IFD in the APP2 segment of the primary image tells us where
+// they are.
+
+use jpeg::tiff::TIFFReader;
+
+pub const MPF_VERSION: u16 = 0xb000;
+pub const NUMBER_OF_IMAGES: u16 = 0xb001;
+pub const MP_ENTRY: u16 = 0xb002;
+
+/// Identifier at the start of an APP2 segment containing MPF data.
+pub const MPF_IDENTIFIER: &[u8] = b"MPF\0";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MPFImageType {
+    BaselinePrimary,
+    LargeThumbnailVGA,
+    LargeThumbnailFullHD,
+    Panorama,
+    Disparity,
+    MultiAngle,
+    Undefined,
+    Unknown(u32),
+}
+
+impl MPFImageType {
+    pub fn from_u32(code: u32) -> MPFImageType {
+        match code {
+            0x030000 => MPFImageType::BaselinePrimary,
+            0x010001 => MPFImageType::LargeThumbnailVGA,
+            0x010002 => MPFImageType::LargeThumbnailFullHD,
+            0x020001 => MPFImageType::Panorama,
+            0x020002 => MPFImageType::Disparity,
+            0x020003 => MPFImageType::MultiAngle,
+            0x000000 => MPFImageType::Undefined,
+            _ => MPFImageType::Unknown(code),
+        }
+    }
+}
+
+/// One entry in the MP Index, describing an image in the file.
+#[derive(Debug, Clone)]
+pub struct MPFEntry {
+    pub image_type: MPFImageType,
+    pub is_dependent_parent: bool,
+    pub is_dependent_child: bool,
+    pub is_representative: bool,
+    /// Format of the image data. `0` is JPEG, which is the only
+    /// format defined by the standard.
+    pub data_format: u8,
+    /// Size of the image data in bytes
+    pub size: usize,
+    /// Offset of the image data, from the start of the file
+    pub offset: usize,
+    /// Entry numbers of dependent images, or `0` if there are none.
+    pub dependent_images: (u16, u16),
+}
+
+#[derive(Debug, Clone)]
+pub struct MPFIndex {
+    /// Version string, usually "0100"
+    pub version: Vec<u8>,
+    /// All images in the file. The first one is the primary image.
+    pub entries: Vec<MPFEntry>,
+}
+
+impl MPFIndex {
+    /// Parse the MP Index IFD of an APP2 segment.
+    ///
+    /// `segment` is the segment data, without the marker and length bytes,
+    /// and `segment_offset` is its offset in the file. The offsets in the
+    /// MP entries are relative to the MP header, so we need this in order to
+    /// make them absolute.
+    pub fn parse(segment: &[u8], segment_offset: usize) -> Result<MPFIndex, String> {
+        if !segment.starts_with(MPF_IDENTIFIER) {
+            return Err("APP2 segment is not an MPF segment".to_string());
+        }
+        let header_offset = segment_offset + MPF_IDENTIFIER.len();
+        let reader = TIFFReader::new(&segment[MPF_IDENTIFIER.len()..])?;
+        let ifd = reader.read_ifd(reader.first_ifd_offset()? as usize)?;
+
+        let version = match ifd.entry(MPF_VERSION) {
+            Some(entry) => reader.entry_bytes(entry)?.to_vec(),
+            None => Vec::new(),
+        };
+        let num_images = match ifd.entry(NUMBER_OF_IMAGES) {
+            Some(entry) => reader.entry_u32(entry)? as usize,
+            None => return Err("MPF index has no NumberOfImages tag".to_string()),
+        };
+        let mp_entry = match ifd.entry(MP_ENTRY) {
+            Some(entry) => entry,
+            None => return Err("MPF index has no MPEntry tag".to_string()),
+        };
+        let entry_bytes = reader.entry_bytes(mp_entry)?;
+        if entry_bytes.len() < num_images * 16 {
+            return Err(format!("MPF index claims {} images, but only has room for {}",
+                               num_images,
+                               entry_bytes.len() / 16));
+        }
+
+        // The entries are stored in the byte order of the MP header, so we
+        // read them through `reader`.
+        let entries_start = mp_entry.value_offset;
+        let mut entries = Vec::with_capacity(num_images);
+        for n in 0..num_images {
+            let entry_offset = entries_start + n * 16;
+            let attribute = reader.u32_at(entry_offset)?;
+            let size = reader.u32_at(entry_offset + 4)? as usize;
+            let offset = reader.u32_at(entry_offset + 8)? as usize;
+            // The primary image has offset 0, since it starts at the SOI
+            // of the file. All other offsets are relative to the MP header.
+            let offset = if offset == 0 {
+                0
+            } else {
+                offset + header_offset
+            };
+            entries.push(MPFEntry {
+                image_type: MPFImageType::from_u32(attribute & 0x00ff_ffff),
+                is_dependent_parent: attribute & 0x8000_0000 != 0,
+                is_dependent_child: attribute & 0x4000_0000 != 0,
+                is_representative: attribute & 0x2000_0000 != 0,
+                data_format: ((attribute >> 24) & 0x07) as u8,
+                size,
+                offset,
+                dependent_images: (reader.u16_at(entry_offset + 12)?,
+                                   reader.u16_at(entry_offset + 14)?),
+            });
+        }
+
+        Ok(MPFIndex {
+            version,
+            entries,
+        })
+    }
+}
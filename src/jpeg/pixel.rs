@@ -0,0 +1,111 @@
+// Pixel formats for decoding into buffers given by the caller.
+
+use jpeg::color::ColorConversion;
+
+/// Layout of each pixel in a buffer given to `decode_into`. Alpha is
+/// always opaque, as JPEG images have no transparency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    /// Red, green and blue, one byte each
+    RGB,
+    /// Red, green, blue and alpha, one byte each
+    RGBA,
+    /// Blue, green and red, one byte each
+    BGR,
+    /// Blue, green, red and alpha, one byte each
+    BGRA,
+    /// Alpha, red, green and blue, one byte each
+    ARGB,
+    /// One byte of luma
+    Gray8,
+    /// One byte of luma and one of alpha
+    GrayAlpha,
+    /// 5 bits of red, 6 of green and 5 of blue, from the most significant
+    /// bit of a little endian `u16`
+    RGB565,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::RGB | PixelFormat::BGR => 3,
+            PixelFormat::RGBA | PixelFormat::BGRA | PixelFormat::ARGB => 4,
+            PixelFormat::Gray8 => 1,
+            PixelFormat::GrayAlpha | PixelFormat::RGB565 => 2,
+        }
+    }
+
+    /// Whether the format has only the luma of each pixel.
+    pub fn is_gray(self) -> bool {
+        self == PixelFormat::Gray8 || self == PixelFormat::GrayAlpha
+    }
+
+    /// Write a pixel to the start of `out`. Gray formats get the luma
+    /// `conversion` gives the pixel.
+    pub fn write(self, out: &mut [u8], pixel: (u8, u8, u8), conversion: &ColorConversion) {
+        let (r, g, b) = pixel;
+        match self {
+            PixelFormat::RGB => out[..3].copy_from_slice(&[r, g, b]),
+            PixelFormat::RGBA => out[..4].copy_from_slice(&[r, g, b, 0xff]),
+            PixelFormat::BGR => out[..3].copy_from_slice(&[b, g, r]),
+            PixelFormat::BGRA => out[..4].copy_from_slice(&[b, g, r, 0xff]),
+            PixelFormat::ARGB => out[..4].copy_from_slice(&[0xff, r, g, b]),
+            PixelFormat::Gray8 => out[0] = conversion.luma(pixel),
+            PixelFormat::GrayAlpha => out[..2].copy_from_slice(&[conversion.luma(pixel), 0xff]),
+            PixelFormat::RGB565 => {
+                let value = ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3);
+                out[0] = value as u8;
+                out[1] = (value >> 8) as u8;
+            }
+        }
+    }
+}
+
+/// A buffer for the pixels of an image, where lines start `stride`
+/// bytes apart.
+pub struct PixelBuffer<'a> {
+    data: &'a mut [u8],
+    format: PixelFormat,
+    stride: usize,
+    /// How the luma of pixels is found for gray formats
+    conversion: ColorConversion,
+}
+
+impl<'a> PixelBuffer<'a> {
+    /// Check that `data` has room for an image of the given size.
+    pub fn new(data: &'a mut [u8],
+               format: PixelFormat,
+               stride: usize,
+               dimensions: (usize, usize),
+               conversion: ColorConversion)
+               -> Result<PixelBuffer<'a>, String> {
+        let (width, height) = dimensions;
+        let line_length = width * format.bytes_per_pixel();
+        if stride < line_length {
+            return Err(format!("Stride of {} bytes is too small for {} pixels of {:?}",
+                               stride,
+                               width,
+                               format));
+        }
+        if height > 0 && data.len() < (height - 1) * stride + line_length {
+            return Err(format!("Buffer of {} bytes is too small for {}x{} pixels with a \
+                                stride of {}",
+                               data.len(),
+                               width,
+                               height,
+                               stride));
+        }
+        Ok(PixelBuffer {
+            data,
+            format,
+            stride,
+            conversion,
+        })
+    }
+
+    /// Write the pixel at `(x, y)`.
+    pub fn put(&mut self, x: usize, y: usize, pixel: (u8, u8, u8)) {
+        let start = y * self.stride + x * self.format.bytes_per_pixel();
+        self.format.write(&mut self.data[start..], pixel, &self.conversion);
+    }
+}
@@ -0,0 +1,582 @@
+// A report of what the headers of a JPEG file say, without decoding the
+// image: the JFIF and EXIF metadata, the frame and scan headers and the
+// tables. It can be written as text for people, or as JSON for scripts.
+
+use std::fmt::Write;
+
+use jpeg::{parse_quantization_tables, u8s_to_u16, FrameHeader, JFIFUnits, ScanHeader};
+use jpeg::decoder::ZIGZAG_INDICES;
+use jpeg::encoder::HuffmanTableSpecification;
+use jpeg::exif::{self, ExifField, ExifValue};
+use jpeg::icc;
+use jpeg::marker::{Marker, MarkerReader, SegmentKind};
+
+/// The APP0 segment of a JFIF file.
+#[derive(Debug)]
+pub struct JFIFHeader {
+    /// Major and minor version
+    pub version: (u8, u8),
+    pub units: JFIFUnits,
+    /// Horizontal and vertical pixel density
+    pub density: (u16, u16),
+    /// Width and height of the uncompressed thumbnail, if it has one
+    pub thumbnail_dimensions: (u8, u8),
+}
+
+/// A table of a DQT segment.
+#[derive(Debug, Clone)]
+pub struct QuantizationTable {
+    pub id: u8,
+    /// Bits of each value, 8 or 16
+    pub bits: u8,
+    /// The values, in natural order
+    pub values: Vec<u16>,
+}
+
+/// A scan, and its entropy coded data.
+#[derive(Debug, Clone)]
+pub struct ScanReport {
+    /// Offset of the SOS marker in the file
+    pub offset: usize,
+    pub header: ScanHeader,
+    /// Number of bytes of entropy coded data, with restart markers
+    pub data_length: usize,
+}
+
+/// A marker, and the segment following it.
+#[derive(Debug, Clone)]
+pub struct SegmentReport {
+    pub marker: Marker,
+    pub offset: usize,
+    /// Number of bytes of the marker and its segment
+    pub length: usize,
+}
+
+/// Everything the headers of a file say, in the order they are in it.
+/// Tables which are defined again are listed each time.
+#[derive(Debug)]
+pub struct Report {
+    pub file_size: usize,
+    pub jfif: Option<JFIFHeader>,
+    /// The fields of the first EXIF segment, or why they can't be read
+    pub exif: Option<Result<Vec<ExifField>, String>>,
+    /// The size of the ICC profile, or why it can't be joined
+    pub icc_profile_size: Option<Result<usize, String>>,
+    pub comments: Vec<String>,
+    /// The headers of the frames. Hierarchical images have several, and
+    /// their DHP segment comes first.
+    pub frames: Vec<FrameHeader>,
+    pub restart_interval: Option<u16>,
+    pub quantization_tables: Vec<QuantizationTable>,
+    pub huffman_tables: Vec<HuffmanTableSpecification>,
+    pub scans: Vec<ScanReport>,
+    pub segments: Vec<SegmentReport>,
+    /// Number of bytes after EOI
+    pub trailer_length: usize,
+}
+
+impl Report {
+    /// Read the headers of the file in `data`.
+    pub fn new(data: &[u8]) -> Result<Report, String> {
+        let mut report = Report {
+            file_size: data.len(),
+            jfif: None,
+            exif: None,
+            icc_profile_size: None,
+            comments: Vec::new(),
+            frames: Vec::new(),
+            restart_interval: None,
+            quantization_tables: Vec::new(),
+            huffman_tables: Vec::new(),
+            scans: Vec::new(),
+            segments: Vec::new(),
+            trailer_length: 0,
+        };
+        let mut icc_chunks = Vec::new();
+        for segment in MarkerReader::new(data) {
+            let segment = segment?;
+            let marker = match segment.kind {
+                SegmentKind::Marker(marker) => marker,
+                SegmentKind::EntropyCoded => {
+                    if let Some(scan) = report.scans.last_mut() {
+                        scan.data_length += segment.payload.len();
+                    }
+                    continue;
+                }
+                SegmentKind::Trailer => {
+                    report.trailer_length = segment.payload.len();
+                    continue;
+                }
+                SegmentKind::Fill => continue,
+            };
+            report.segments.push(SegmentReport {
+                marker,
+                offset: segment.offset,
+                length: segment.encoded_length(),
+            });
+            let payload = segment.payload;
+            match marker {
+                Marker::StartOfScan => {
+                    report.scans.push(ScanReport {
+                        offset: segment.offset,
+                        header: ScanHeader::parse(payload)?,
+                        data_length: 0,
+                    })
+                }
+                Marker::Restart(_) => {
+                    if let Some(scan) = report.scans.last_mut() {
+                        scan.data_length += 2;
+                    }
+                }
+                Marker::DefineHierarchicalProgression => {
+                    report.frames.push(FrameHeader::parse(marker, payload)?)
+                }
+                marker if marker.is_start_of_frame() => {
+                    report.frames.push(FrameHeader::parse(marker, payload)?)
+                }
+                Marker::QuantizationTable => {
+                    for (precision, id, table) in parse_quantization_tables(payload)? {
+                        let mut values = vec![0; 64];
+                        for (&index, &value) in ZIGZAG_INDICES.iter().zip(&table) {
+                            values[index] = value;
+                        }
+                        report.quantization_tables.push(QuantizationTable {
+                            id,
+                            bits: if precision == 0 { 8 } else { 16 },
+                            values,
+                        });
+                    }
+                }
+                Marker::DefineHuffmanTable => {
+                    report.huffman_tables.extend(HuffmanTableSpecification::parse(payload)?)
+                }
+                Marker::RestartIntervalDefinition if payload.len() >= 2 => {
+                    report.restart_interval = Some(u8s_to_u16(payload))
+                }
+                Marker::Comment => {
+                    report.comments.push(String::from_utf8_lossy(payload).into_owned())
+                }
+                Marker::ApplicationSegment(0) if payload.starts_with(b"JFIF\0") &&
+                                                 payload.len() >= 14 => {
+                    report.jfif = Some(JFIFHeader {
+                        version: (payload[5], payload[6]),
+                        units: JFIFUnits::from_u8(payload[7]),
+                        density: (u8s_to_u16(&payload[8..]), u8s_to_u16(&payload[10..])),
+                        thumbnail_dimensions: (payload[12], payload[13]),
+                    });
+                }
+                Marker::ApplicationSegment(1) if payload.starts_with(exif::EXIF_IDENTIFIER) &&
+                                                 report.exif.is_none() => {
+                    report.exif = Some(exif::fields(payload));
+                }
+                Marker::ApplicationSegment(2) if payload.starts_with(icc::ICC_IDENTIFIER) => {
+                    icc_chunks.push(payload);
+                }
+                _ => {}
+            }
+        }
+        if !icc_chunks.is_empty() {
+            report.icc_profile_size = icc::join_chunks(icc_chunks)
+                .map(|profile| profile.map(|profile| profile.len()))
+                .transpose();
+        }
+        Ok(report)
+    }
+
+    /// The report as text, a line for each header or table.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        // Writing to a `String` can't fail.
+        let _ = self.write_text(&mut text);
+        text
+    }
+
+    fn write_text(&self, text: &mut String) -> ::std::fmt::Result {
+        writeln!(text, "File size: {} bytes", self.file_size)?;
+        if let Some(ref jfif) = self.jfif {
+            writeln!(text,
+                     "JFIF {}.{:02}, density {}x{} {}, thumbnail {}x{}",
+                     jfif.version.0,
+                     jfif.version.1,
+                     jfif.density.0,
+                     jfif.density.1,
+                     units_name(&jfif.units),
+                     jfif.thumbnail_dimensions.0,
+                     jfif.thumbnail_dimensions.1)?;
+        }
+        match self.exif {
+            Some(Ok(ref fields)) => {
+                for field in fields {
+                    writeln!(text,
+                             "EXIF {:?} {} ({:#06x}): {}",
+                             field.ifd,
+                             field.name().unwrap_or("unknown"),
+                             field.tag,
+                             exif_value_text(&field.value))?;
+                }
+            }
+            Some(Err(ref error)) => writeln!(text, "EXIF: {}", error)?,
+            None => {}
+        }
+        match self.icc_profile_size {
+            Some(Ok(size)) => writeln!(text, "ICC profile: {} bytes", size)?,
+            Some(Err(ref error)) => writeln!(text, "ICC profile: {}", error)?,
+            None => {}
+        }
+        for comment in &self.comments {
+            writeln!(text, "Comment: {}", comment.trim_end())?;
+        }
+        for frame in &self.frames {
+            writeln!(text,
+                     "Frame {} ({:?}): {}x{}, {} bits, {} components",
+                     frame.frame_type.name(),
+                     frame.frame_type,
+                     frame.samples_per_line,
+                     frame.num_lines,
+                     frame.sample_precision,
+                     frame.frame_components.len())?;
+            for component in &frame.frame_components {
+                writeln!(text,
+                         "  Component {}: sampling {}x{}, quantization table {}",
+                         component.component_id,
+                         component.horizontal_sampling_factor,
+                         component.vertical_sampling_factor,
+                         component.quantization_selector)?;
+            }
+        }
+        if let Some(interval) = self.restart_interval {
+            writeln!(text, "Restart interval: {} MCUs", interval)?;
+        }
+        for table in &self.quantization_tables {
+            writeln!(text, "Quantization table {}, {} bits:", table.id, table.bits)?;
+            for row in table.values.chunks(8) {
+                let row = row.iter().map(|value| format!("{:5}", value)).collect::<String>();
+                writeln!(text, "  {}", row)?;
+            }
+        }
+        for table in &self.huffman_tables {
+            writeln!(text,
+                     "Huffman table {} {}: {} codes, of lengths 1 to 16: {}",
+                     if table.class == 0 { "DC" } else { "AC" },
+                     table.destination,
+                     table.values.len(),
+                     join(table.code_lengths.iter(), " "))?;
+        }
+        for (i, scan) in self.scans.iter().enumerate() {
+            let header = &scan.header;
+            let components = header.scan_components
+                .iter()
+                .map(|c| {
+                    format!("{} (DC {}, AC {})",
+                            c.component_id,
+                            c.dc_table_selector,
+                            c.ac_table_selector)
+                })
+                .collect::<Vec<_>>();
+            writeln!(text,
+                     "Scan {} at byte {}: components {}, spectral selection {}-{}, successive \
+                      approximation {}/{}, {} bytes of data",
+                     i + 1,
+                     scan.offset,
+                     components.join(", "),
+                     header.start_spectral_selection,
+                     header.end_spectral_selection,
+                     header.successive_approximation_bit_pos_high,
+                     header.successive_approximation_bit_pos_low,
+                     scan.data_length)?;
+        }
+        if self.trailer_length > 0 {
+            writeln!(text, "Trailing data: {} bytes", self.trailer_length)?;
+        }
+        let mut markers = Vec::new();
+        for segment in &self.segments {
+            let name = segment.marker.name();
+            if !markers.contains(&name) {
+                markers.push(name);
+            }
+        }
+        writeln!(text, "Markers: {}", markers.join(" "))
+    }
+
+    /// The report as a JSON object.
+    pub fn to_json(&self) -> String {
+        let jfif = match self.jfif {
+            Some(ref jfif) => {
+                Json::Object(vec![("version",
+                                   Json::String(format!("{}.{:02}",
+                                                        jfif.version.0,
+                                                        jfif.version.1))),
+                                  ("units", Json::String(units_name(&jfif.units))),
+                                  ("density", pair(jfif.density.0, jfif.density.1)),
+                                  ("thumbnail",
+                                   pair(jfif.thumbnail_dimensions.0,
+                                        jfif.thumbnail_dimensions.1))])
+            }
+            None => Json::Null,
+        };
+        let exif = match self.exif {
+            Some(Ok(ref fields)) => {
+                Json::Array(fields.iter()
+                    .map(|field| {
+                        Json::Object(vec![("ifd", Json::String(format!("{:?}", field.ifd))),
+                                          ("tag", Json::Number(field.tag as i64)),
+                                          ("name", field.name().map_or(Json::Null, |name| {
+                                              Json::String(name.to_string())
+                                          })),
+                                          ("value", exif_value_json(&field.value))])
+                    })
+                    .collect())
+            }
+            Some(Err(ref error)) => error_json(error),
+            None => Json::Null,
+        };
+        let icc_profile_size = match self.icc_profile_size {
+            Some(Ok(size)) => Json::Number(size as i64),
+            Some(Err(ref error)) => error_json(error),
+            None => Json::Null,
+        };
+        let frames = self.frames
+            .iter()
+            .map(|frame| {
+                let components = frame.frame_components
+                    .iter()
+                    .map(|c| {
+                        Json::Object(vec![("id", Json::Number(c.component_id as i64)),
+                                          ("sampling",
+                                           pair(c.horizontal_sampling_factor,
+                                                c.vertical_sampling_factor)),
+                                          ("quantization_table",
+                                           Json::Number(c.quantization_selector as i64))])
+                    })
+                    .collect();
+                Json::Object(vec![("marker", Json::String(frame.frame_type.name())),
+                                  ("precision", Json::Number(frame.sample_precision as i64)),
+                                  ("width", Json::Number(frame.samples_per_line as i64)),
+                                  ("height", Json::Number(frame.num_lines as i64)),
+                                  ("components", Json::Array(components))])
+            })
+            .collect();
+        let quantization_tables = self.quantization_tables
+            .iter()
+            .map(|table| {
+                Json::Object(vec![("id", Json::Number(table.id as i64)),
+                                  ("bits", Json::Number(table.bits as i64)),
+                                  ("values", numbers(table.values.iter()))])
+            })
+            .collect();
+        let huffman_tables = self.huffman_tables
+            .iter()
+            .map(|table| {
+                let class = if table.class == 0 { "DC" } else { "AC" };
+                Json::Object(vec![("class", Json::String(class.to_string())),
+                                  ("id", Json::Number(table.destination as i64)),
+                                  ("codes", Json::Number(table.values.len() as i64)),
+                                  ("codes_per_length", numbers(table.code_lengths.iter()))])
+            })
+            .collect();
+        let scans = self.scans
+            .iter()
+            .map(|scan| {
+                let header = &scan.header;
+                let components = header.scan_components
+                    .iter()
+                    .map(|c| {
+                        Json::Object(vec![("id", Json::Number(c.component_id as i64)),
+                                          ("dc_table", Json::Number(c.dc_table_selector as i64)),
+                                          ("ac_table", Json::Number(c.ac_table_selector as i64))])
+                    })
+                    .collect();
+                Json::Object(vec![("offset", Json::Number(scan.offset as i64)),
+                                  ("components", Json::Array(components)),
+                                  ("spectral_selection",
+                                   pair(header.start_spectral_selection,
+                                        header.end_spectral_selection)),
+                                  ("successive_approximation",
+                                   pair(header.successive_approximation_bit_pos_high,
+                                        header.successive_approximation_bit_pos_low)),
+                                  ("data_length", Json::Number(scan.data_length as i64))])
+            })
+            .collect();
+        let segments = self.segments
+            .iter()
+            .map(|segment| {
+                Json::Object(vec![("marker", Json::String(segment.marker.name())),
+                                  ("offset", Json::Number(segment.offset as i64)),
+                                  ("length", Json::Number(segment.length as i64))])
+            })
+            .collect();
+        let restart_interval = self.restart_interval
+            .map_or(Json::Null, |interval| Json::Number(interval as i64));
+
+        let report = Json::Object(vec![("file_size", Json::Number(self.file_size as i64)),
+                                       ("jfif", jfif),
+                                       ("exif", exif),
+                                       ("icc_profile_size", icc_profile_size),
+                                       ("comments",
+                                        Json::Array(self.comments
+                                            .iter()
+                                            .map(|c| Json::String(c.clone()))
+                                            .collect())),
+                                       ("frames", Json::Array(frames)),
+                                       ("restart_interval", restart_interval),
+                                       ("quantization_tables", Json::Array(quantization_tables)),
+                                       ("huffman_tables", Json::Array(huffman_tables)),
+                                       ("scans", Json::Array(scans)),
+                                       ("segments", Json::Array(segments)),
+                                       ("trailer_length",
+                                        Json::Number(self.trailer_length as i64))]);
+        let mut json = String::new();
+        report.write(&mut json, 0);
+        json.push('\n');
+        json
+    }
+}
+
+fn units_name(units: &JFIFUnits) -> String {
+    match *units {
+        JFIFUnits::NoUnits => "without units".to_string(),
+        JFIFUnits::DotsPerInch => "dots per inch".to_string(),
+        JFIFUnits::DotsPerCm => "dots per cm".to_string(),
+        JFIFUnits::Unknown(n) => format!("of unknown units {}", n),
+    }
+}
+
+fn join<T: ToString, I: Iterator<Item = T>>(items: I, separator: &str) -> String {
+    items.map(|item| item.to_string()).collect::<Vec<_>>().join(separator)
+}
+
+fn exif_value_text(value: &ExifValue) -> String {
+    match *value {
+        ExifValue::Text(ref text) => format!("{:?}", text),
+        ExifValue::Integers(ref integers) => join(integers.iter(), " "),
+        ExifValue::Rationals(ref rationals) => {
+            join(rationals.iter().map(|&(n, d)| format!("{}/{}", n, d)), " ")
+        }
+        ExifValue::Bytes(ref bytes) if bytes.len() <= 16 => {
+            bytes.iter().map(|b| format!("{:02x}", b)).collect()
+        }
+        ExifValue::Bytes(ref bytes) => format!("{} bytes", bytes.len()),
+    }
+}
+
+/// A single value is written as it is, and several as an array.
+/// Rationals are arrays of numerator and denominator, and bytes are a
+/// string of hex digits.
+fn exif_value_json(value: &ExifValue) -> Json {
+    let one_or_many = |mut values: Vec<Json>| if values.len() == 1 {
+        values.remove(0)
+    } else {
+        Json::Array(values)
+    };
+    match *value {
+        ExifValue::Text(ref text) => Json::String(text.clone()),
+        ExifValue::Integers(ref integers) => {
+            one_or_many(integers.iter().map(|&n| Json::Number(n)).collect())
+        }
+        ExifValue::Rationals(ref rationals) => {
+            one_or_many(rationals.iter().map(|&(n, d)| pair(n, d)).collect())
+        }
+        ExifValue::Bytes(ref bytes) => {
+            Json::String(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+        }
+    }
+}
+
+fn error_json(error: &str) -> Json {
+    Json::Object(vec![("error", Json::String(error.to_string()))])
+}
+
+fn pair<T: Into<i64>>(a: T, b: T) -> Json {
+    Json::Array(vec![Json::Number(a.into()), Json::Number(b.into())])
+}
+
+fn numbers<'a, T, I>(values: I) -> Json
+    where T: 'a + Into<i64> + Copy,
+          I: Iterator<Item = &'a T>
+{
+    Json::Array(values.map(|&n| Json::Number(n.into())).collect())
+}
+
+/// Just enough of JSON to write a report.
+enum Json {
+    Null,
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    /// Write the value, with objects and arrays of objects on several
+    /// lines, indented by `indent` levels.
+    fn write(&self, out: &mut String, indent: usize) {
+        match *self {
+            Json::Null => out.push_str("null"),
+            Json::Number(n) => out.push_str(&n.to_string()),
+            Json::String(ref s) => write_json_string(out, s),
+            Json::Array(ref values) => {
+                let nested = values.iter().any(|value| matches!(*value, Json::Object(_)));
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                        if !nested {
+                            out.push(' ');
+                        }
+                    }
+                    if nested {
+                        new_line(out, indent + 1);
+                    }
+                    value.write(out, indent + 1);
+                }
+                if nested {
+                    new_line(out, indent);
+                }
+                out.push(']');
+            }
+            Json::Object(ref fields) => {
+                out.push('{');
+                for (i, &(name, ref value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    new_line(out, indent + 1);
+                    write_json_string(out, name);
+                    out.push_str(": ");
+                    value.write(out, indent + 1);
+                }
+                if !fields.is_empty() {
+                    new_line(out, indent);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+fn new_line(out: &mut String, indent: usize) {
+    out.push('\n');
+    for _ in 0..indent {
+        out.push_str("  ");
+    }
+}
+
+/// Write `s` as a JSON string, with quotes and escapes. See RFC 8259
+/// section 7.
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
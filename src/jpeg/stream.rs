@@ -0,0 +1,655 @@
+// Decoding images as they are read.
+//
+// `JPEGImage::parse` needs the whole file in memory. The decoders here
+// take it a piece at a time instead: `JPEGStreamDecoder` pulls from any
+// `Read`, like a socket or a decompressing reader, and
+// `JPEGPushDecoder` is given the data as it arrives. Entropy coded data
+// is decoded as it comes, so we only hold a few MCUs worth of it.
+
+use std::io::{self, BufRead, BufReader, Read};
+
+use jpeg::{ImageBuilder, JPEGImage};
+use jpeg::color::ColorConversion;
+use jpeg::decoder::{CoefficientOrder, ComponentCoefficients, MCURow, MAX_MCU_SIZE};
+use jpeg::marker::Marker;
+use jpeg::pixel::PixelFormat;
+use jpeg::u8s_to_u16;
+
+/// Size of the buffer put around readers which are not buffered already.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+fn read_error(error: io::Error) -> String {
+    format!("Could not read the image: {}", error)
+}
+
+/// Decodes an image from a reader.
+///
+/// Use `JPEGStreamDecoder::new` for any `Read`, or
+/// `JPEGStreamDecoder::from_buf_read` for readers which are buffered
+/// already. In the latter case, entropy coded data is decoded straight
+/// from the buffer of the reader whenever it holds enough of it.
+///
+/// Reading stops at the EOI marker, so anything after it, like the
+/// embedded images of the MPF index, is not available.
+pub struct JPEGStreamDecoder<R> {
+    input: Input<R>,
+    builder: ImageBuilder,
+}
+
+impl<R: Read> JPEGStreamDecoder<BufReader<R>> {
+    pub fn new(reader: R) -> JPEGStreamDecoder<BufReader<R>> {
+        JPEGStreamDecoder::from_buf_read(BufReader::with_capacity(BUFFER_SIZE, reader))
+    }
+}
+
+impl<R: BufRead> JPEGStreamDecoder<R> {
+    pub fn from_buf_read(reader: R) -> JPEGStreamDecoder<R> {
+        JPEGStreamDecoder {
+            input: Input {
+                reader,
+                pending: Vec::new(),
+                position: 0,
+            },
+            builder: ImageBuilder::new(),
+        }
+    }
+
+    /// Decode the image at `scale / 8` of its size, for a `scale` from 1
+    /// to 16. Call this before reading anything. See `JPEGDecoder::scale`.
+    pub fn scale(&mut self, scale: usize) -> Result<(), String> {
+        if self.builder.header_ready() {
+            return Err("The image can only be scaled before its header is read".to_string());
+        }
+        self.builder.scale(scale)
+    }
+
+    /// Only decode the first component of the image, which is the luma
+    /// of YCbCr images, and give gray pixels. Call this before reading
+    /// anything. See `JPEGDecoder::luma_only`.
+    pub fn luma_only(&mut self) -> Result<(), String> {
+        if self.builder.header_ready() {
+            return Err("Decoding only the luma must be chosen before the header is read"
+                .to_string());
+        }
+        self.builder.luma_only();
+        Ok(())
+    }
+
+    /// Convert YCbCr to RGB with `conversion`, for images made from
+    /// video frames. Call this before reading anything. See
+    /// `JPEGDecoder::color_conversion`.
+    pub fn color_conversion(&mut self, conversion: ColorConversion) -> Result<(), String> {
+        if self.builder.header_ready() {
+            return Err("The color conversion must be chosen before the header is read"
+                .to_string());
+        }
+        self.builder.color_conversion(conversion);
+        Ok(())
+    }
+
+    /// Decode as much as possible of truncated and corrupt files instead
+    /// of failing, and describe what was wrong in
+    /// `JPEGImage::warnings`. Corrupt entropy coded data is skipped up to
+    /// the next restart marker, and segments after the frame header
+    /// which can't be read end the image. Call this before reading
+    /// anything. See `JPEGDecoder::lenient`.
+    pub fn lenient(&mut self) -> Result<(), String> {
+        if self.builder.header_ready() {
+            return Err("Lenient mode must be chosen before the header is read".to_string());
+        }
+        self.builder.lenient();
+        Ok(())
+    }
+
+    /// Read up to the frame header, and return the width and height of
+    /// the image, as it will be decoded. The height is `0` if it is given
+    /// by a DNL segment.
+    pub fn read_header(&mut self) -> Result<(usize, usize), String> {
+        while !self.builder.header_ready() {
+            if !self.input.read_segment(&mut self.builder)? {
+                return Err("The image has no frame header".to_string());
+            }
+        }
+        Ok((self.builder.image.width(), self.builder.image.height()))
+    }
+
+    /// Read and decode the image.
+    pub fn decode(mut self) -> Result<JPEGImage, String> {
+        self.read_all()?;
+        self.builder.finish()
+    }
+
+    /// Read and decode the image, and write its pixels to `buffer` in the
+    /// given format, with lines `stride` bytes apart. Use `read_header`
+    /// to find the size of the buffer. The image which is returned has
+    /// no pixels of its own.
+    pub fn decode_into(mut self,
+                       buffer: &mut [u8],
+                       format: PixelFormat,
+                       stride: usize)
+                       -> Result<JPEGImage, String> {
+        self.read_all()?;
+        self.builder.finish_into(buffer, format, stride)
+    }
+
+    /// Read the image, and decode only its luma, as a `PixelFormat::Gray8`
+    /// buffer with no padding between lines. The image which is returned
+    /// has no pixels of its own. See `luma_only`.
+    pub fn decode_luma(mut self) -> Result<(JPEGImage, Vec<u8>), String> {
+        self.luma_only()?;
+        self.read_all()?;
+        let (width, height) = (self.builder.image.width(), self.builder.image.height());
+        let mut buffer = vec![0; width * height];
+        let image = self.builder.finish_into(&mut buffer, PixelFormat::Gray8, width)?;
+        Ok((image, buffer))
+    }
+
+    /// Read the image, and decode only the `width` x `height` rectangle at
+    /// `(x, y)` of it, which becomes the image that is returned. If the
+    /// image is scaled, the rectangle is in the scaled image. Entropy
+    /// coded data after the rectangle is skipped without decoding it, as
+    /// is data before it when there are restart markers. Use
+    /// `read_header` to find the size of the image first.
+    pub fn decode_region(mut self,
+                         x: usize,
+                         y: usize,
+                         width: usize,
+                         height: usize)
+                         -> Result<JPEGImage, String> {
+        self.builder.region(x, y, width, height)?;
+        self.read_all()?;
+        self.builder.finish()
+    }
+
+    /// Read the image, and get the quantized DCT coefficients of each of
+    /// its components instead of its pixels. See
+    /// `JPEGDecoder::coefficients`.
+    pub fn read_coefficients(mut self,
+                             order: CoefficientOrder)
+                             -> Result<(JPEGImage, Vec<ComponentCoefficients>), String> {
+        self.read_all()?;
+        self.builder.finish_coefficients(order)
+    }
+
+    /// Decode the image one MCU row at a time. See `JPEGRowDecoder`.
+    pub fn rows(self) -> JPEGRowDecoder<R> {
+        let mut builder = self.builder;
+        builder.output_rows();
+        JPEGRowDecoder {
+            input: self.input,
+            scale: builder.scale,
+            builder: Some(builder),
+            image: None,
+            next_line: 0,
+        }
+    }
+
+    fn read_all(&mut self) -> Result<(), String> {
+        match self.read_segments() {
+            // What is decoded so far becomes the image.
+            Err(error) if self.builder.lenient && self.builder.header_ready() => {
+                self.builder.warn(error);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn read_segments(&mut self) -> Result<(), String> {
+        while self.input.read_segment(&mut self.builder)? {
+            if self.builder.scan_in_progress() {
+                self.input.read_scan(&mut self.builder)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The data of an image which is read a piece at a time.
+struct Input<R> {
+    reader: R,
+    /// Bytes taken from `reader` which are not used yet. Entropy coded
+    /// data is collected here when the buffer of `reader` has too little
+    /// of it to decode an MCU from.
+    pending: Vec<u8>,
+    /// Position in the file of the next byte which is not used
+    position: usize,
+}
+
+impl<R: BufRead> Input<R> {
+    /// Read the next segment and give it to `builder`. Returns `false`
+    /// at the end of the image.
+    fn read_segment(&mut self, builder: &mut ImageBuilder) -> Result<bool, String> {
+        loop {
+            let marker = match self.read_marker()? {
+                Some(Marker::EndOfImage) => return Ok(false),
+                None => {
+                    let error = "The file ends without an EOI marker".to_string();
+                    if !builder.lenient {
+                        return Err(error);
+                    }
+                    builder.warn(error);
+                    return Ok(false);
+                }
+                Some(marker) => marker,
+            };
+            if !marker.has_length() {
+                // SOI, TEM and stray RSTn markers have no data.
+                continue;
+            }
+            let length = self.read_bytes(2)?;
+            let length = ((length[0] as usize) << 8) + length[1] as usize;
+            if length < 2 {
+                return Err(format!("{:?} segment at byte {} has invalid length {}",
+                                   marker,
+                                   self.position - 4,
+                                   length));
+            }
+            let offset = self.position;
+            let data = self.read_bytes(length - 2)?;
+            builder.segment(marker, &data, offset)?;
+            return Ok(true);
+        }
+    }
+
+    /// Read the next marker, skipping any fill bytes in front of it.
+    /// Returns `None` at the end of the file.
+    fn read_marker(&mut self) -> Result<Option<Marker>, String> {
+        if self.at_end()? {
+            return Ok(None);
+        }
+        let start = self.position;
+        let byte = self.read_bytes(1)?[0];
+        if byte != 0xff {
+            return Err(format!("Expected a marker at byte {}, found {:02x}", start, byte));
+        }
+        loop {
+            let code = match self.read_bytes(1) {
+                Ok(code) => code[0],
+                Err(_) => {
+                    return Err(format!("File ends in the middle of a marker at byte {}", start))
+                }
+            };
+            match code {
+                0xff => continue,
+                0x00 => return Err(format!("Expected a marker at byte {}, found ff00", start)),
+                code => return Ok(Some(Marker::from_code(code))),
+            }
+        }
+    }
+
+    /// Whether all bytes of the file are used.
+    fn at_end(&mut self) -> Result<bool, String> {
+        Ok(self.pending.is_empty() && self.reader.fill_buf().map_err(read_error)?.is_empty())
+    }
+
+    /// Read the next `length` bytes.
+    fn read_bytes(&mut self, length: usize) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::with_capacity(length);
+        let from_pending = length.min(self.pending.len());
+        bytes.extend(self.pending.drain(..from_pending));
+        while bytes.len() < length {
+            let num_bytes = {
+                let buffer = self.reader.fill_buf().map_err(read_error)?;
+                if buffer.is_empty() {
+                    return Err(format!("File ends in the middle of a segment, at byte {}",
+                                       self.position + bytes.len()));
+                }
+                let num_bytes = buffer.len().min(length - bytes.len());
+                bytes.extend_from_slice(&buffer[..num_bytes]);
+                num_bytes
+            };
+            self.reader.consume(num_bytes);
+        }
+        self.position += length;
+        Ok(bytes)
+    }
+
+    /// Give the entropy coded data following a SOS segment to `builder`,
+    /// until all MCUs of the scan are decoded.
+    fn read_scan(&mut self, builder: &mut ImageBuilder) -> Result<(), String> {
+        while builder.scan_in_progress() {
+            self.read_scan_data(builder)?;
+        }
+        Ok(())
+    }
+
+    /// Give the next piece of entropy coded data to `builder`.
+    fn read_scan_data(&mut self, builder: &mut ImageBuilder) -> Result<(), String> {
+        if self.pending.is_empty() {
+            let (used, unused) = {
+                let buffer = self.reader.fill_buf().map_err(read_error)?;
+                let used = builder.scan_data(buffer, buffer.is_empty())?;
+                (used, buffer.len() - used)
+            };
+            self.reader.consume(used);
+            self.position += used;
+            // If the decoder stopped with enough data left, it stopped
+            // to output a row. Otherwise there is too little data left in
+            // the buffer to know that the next MCU is complete, so we
+            // continue with a copy of it.
+            if builder.scan_in_progress() && unused < MAX_MCU_SIZE {
+                self.pending.extend_from_slice(&self.reader
+                    .fill_buf()
+                    .map_err(read_error)?[..unused]);
+                self.reader.consume(unused);
+            }
+        } else {
+            // Decode from the pending data followed by a copy of the
+            // start of the buffer. Once we are past the pending data,
+            // we can go back to decoding from the buffer.
+            let num_pending = self.pending.len();
+            let (used, copied) = {
+                let buffer = self.reader.fill_buf().map_err(read_error)?;
+                let copied = buffer.len().min(2 * MAX_MCU_SIZE);
+                self.pending.extend_from_slice(&buffer[..copied]);
+                (builder.scan_data(&self.pending, copied == 0)?, copied)
+            };
+            if used >= num_pending {
+                self.pending.clear();
+                self.reader.consume(used - num_pending);
+            } else if used > 0 {
+                // Still inside the pending data, so the copy is dropped,
+                // and made again from the buffer next time.
+                self.pending.truncate(num_pending);
+                self.pending.drain(..used);
+            } else {
+                // The buffer holds too little to finish the next MCU, so
+                // we keep the copy and read more.
+                self.reader.consume(copied);
+            }
+            self.position += used;
+        }
+        Ok(())
+    }
+}
+
+/// How far `JPEGPushDecoder::feed` got. Each status implies the ones
+/// before it, so `RowsAvailable` also means that the header is ready.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DecodeStatus {
+    /// Nothing new can be decoded until more data is given
+    NeedMoreData,
+    /// The frame header is read, so the size of the image is known
+    HeaderReady,
+    /// The current scan has decoded more lines of the image. See
+    /// `JPEGPushDecoder::decoded_lines`.
+    RowsAvailable,
+    /// The EOI marker is read, and the image is decoded
+    Done,
+}
+
+/// Decodes an image which is given a piece at a time, as it arrives.
+///
+/// The data can be split anywhere, also in the middle of a segment or an
+/// MCU. Whatever can not be used yet is kept until the next call to
+/// `feed`. After an error, every later call to `feed` returns it again.
+pub struct JPEGPushDecoder {
+    /// `None` once the image is done
+    builder: Option<ImageBuilder>,
+    /// Data which is given, but not used yet
+    buffer: Vec<u8>,
+    /// Position in the file of the first byte in `buffer`
+    position: usize,
+    /// Whether we have reported `DecodeStatus::HeaderReady`
+    header_ready: bool,
+    /// Number of lines the current scan had decoded when we last
+    /// reported `DecodeStatus::RowsAvailable`
+    decoded_lines: usize,
+    image: Option<JPEGImage>,
+    /// The error which stopped decoding, if any
+    error: Option<String>,
+}
+
+impl Default for JPEGPushDecoder {
+    fn default() -> JPEGPushDecoder {
+        JPEGPushDecoder::new()
+    }
+}
+
+impl JPEGPushDecoder {
+    pub fn new() -> JPEGPushDecoder {
+        JPEGPushDecoder {
+            builder: Some(ImageBuilder::new()),
+            buffer: Vec::new(),
+            position: 0,
+            header_ready: false,
+            decoded_lines: 0,
+            image: None,
+            error: None,
+        }
+    }
+
+    /// Decode as much as possible of the image with `data` added. Data
+    /// after the EOI marker is ignored.
+    pub fn feed(&mut self, data: &[u8]) -> Result<DecodeStatus, String> {
+        if let Some(ref error) = self.error {
+            return Err(error.clone());
+        }
+        let result = self.decode(data);
+        if let Err(ref error) = result {
+            self.error = Some(error.clone());
+        }
+        result
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Result<DecodeStatus, String> {
+        let mut status = DecodeStatus::NeedMoreData;
+        let mut builder = match self.builder.take() {
+            Some(builder) => builder,
+            None => return Ok(DecodeStatus::Done),
+        };
+        self.buffer.extend_from_slice(data);
+
+        let mut used = 0;
+        loop {
+            let rest = &self.buffer[used..];
+            if builder.scan_in_progress() {
+                used += builder.scan_data(rest, false)?;
+                let decoded_lines = builder.decoded_lines();
+                if decoded_lines > self.decoded_lines {
+                    status = status.max(DecodeStatus::RowsAvailable);
+                }
+                if builder.scan_in_progress() {
+                    self.decoded_lines = decoded_lines;
+                    break;
+                }
+                self.decoded_lines = 0;
+                continue;
+            }
+
+            // The next marker, with any number of fill bytes in front of it.
+            if rest.is_empty() {
+                break;
+            }
+            if rest[0] != 0xff {
+                return Err(format!("Expected a marker at byte {}, found {:02x}",
+                                   self.position + used,
+                                   rest[0]));
+            }
+            let mut code_index = 1;
+            while code_index < rest.len() && rest[code_index] == 0xff {
+                code_index += 1;
+            }
+            if code_index >= rest.len() {
+                break;
+            }
+            if rest[code_index] == 0x00 {
+                return Err(format!("Expected a marker at byte {}, found ff00",
+                                   self.position + used));
+            }
+            let marker = Marker::from_code(rest[code_index]);
+            let data_start = code_index + 3;
+            if !marker.has_length() {
+                used += code_index + 1;
+                if marker == Marker::EndOfImage {
+                    self.image = Some(builder.finish()?);
+                    self.buffer.clear();
+                    return Ok(DecodeStatus::Done);
+                }
+                continue;
+            }
+            // Wait until we have the whole segment.
+            if rest.len() < data_start {
+                break;
+            }
+            let length = u8s_to_u16(&rest[code_index + 1..]) as usize;
+            if length < 2 {
+                return Err(format!("{:?} segment at byte {} has invalid length {}",
+                                   marker,
+                                   self.position + used,
+                                   length));
+            }
+            let data_end = data_start + length - 2;
+            if rest.len() < data_end {
+                break;
+            }
+            builder.segment(marker,
+                         &rest[data_start..data_end],
+                         self.position + used + data_start)?;
+            used += data_end;
+            if !self.header_ready && builder.header_ready() {
+                self.header_ready = true;
+                status = status.max(DecodeStatus::HeaderReady);
+            }
+        }
+        self.buffer.drain(..used);
+        self.position += used;
+        self.builder = Some(builder);
+        Ok(status)
+    }
+
+    /// Width of the image, once the header is ready.
+    pub fn width(&self) -> usize {
+        self.builder.as_ref().map_or_else(|| self.image.as_ref().map_or(0, |i| i.width()),
+                                          |builder| builder.image.width())
+    }
+
+    /// Height of the image, once the header is ready. If the frame header
+    /// has no height, this is `0` until the first scan is decoded.
+    pub fn height(&self) -> usize {
+        self.builder.as_ref().map_or_else(|| self.image.as_ref().map_or(0, |i| i.height()),
+                                          |builder| builder.image.height())
+    }
+
+    /// Number of lines of the image the current scan has decoded. In
+    /// progressive images, the other lines have what the previous scans
+    /// decoded.
+    pub fn decoded_lines(&self) -> usize {
+        match self.builder {
+            Some(ref builder) => builder.decoded_lines(),
+            None => self.height(),
+        }
+    }
+
+    /// Get the pixels of the image from what is decoded so far. Parts
+    /// which are not decoded yet are gray.
+    pub fn image_data(&self) -> Result<Vec<(u8, u8, u8)>, String> {
+        match (self.builder.as_ref(), self.image.as_ref().and_then(|i| i.image_data())) {
+            (Some(builder), _) => builder.preview(),
+            (None, Some(image_data)) => Ok(image_data.clone()),
+            (None, None) => Err("The image has no scans".to_string()),
+        }
+    }
+
+    /// The decoded image, once `feed` has returned `DecodeStatus::Done`.
+    pub fn image(&self) -> Option<&JPEGImage> {
+        self.image.as_ref()
+    }
+
+    pub fn into_image(self) -> Option<JPEGImage> {
+        self.image
+    }
+}
+
+/// Decodes an image one MCU row at a time, from `JPEGStreamDecoder::rows`.
+///
+/// In sequential images where the first scan has all components, which
+/// is the most common kind, each row is turned into pixels as soon as
+/// it is decoded, so the memory used does not depend on the height of
+/// the image. Other images need all their scans before any pixels are
+/// known, so they are decoded in full before the first row is given.
+pub struct JPEGRowDecoder<R> {
+    input: Input<R>,
+    /// `None` once all segments are read
+    builder: Option<ImageBuilder>,
+    /// See `JPEGStreamDecoder::scale`
+    scale: usize,
+    /// The image, if it was decoded in full
+    image: Option<JPEGImage>,
+    /// The next line of `image` to give
+    next_line: usize,
+}
+
+impl<R: BufRead> JPEGRowDecoder<R> {
+    fn next_row(&mut self) -> Result<Option<MCURow>, String> {
+        while let Some(mut builder) = self.builder.take() {
+            if let Some(row) = builder.take_mcu_row() {
+                self.builder = Some(builder);
+                return Ok(Some(row));
+            }
+            if builder.scan_in_progress() {
+                self.input.read_scan_data(&mut builder)?;
+                self.builder = Some(builder);
+            } else if self.input.read_segment(&mut builder)? {
+                self.builder = Some(builder);
+            } else if !builder.is_streaming_rows() {
+                self.image = Some(builder.finish()?);
+            }
+        }
+
+        // Give the rows of the decoded image, with as many lines as the
+        // MCU rows of the frame.
+        let image = match self.image {
+            Some(ref image) => image,
+            None => return Ok(None),
+        };
+        let image_data = match image.image_data() {
+            Some(image_data) => image_data,
+            None => return Ok(None),
+        };
+        let lines_per_row = match image.frame_header {
+            Some(ref frame_header) => {
+                let max_v = frame_header.frame_components
+                    .iter()
+                    .map(|c| c.vertical_sampling_factor as usize)
+                    .max()
+                    .unwrap_or(1);
+                if frame_header.frame_type.is_lossless() {
+                    max_v
+                } else {
+                    self.scale * max_v
+                }
+            }
+            None => self.scale,
+        };
+        if self.next_line >= image.height() {
+            return Ok(None);
+        }
+        let first_line = self.next_line;
+        let num_lines = lines_per_row.min(image.height() - first_line);
+        self.next_line += num_lines;
+        let width = image.width();
+        Ok(Some(MCURow {
+            first_line,
+            num_lines,
+            pixels: image_data[first_line * width..(first_line + num_lines) * width].to_vec(),
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for JPEGRowDecoder<R> {
+    type Item = Result<MCURow, String>;
+
+    fn next(&mut self) -> Option<Result<MCURow, String>> {
+        match self.next_row() {
+            Ok(row) => row.map(Ok),
+            Err(error) => {
+                // Stop after an error.
+                self.builder = None;
+                self.image = None;
+                Some(Err(error))
+            }
+        }
+    }
+}
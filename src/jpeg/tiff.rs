@@ -0,0 +1,202 @@
+// Minimal reader for TIFF structured data.
+//
+// Both EXIF (APP1) and the Multi-Picture Format (APP2) store their
+// metadata as a TIFF header followed by a chain of IFDs (Image File
+// Directories). All offsets in these structures are relative to the
+// start of the TIFF header, which is also the start of `data` here.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteOrder {
+    LittleEndian,
+    BigEndian,
+}
+
+/// A single 12 byte entry in an IFD.
+#[derive(Debug, Clone)]
+pub struct IFDEntry {
+    /// Tag identifying the field
+    pub tag: u16,
+    /// Field type (BYTE = 1, ASCII = 2, SHORT = 3, LONG = 4, ...)
+    pub field_type: u16,
+    /// Number of values (not bytes!) in the field
+    pub count: u32,
+    /// Offset of the entry itself, relative to the TIFF header
+    pub entry_offset: usize,
+    /// Offset of the field value, relative to the TIFF header.
+    /// Values of four bytes or less are stored inside the entry.
+    pub value_offset: usize,
+}
+
+impl IFDEntry {
+    /// Number of bytes the value of this entry occupies.
+    pub fn value_length(&self) -> usize {
+        field_type_size(self.field_type) * self.count as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IFD {
+    pub entries: Vec<IFDEntry>,
+    /// Offset of the next IFD in the chain, or `0` if this is the last one.
+    pub next_ifd_offset: u32,
+}
+
+impl IFD {
+    pub fn entry(&self, tag: u16) -> Option<&IFDEntry> {
+        self.entries.iter().find(|e| e.tag == tag)
+    }
+}
+
+/// Size in bytes of one value of the given TIFF field type.
+fn field_type_size(field_type: u16) -> usize {
+    match field_type {
+        // BYTE, ASCII, SBYTE, UNDEFINED
+        1 | 2 | 6 | 7 => 1,
+        // SHORT, SSHORT
+        3 | 8 => 2,
+        // LONG, SLONG, FLOAT, IFD
+        4 | 9 | 11 | 13 => 4,
+        // RATIONAL, SRATIONAL, DOUBLE
+        5 | 10 | 12 => 8,
+        _ => 1,
+    }
+}
+
+pub struct TIFFReader<'a> {
+    data: &'a [u8],
+    byte_order: ByteOrder,
+}
+
+impl<'a> TIFFReader<'a> {
+    /// Create a reader from data starting at a TIFF header.
+    pub fn new(data: &'a [u8]) -> Result<TIFFReader<'a>, String> {
+        if data.len() < 8 {
+            return Err("TIFF header is too short".to_string());
+        }
+        let byte_order = match (data[0], data[1]) {
+            (b'I', b'I') => ByteOrder::LittleEndian,
+            (b'M', b'M') => ByteOrder::BigEndian,
+            (a, b) => return Err(format!("Unknown TIFF byte order: {:02x} {:02x}", a, b)),
+        };
+        let reader = TIFFReader {
+            data,
+            byte_order,
+        };
+        if reader.u16_at(2)? != 42 {
+            return Err("Invalid TIFF magic number".to_string());
+        }
+        Ok(reader)
+    }
+
+    pub fn byte_order(&self) -> ByteOrder {
+        self.byte_order
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn first_ifd_offset(&self) -> Result<u32, String> {
+        self.u32_at(4)
+    }
+
+    pub fn u16_at(&self, offset: usize) -> Result<u16, String> {
+        if offset + 2 > self.data.len() {
+            return Err(format!("TIFF offset {} is out of bounds", offset));
+        }
+        let (a, b) = (self.data[offset] as u16, self.data[offset + 1] as u16);
+        Ok(match self.byte_order {
+            ByteOrder::LittleEndian => (b << 8) | a,
+            ByteOrder::BigEndian => (a << 8) | b,
+        })
+    }
+
+    pub fn u32_at(&self, offset: usize) -> Result<u32, String> {
+        let a = self.u16_at(offset)? as u32;
+        let b = self.u16_at(offset + 2)? as u32;
+        Ok(match self.byte_order {
+            ByteOrder::LittleEndian => (b << 16) | a,
+            ByteOrder::BigEndian => (a << 16) | b,
+        })
+    }
+
+    /// Read the first value of an entry as an integer, if it is of an
+    /// integer type and has a value.
+    pub fn entry_u32(&self, entry: &IFDEntry) -> Result<u32, String> {
+        if entry.count == 0 {
+            return Err(format!("TIFF tag {:04x} has no value", entry.tag));
+        }
+        match entry.field_type {
+            1 | 7 => {
+                self.entry_bytes(entry)?
+                    .first()
+                    .map(|&n| n as u32)
+                    .ok_or_else(|| format!("TIFF tag {:04x} has no value", entry.tag))
+            }
+            3 => self.u16_at(entry.value_offset).map(|n| n as u32),
+            4 | 13 => self.u32_at(entry.value_offset),
+            t => Err(format!("TIFF tag {:04x} has non-integer type {}", entry.tag, t)),
+        }
+    }
+
+    /// The raw bytes of an entry's value.
+    pub fn entry_bytes(&self, entry: &IFDEntry) -> Result<&'a [u8], String> {
+        let end = entry.value_offset + entry.value_length();
+        if end > self.data.len() {
+            return Err(format!("Value of TIFF tag {:04x} is out of bounds", entry.tag));
+        }
+        Ok(&self.data[entry.value_offset..end])
+    }
+
+    /// Read the IFD located at `offset`.
+    pub fn read_ifd(&self, offset: usize) -> Result<IFD, String> {
+        let num_entries = self.u16_at(offset)? as usize;
+        let mut entries = Vec::with_capacity(num_entries);
+        for n in 0..num_entries {
+            let entry_offset = offset + 2 + n * 12;
+            let tag = self.u16_at(entry_offset)?;
+            let field_type = self.u16_at(entry_offset + 2)?;
+            let count = self.u32_at(entry_offset + 4)?;
+            let mut entry = IFDEntry {
+                tag,
+                field_type,
+                count,
+                entry_offset,
+                value_offset: entry_offset + 8,
+            };
+            if entry.value_length() > 4 {
+                entry.value_offset = self.u32_at(entry_offset + 8)? as usize;
+            }
+            entries.push(entry);
+        }
+        let next_ifd_offset = self.u32_at(offset + 2 + num_entries * 12)?;
+        Ok(IFD {
+            entries,
+            next_ifd_offset,
+        })
+    }
+}
+
+/// Write `value` at `offset` in `data`, using the given byte order.
+pub fn write_u16(data: &mut [u8], offset: usize, value: u16, byte_order: ByteOrder) {
+    let bytes = match byte_order {
+        ByteOrder::LittleEndian => [value as u8, (value >> 8) as u8],
+        ByteOrder::BigEndian => [(value >> 8) as u8, value as u8],
+    };
+    data[offset..offset + 2].copy_from_slice(&bytes);
+}
+
+/// Write `value` at `offset` in `data`, using the given byte order.
+pub fn write_u32(data: &mut [u8], offset: usize, value: u32, byte_order: ByteOrder) {
+    let (high, low) = ((value >> 16) as u16, value as u16);
+    match byte_order {
+        ByteOrder::LittleEndian => {
+            write_u16(data, offset, low, byte_order);
+            write_u16(data, offset + 2, high, byte_order);
+        }
+        ByteOrder::BigEndian => {
+            write_u16(data, offset, high, byte_order);
+            write_u16(data, offset + 2, low, byte_order);
+        }
+    }
+}
@@ -0,0 +1,377 @@
+// Lossless transforms of images, like those of jpegtran. The quantized
+// DCT coefficients are moved around, cropped or change sign, instead of
+// decoding the image and encoding it again, so no quality is lost.
+
+use jpeg::FrameHeader;
+use jpeg::decoder::{Block, CoefficientOrder, ComponentCoefficients, ZIGZAG_INDICES};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    /// Mirror left and right
+    FlipHorizontal,
+    /// Mirror top and bottom
+    FlipVertical,
+    /// Mirror along the diagonal from the top left corner
+    Transpose,
+    /// Mirror along the diagonal from the top right corner
+    Transverse,
+    /// Rotate 90 degrees clockwise
+    Rotate90,
+    Rotate180,
+    /// Rotate 270 degrees clockwise, or 90 counterclockwise
+    Rotate270,
+}
+
+/// What to do with partial MCUs at the right or bottom edge, when a
+/// transform moves that edge to the left or top. The image must start
+/// with whole MCUs there, so they can't be moved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartialMCUs {
+    /// Drop them, making the image a little smaller
+    Trim,
+    /// Fail, leaving the image as it is
+    Error,
+}
+
+impl Transform {
+    /// The transform turning an image with the given EXIF orientation the
+    /// way it should be shown, if it needs turning. See EXIF 4.6.4.
+    pub fn from_exif_orientation(orientation: u32) -> Option<Transform> {
+        match orientation {
+            2 => Some(Transform::FlipHorizontal),
+            3 => Some(Transform::Rotate180),
+            4 => Some(Transform::FlipVertical),
+            5 => Some(Transform::Transpose),
+            6 => Some(Transform::Rotate90),
+            7 => Some(Transform::Transverse),
+            8 => Some(Transform::Rotate270),
+            _ => None,
+        }
+    }
+
+    /// Whether the width and height of the image are swapped.
+    pub fn transposes(self) -> bool {
+        matches!(self,
+                 Transform::Transpose | Transform::Transverse | Transform::Rotate90 |
+                 Transform::Rotate270)
+    }
+
+    /// Whether the source image is mirrored horizontally and vertically,
+    /// before it is transposed. Every transform is one of these, maybe
+    /// followed by a transposition.
+    fn mirrors(self) -> (bool, bool) {
+        match self {
+            Transform::FlipHorizontal | Transform::Rotate270 => (true, false),
+            Transform::FlipVertical | Transform::Rotate90 => (false, true),
+            Transform::Transpose => (false, false),
+            Transform::Transverse | Transform::Rotate180 => (true, true),
+        }
+    }
+
+    /// The position in the source of position `(x, y)` in the output,
+    /// where the source is `width` by `height`.
+    fn source_position(self, position: (usize, usize), size: (usize, usize)) -> (usize, usize) {
+        let (x, y) = if self.transposes() {
+            (position.1, position.0)
+        } else {
+            position
+        };
+        let (mirror_x, mirror_y) = self.mirrors();
+        (if mirror_x { size.0 - 1 - x } else { x }, if mirror_y { size.1 - 1 - y } else { y })
+    }
+
+    /// Transform the values of a block, where each value is a coefficient
+    /// or its quantization step, stored in `order`. Mirroring changes the
+    /// sign of the coefficients with odd frequencies in that direction,
+    /// if `signs` is set.
+    fn transform_block<T>(self, block: &[T], order: CoefficientOrder, signs: bool) -> Vec<T>
+        where T: Copy + Negate
+    {
+        let (mirror_x, mirror_y) = self.mirrors();
+        (0..64)
+            .map(|i| {
+                let (u, v) = match natural_index(i, order) {
+                    n if self.transposes() => (n / 8, n % 8),
+                    n => (n % 8, n / 8),
+                };
+                let value = block[storage_index(v * 8 + u, order)];
+                if signs && ((mirror_x && u % 2 == 1) != (mirror_y && v % 2 == 1)) {
+                    value.negate()
+                } else {
+                    value
+                }
+            })
+            .collect()
+    }
+}
+
+/// Values which can change sign.
+trait Negate {
+    fn negate(self) -> Self;
+}
+
+impl Negate for i16 {
+    fn negate(self) -> i16 {
+        self.wrapping_neg()
+    }
+}
+
+impl Negate for u16 {
+    fn negate(self) -> u16 {
+        self
+    }
+}
+
+/// Index in natural order of value `i` of a block stored in `order`.
+fn natural_index(i: usize, order: CoefficientOrder) -> usize {
+    match order {
+        CoefficientOrder::Natural => i,
+        CoefficientOrder::Zigzag => ZIGZAG_INDICES[i],
+    }
+}
+
+/// Index in a block stored in `order` of the value with index `n` in
+/// natural order.
+fn storage_index(n: usize, order: CoefficientOrder) -> usize {
+    match order {
+        CoefficientOrder::Natural => n,
+        CoefficientOrder::Zigzag => ZIGZAG_INDICES.iter().position(|&i| i == n).unwrap(),
+    }
+}
+
+/// Transform the coefficients of all components of `frame`, giving the
+/// header and coefficients of the transformed frame. Partial MCUs which
+/// would end up at the left or top edge are handled as `partial_mcus`
+/// says.
+pub fn transform_coefficients(frame: &FrameHeader,
+                              components: &[ComponentCoefficients],
+                              transform: Transform,
+                              partial_mcus: PartialMCUs)
+                              -> Result<(FrameHeader, Vec<ComponentCoefficients>), String> {
+    let max_h = frame.frame_components
+        .iter()
+        .map(|c| c.horizontal_sampling_factor as usize)
+        .max()
+        .unwrap_or(1);
+    let max_v = frame.frame_components
+        .iter()
+        .map(|c| c.vertical_sampling_factor as usize)
+        .max()
+        .unwrap_or(1);
+
+    // The edges which move must consist of whole MCUs.
+    let (mirror_x, mirror_y) = transform.mirrors();
+    let mut width = frame.samples_per_line as usize;
+    let mut height = frame.num_lines as usize;
+    if mirror_x {
+        width = whole_mcus(width, 8 * max_h, partial_mcus)?;
+    }
+    if mirror_y {
+        height = whole_mcus(height, 8 * max_v, partial_mcus)?;
+    }
+
+    let mut output_frame = frame.clone();
+    let (output_width, output_height) = if transform.transposes() {
+        for component in &mut output_frame.frame_components {
+            let (h, v) = (component.horizontal_sampling_factor, component.vertical_sampling_factor);
+            component.horizontal_sampling_factor = v;
+            component.vertical_sampling_factor = h;
+        }
+        (height, width)
+    } else {
+        (width, height)
+    };
+    output_frame.samples_per_line = output_width as u16;
+    output_frame.num_lines = output_height as u16;
+    let (output_max_h, output_max_v) = if transform.transposes() {
+        (max_v, max_h)
+    } else {
+        (max_h, max_v)
+    };
+    let output_mcus_per_line = output_width.div_ceil(8 * output_max_h);
+    let output_mcu_rows = output_height.div_ceil(8 * output_max_v);
+
+    let mut output = Vec::with_capacity(components.len());
+    for frame_component in &frame.frame_components {
+        let id = frame_component.component_id;
+        let source = match components.iter().find(|c| c.component == id) {
+            Some(source) => source,
+            None => return Err(format!("No coefficients for component {}", id)),
+        };
+        let h = frame_component.horizontal_sampling_factor as usize;
+        let v = frame_component.vertical_sampling_factor as usize;
+        // The blocks of the source which are transformed
+        let source_size = ((width * h).div_ceil(max_h).div_ceil(8),
+                           (height * v).div_ceil(max_v).div_ceil(8));
+        let rows = source.blocks.len() / source.blocks_per_line.max(1);
+        if source.blocks_per_line < source_size.0 || rows < source_size.1 {
+            return Err(format!("The coefficients of component {} don't match the frame", id));
+        }
+
+        let (width_in_blocks, height_in_blocks, output_h, output_v) = if transform.transposes() {
+            (source_size.1, source_size.0, v, h)
+        } else {
+            (source_size.0, source_size.1, h, v)
+        };
+        let blocks_per_line = output_mcus_per_line * output_h;
+        let mut blocks = vec![[0; 64]; blocks_per_line * output_mcu_rows * output_v];
+        for y in 0..height_in_blocks {
+            for x in 0..width_in_blocks {
+                let (source_x, source_y) = transform.source_position((x, y), source_size);
+                let block = &source.blocks[source_y * source.blocks_per_line + source_x];
+                let transformed = transform.transform_block(&block[..], source.order, true);
+                let mut output_block: Block = [0; 64];
+                output_block.copy_from_slice(&transformed);
+                blocks[y * blocks_per_line + x] = output_block;
+            }
+        }
+        output.push(ComponentCoefficients {
+            component: id,
+            horizontal_sampling_factor: output_h as u8,
+            vertical_sampling_factor: output_v as u8,
+            quantization_id: source.quantization_id,
+            order: source.order,
+            quantization_table: transform.transform_block(&source.quantization_table,
+                                                          source.order,
+                                                          false),
+            width_in_blocks,
+            height_in_blocks,
+            blocks_per_line,
+            blocks,
+        });
+    }
+    Ok((output_frame, output))
+}
+
+/// Crop the coefficients of all components of `frame` to the rectangle
+/// at `(x, y)` of `width` by `height` pixels, giving the header and
+/// coefficients of the cropped frame. The left and top edges of the
+/// rectangle must be on MCU boundaries, as blocks can't be split.
+pub fn crop_coefficients(frame: &FrameHeader,
+                         components: &[ComponentCoefficients],
+                         rectangle: (usize, usize, usize, usize))
+                         -> Result<(FrameHeader, Vec<ComponentCoefficients>), String> {
+    let (x, y, width, height) = rectangle;
+    let max_h = frame.frame_components
+        .iter()
+        .map(|c| c.horizontal_sampling_factor as usize)
+        .max()
+        .unwrap_or(1);
+    let max_v = frame.frame_components
+        .iter()
+        .map(|c| c.vertical_sampling_factor as usize)
+        .max()
+        .unwrap_or(1);
+    if width == 0 || height == 0 || x + width > frame.samples_per_line as usize ||
+       y + height > frame.num_lines as usize {
+        return Err(format!("Crop of {}x{} at ({}, {}) is outside the image of {}x{}",
+                           width,
+                           height,
+                           x,
+                           y,
+                           frame.samples_per_line,
+                           frame.num_lines));
+    }
+    if !x.is_multiple_of(8 * max_h) || !y.is_multiple_of(8 * max_v) {
+        return Err(format!("Crop at ({}, {}) is not on the boundary of an MCU of {}x{} pixels",
+                           x,
+                           y,
+                           8 * max_h,
+                           8 * max_v));
+    }
+
+    let mut output_frame = frame.clone();
+    output_frame.samples_per_line = width as u16;
+    output_frame.num_lines = height as u16;
+    let mcus_per_line = width.div_ceil(8 * max_h);
+    let mcu_rows = height.div_ceil(8 * max_v);
+
+    let mut output = Vec::with_capacity(components.len());
+    for frame_component in &frame.frame_components {
+        let id = frame_component.component_id;
+        let source = match components.iter().find(|c| c.component == id) {
+            Some(source) => source,
+            None => return Err(format!("No coefficients for component {}", id)),
+        };
+        let h = frame_component.horizontal_sampling_factor as usize;
+        let v = frame_component.vertical_sampling_factor as usize;
+        let (first_x, first_y) = (x / (8 * max_h) * h, y / (8 * max_v) * v);
+        let source_rows = source.blocks.len() / source.blocks_per_line.max(1);
+
+        // The padding of the last MCUs keeps the blocks of the source
+        // which are there.
+        let blocks_per_line = mcus_per_line * h;
+        let mut blocks = vec![[0; 64]; blocks_per_line * mcu_rows * v];
+        for (row, output_row) in blocks.chunks_mut(blocks_per_line).enumerate() {
+            let source_y = first_y + row;
+            if source_y >= source_rows || first_x >= source.blocks_per_line {
+                continue;
+            }
+            let start = source_y * source.blocks_per_line + first_x;
+            let length = blocks_per_line.min(source.blocks_per_line - first_x);
+            output_row[..length].copy_from_slice(&source.blocks[start..start + length]);
+        }
+        output.push(ComponentCoefficients {
+            width_in_blocks: (width * h).div_ceil(max_h).div_ceil(8),
+            height_in_blocks: (height * v).div_ceil(max_v).div_ceil(8),
+            blocks_per_line,
+            blocks,
+            quantization_table: source.quantization_table.clone(),
+            ..*source
+        });
+    }
+    Ok((output_frame, output))
+}
+
+/// The size of an image of `size` pixels along an edge which moves,
+/// with partial MCUs handled as `partial_mcus` says.
+fn whole_mcus(size: usize, mcu_size: usize, partial_mcus: PartialMCUs) -> Result<usize, String> {
+    if size.is_multiple_of(mcu_size) {
+        return Ok(size);
+    }
+    match partial_mcus {
+        PartialMCUs::Trim if size >= mcu_size => Ok(size - size % mcu_size),
+        PartialMCUs::Trim => Err(format!("The image is smaller than an MCU of {} pixels", mcu_size)),
+        PartialMCUs::Error => {
+            Err(format!("The image has a partial MCU of {} pixels at the edge, which can't be \
+                         transformed",
+                        mcu_size))
+        }
+    }
+}
+
+/// Transpose the quantization tables in the data of a DQT segment, for
+/// a transform which swaps width and height. See JPEG B.2.4.1.
+pub fn transpose_quantization_tables(data: &mut [u8]) -> Result<(), String> {
+    let mut index = 0;
+    while index < data.len() {
+        let size = match data[index] >> 4 {
+            0 => 1,
+            1 => 2,
+            precision => {
+                return Err(format!("Unknown precision of quantization table: {}", precision))
+            }
+        };
+        if data.len() < index + 1 + 64 * size {
+            return Err("DQT segment is too short".to_string());
+        }
+        let table = data[index + 1..index + 1 + 64 * size]
+            .chunks(size)
+            .map(|value| value.iter().fold(0u16, |acc, &b| acc << 8 | b as u16))
+            .collect::<Vec<_>>();
+        let transposed = Transform::Transpose.transform_block(&table,
+                                                              CoefficientOrder::Zigzag,
+                                                              false);
+        for (i, value) in transposed.into_iter().enumerate() {
+            let start = index + 1 + i * size;
+            if size == 1 {
+                data[start] = value as u8;
+            } else {
+                data[start] = (value >> 8) as u8;
+                data[start + 1] = value as u8;
+            }
+        }
+        index += 1 + 64 * size;
+    }
+    Ok(())
+}
@@ -0,0 +1,460 @@
+// Checks whether a file follows the JPEG standard, without decoding the
+// image. This finds problems which our decoder tolerates, but which
+// stricter decoders may reject. See JPEG Annex B for the rules.
+
+use std::fmt;
+
+use jpeg::{parse_quantization_tables, FrameHeader, ScanHeader};
+use jpeg::encoder::HuffmanTableSpecification;
+use jpeg::marker::{Marker, MarkerReader, SegmentKind};
+
+/// How bad a violation is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Allowed, but worth knowing, like an image appended after EOI
+    Info,
+    /// Allowed by the standard or commonly accepted, but likely to cause
+    /// problems with some decoders
+    Warning,
+    /// Not allowed by the standard
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// A problem with a file, and where it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub severity: Severity,
+    /// Offset in the file of the marker with the problem
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}: {}", self.severity, self.offset, self.message)
+    }
+}
+
+/// Check the marker structure, headers and tables of the file in `data`.
+/// The violations are sorted by offset. The file is strictly valid if
+/// none of them is an `Error`.
+pub fn validate(data: &[u8]) -> Vec<Violation> {
+    let mut validator = Validator {
+        violations: Vec::new(),
+        frame: None,
+        hierarchical: false,
+        quantization_tables: [false; 4],
+        huffman_tables: [[false; 4]; 2],
+        sixteen_bit_quantization_tables: Vec::new(),
+        huffman_table_destinations: Vec::new(),
+        restart_interval: false,
+        next_restart: 0,
+    };
+    validator.check(data);
+    validator.violations.sort_by_key(|violation| violation.offset);
+    validator.violations
+}
+
+struct Validator {
+    violations: Vec<Violation>,
+    /// The last frame header, and its offset
+    frame: Option<(usize, FrameHeader)>,
+    /// Whether there was a DHP segment, which allows several frames
+    hierarchical: bool,
+    /// Which quantization tables have been defined
+    quantization_tables: [bool; 4],
+    /// Which DC and AC Huffman tables have been defined
+    huffman_tables: [[bool; 4]; 2],
+    /// Offsets and destinations of the DQT tables with 16-bit values,
+    /// which are checked against the precision of the frame at the end
+    sixteen_bit_quantization_tables: Vec<(usize, u8)>,
+    /// Offsets, classes and destinations of all Huffman tables, which
+    /// are checked against the limits of baseline frames at the end
+    huffman_table_destinations: Vec<(usize, u8, u8)>,
+    restart_interval: bool,
+    /// The number of the next RSTn marker in the scan
+    next_restart: u8,
+}
+
+impl Validator {
+    fn report(&mut self, severity: Severity, offset: usize, message: String) {
+        self.violations.push(Violation {
+            severity,
+            offset,
+            message,
+        });
+    }
+
+    fn check(&mut self, data: &[u8]) {
+        let mut reader = MarkerReader::new(data);
+        let mut end_of_image = false;
+        let mut first = true;
+        while let Some(segment) = reader.next() {
+            let segment = match segment {
+                Ok(segment) => segment,
+                Err(error) => {
+                    if first {
+                        self.report(Severity::Error, 0, "File does not start with SOI".to_string());
+                    }
+                    let offset = reader.position();
+                    self.report(Severity::Error, offset, error);
+                    return;
+                }
+            };
+            let offset = segment.offset;
+            let marker = match segment.kind {
+                SegmentKind::Marker(marker) => marker,
+                SegmentKind::Trailer if segment.payload.starts_with(&[0xff, 0xd8]) => {
+                    self.report(Severity::Info,
+                                offset,
+                                format!("{} bytes after EOI, starting with another image",
+                                        segment.payload.len()));
+                    continue;
+                }
+                SegmentKind::Trailer => {
+                    self.report(Severity::Warning,
+                                offset,
+                                format!("{} bytes of trailing data after EOI",
+                                        segment.payload.len()));
+                    continue;
+                }
+                SegmentKind::Fill | SegmentKind::EntropyCoded => continue,
+            };
+            if first && marker != Marker::StartOfImage {
+                self.report(Severity::Error, 0, "File does not start with SOI".to_string());
+            }
+            first = false;
+            let payload = segment.payload;
+            match marker {
+                Marker::StartOfImage if offset > 0 => {
+                    self.report(Severity::Error, offset, "SOI inside the image".to_string())
+                }
+                Marker::StartOfImage => {}
+                Marker::EndOfImage => end_of_image = true,
+                Marker::DefineHierarchicalProgression => self.hierarchical = true,
+                marker if marker.is_start_of_frame() => self.check_frame(offset, marker, payload),
+                Marker::StartOfScan => self.check_scan(offset, payload),
+                Marker::QuantizationTable => self.check_quantization_tables(offset, payload),
+                Marker::DefineHuffmanTable => self.check_huffman_tables(offset, payload),
+                Marker::RestartIntervalDefinition if payload.len() != 2 => {
+                    self.report(Severity::Error,
+                                offset,
+                                format!("DRI segment has {} bytes instead of 2", payload.len()))
+                }
+                Marker::RestartIntervalDefinition => {
+                    self.restart_interval = payload != [0, 0];
+                }
+                Marker::Restart(n) => {
+                    if !self.restart_interval {
+                        self.report(Severity::Warning,
+                                    offset,
+                                    format!("RST{} without a restart interval", n));
+                    } else if n != self.next_restart {
+                        self.report(Severity::Warning,
+                                    offset,
+                                    format!("RST{} where RST{} was expected",
+                                            n,
+                                            self.next_restart));
+                    }
+                    self.next_restart = (n + 1) % 8;
+                }
+                Marker::ReservedJPEGExtension | Marker::JPEGExtension(_) |
+                Marker::Temporary | Marker::Reserved(_) => {
+                    self.report(Severity::Warning,
+                                offset,
+                                format!("Reserved marker {}", marker.name()))
+                }
+                _ => {}
+            }
+        }
+        if first {
+            self.report(Severity::Error, 0, "File is empty".to_string());
+            return;
+        }
+        if !end_of_image {
+            self.report(Severity::Error, data.len(), "File ends without EOI".to_string());
+        }
+        if self.frame.is_none() {
+            self.report(Severity::Error, data.len(), "File has no frame header".to_string());
+        }
+        self.check_table_limits();
+    }
+
+    fn check_frame(&mut self, offset: usize, marker: Marker, payload: &[u8]) {
+        let frame = match FrameHeader::parse(marker, payload) {
+            Ok(frame) => frame,
+            Err(error) => return self.report(Severity::Error, offset, error),
+        };
+        if self.frame.is_some() && !self.hierarchical {
+            self.report(Severity::Error,
+                        offset,
+                        "Second frame, but only hierarchical images have several".to_string());
+        }
+
+        let precision = frame.sample_precision;
+        let valid_precision = match marker {
+            Marker::BaselineDCT => precision == 8,
+            Marker::Lossless | Marker::DifferentialLossless | Marker::LosslessArithmetic |
+            Marker::DifferentialLosslessArithmetic => (2..=16).contains(&precision),
+            _ => precision == 8 || precision == 12,
+        };
+        if !valid_precision {
+            self.report(Severity::Error,
+                        offset,
+                        format!("{} frames can't have {}-bit samples", marker.name(), precision));
+        }
+        if frame.samples_per_line == 0 {
+            self.report(Severity::Error, offset, "Frame has a width of 0".to_string());
+        }
+        if frame.frame_components.is_empty() {
+            self.report(Severity::Error, offset, "Frame has no components".to_string());
+        }
+        for (i, component) in frame.frame_components.iter().enumerate() {
+            if frame.frame_components[..i]
+                .iter()
+                .any(|other| other.component_id == component.component_id) {
+                self.report(Severity::Error,
+                            offset,
+                            format!("Component {} is defined twice", component.component_id));
+            }
+            if component.quantization_selector > 3 {
+                self.report(Severity::Error,
+                            offset,
+                            format!("Component {} has invalid quantization table {}",
+                                    component.component_id,
+                                    component.quantization_selector));
+            }
+        }
+        self.frame = Some((offset, frame));
+    }
+
+    fn check_scan(&mut self, offset: usize, payload: &[u8]) {
+        self.next_restart = 0;
+        let scan = match ScanHeader::parse(payload) {
+            Ok(scan) => scan,
+            Err(error) => return self.report(Severity::Error, offset, error),
+        };
+        let frame = match self.frame {
+            Some((_, ref frame)) => frame.clone(),
+            None => {
+                return self.report(Severity::Error, offset, "SOS before any SOFn".to_string())
+            }
+        };
+        let frame_type = frame.frame_type;
+        let lossless = matches!(frame_type,
+                                Marker::Lossless | Marker::DifferentialLossless |
+                                Marker::LosslessArithmetic |
+                                Marker::DifferentialLosslessArithmetic);
+        let progressive = matches!(frame_type,
+                                   Marker::ProgressiveDCT | Marker::DifferentialProgressiveDCT |
+                                   Marker::ProgressiveDCTArithmetic |
+                                   Marker::DifferentialProgressiveDCTArithmetic);
+        let (start, end) = (scan.start_spectral_selection, scan.end_spectral_selection);
+        let (high, low) = (scan.successive_approximation_bit_pos_high,
+                           scan.successive_approximation_bit_pos_low);
+
+        if scan.scan_components.is_empty() || scan.scan_components.len() > 4 {
+            self.report(Severity::Error,
+                        offset,
+                        format!("Scan has {} components", scan.scan_components.len()));
+        }
+        if progressive {
+            if start > end || end > 63 || (start == 0 && end != 0) {
+                self.report(Severity::Error,
+                            offset,
+                            format!("Invalid spectral selection {}-{}", start, end));
+            }
+            if start > 0 && scan.scan_components.len() > 1 {
+                self.report(Severity::Error,
+                            offset,
+                            "Scan of AC coefficients has several components".to_string());
+            }
+            if high != 0 && high != low + 1 {
+                self.report(Severity::Error,
+                            offset,
+                            format!("Invalid successive approximation {}/{}", high, low));
+            }
+        } else if !lossless && (start != 0 || end != 63 || high != 0 || low != 0) {
+            self.report(Severity::Error,
+                        offset,
+                        format!("Sequential scan has spectral selection {}-{} and successive \
+                                 approximation {}/{}",
+                                start,
+                                end,
+                                high,
+                                low));
+        }
+
+        let mut blocks_per_mcu = 0;
+        for scan_component in &scan.scan_components {
+            let id = scan_component.component_id;
+            let component = match frame.frame_components
+                .iter()
+                .find(|component| component.component_id == id) {
+                Some(component) => component,
+                None => {
+                    self.report(Severity::Error,
+                                offset,
+                                format!("Scan component {} is not in the frame", id));
+                    continue;
+                }
+            };
+            blocks_per_mcu += component.horizontal_sampling_factor as usize *
+                              component.vertical_sampling_factor as usize;
+
+            let quantization = component.quantization_selector as usize;
+            if !lossless && quantization < 4 && !self.quantization_tables[quantization] {
+                self.report(Severity::Error,
+                            offset,
+                            format!("Quantization table {} of component {} is not defined",
+                                    quantization,
+                                    id));
+            }
+            if frame_type.is_arithmetic_coded() {
+                continue;
+            }
+            // Refining DC scans have no codes, and neither do AC tables in
+            // DC scans.
+            let needs_dc_table = lossless || (start == 0 && (!progressive || high == 0));
+            let needs_ac_table = !lossless && end > 0;
+            let tables = [(0, "DC", scan_component.dc_table_selector, needs_dc_table),
+                          (1, "AC", scan_component.ac_table_selector, needs_ac_table)];
+            for &(class, name, table, needed) in &tables {
+                if !needed {
+                    continue;
+                }
+                if table > 3 || (frame_type == Marker::BaselineDCT && table > 1) {
+                    self.report(Severity::Error,
+                                offset,
+                                format!("Component {} uses invalid {} table {}", id, name, table));
+                } else if !self.huffman_tables[class][table as usize] {
+                    self.report(Severity::Error,
+                                offset,
+                                format!("Huffman {} table {} of component {} is not defined",
+                                        name,
+                                        table,
+                                        id));
+                }
+            }
+        }
+        // See JPEG B.2.3.
+        if scan.scan_components.len() > 1 && blocks_per_mcu > 10 {
+            self.report(Severity::Error,
+                        offset,
+                        format!("Sampling factors of the scan components add up to {}, more \
+                                 than 10",
+                                blocks_per_mcu));
+        }
+    }
+
+    fn check_quantization_tables(&mut self, offset: usize, payload: &[u8]) {
+        let tables = match parse_quantization_tables(payload) {
+            Ok(tables) => tables,
+            Err(error) => return self.report(Severity::Error, offset, error),
+        };
+        for (precision, id, values) in tables {
+            if values.contains(&0) {
+                self.report(Severity::Error,
+                            offset,
+                            format!("Quantization table {} has a value of 0", id));
+            }
+            if precision == 1 {
+                self.sixteen_bit_quantization_tables.push((offset, id));
+            }
+            self.quantization_tables[id as usize] = true;
+        }
+    }
+
+    fn check_huffman_tables(&mut self, offset: usize, payload: &[u8]) {
+        let tables = match HuffmanTableSpecification::parse(payload) {
+            Ok(tables) => tables,
+            Err(error) => return self.report(Severity::Error, offset, error),
+        };
+        for table in tables {
+            let name = format!("Huffman {} table {}",
+                               if table.class == 0 { "DC" } else { "AC" },
+                               table.destination);
+            // Codes are assigned in order of length, as in JPEG C.2. If
+            // there are more than fit in the bits, the code is not a
+            // prefix code.
+            let mut code = 0u32;
+            let mut all_ones = false;
+            for (i, &count) in table.code_lengths.iter().enumerate() {
+                let length = i + 1;
+                code += count as u32;
+                if code > 1 << length {
+                    self.report(Severity::Error,
+                                offset,
+                                format!("{} has too many codes of length {}", name, length));
+                    break;
+                }
+                if count > 0 {
+                    all_ones = code == 1 << length;
+                }
+                code <<= 1;
+            }
+            if all_ones {
+                self.report(Severity::Warning,
+                            offset,
+                            format!("{} has a code of all 1 bits, which is reserved", name));
+            }
+            if table.values.is_empty() {
+                self.report(Severity::Warning, offset, format!("{} has no codes", name));
+            }
+            if table.values.len() > 256 {
+                self.report(Severity::Error,
+                            offset,
+                            format!("{} has {} codes, more than 256", name, table.values.len()));
+            }
+            if table.class == 0 && table.values.iter().any(|&value| value > 16) {
+                self.report(Severity::Error,
+                            offset,
+                            format!("{} has a difference of more than 16 bits", name));
+            }
+            self.huffman_tables[table.class as usize][table.destination as usize] = true;
+            self.huffman_table_destinations.push((offset, table.class, table.destination));
+        }
+    }
+
+    /// The checks which need the frame header, which may come after the
+    /// tables.
+    fn check_table_limits(&mut self) {
+        let (precision, baseline) = match self.frame {
+            Some((_, ref frame)) => {
+                (frame.sample_precision, frame.frame_type == Marker::BaselineDCT)
+            }
+            None => return,
+        };
+        if precision == 8 {
+            for (offset, id) in self.sixteen_bit_quantization_tables.clone() {
+                self.report(Severity::Error,
+                            offset,
+                            format!("Quantization table {} has 16-bit values, but the samples \
+                                     have 8 bits",
+                                    id));
+            }
+        }
+        if baseline {
+            for (offset, class, id) in self.huffman_table_destinations.clone() {
+                if id > 1 {
+                    self.report(Severity::Error,
+                                offset,
+                                format!("Baseline frames have only 2 Huffman {} tables, but \
+                                         table {} is defined",
+                                        if class == 0 { "DC" } else { "AC" },
+                                        id));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,808 @@
+use std::io::{self, Write};
+
+use jpeg::{encoder, exif, icc, mpf, parse_comment, parse_huffman_tables, tiff, FrameHeader,
+           JPEGImage, ScanHeader};
+use jpeg::arithmetic::ArithmeticConditioning;
+use jpeg::decoder::{CoefficientOrder, ComponentCoefficients};
+use jpeg::huffman::HuffmanTable;
+use jpeg::marker::{Marker, MarkerReader, SegmentKind};
+use jpeg::tiff::TIFFReader;
+use jpeg::transform::{self, PartialMCUs, Transform};
+
+/// The largest payload a marker segment can have, since the two length
+/// bytes count themselves.
+const MAX_SEGMENT_LENGTH: usize = 0xffff - 2;
+
+fn application_marker(n: u8) -> Result<Marker, String> {
+    if n >= 16 {
+        return Err(format!("There is no APP{} segment, only APP0 to APP15", n));
+    }
+    Ok(Marker::ApplicationSegment(n))
+}
+
+fn is_metadata_marker(marker: Marker) -> bool {
+    matches!(marker, Marker::Comment | Marker::ApplicationSegment(_))
+}
+
+/// A piece of a JPEG file, as seen by `JPEGSegmentWriter`.
+#[derive(Debug, Clone)]
+pub enum Segment {
+    /// A marker without any data, like SOI, EOI and RSTn
+    Marker(Marker),
+    /// A marker followed by two length bytes and `data`.
+    /// The length bytes are not part of `data`.
+    MarkerSegment(Marker, Vec<u8>),
+    /// Entropy coded data following a scan header or a restart marker,
+    /// including byte stuffing.
+    EntropyCodedData(Vec<u8>),
+    /// Everything after EOI, like embedded MPF images.
+    Trailer(Vec<u8>),
+}
+
+impl Segment {
+    /// Number of bytes the segment takes up in the file.
+    pub fn encoded_length(&self) -> usize {
+        match *self {
+            Segment::Marker(_) => 2,
+            Segment::MarkerSegment(_, ref data) => 4 + data.len(),
+            Segment::EntropyCodedData(ref data) |
+            Segment::Trailer(ref data) => data.len(),
+        }
+    }
+}
+
+/// Writer used to edit the metadata of a JPEG file without recompressing it.
+///
+/// The file is split into segments, which can be removed, replaced or
+/// inserted. Everything else is written back exactly as it was read,
+/// including the entropy coded data unless `replace_coefficients` codes
+/// it again.
+#[derive(Debug, Clone)]
+pub struct JPEGSegmentWriter {
+    segments: Vec<Segment>,
+    /// Offset of the MP header in the original file, if there is one.
+    /// Needed in order to update the MP index when writing.
+    original_mpf_header: Option<usize>,
+    /// Offset of the trailer in the original file, if there is one.
+    original_trailer_start: Option<usize>,
+}
+
+impl JPEGSegmentWriter {
+    pub fn new(data: &[u8]) -> Result<JPEGSegmentWriter, String> {
+        let segments = split_segments(data)?;
+
+        let mut original_mpf_header = None;
+        let mut original_trailer_start = None;
+        let mut offset = 0;
+        for segment in &segments {
+            match *segment {
+                Segment::MarkerSegment(Marker::ApplicationSegment(2), ref data)
+                    if data.starts_with(mpf::MPF_IDENTIFIER) => {
+                    original_mpf_header = Some(offset + 4 + mpf::MPF_IDENTIFIER.len());
+                }
+                Segment::Trailer(_) => original_trailer_start = Some(offset),
+                _ => {}
+            }
+            offset += segment.encoded_length();
+        }
+
+        Ok(JPEGSegmentWriter {
+            segments,
+            original_mpf_header,
+            original_trailer_start,
+        })
+    }
+
+    /// Create a writer from the bytes of an already parsed image.
+    pub fn from_image(image: &JPEGImage) -> Result<JPEGSegmentWriter, String> {
+        JPEGSegmentWriter::new(&image.data)
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Remove all APPn and COM segments for which `predicate` returns `true`.
+    /// The predicate is given the marker and the segment data.
+    pub fn remove_segments<F>(&mut self, predicate: F)
+        where F: Fn(Marker, &[u8]) -> bool
+    {
+        self.segments.retain(|segment| match *segment {
+            Segment::MarkerSegment(marker, ref data) if is_metadata_marker(marker) => {
+                !predicate(marker, data)
+            }
+            _ => true,
+        });
+    }
+
+    /// Remove all APPn segments whose data starts with `identifier`,
+    /// eg. `b"Exif\0\0"` for EXIF data in APP1.
+    pub fn remove_application_segments(&mut self, n: u8, identifier: &[u8]) -> Result<(), String> {
+        let app_marker = application_marker(n)?;
+        self.remove_segments(|marker, data| marker == app_marker && data.starts_with(identifier));
+        Ok(())
+    }
+
+    /// Remove every APPn and COM segment, except JFIF and Adobe ones,
+    /// which are needed in order to interpret the image data correctly,
+    /// and ICC profiles, which are needed for its colors.
+    ///
+    /// The MPF index goes as well, so the images after EOI which it lists
+    /// are removed with it.
+    pub fn remove_all_metadata(&mut self) {
+        self.remove_segments(|marker, data| {
+            let is_jfif = marker == Marker::ApplicationSegment(0) && data.starts_with(b"JFIF\0");
+            let is_icc = marker == Marker::ApplicationSegment(2) &&
+                         data.starts_with(icc::ICC_IDENTIFIER);
+            let is_adobe = marker == Marker::ApplicationSegment(14) && data.starts_with(b"Adobe");
+            !is_jfif && !is_icc && !is_adobe
+        });
+        self.segments.retain(|segment| !matches!(*segment, Segment::Trailer(_)));
+    }
+
+    /// Replace the first APPn segment whose data starts with `identifier`,
+    /// or insert a new segment if there is none.
+    pub fn replace_application_segment(&mut self,
+                                       n: u8,
+                                       identifier: &[u8],
+                                       data: Vec<u8>)
+                                       -> Result<(), String> {
+        check_segment_length(&data)?;
+        let app_marker = application_marker(n)?;
+        let existing = self.segments.iter_mut().find(|segment| match **segment {
+            Segment::MarkerSegment(marker, ref data) => {
+                marker == app_marker && data.starts_with(identifier)
+            }
+            _ => false,
+        });
+        if let Some(segment) = existing {
+            *segment = Segment::MarkerSegment(app_marker, data);
+            return Ok(());
+        }
+        self.insert_application_segment(n, data)
+    }
+
+    /// Insert a new APPn segment. It is placed after the existing
+    /// application segments with a number less than or equal to `n`,
+    /// so that eg. JFIF APP0 stays first.
+    pub fn insert_application_segment(&mut self, n: u8, data: Vec<u8>) -> Result<(), String> {
+        check_segment_length(&data)?;
+        let app_marker = application_marker(n)?;
+        let index = self.metadata_insert_index(|marker| match marker {
+            Marker::ApplicationSegment(m) => m <= n,
+            _ => false,
+        });
+        self.segments.insert(index, Segment::MarkerSegment(app_marker, data));
+        Ok(())
+    }
+
+    /// All comments in the file.
+    pub fn comments(&self) -> Vec<String> {
+        self.segments
+            .iter()
+            .filter_map(|segment| match *segment {
+                Segment::MarkerSegment(Marker::Comment, ref data) => parse_comment(data),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The ICC profile of the file, joined from its APP2 segments.
+    pub fn icc_profile(&self) -> Result<Option<Vec<u8>>, String> {
+        icc::join_chunks(self.segments.iter().filter_map(|segment| match *segment {
+            Segment::MarkerSegment(Marker::ApplicationSegment(2), ref data)
+                if data.starts_with(icc::ICC_IDENTIFIER) => Some(&data[..]),
+            _ => None,
+        }))
+    }
+
+    /// The JPEG thumbnail of the first EXIF segment which has one.
+    pub fn exif_thumbnail(&self) -> Result<Option<&[u8]>, String> {
+        for segment in &self.segments {
+            if let Segment::MarkerSegment(Marker::ApplicationSegment(1), ref data) = *segment {
+                if data.starts_with(exif::EXIF_IDENTIFIER) {
+                    if let Some(thumbnail) = exif::thumbnail(data)? {
+                        return Ok(Some(thumbnail));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Replace all comments with `comment`.
+    pub fn set_comment(&mut self, comment: &str) -> Result<(), String> {
+        check_segment_length(comment.as_bytes())?;
+        self.remove_segments(|marker, _| marker == Marker::Comment);
+        self.add_comment(comment)
+    }
+
+    /// Add a comment, after all other metadata segments.
+    pub fn add_comment(&mut self, comment: &str) -> Result<(), String> {
+        check_segment_length(comment.as_bytes())?;
+        let index = self.metadata_insert_index(|_| true);
+        let data = comment.as_bytes().to_vec();
+        self.segments.insert(index, Segment::MarkerSegment(Marker::Comment, data));
+        Ok(())
+    }
+
+    /// Remove GPS information from all EXIF segments, including those of
+    /// the images after EOI which the MPF index lists.
+    /// Returns `true` if anything was removed.
+    pub fn strip_gps(&mut self) -> Result<bool, String> {
+        let mut removed = self.strip_trailer_gps()?;
+        for segment in &mut self.segments {
+            if let Segment::MarkerSegment(Marker::ApplicationSegment(1), ref mut data) = *segment {
+                if data.starts_with(exif::EXIF_IDENTIFIER) {
+                    removed |= exif::remove_gps(data)?;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Remove GPS information from the EXIF segments of the JPEG images in
+    /// the trailer, as found through the MPF index. `exif::remove_gps`
+    /// does not move anything, so the offsets in the index stay valid.
+    fn strip_trailer_gps(&mut self) -> Result<bool, String> {
+        let (mpf_header, trailer_start) = match (self.original_mpf_header,
+                                                 self.original_trailer_start) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Ok(false),
+        };
+        let index = self.segments.iter().find_map(|segment| match *segment {
+            Segment::MarkerSegment(Marker::ApplicationSegment(2), ref data)
+                if data.starts_with(mpf::MPF_IDENTIFIER) => {
+                Some(mpf::MPFIndex::parse(data, mpf_header - mpf::MPF_IDENTIFIER.len()))
+            }
+            _ => None,
+        });
+        let index = match index {
+            Some(index) => index?,
+            None => return Ok(false),
+        };
+        let trailer = match self.segments.iter_mut().find_map(|segment| match *segment {
+            Segment::Trailer(ref mut data) => Some(data),
+            _ => None,
+        }) {
+            Some(trailer) => trailer,
+            None => return Ok(false),
+        };
+
+        let mut removed = false;
+        // The primary image has offset 0, and its EXIF data is in `segments`.
+        // Images with another data format than JPEG have no EXIF segments.
+        let secondary = index.entries.iter().filter(|entry| entry.offset != 0);
+        for entry in secondary.filter(|entry| entry.data_format == 0) {
+            if entry.offset < trailer_start ||
+               entry.offset + entry.size > trailer_start + trailer.len() {
+                return Err(format!("MPF image at byte {} with {} bytes is not after EOI",
+                                   entry.offset,
+                                   entry.size));
+            }
+            let start = entry.offset - trailer_start;
+            let image = &mut trailer[start..start + entry.size];
+            let mut exif_segments = Vec::new();
+            for segment in MarkerReader::new(image) {
+                let segment = segment?;
+                if segment.kind == SegmentKind::Marker(Marker::ApplicationSegment(1)) &&
+                   segment.payload.starts_with(exif::EXIF_IDENTIFIER) {
+                    exif_segments.push((segment.offset + 4, segment.payload.len()));
+                }
+            }
+            for (offset, length) in exif_segments {
+                removed |= exif::remove_gps(&mut image[offset..offset + length])?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Set the EXIF orientation to `1`, which means the image is shown as
+    /// it is. Returns `true` if it was something else.
+    pub fn reset_orientation(&mut self) -> Result<bool, String> {
+        let mut changed = false;
+        for segment in &mut self.segments {
+            if let Segment::MarkerSegment(Marker::ApplicationSegment(1), ref mut data) = *segment {
+                if data.starts_with(exif::EXIF_IDENTIFIER) {
+                    changed |= exif::reset_orientation(data)?;
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Rotate or mirror the image without recompressing it, by moving its
+    /// DCT coefficients around. The scans are coded with new optimal
+    /// Huffman tables, and the EXIF orientation is set to `1`. Fails if
+    /// an EXIF segment is broken, as its orientation would be wrong.
+    pub fn transform(&mut self,
+                     transform: Transform,
+                     partial_mcus: PartialMCUs)
+                     -> Result<(), String> {
+        let (frame, components) = self.read_coefficients()?;
+        let (frame, components) = transform::transform_coefficients(&frame,
+                                                                    &components,
+                                                                    transform,
+                                                                    partial_mcus)?;
+        let mut writer = self.clone();
+        writer.replace_frame(&frame, &components, transform.transposes())?;
+        writer.reset_orientation()?;
+        *self = writer;
+        Ok(())
+    }
+
+    /// Crop the image without recompressing it, to the rectangle at
+    /// `(x, y)` of `width` by `height` pixels. The left and top edges must
+    /// be on MCU boundaries. The scans are coded with new optimal Huffman
+    /// tables.
+    pub fn crop(&mut self, x: usize, y: usize, width: usize, height: usize) -> Result<(), String> {
+        let (frame, components) = self.read_coefficients()?;
+        let (frame, components) = transform::crop_coefficients(&frame,
+                                                               &components,
+                                                               (x, y, width, height))?;
+        let mut writer = self.clone();
+        writer.replace_frame(&frame, &components, false)?;
+        *self = writer;
+        Ok(())
+    }
+
+    /// Code the scans again with new Huffman tables, which code them in
+    /// the fewest bits, like `jpegtran -optimize`.
+    pub fn optimize_huffman_tables(&mut self) -> Result<(), String> {
+        let (_, components) = self.read_coefficients()?;
+        self.encode_coefficients(&components, true)
+    }
+
+    /// Make the image progressive, with the scans libjpeg uses, without
+    /// recompressing it. Like `jpegtran -progressive`.
+    pub fn make_progressive(&mut self) -> Result<(), String> {
+        let (mut frame, components) = self.read_coefficients()?;
+        frame.frame_type = Marker::ProgressiveDCT;
+        let scans = encoder::progressive_scans(&frame);
+        self.replace_scans(&frame, &scans, &components)
+    }
+
+    /// Make the image sequential, with a single scan, without recompressing
+    /// it. The frame is baseline if the samples have 8 bits.
+    pub fn make_sequential(&mut self) -> Result<(), String> {
+        let (mut frame, components) = self.read_coefficients()?;
+        frame.frame_type = if frame.sample_precision == 8 {
+            Marker::BaselineDCT
+        } else {
+            Marker::ExtendedSequentialDCT
+        };
+        let scans = encoder::sequential_scans(&frame);
+        self.replace_scans(&frame, &scans, &components)
+    }
+
+    /// Code the scans with arithmetic coding instead of Huffman coding,
+    /// without recompressing the image, like `jpegtran -arithmetic`. The
+    /// tables get the default conditioning. The scans, quantization
+    /// tables and metadata are kept.
+    pub fn make_arithmetic(&mut self) -> Result<(), String> {
+        self.change_entropy_coding(true)
+    }
+
+    /// Code the scans with Huffman coding instead of arithmetic coding,
+    /// without recompressing the image, with optimal Huffman tables for
+    /// each scan. The scans, quantization tables and metadata are kept.
+    pub fn make_huffman(&mut self) -> Result<(), String> {
+        self.change_entropy_coding(false)
+    }
+
+    fn change_entropy_coding(&mut self, arithmetic: bool) -> Result<(), String> {
+        let (mut frame, components) = self.read_coefficients()?;
+        let progressive = match frame.frame_type {
+            Marker::BaselineDCT |
+            Marker::ExtendedSequentialDCT |
+            Marker::ExtendedSequentialDCTArithmetic => false,
+            Marker::ProgressiveDCT | Marker::ProgressiveDCTArithmetic => true,
+            frame_type => return Err(format!("{:?} frames can't be converted", frame_type)),
+        };
+        let conditioning = ArithmeticConditioning::default();
+        let mut baseline = frame.sample_precision == 8;
+        let mut frame_index = None;
+        let mut writer = self.clone();
+        let mut segments = Vec::with_capacity(writer.segments.len());
+        for segment in writer.segments.drain(..) {
+            match segment {
+                Segment::MarkerSegment(marker, _) if marker.is_start_of_frame() => {
+                    frame_index = Some(segments.len());
+                    segments.push(segment);
+                }
+                Segment::MarkerSegment(Marker::StartOfScan, ref data) => {
+                    let scan = ScanHeader::parse(data)?;
+                    baseline &= scan.scan_components
+                        .iter()
+                        .all(|c| c.dc_table_selector <= 1 && c.ac_table_selector <= 1);
+                    let conditioning_data = if arithmetic {
+                        conditioning.segment_data(&scan)
+                    } else {
+                        None
+                    };
+                    if let Some(data) = conditioning_data {
+                        // The conditioning goes before a DRI segment of the
+                        // scan, like libjpeg writes it.
+                        let mut index = segments.len();
+                        if let Some(&Segment::MarkerSegment(Marker::RestartIntervalDefinition,
+                                                            _)) = segments.last() {
+                            index -= 1;
+                        }
+                        segments.insert(index,
+                                        Segment::MarkerSegment(Marker::DefineArithmeticConditioning,
+                                                               data));
+                    }
+                    segments.push(segment);
+                }
+                Segment::MarkerSegment(Marker::DefineHuffmanTable, _) |
+                Segment::MarkerSegment(Marker::DefineArithmeticConditioning, _) |
+                Segment::MarkerSegment(Marker::DefineNumberOfLines, _) => {}
+                segment => segments.push(segment),
+            }
+        }
+        frame.frame_type = match (arithmetic, progressive) {
+            (true, false) => Marker::ExtendedSequentialDCTArithmetic,
+            (true, true) => Marker::ProgressiveDCTArithmetic,
+            (false, true) => Marker::ProgressiveDCT,
+            (false, false) if baseline => Marker::BaselineDCT,
+            (false, false) => Marker::ExtendedSequentialDCT,
+        };
+        if let Some(index) = frame_index {
+            segments[index] = Segment::MarkerSegment(frame.frame_type, frame.segment_data());
+        }
+        writer.segments = segments;
+        writer.encode_coefficients(&components, true)?;
+        *self = writer;
+        Ok(())
+    }
+
+    /// The frame header of the image, and the coefficients of its
+    /// components. The number of lines is known even if the image has a
+    /// DNL segment.
+    fn read_coefficients(&self) -> Result<(FrameHeader, Vec<ComponentCoefficients>), String> {
+        let (image, components) = JPEGImage::read_coefficients(&self.to_bytes(),
+                                                               CoefficientOrder::Natural)?;
+        let mut frame = match image.frame_header {
+            Some(ref frame) => frame.clone(),
+            None => return Err("The image has no frame".to_string()),
+        };
+        if frame.num_lines == 0 {
+            frame.num_lines = image.height() as u16;
+        }
+        Ok((frame, components))
+    }
+
+    /// Replace the frame header with `frame`, and the scans with `scans`
+    /// coded from `components` with optimal Huffman tables.
+    fn replace_scans(&mut self,
+                     frame: &FrameHeader,
+                     scans: &[ScanHeader],
+                     components: &[ComponentCoefficients])
+                     -> Result<(), String> {
+        let mut writer = self.clone();
+        let mut segments = Vec::with_capacity(writer.segments.len());
+        let mut scans_written = false;
+        for segment in writer.segments.drain(..) {
+            match segment {
+                Segment::MarkerSegment(marker, _) if marker.is_start_of_frame() => {
+                    segments.push(Segment::MarkerSegment(frame.frame_type, frame.segment_data()));
+                }
+                Segment::MarkerSegment(Marker::StartOfScan, _) => {
+                    if !scans_written {
+                        segments.extend(scans.iter().map(|scan| {
+                            Segment::MarkerSegment(Marker::StartOfScan, scan.segment_data())
+                        }));
+                        scans_written = true;
+                    }
+                }
+                Segment::MarkerSegment(Marker::DefineHuffmanTable, _) |
+                Segment::MarkerSegment(Marker::DefineArithmeticConditioning, _) |
+                Segment::MarkerSegment(Marker::DefineNumberOfLines, _) |
+                Segment::EntropyCodedData(_) |
+                Segment::Marker(Marker::Restart(_)) => {}
+                segment => segments.push(segment),
+            }
+        }
+        writer.segments = segments;
+        writer.encode_coefficients(components, true)?;
+        *self = writer;
+        Ok(())
+    }
+
+    /// Replace the frame header with `frame`, and code the scans again
+    /// from `components`. The quantization tables are transposed too if
+    /// `transpose` is set.
+    fn replace_frame(&mut self,
+                     frame: &FrameHeader,
+                     components: &[ComponentCoefficients],
+                     transpose: bool)
+                     -> Result<(), String> {
+        for segment in &mut self.segments {
+            match *segment {
+                Segment::MarkerSegment(marker, ref mut data) if marker.is_start_of_frame() => {
+                    *data = frame.segment_data();
+                }
+                Segment::MarkerSegment(Marker::QuantizationTable, ref mut data) if transpose => {
+                    transform::transpose_quantization_tables(data)?;
+                }
+                _ => {}
+            }
+        }
+        // The number of lines is in the frame header now.
+        self.segments.retain(|segment| {
+            !matches!(*segment, Segment::MarkerSegment(Marker::DefineNumberOfLines, _))
+        });
+        self.encode_coefficients(components, true)
+    }
+
+    /// Find the index at which to insert a new metadata segment: after the
+    /// last APPn or COM segment in the header for which `after` returns `true`,
+    /// or right after SOI if there is none.
+    fn metadata_insert_index<F>(&self, after: F) -> usize
+        where F: Fn(Marker) -> bool
+    {
+        let mut index = match self.segments.first() {
+            Some(&Segment::Marker(Marker::StartOfImage)) => 1,
+            _ => 0,
+        };
+        for (i, segment) in self.segments.iter().enumerate().skip(index) {
+            match *segment {
+                Segment::MarkerSegment(marker, _) if is_metadata_marker(marker) => {
+                    if after(marker) {
+                        index = i + 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+        index
+    }
+
+    /// Code the scans again from `components`, which are usually read with
+    /// `JPEGImage::read_coefficients` and then changed. The tables, frame
+    /// and scan headers of the file are kept, so the coefficients must
+    /// fit the frame and the Huffman tables must have codes for them.
+    /// Only sequential and progressive images are supported, Huffman or
+    /// arithmetic coded.
+    pub fn replace_coefficients(&mut self,
+                                components: &[ComponentCoefficients])
+                                -> Result<(), String> {
+        self.encode_coefficients(components, false)
+    }
+
+    /// Like `replace_coefficients`, but with new Huffman tables for each
+    /// scan, which code it in the fewest bits. The old tables are removed.
+    /// Arithmetic coded images have no tables to replace.
+    pub fn replace_coefficients_with_optimal_tables(&mut self,
+                                                    components: &[ComponentCoefficients])
+                                                    -> Result<(), String> {
+        self.encode_coefficients(components, true)
+    }
+
+    fn encode_coefficients(&mut self,
+                           components: &[ComponentCoefficients],
+                           optimize: bool)
+                           -> Result<(), String> {
+        let mut dc_tables: Vec<Option<HuffmanTable>> = vec![None, None, None, None];
+        let mut ac_tables: Vec<Option<HuffmanTable>> = vec![None, None, None, None];
+        let mut conditioning = ArithmeticConditioning::default();
+        let mut frame: Option<FrameHeader> = None;
+        let mut restart_interval = 0;
+        let mut segments = Vec::with_capacity(self.segments.len());
+        let mut old_segments = self.segments.iter().peekable();
+        while let Some(segment) = old_segments.next() {
+            match *segment {
+                Segment::MarkerSegment(Marker::DefineHuffmanTable, ref data) => {
+                    for (class, id, table) in parse_huffman_tables(data)? {
+                        let tables = if class == 0 { &mut dc_tables } else { &mut ac_tables };
+                        tables[id as usize] = Some(table);
+                    }
+                    if optimize {
+                        continue;
+                    }
+                }
+                Segment::MarkerSegment(Marker::DefineArithmeticConditioning, ref data) => {
+                    conditioning.update(data)?;
+                }
+                Segment::MarkerSegment(Marker::RestartIntervalDefinition, ref data) => {
+                    if data.len() < 2 {
+                        return Err("DRI segment is too short".to_string());
+                    }
+                    restart_interval = ((data[0] as u16) << 8) | data[1] as u16;
+                }
+                Segment::MarkerSegment(Marker::DefineHierarchicalProgression, _) => {
+                    return Err("Hierarchical images are not supported".to_string());
+                }
+                Segment::MarkerSegment(marker, ref data) if marker.is_start_of_frame() => {
+                    if frame.is_some() {
+                        return Err("The image has more than one frame".to_string());
+                    }
+                    let header = FrameHeader::parse(marker, data)?;
+                    if header.num_lines == 0 {
+                        return Err("Images with a DNL segment are not supported".to_string());
+                    }
+                    frame = Some(header);
+                }
+                Segment::MarkerSegment(Marker::StartOfScan, ref data) => {
+                    let frame = match frame {
+                        Some(ref frame) => frame,
+                        None => return Err("SOS segment before the frame header".to_string()),
+                    };
+                    let scan = ScanHeader::parse(data)?;
+                    let arithmetic = frame.frame_type.is_arithmetic_coded();
+                    if optimize && !arithmetic {
+                        // The tables go before a DRI segment of the scan,
+                        // like libjpeg writes them.
+                        let mut index = segments.len();
+                        if let Some(&Segment::MarkerSegment(Marker::RestartIntervalDefinition,
+                                                            _)) = segments.last() {
+                            index -= 1;
+                        }
+                        let specifications = encoder::optimal_huffman_tables(frame,
+                                                                             &scan,
+                                                                             components,
+                                                                             restart_interval)?;
+                        for specification in &specifications {
+                            let tables = if specification.class == 0 {
+                                &mut dc_tables
+                            } else {
+                                &mut ac_tables
+                            };
+                            tables[specification.destination as usize] =
+                                Some(specification.table());
+                            segments.insert(index,
+                                            Segment::MarkerSegment(Marker::DefineHuffmanTable,
+                                                                   specification.segment_data()));
+                            index += 1;
+                        }
+                    }
+                    let scan_data = if arithmetic {
+                        encoder::encode_arithmetic_scan(frame,
+                                                        &scan,
+                                                        components,
+                                                        &conditioning,
+                                                        restart_interval)?
+                    } else {
+                        encoder::encode_scan(frame,
+                                             &scan,
+                                             components,
+                                             &dc_tables,
+                                             &ac_tables,
+                                             restart_interval)?
+                    };
+                    segments.push(segment.clone());
+                    // The old data of the scan, with its restart markers, is
+                    // replaced by the new data, which has its own.
+                    while let Some(next) = old_segments.peek() {
+                        match **next {
+                            Segment::EntropyCodedData(_) |
+                            Segment::Marker(Marker::Restart(_)) => {}
+                            _ => break,
+                        }
+                        old_segments.next();
+                    }
+                    segments.push(Segment::EntropyCodedData(scan_data));
+                    continue;
+                }
+                _ => {}
+            }
+            segments.push(segment.clone());
+        }
+        self.segments = segments;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut mpf_header = None;
+        let mut trailer_start = None;
+        for segment in &self.segments {
+            match *segment {
+                Segment::Marker(marker) => bytes.extend_from_slice(&[0xff, marker.code()]),
+                Segment::MarkerSegment(marker, ref data) => {
+                    if marker == Marker::ApplicationSegment(2) &&
+                       data.starts_with(mpf::MPF_IDENTIFIER) {
+                        mpf_header = Some(bytes.len() + 4 + mpf::MPF_IDENTIFIER.len());
+                    }
+                    let length = data.len() + 2;
+                    bytes.extend_from_slice(&[0xff,
+                                              marker.code(),
+                                              (length >> 8) as u8,
+                                              length as u8]);
+                    bytes.extend_from_slice(data);
+                }
+                Segment::EntropyCodedData(ref data) => bytes.extend_from_slice(data),
+                Segment::Trailer(ref data) => {
+                    trailer_start = Some(bytes.len());
+                    bytes.extend_from_slice(data);
+                }
+            }
+        }
+        if let (Some(mpf_header), Some(trailer_start)) = (mpf_header, trailer_start) {
+            // Editing might have moved both the MP header and the embedded
+            // images, so the offsets in the MP index must be updated.
+            // If the index is broken, we leave it as it was.
+            let _ = self.fix_mpf_offsets(&mut bytes, mpf_header, trailer_start);
+        }
+        bytes
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    /// Update the offsets in the MP index after the segments have been
+    /// written to `bytes`, where the MP header is at `mpf_header` and the
+    /// trailer at `trailer_start`.
+    fn fix_mpf_offsets(&self,
+                       bytes: &mut [u8],
+                       mpf_header: usize,
+                       trailer_start: usize)
+                       -> Result<(), String> {
+        let (old_mpf_header, old_trailer_start) = match (self.original_mpf_header,
+                                                         self.original_trailer_start) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Ok(()),
+        };
+        let (byte_order, entries) = {
+            let reader = TIFFReader::new(&bytes[mpf_header..trailer_start])?;
+            let ifd = reader.read_ifd(reader.first_ifd_offset()? as usize)?;
+            let num_images = match ifd.entry(mpf::NUMBER_OF_IMAGES) {
+                Some(entry) => reader.entry_u32(entry)? as usize,
+                None => return Ok(()),
+            };
+            let entries_start = match ifd.entry(mpf::MP_ENTRY) {
+                Some(entry) => mpf_header + entry.value_offset,
+                None => return Ok(()),
+            };
+            let entries = (0..num_images)
+                .map(|n| {
+                    let entry_offset = entries_start + n * 16;
+                    reader.u32_at(entry_offset + 8 - mpf_header).map(|offset| (entry_offset, offset))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            (reader.byte_order(), entries)
+        };
+        for (entry_offset, offset) in entries {
+            if offset == 0 {
+                // The primary image, which ends where the trailer starts
+                tiff::write_u32(bytes, entry_offset + 4, trailer_start as u32, byte_order);
+                continue;
+            }
+            let original = offset as usize + old_mpf_header;
+            if original < old_trailer_start {
+                continue;
+            }
+            let new_offset = original - old_trailer_start + trailer_start - mpf_header;
+            tiff::write_u32(bytes, entry_offset + 8, new_offset as u32, byte_order);
+        }
+        Ok(())
+    }
+}
+
+fn check_segment_length(data: &[u8]) -> Result<(), String> {
+    if data.len() > MAX_SEGMENT_LENGTH {
+        return Err(format!("Segment data is {} bytes, but the maximum is {}",
+                           data.len(),
+                           MAX_SEGMENT_LENGTH));
+    }
+    Ok(())
+}
+
+/// Split `data` into segments. Fill bytes between segments are dropped.
+fn split_segments(data: &[u8]) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    for segment in MarkerReader::new(data) {
+        let segment = segment?;
+        segments.push(match segment.kind {
+            SegmentKind::Marker(marker) if marker.has_length() => {
+                Segment::MarkerSegment(marker, segment.payload.to_vec())
+            }
+            SegmentKind::Marker(marker) => Segment::Marker(marker),
+            SegmentKind::Fill => continue,
+            SegmentKind::EntropyCoded => Segment::EntropyCodedData(segment.payload.to_vec()),
+            SegmentKind::Trailer => Segment::Trailer(segment.payload.to_vec()),
+        });
+    }
+    Ok(segments)
+}
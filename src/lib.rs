@@ -0,0 +1,8 @@
+#[macro_use]
+extern crate itertools;
+
+#[allow(unused_variables)]
+#[allow(dead_code)]
+mod transform;
+pub mod jpeg;
+pub mod output;
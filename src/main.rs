@@ -1,40 +1,505 @@
-#[macro_use]
-extern crate itertools;
-#[allow(unused_variables)]
-#[allow(dead_code)]
-mod transform;
-mod jpeg;
+extern crate jpeg_rust;
 
 use std::env;
-use std::fs::File;
-use std::io::Read;
-use std::io::Write;
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::path::Path;
+use std::process;
 
-use jpeg::*;
+use jpeg_rust::jpeg::color::{ColorConversion, Matrix, Range};
+use jpeg_rust::jpeg::compress::{ChromaSubsampling, JPEGEncoder};
+use jpeg_rust::jpeg::marker::{MarkerReader, SegmentKind};
+use jpeg_rust::jpeg::report::Report;
+use jpeg_rust::jpeg::stream::JPEGStreamDecoder;
+use jpeg_rust::jpeg::transform::{PartialMCUs, Transform};
+use jpeg_rust::jpeg::validate::{self, Severity};
+use jpeg_rust::jpeg::writer::JPEGSegmentWriter;
+use jpeg_rust::output::{self, ColorType, Image};
 
-fn file_to_bytes(path: &Path) -> Result<Vec<u8>, std::io::Error> {
-    File::open(path).and_then(|mut file| {
-        let mut bytes = Vec::new();
-        try!(file.read_to_end(&mut bytes));
-        Ok(bytes)
-    })
+const USAGE: &str = "\
+Usage: jpeg-rust <command> [options] <files>
+
+Commands:
+  decode [--scale N] [--gray] [--bt709] [--limited-range] [--lenient]
+         <input> <output>
+      Decode to PPM, PGM, PNM, PAM, BMP, TGA or PNG, by the output extension.
+      The image is scaled by N/8, from 1 to 16. With --lenient, truncated
+      and corrupt files are decoded as far as possible, with warnings.
+  info [--json] <input>
+      Show the metadata, frame and scan headers and tables of the image.
+  validate <input>
+      Check that the file follows the JPEG standard, without decoding it.
+      Fails if there are errors, but not if there are only warnings.
+  dump-markers <input>
+      List every marker segment, with its offset and length.
+  extract-thumbnail <input> <output>
+      Write the JPEG thumbnail of the EXIF metadata.
+  extract-icc <input> <output>
+      Write the ICC color profile.
+  strip [--gps-only] <input> <output>
+      Remove all metadata but JFIF, ICC and Adobe segments, or only EXIF GPS.
+  transform [--rotate 90|180|270] [--flip horizontal|vertical] [--transpose]
+            [--transverse] [--trim] [--crop WxH+X+Y] [--optimize]
+            [--progressive | --sequential] [--arithmetic | --huffman]
+            <input> <output>
+      Change the image without recompressing it, like jpegtran. The crop is
+      taken from the rotated or flipped image.
+  encode [--quality N] [--subsampling 444|422|420] [--progressive] [--gray]
+         <input> <output>
+      Encode a PPM, PGM or PAM image, or a JPEG image again.
+
+Exit status is 0 on success, 1 if the command fails and 2 on bad usage.";
+
+/// Why a command didn't work, which tells the exit status.
+enum Error {
+    /// The command line is wrong
+    Usage(String),
+    /// The command failed, usually with an error from the library
+    Failed(String),
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::Failed(message)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Failed(format!("Could not write the output: {}", error))
+    }
 }
 
 fn main() {
-    let mut args = env::args();
-    args.next();
-    let input_file = args.next().expect("Must supply an input file");
-    let output_file = args.next().expect("Must supply an output file");
+    let mut args = env::args().skip(1);
+    let result = match args.next() {
+        Some(command) => run(&command, Arguments { args: args.collect() }),
+        None => Err(Error::Usage("No command given".to_string())),
+    };
+    match result {
+        Ok(()) => {}
+        Err(Error::Usage(message)) => {
+            eprintln!("jpeg-rust: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+        Err(Error::Failed(message)) => {
+            eprintln!("jpeg-rust: {}", message);
+            process::exit(1);
+        }
+    }
+}
+
+fn run(command: &str, mut args: Arguments) -> Result<(), Error> {
+    match command {
+        "decode" => decode(args),
+        "info" => {
+            let json = args.flag("json");
+            let files = args.files(&["input"])?;
+            info(&read(&files[0])?, json, &mut io::stdout())
+        }
+        "validate" => {
+            let files = args.files(&["input"])?;
+            validate(&read(&files[0])?, &mut io::stdout())
+        }
+        "dump-markers" => {
+            let files = args.files(&["input"])?;
+            dump_markers(&read(&files[0])?, &mut io::stdout())
+        }
+        "extract-thumbnail" => {
+            let files = args.files(&["input", "output"])?;
+            let writer = JPEGSegmentWriter::new(&read(&files[0])?)?;
+            match writer.exif_thumbnail()? {
+                Some(thumbnail) => write(&files[1], thumbnail),
+                None => Err(Error::Failed(format!("{} has no EXIF thumbnail", files[0]))),
+            }
+        }
+        "extract-icc" => {
+            let files = args.files(&["input", "output"])?;
+            let writer = JPEGSegmentWriter::new(&read(&files[0])?)?;
+            match writer.icc_profile()? {
+                Some(profile) => write(&files[1], &profile),
+                None => Err(Error::Failed(format!("{} has no ICC profile", files[0]))),
+            }
+        }
+        "strip" => {
+            let gps_only = args.flag("gps-only");
+            let files = args.files(&["input", "output"])?;
+            let mut writer = JPEGSegmentWriter::new(&read(&files[0])?)?;
+            if gps_only {
+                writer.strip_gps()?;
+            } else {
+                writer.remove_all_metadata();
+            }
+            write(&files[1], &writer.to_bytes())
+        }
+        "transform" => transform(args),
+        "encode" => encode(args),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(Error::Usage(format!("Unknown command {}", command))),
+    }
+}
+
+/// The arguments of a command. Options are taken out by name, and the
+/// files are what is left.
+struct Arguments {
+    args: Vec<String>,
+}
+
+impl Arguments {
+    /// Take out `--name`, and tell whether it was there.
+    fn flag(&mut self, name: &str) -> bool {
+        let option = format!("--{}", name);
+        let found = self.args.contains(&option);
+        self.args.retain(|arg| *arg != option);
+        found
+    }
 
-    let bytes = file_to_bytes(Path::new(&input_file)).unwrap();
-    let image = JPEGImage::parse(bytes).unwrap();
-    // Show the image, somehow.
+    /// Take out `--name value` or `--name=value`, and give the value.
+    fn value(&mut self, name: &str) -> Result<Option<String>, Error> {
+        let option = format!("--{}", name);
+        let prefix = format!("--{}=", name);
+        let position = self.args.iter().position(|arg| *arg == option || arg.starts_with(&prefix));
+        let position = match position {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+        let arg = self.args.remove(position);
+        if arg != option {
+            return Ok(Some(arg[prefix.len()..].to_string()));
+        }
+        if position < self.args.len() {
+            Ok(Some(self.args.remove(position)))
+        } else {
+            Err(Error::Usage(format!("{} needs a value", option)))
+        }
+    }
+
+    /// Like `value`, parsed as a number.
+    fn number<T: std::str::FromStr>(&mut self, name: &str) -> Result<Option<T>, Error> {
+        match self.value(name)? {
+            Some(value) => {
+                value.parse().map(Some).map_err(|_| {
+                    Error::Usage(format!("--{} must be a number, not {}", name, value))
+                })
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The files which are left, one for each of `names`.
+    fn files(self, names: &[&str]) -> Result<Vec<String>, Error> {
+        if let Some(option) = self.args.iter().find(|arg| arg.starts_with("--")) {
+            return Err(Error::Usage(format!("Unknown option {}", option)));
+        }
+        if self.args.len() != names.len() {
+            return Err(Error::Usage(format!("Expected {} arguments, {}, but found {}",
+                                            names.len(),
+                                            names.join(" and "),
+                                            self.args.len())));
+        }
+        Ok(self.args)
+    }
+}
+
+fn read(path: &str) -> Result<Vec<u8>, Error> {
+    fs::read(path).map_err(|error| Error::Failed(format!("Could not read {}: {}", path, error)))
+}
+
+fn write(path: &str, data: &[u8]) -> Result<(), Error> {
+    fs::write(path, data)
+        .map_err(|error| Error::Failed(format!("Could not write {}: {}", path, error)))
+}
+
+fn decode(mut args: Arguments) -> Result<(), Error> {
+    let scale = args.number("scale")?;
+    let gray = args.flag("gray");
+    let matrix = if args.flag("bt709") { Matrix::BT709 } else { Matrix::BT601 };
+    let range = if args.flag("limited-range") { Range::Limited } else { Range::Full };
+    let lenient = args.flag("lenient");
+    let files = args.files(&["input", "output"])?;
+
+    let file = File::open(&files[0])
+        .map_err(|error| format!("Could not read {}: {}", files[0], error))?;
+    let mut decoder = JPEGStreamDecoder::new(file);
+    if let Some(scale) = scale {
+        decoder.scale(scale)?;
+    }
+    decoder.color_conversion(ColorConversion::new(matrix, range))?;
+    if lenient {
+        decoder.lenient()?;
+    }
+    let (image, pixels, color_type) = if gray {
+        decoder.luma_only()?;
+        let (image, luma) = decoder.decode_luma()?;
+        (image, luma, ColorType::Gray)
+    } else {
+        let image = decoder.decode()?;
+        let pixels = match image.image_data() {
+            Some(pixels) => pixels.iter().flat_map(|&(r, g, b)| [r, g, b]).collect(),
+            None => return Err(Error::Failed("The image has no pixels".to_string())),
+        };
+        (image, pixels, ColorType::RGB)
+    };
+    for warning in image.warnings() {
+        eprintln!("jpeg-rust: warning: {}", warning);
+    }
+    output::save(Path::new(&files[1]),
+                 &Image::new(&pixels, image.width(), image.height(), color_type)?)?;
+    Ok(())
+}
+
+fn transform(mut args: Arguments) -> Result<(), Error> {
+    let mut transforms = Vec::new();
+    match args.value("rotate")?.as_deref() {
+        Some("90") => transforms.push(Transform::Rotate90),
+        Some("180") => transforms.push(Transform::Rotate180),
+        Some("270") => transforms.push(Transform::Rotate270),
+        Some(angle) => return Err(Error::Usage(format!("Can't rotate by {} degrees", angle))),
+        None => {}
+    }
+    match args.value("flip")?.as_deref() {
+        Some("horizontal") => transforms.push(Transform::FlipHorizontal),
+        Some("vertical") => transforms.push(Transform::FlipVertical),
+        Some(direction) => return Err(Error::Usage(format!("Can't flip {}", direction))),
+        None => {}
+    }
+    if args.flag("transpose") {
+        transforms.push(Transform::Transpose);
+    }
+    if args.flag("transverse") {
+        transforms.push(Transform::Transverse);
+    }
+    let partial_mcus = if args.flag("trim") {
+        PartialMCUs::Trim
+    } else {
+        PartialMCUs::Error
+    };
+    let crop = match args.value("crop")? {
+        Some(crop) => Some(parse_crop(&crop)?),
+        None => None,
+    };
+    let optimize = args.flag("optimize");
+    let (progressive, sequential) = (args.flag("progressive"), args.flag("sequential"));
+    let (arithmetic, huffman) = (args.flag("arithmetic"), args.flag("huffman"));
+    if transforms.len() > 1 || (progressive && sequential) || (arithmetic && huffman) ||
+       (optimize && arithmetic) {
+        return Err(Error::Usage("Options of transform contradict each other".to_string()));
+    }
+    if partial_mcus == PartialMCUs::Trim && transforms.is_empty() {
+        return Err(Error::Usage("--trim needs a rotation, flip, transpose or transverse"
+            .to_string()));
+    }
+    let files = args.files(&["input", "output"])?;
+
+    let mut writer = JPEGSegmentWriter::new(&read(&files[0])?)?;
+    if let Some(&transform) = transforms.first() {
+        writer.transform(transform, partial_mcus)?;
+    }
+    // Like jpegtran, the crop is in the coordinates of the output.
+    if let Some((x, y, width, height)) = crop {
+        writer.crop(x, y, width, height)?;
+    }
+    if progressive {
+        writer.make_progressive()?;
+    }
+    if sequential {
+        writer.make_sequential()?;
+    }
+    if arithmetic {
+        writer.make_arithmetic()?;
+    }
+    if huffman {
+        writer.make_huffman()?;
+    }
+    if optimize {
+        writer.optimize_huffman_tables()?;
+    }
+    write(&files[1], &writer.to_bytes())
+}
+
+/// Read a rectangle written as `WxH+X+Y`, like jpegtran takes it.
+fn parse_crop(crop: &str) -> Result<(usize, usize, usize, usize), Error> {
+    let error = || Error::Usage(format!("--crop must be WxH+X+Y, not {}", crop));
+    let (size, offset) = crop.split_once('+').ok_or_else(error)?;
+    let (width, height) = size.split_once('x').ok_or_else(error)?;
+    let (x, y) = offset.split_once('+').ok_or_else(error)?;
+    let parse = |n: &str| n.parse::<usize>().map_err(|_| error());
+    Ok((parse(x)?, parse(y)?, parse(width)?, parse(height)?))
+}
+
+fn encode(mut args: Arguments) -> Result<(), Error> {
+    let mut encoder = JPEGEncoder::new();
+    if let Some(quality) = args.number("quality")? {
+        encoder.quality(quality)?;
+    }
+    match args.value("subsampling")?.as_deref() {
+        Some("444") => encoder.chroma_subsampling(ChromaSubsampling::YCbCr444),
+        Some("422") => encoder.chroma_subsampling(ChromaSubsampling::YCbCr422),
+        Some("420") => encoder.chroma_subsampling(ChromaSubsampling::YCbCr420),
+        Some(subsampling) => {
+            return Err(Error::Usage(format!("Unknown chroma subsampling {}", subsampling)))
+        }
+        None => {}
+    }
+    if args.flag("progressive") {
+        encoder.progressive();
+    }
+    let gray = args.flag("gray");
+    let files = args.files(&["input", "output"])?;
+
+    let data = read(&files[0])?;
+    let (pixels, width, height) = if data.starts_with(&[0xff, 0xd8]) {
+        let image = JPEGStreamDecoder::new(&data[..]).decode()?;
+        match image.image_data() {
+            Some(pixels) => (pixels.clone(), image.width(), image.height()),
+            None => return Err(Error::Failed("The image has no pixels".to_string())),
+        }
+    } else {
+        read_netpbm(&data)?
+    };
+    let encoded = if gray {
+        // JFIF images have the luma of BT.601.
+        let conversion = ColorConversion::default();
+        let luma = pixels.iter().map(|&pixel| conversion.luma(pixel)).collect::<Vec<_>>();
+        encoder.encode_gray(&luma, width, height)?
+    } else {
+        encoder.encode(&pixels, width, height)?
+    };
+    write(&files[1], &encoded)
+}
+
+/// RGB pixels, with the width and height of the image
+type Pixels = (Vec<(u8, u8, u8)>, usize, usize);
+
+/// Read the pixels of a binary PPM, PGM or PAM image with 8 bit
+/// samples, as RGB. Alpha is dropped.
+fn read_netpbm(data: &[u8]) -> Result<Pixels, String> {
+    let magic = data.get(..2).unwrap_or(&[]);
+    if magic != b"P5" && magic != b"P6" && magic != b"P7" {
+        return Err("The input is not a JPEG, PPM, PGM or PAM image".to_string());
+    }
+    // The header is whitespace separated words, and comments from `#`
+    // to the end of the line. Only one whitespace byte follows it.
+    let mut words: Vec<String> = Vec::new();
+    let mut position = 2;
+    let mut word = String::new();
+    let header_words = if magic == b"P7" { usize::MAX } else { 3 };
+    while position < data.len() && words.len() < header_words {
+        let byte = data[position];
+        position += 1;
+        if byte == b'#' && word.is_empty() {
+            while position < data.len() && data[position] != b'\n' {
+                position += 1;
+            }
+        } else if byte.is_ascii_whitespace() {
+            if !word.is_empty() {
+                if word == "ENDHDR" {
+                    break;
+                }
+                words.push(word.clone());
+                word.clear();
+            }
+        } else {
+            word.push(byte as char);
+        }
+    }
+    let field = |name: &str| {
+        words.iter()
+            .position(|word| word == name)
+            .and_then(|i| words.get(i + 1))
+            .and_then(|value| value.parse::<usize>().ok())
+            .ok_or_else(|| format!("The PAM header has no {}", name))
+    };
+    let (width, height, depth, maxval) = if magic == b"P7" {
+        (field("WIDTH")?, field("HEIGHT")?, field("DEPTH")?, field("MAXVAL")?)
+    } else {
+        let number = |i: usize| {
+            words.get(i)
+                .and_then(|word| word.parse::<usize>().ok())
+                .ok_or_else(|| "The header of the image is broken".to_string())
+        };
+        (number(0)?, number(1)?, if magic == b"P5" { 1 } else { 3 }, number(2)?)
+    };
+    if maxval != 255 || !(1..=4).contains(&depth) {
+        return Err(format!("Images with {} channels of maximum {} are not supported",
+                           depth,
+                           maxval));
+    }
+    let length = width.checked_mul(height)
+        .and_then(|n| n.checked_mul(depth))
+        .ok_or_else(|| "The image is too large".to_string())?;
+    let samples = match data.get(position..) {
+        Some(samples) if samples.len() >= length => &samples[..length],
+        _ => return Err("The image has fewer pixels than its header says".to_string()),
+    };
+    let pixels = samples.chunks(depth)
+        .map(|pixel| if depth < 3 {
+            (pixel[0], pixel[0], pixel[0])
+        } else {
+            (pixel[0], pixel[1], pixel[2])
+        })
+        .collect();
+    Ok((pixels, width, height))
+}
+
+/// Show what the headers of the image say, without decoding it.
+fn info(data: &[u8], json: bool, out: &mut dyn Write) -> Result<(), Error> {
+    let report = Report::new(data)?;
+    if json {
+        out.write_all(report.to_json().as_bytes())?;
+    } else {
+        out.write_all(report.to_text().as_bytes())?;
+    }
+    Ok(())
+}
+
+/// List the violations of the standard, and fail if any is an error.
+fn validate(data: &[u8], out: &mut dyn Write) -> Result<(), Error> {
+    let violations = validate::validate(data);
+    for violation in &violations {
+        writeln!(out, "{}", violation)?;
+    }
+    let errors = violations.iter().filter(|v| v.severity == Severity::Error).count();
+    match errors {
+        0 if violations.is_empty() => writeln!(out, "No problems found")?,
+        0 => {}
+        1 => return Err(Error::Failed("The file has an error".to_string())),
+        _ => return Err(Error::Failed(format!("The file has {} errors", errors))),
+    }
+    Ok(())
+}
 
-    let mut file = File::create(output_file).unwrap();
-    let _ = file.write(format!("P3\n{} {}\n255\n", image.width(), image.height()).as_bytes());
-    for &(r, g, b) in image.image_data().unwrap() {
-        let s = format!("{} {} {}\n", r, g, b);
-        let _ = file.write(s.as_bytes());
+/// List every piece of the file, with its offset.
+fn dump_markers(data: &[u8], out: &mut dyn Write) -> Result<(), Error> {
+    for segment in MarkerReader::new(data) {
+        let segment = segment?;
+        match segment.kind {
+            SegmentKind::Marker(marker) if marker.has_length() => {
+                writeln!(out, "{:>10}  {:<6} length {}",
+                         segment.offset,
+                         marker.name(),
+                         segment.payload.len() + 2)?
+            }
+            SegmentKind::Marker(marker) => {
+                writeln!(out, "{:>10}  {}", segment.offset, marker.name())?
+            }
+            SegmentKind::Fill => {
+                writeln!(out, "{:>10}  fill, {} bytes", segment.offset, segment.payload.len())?
+            }
+            SegmentKind::EntropyCoded => {
+                writeln!(out, "{:>10}  entropy coded data, {} bytes",
+                         segment.offset,
+                         segment.payload.len())?
+            }
+            SegmentKind::Trailer => {
+                writeln!(out, "{:>10}  trailing data, {} bytes",
+                         segment.offset,
+                         segment.payload.len())?
+            }
+        }
     }
+    Ok(())
 }
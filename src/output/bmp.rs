@@ -0,0 +1,64 @@
+// Uncompressed Windows bitmaps, with a BITMAPINFOHEADER. Color images
+// have 24 bits per pixel, and gray images 8 bits with a palette of the
+// grays. Lines are stored from the bottom up, each padded to a multiple
+// of four bytes.
+
+use output::{ColorType, Image};
+
+const FILE_HEADER_SIZE: usize = 14;
+const INFO_HEADER_SIZE: usize = 40;
+
+pub fn encode(image: &Image) -> Result<Vec<u8>, String> {
+    if image.width() > i32::MAX as usize || image.height() > i32::MAX as usize {
+        return Err(format!("Images of {}x{} pixels are too large for BMP",
+                           image.width(),
+                           image.height()));
+    }
+    let (bits_per_pixel, palette_size) = match image.color_type() {
+        ColorType::Gray => (8, 256),
+        _ => (24, 0),
+    };
+    let line_size = (image.width() * bits_per_pixel / 8).div_ceil(4) * 4;
+    let offset = FILE_HEADER_SIZE + INFO_HEADER_SIZE + 4 * palette_size;
+    let image_size = line_size * image.height();
+    let file_size = offset + image_size;
+    if file_size > u32::MAX as usize {
+        return Err(format!("Images of {}x{} pixels are too large for BMP",
+                           image.width(),
+                           image.height()));
+    }
+
+    let mut data = Vec::with_capacity(file_size);
+    data.extend_from_slice(b"BM");
+    data.extend_from_slice(&(file_size as u32).to_le_bytes());
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&(offset as u32).to_le_bytes());
+
+    data.extend_from_slice(&(INFO_HEADER_SIZE as u32).to_le_bytes());
+    data.extend_from_slice(&(image.width() as i32).to_le_bytes());
+    // A positive height means the lines go from the bottom up.
+    data.extend_from_slice(&(image.height() as i32).to_le_bytes());
+    // One plane, and no compression
+    data.extend_from_slice(&1u16.to_le_bytes());
+    data.extend_from_slice(&(bits_per_pixel as u16).to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&(image_size as u32).to_le_bytes());
+    // No resolution, and all colors of the palette are used.
+    data.extend_from_slice(&[0; 16]);
+
+    for gray in 0..palette_size {
+        data.extend_from_slice(&[gray as u8, gray as u8, gray as u8, 0]);
+    }
+    let padding = line_size - image.width() * bits_per_pixel / 8;
+    for line in image.lines().rev() {
+        if image.color_type() == ColorType::Gray {
+            data.extend_from_slice(line);
+        } else {
+            for pixel in line.chunks(3) {
+                data.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+        }
+        data.extend(::std::iter::repeat_n(0, padding));
+    }
+    Ok(data)
+}
@@ -0,0 +1,240 @@
+// Writing decoded images to files in other formats.
+//
+// The format is chosen by the extension of the file name. Each format
+// holds some kinds of pixels, and images of other kinds are converted to
+// the closest one it holds: gray is made color, alpha is added or
+// dropped, and color is made gray only if the format holds nothing else.
+
+pub mod zlib;
+mod pnm;
+mod bmp;
+mod tga;
+mod png;
+
+use std::fs;
+use std::path::Path;
+
+use jpeg::color::ColorConversion;
+
+/// What the bytes of each pixel of an `Image` are.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorType {
+    /// One byte of luma
+    Gray,
+    /// One byte of luma and one of alpha
+    GrayAlpha,
+    /// Red, green and blue, one byte each
+    RGB,
+    /// Red, green, blue and alpha, one byte each
+    RGBA,
+    /// Cyan, magenta, yellow and black, one byte each
+    CMYK,
+}
+
+impl ColorType {
+    pub fn channels(self) -> usize {
+        match self {
+            ColorType::Gray => 1,
+            ColorType::GrayAlpha => 2,
+            ColorType::RGB => 3,
+            ColorType::RGBA | ColorType::CMYK => 4,
+        }
+    }
+
+    /// The kinds of pixels an image of this kind is converted to, best
+    /// first, for formats which can't hold it.
+    fn conversions(self) -> &'static [ColorType] {
+        match self {
+            ColorType::Gray => &[ColorType::RGB, ColorType::GrayAlpha, ColorType::RGBA],
+            ColorType::GrayAlpha => &[ColorType::RGBA, ColorType::Gray, ColorType::RGB],
+            ColorType::RGB => &[ColorType::RGBA, ColorType::Gray, ColorType::GrayAlpha],
+            ColorType::RGBA => &[ColorType::RGB, ColorType::GrayAlpha, ColorType::Gray],
+            ColorType::CMYK => &[],
+        }
+    }
+}
+
+/// The pixels of an image, line by line with no padding.
+pub struct Image<'a> {
+    width: usize,
+    height: usize,
+    color_type: ColorType,
+    data: &'a [u8],
+}
+
+impl<'a> Image<'a> {
+    /// Check that `data` has the pixels of an image of the given size.
+    pub fn new(data: &'a [u8],
+               width: usize,
+               height: usize,
+               color_type: ColorType)
+               -> Result<Image<'a>, String> {
+        if width == 0 || height == 0 {
+            return Err(format!("Images of {}x{} pixels have no pixels to write", width, height));
+        }
+        if data.len() != width * height * color_type.channels() {
+            return Err(format!("{} bytes are not the pixels of a {}x{} {:?} image",
+                               data.len(),
+                               width,
+                               height,
+                               color_type));
+        }
+        Ok(Image {
+            width,
+            height,
+            color_type,
+            data,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn color_type(&self) -> ColorType {
+        self.color_type
+    }
+
+    pub fn data(&self) -> &[u8] {
+        self.data
+    }
+
+    /// Each line of pixels.
+    fn lines(&self) -> ::std::slice::Chunks<'_, u8> {
+        self.data.chunks(self.width * self.color_type.channels())
+    }
+}
+
+/// The file formats images can be written in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Binary PPM (P6), for color images
+    PPM,
+    /// Binary PGM (P5), for gray images
+    PGM,
+    /// Binary PGM for gray images, and PPM for the others
+    PNM,
+    /// PAM (P7), which holds all kinds of pixels
+    PAM,
+    /// Windows bitmap, uncompressed
+    BMP,
+    /// Truevision TGA, uncompressed
+    TGA,
+    PNG,
+}
+
+impl Format {
+    /// The format of files with the extension `extension`, in any case.
+    pub fn from_extension(extension: &str) -> Option<Format> {
+        match extension.to_ascii_lowercase().as_str() {
+            "ppm" => Some(Format::PPM),
+            "pgm" => Some(Format::PGM),
+            "pnm" => Some(Format::PNM),
+            "pam" => Some(Format::PAM),
+            "bmp" => Some(Format::BMP),
+            "tga" => Some(Format::TGA),
+            "png" => Some(Format::PNG),
+            _ => None,
+        }
+    }
+
+    /// The format of the file at `path`, from its extension.
+    pub fn from_path(path: &Path) -> Result<Format, String> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) => {
+                Format::from_extension(extension)
+                    .ok_or_else(|| format!("Unknown image format .{}", extension))
+            }
+            None => Err(format!("{} has no extension to tell its format", path.display())),
+        }
+    }
+
+    /// The kinds of pixels the format holds.
+    pub fn color_types(self) -> &'static [ColorType] {
+        match self {
+            Format::PPM => &[ColorType::RGB],
+            Format::PGM => &[ColorType::Gray],
+            Format::PNM | Format::BMP => &[ColorType::Gray, ColorType::RGB],
+            Format::PAM => {
+                &[ColorType::Gray,
+                  ColorType::GrayAlpha,
+                  ColorType::RGB,
+                  ColorType::RGBA,
+                  ColorType::CMYK]
+            }
+            Format::TGA => &[ColorType::Gray, ColorType::RGB, ColorType::RGBA],
+            Format::PNG => {
+                &[ColorType::Gray, ColorType::GrayAlpha, ColorType::RGB, ColorType::RGBA]
+            }
+        }
+    }
+
+    /// The kind of pixels an image of `color_type` is written with, or
+    /// `None` if it can't be written in the format.
+    pub fn color_type_for(self, color_type: ColorType) -> Option<ColorType> {
+        let color_types = self.color_types();
+        if color_types.contains(&color_type) {
+            return Some(color_type);
+        }
+        color_type.conversions().iter().cloned().find(|c| color_types.contains(c))
+    }
+}
+
+/// Encode `image` in `format`, converting its pixels if the format
+/// can't hold them.
+pub fn encode(image: &Image, format: Format) -> Result<Vec<u8>, String> {
+    let color_type = match format.color_type_for(image.color_type) {
+        Some(color_type) => color_type,
+        None => {
+            return Err(format!("{:?} images can't be written as {:?}",
+                               image.color_type,
+                               format))
+        }
+    };
+    if color_type != image.color_type {
+        let data = convert(image.data, image.color_type, color_type);
+        let image = Image::new(&data, image.width, image.height, color_type)?;
+        return encode(&image, format);
+    }
+    match format {
+        Format::PPM | Format::PGM | Format::PNM | Format::PAM => pnm::encode(image, format),
+        Format::BMP => bmp::encode(image),
+        Format::TGA => tga::encode(image),
+        Format::PNG => Ok(png::encode(image)),
+    }
+}
+
+/// Write `image` to the file at `path`, in the format its extension
+/// tells.
+pub fn save(path: &Path, image: &Image) -> Result<(), String> {
+    let data = encode(image, Format::from_path(path)?)?;
+    fs::write(path, data).map_err(|error| format!("Could not write {}: {}", path.display(), error))
+}
+
+/// Convert pixels between kinds other than CMYK. Color is made gray
+/// with the luma weights of ITU-R BT.601, and added alpha is opaque.
+fn convert(data: &[u8], from: ColorType, to: ColorType) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(data.len() / from.channels() * to.channels());
+    let luma = |pixel| ColorConversion::default().luma(pixel);
+    for pixel in data.chunks(from.channels()) {
+        let (r, g, b, a) = match from {
+            ColorType::Gray => (pixel[0], pixel[0], pixel[0], 0xff),
+            ColorType::GrayAlpha => (pixel[0], pixel[0], pixel[0], pixel[1]),
+            ColorType::RGB => (pixel[0], pixel[1], pixel[2], 0xff),
+            ColorType::RGBA => (pixel[0], pixel[1], pixel[2], pixel[3]),
+            ColorType::CMYK => unreachable!(),
+        };
+        match to {
+            ColorType::Gray => converted.push(luma((r, g, b))),
+            ColorType::GrayAlpha => converted.extend_from_slice(&[luma((r, g, b)), a]),
+            ColorType::RGB => converted.extend_from_slice(&[r, g, b]),
+            ColorType::RGBA => converted.extend_from_slice(&[r, g, b, a]),
+            ColorType::CMYK => unreachable!(),
+        }
+    }
+    converted
+}
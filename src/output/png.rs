@@ -0,0 +1,111 @@
+// PNG images with 8 bits per sample. Each line is filtered with the
+// filter which gives the smallest sum of absolute differences, as libpng
+// does, and the filtered lines are compressed with `zlib`.
+
+use output::{zlib, ColorType, Image};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+/// Most bytes of image data we put in each IDAT chunk
+const MAX_IDAT_SIZE: usize = 1 << 20;
+
+pub fn encode(image: &Image) -> Vec<u8> {
+    let color_type = match image.color_type() {
+        ColorType::Gray => 0,
+        ColorType::RGB => 2,
+        ColorType::GrayAlpha => 4,
+        ColorType::RGBA => 6,
+        ColorType::CMYK => unreachable!(),
+    };
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(image.width() as u32).to_be_bytes());
+    header.extend_from_slice(&(image.height() as u32).to_be_bytes());
+    // 8 bits per sample, deflate, adaptive filters and no interlacing
+    header.extend_from_slice(&[8, color_type, 0, 0, 0]);
+
+    let image_data = zlib::compress(&filter(image));
+    let mut data = SIGNATURE.to_vec();
+    write_chunk(&mut data, b"IHDR", &header);
+    for chunk in image_data.chunks(MAX_IDAT_SIZE) {
+        write_chunk(&mut data, b"IDAT", chunk);
+    }
+    write_chunk(&mut data, b"IEND", &[]);
+    data
+}
+
+/// The lines of the image, each filtered and preceded by its filter
+/// type. See PNG section 9.
+fn filter(image: &Image) -> Vec<u8> {
+    let channels = image.color_type().channels();
+    let line_size = image.width() * channels;
+    let mut filtered = Vec::with_capacity((line_size + 1) * image.height());
+    let zeros = vec![0; line_size];
+    let mut previous = &zeros[..];
+    let mut candidate = vec![0; line_size];
+    let mut best = vec![0; line_size];
+    for line in image.lines() {
+        let mut best_filter = 0;
+        let mut best_sum = usize::MAX;
+        for filter_type in 0..5 {
+            for i in 0..line_size {
+                let a = if i >= channels { line[i - channels] } else { 0 };
+                let b = previous[i];
+                let c = if i >= channels { previous[i - channels] } else { 0 };
+                let prediction = match filter_type {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((a as u16 + b as u16) / 2) as u8,
+                    _ => paeth(a, b, c),
+                };
+                candidate[i] = line[i].wrapping_sub(prediction);
+            }
+            // The bytes as signed differences
+            let sum = candidate.iter().map(|&n| (n as i8).unsigned_abs() as usize).sum();
+            if sum < best_sum {
+                best_sum = sum;
+                best_filter = filter_type;
+                best.copy_from_slice(&candidate);
+            }
+        }
+        filtered.push(best_filter);
+        filtered.extend_from_slice(&best);
+        previous = line;
+    }
+    filtered
+}
+
+/// The one of `a`, `b` and `c` closest to `a + b - c`.
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Write a chunk with its length and CRC. See PNG section 5.3.
+fn write_chunk(data: &mut Vec<u8>, chunk_type: &[u8; 4], chunk_data: &[u8]) {
+    data.extend_from_slice(&(chunk_data.len() as u32).to_be_bytes());
+    let start = data.len();
+    data.extend_from_slice(chunk_type);
+    data.extend_from_slice(chunk_data);
+    let crc = crc32(&data[start..]);
+    data.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// The CRC-32 of ISO 3309, which PNG uses. See PNG Annex D.
+fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 == 1 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+        }
+        *entry = c;
+    }
+    !data.iter().fold(!0u32, |c, &byte| table[((c ^ byte as u32) & 0xff) as usize] ^ (c >> 8))
+}
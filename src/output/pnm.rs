@@ -0,0 +1,40 @@
+// The binary formats of Netpbm: PGM (P5) for gray images, PPM (P6) for
+// color images, and PAM (P7) for any kind of pixels, with a tuple type
+// naming them.
+
+use output::{ColorType, Format, Image};
+
+pub fn encode(image: &Image, format: Format) -> Result<Vec<u8>, String> {
+    let header = match format {
+        Format::PAM => {
+            let tuple_type = match image.color_type() {
+                ColorType::Gray => "GRAYSCALE",
+                ColorType::GrayAlpha => "GRAYSCALE_ALPHA",
+                ColorType::RGB => "RGB",
+                ColorType::RGBA => "RGB_ALPHA",
+                ColorType::CMYK => "CMYK",
+            };
+            format!("P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL 255\nTUPLTYPE {}\nENDHDR\n",
+                    image.width(),
+                    image.height(),
+                    image.color_type().channels(),
+                    tuple_type)
+        }
+        _ => {
+            let magic = match image.color_type() {
+                ColorType::Gray => "P5",
+                ColorType::RGB => "P6",
+                color_type => {
+                    return Err(format!("{:?} images can't be written as {:?}",
+                                       color_type,
+                                       format))
+                }
+            };
+            format!("{}\n{} {}\n255\n", magic, image.width(), image.height())
+        }
+    };
+    let mut data = Vec::with_capacity(header.len() + image.data().len());
+    data.extend_from_slice(header.as_bytes());
+    data.extend_from_slice(image.data());
+    Ok(data)
+}
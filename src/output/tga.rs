@@ -0,0 +1,50 @@
+// Uncompressed Truevision TGA images: true color with 24 or 32 bits per
+// pixel, stored as blue, green, red and alpha, or 8 bit gray. Lines are
+// stored from the top down.
+
+use output::{ColorType, Image};
+
+pub fn encode(image: &Image) -> Result<Vec<u8>, String> {
+    if image.width() > u16::MAX as usize || image.height() > u16::MAX as usize {
+        return Err(format!("Images of {}x{} pixels are too large for TGA",
+                           image.width(),
+                           image.height()));
+    }
+    // Image type, bits per pixel and the bits of alpha in each
+    let (image_type, bits_per_pixel, alpha_bits) = match image.color_type() {
+        ColorType::Gray => (3, 8, 0),
+        ColorType::RGB => (2, 24, 0),
+        ColorType::RGBA => (2, 32, 8),
+        color_type => return Err(format!("{:?} images can't be written as TGA", color_type)),
+    };
+
+    let mut data = Vec::with_capacity(18 + image.data().len() + 26);
+    // No image ID and no color map
+    data.extend_from_slice(&[0, 0, image_type]);
+    data.extend_from_slice(&[0; 5]);
+    // The origin of the image on the screen
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&(image.width() as u16).to_le_bytes());
+    data.extend_from_slice(&(image.height() as u16).to_le_bytes());
+    // Bit 5 of the descriptor puts the first line at the top.
+    data.extend_from_slice(&[bits_per_pixel, alpha_bits | 0x20]);
+
+    match image.color_type() {
+        ColorType::RGB => {
+            for pixel in image.data().chunks(3) {
+                data.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+        }
+        ColorType::RGBA => {
+            for pixel in image.data().chunks(4) {
+                data.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+            }
+        }
+        _ => data.extend_from_slice(image.data()),
+    }
+
+    // The footer of TGA 2.0, with no extension or developer area
+    data.extend_from_slice(&[0; 8]);
+    data.extend_from_slice(b"TRUEVISION-XFILE.\0");
+    Ok(data)
+}
@@ -0,0 +1,550 @@
+// zlib streams (RFC 1950) of data compressed with deflate (RFC 1951),
+// for the image data of PNG files.
+//
+// Repeated strings are found with hash chains over the last 32 KiB,
+// taking a match only if the next byte does not start a longer one, like
+// zlib does at its default level. Each block is then coded with Huffman
+// codes made for its symbols, with the fixed Huffman codes, or stored
+// as it is, whichever is smallest.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: usize = 15;
+/// Number of earlier positions with the same hash we try at most
+const MAX_CHAIN: usize = 128;
+/// Matches at least this long are taken without trying the next byte
+const LAZY_LENGTH: usize = 32;
+/// Matches of three bytes further back than this cost more than the
+/// literals, as in zlib.
+const TOO_FAR: usize = 4096;
+/// Number of symbols in each block
+const BLOCK_SYMBOLS: usize = 16384;
+/// Most bytes a stored block can have
+const MAX_STORED: usize = 65535;
+
+const END_OF_BLOCK: usize = 256;
+const NUM_LITERAL_LENGTH_CODES: usize = 286;
+const NUM_DISTANCE_CODES: usize = 30;
+const MAX_CODE_LENGTH: usize = 15;
+const MAX_CODE_LENGTH_CODE_LENGTH: usize = 7;
+
+/// The smallest length of each length code, from code 257 on. See RFC
+/// 1951 section 3.2.5.
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43,
+                                51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA_BITS: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3,
+                                     4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+                                  257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193,
+                                  12289, 16385, 24577];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8,
+                                       8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// The order the lengths of the code length codes are written in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2,
+                                        14, 1, 15];
+
+/// Compress `data` into a zlib stream.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    // Deflate with a window of 32 KiB, and the check bits of the
+    // default compression level.
+    writer.data.extend_from_slice(&[0x78, 0x9c]);
+
+    let mut matcher = Matcher::new();
+    let mut symbols = Vec::with_capacity(BLOCK_SYMBOLS);
+    let mut block_start = 0;
+    let mut i = 0;
+    // A match at `i`, which is inserted in the hash chains already
+    let mut found = None;
+    while i < data.len() {
+        let (length, distance) = match found.take() {
+            Some(found) => found,
+            None => matcher.insert_and_find(data, i),
+        };
+        if length >= MIN_MATCH {
+            if length < LAZY_LENGTH && i + 1 < data.len() {
+                let next = matcher.insert_and_find(data, i + 1);
+                if next.0 > length {
+                    symbols.push(Symbol::literal(data[i]));
+                    i += 1;
+                    found = Some(next);
+                    continue;
+                }
+                for j in i + 2..i + length {
+                    matcher.insert(data, j);
+                }
+            } else {
+                for j in i + 1..i + length {
+                    matcher.insert(data, j);
+                }
+            }
+            symbols.push(Symbol::copy(length, distance));
+            i += length;
+        } else {
+            symbols.push(Symbol::literal(data[i]));
+            i += 1;
+        }
+        if symbols.len() >= BLOCK_SYMBOLS {
+            write_block(&mut writer, &symbols, &data[block_start..i], false);
+            symbols.clear();
+            block_start = i;
+        }
+    }
+    write_block(&mut writer, &symbols, &data[block_start..], true);
+
+    let mut output = writer.finish();
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
+
+/// The Adler-32 checksum of `data`. See RFC 1950 section 8.
+pub fn adler32(data: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    // The most bytes we can add before the sums may overflow
+    const CHUNK_SIZE: usize = 5552;
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(CHUNK_SIZE) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MODULUS;
+        b %= MODULUS;
+    }
+    (b << 16) | a
+}
+
+/// A literal byte, or a copy of `length` bytes from `distance` bytes
+/// back, which is a literal when `distance` is `0`.
+#[derive(Clone, Copy)]
+struct Symbol {
+    length: u16,
+    distance: u16,
+}
+
+impl Symbol {
+    fn literal(byte: u8) -> Symbol {
+        Symbol {
+            length: byte as u16,
+            distance: 0,
+        }
+    }
+
+    fn copy(length: usize, distance: usize) -> Symbol {
+        Symbol {
+            length: length as u16,
+            distance: distance as u16,
+        }
+    }
+
+    /// The literal/length code of the symbol, and the extra bits of the
+    /// length with their number.
+    fn length_code(self) -> (usize, u32, u8) {
+        if self.distance == 0 {
+            return (self.length as usize, 0, 0);
+        }
+        let i = LENGTH_BASE.iter().rposition(|&base| base <= self.length).unwrap();
+        (257 + i, (self.length - LENGTH_BASE[i]) as u32, LENGTH_EXTRA_BITS[i])
+    }
+
+    /// The distance code of a copy, and the extra bits of the distance
+    /// with their number.
+    fn distance_code(self) -> (usize, u32, u8) {
+        let i = DISTANCE_BASE.iter().rposition(|&base| base <= self.distance).unwrap();
+        (i, (self.distance - DISTANCE_BASE[i]) as u32, DISTANCE_EXTRA_BITS[i])
+    }
+}
+
+/// Finds earlier occurrences of the bytes at a position, with a chain
+/// of the earlier positions for each hash of three bytes.
+struct Matcher {
+    /// The last position with each hash, plus one, or `0` if none
+    head: Vec<u32>,
+    /// The previous position with the same hash as each position in the
+    /// window, plus one, or `0` if none
+    previous: Vec<u32>,
+}
+
+impl Matcher {
+    fn new() -> Matcher {
+        Matcher {
+            head: vec![0; 1 << HASH_BITS],
+            previous: vec![0; WINDOW_SIZE],
+        }
+    }
+
+    fn hash(data: &[u8], i: usize) -> usize {
+        (((data[i] as usize) << 10) ^ ((data[i + 1] as usize) << 5) ^ data[i + 2] as usize) &
+        ((1 << HASH_BITS) - 1)
+    }
+
+    /// Add position `i` to the chain of its hash.
+    fn insert(&mut self, data: &[u8], i: usize) {
+        if i + MIN_MATCH > data.len() {
+            return;
+        }
+        let hash = Matcher::hash(data, i);
+        self.previous[i % WINDOW_SIZE] = self.head[hash];
+        self.head[hash] = i as u32 + 1;
+    }
+
+    /// Add position `i` to the chain of its hash, and find the longest
+    /// earlier match of the bytes from `i` on, as its length and
+    /// distance. The length is `0` if there is none.
+    fn insert_and_find(&mut self, data: &[u8], i: usize) -> (usize, usize) {
+        if i + MIN_MATCH > data.len() {
+            return (0, 0);
+        }
+        let hash = Matcher::hash(data, i);
+        let mut candidate = self.head[hash] as usize;
+        self.previous[i % WINDOW_SIZE] = self.head[hash];
+        self.head[hash] = i as u32 + 1;
+
+        let max_length = MAX_MATCH.min(data.len() - i);
+        let (mut best_length, mut best_distance) = (0, 0);
+        let mut chain = 0;
+        while candidate > 0 && chain < MAX_CHAIN {
+            let j = candidate - 1;
+            if i - j > WINDOW_SIZE {
+                break;
+            }
+            if data[j + best_length.min(max_length - 1)] ==
+               data[i + best_length.min(max_length - 1)] {
+                let length = data[j..j + max_length]
+                    .iter()
+                    .zip(&data[i..i + max_length])
+                    .take_while(|&(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    best_distance = i - j;
+                    if length == max_length {
+                        break;
+                    }
+                }
+            }
+            let previous = self.previous[j % WINDOW_SIZE] as usize;
+            // The slot may be taken by a later position already.
+            if previous >= candidate {
+                break;
+            }
+            candidate = previous;
+            chain += 1;
+        }
+        if best_length < MIN_MATCH || (best_length == MIN_MATCH && best_distance > TOO_FAR) {
+            return (0, 0);
+        }
+        (best_length, best_distance)
+    }
+}
+
+/// Writes bits from the least significant bit of each byte on.
+struct BitWriter {
+    data: Vec<u8>,
+    bits: u64,
+    num_bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            data: Vec::new(),
+            bits: 0,
+            num_bits: 0,
+        }
+    }
+
+    /// Write the `length` lowest bits of `value`, the least significant
+    /// first.
+    fn write_bits(&mut self, value: u32, length: u8) {
+        self.bits |= (value as u64) << self.num_bits;
+        self.num_bits += length as u32;
+        while self.num_bits >= 8 {
+            self.data.push(self.bits as u8);
+            self.bits >>= 8;
+            self.num_bits -= 8;
+        }
+    }
+
+    /// Fill the last byte with `0` bits.
+    fn align(&mut self) {
+        if self.num_bits > 0 {
+            self.write_bits(0, (8 - self.num_bits) as u8);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.align();
+        self.data
+    }
+}
+
+/// A Huffman code for each symbol, from their code lengths.
+struct HuffmanCodes {
+    /// The codes with their bits reversed, as they are written from the
+    /// most significant bit on
+    codes: Vec<u16>,
+    lengths: Vec<u8>,
+}
+
+impl HuffmanCodes {
+    /// The canonical codes for the given code lengths. See RFC 1951
+    /// section 3.2.2.
+    fn new(lengths: &[u8]) -> HuffmanCodes {
+        let mut count = [0u16; MAX_CODE_LENGTH + 1];
+        for &length in lengths.iter().filter(|&&length| length > 0) {
+            count[length as usize] += 1;
+        }
+        let mut next_code = [0u16; MAX_CODE_LENGTH + 1];
+        let mut code = 0;
+        for bits in 1..MAX_CODE_LENGTH + 1 {
+            code = (code + count[bits - 1]) << 1;
+            next_code[bits] = code;
+        }
+        let codes = lengths.iter()
+            .map(|&length| {
+                if length == 0 {
+                    return 0;
+                }
+                let code = next_code[length as usize];
+                next_code[length as usize] += 1;
+                code.reverse_bits() >> (16 - length)
+            })
+            .collect();
+        HuffmanCodes {
+            codes,
+            lengths: lengths.to_vec(),
+        }
+    }
+
+    fn write(&self, writer: &mut BitWriter, symbol: usize) {
+        writer.write_bits(self.codes[symbol] as u32, self.lengths[symbol]);
+    }
+}
+
+/// Code lengths of at most `max_length` bits which code symbols with
+/// the given frequencies in the fewest bits. At least two symbols get a
+/// code, so that the code is complete.
+fn code_lengths(frequencies: &[u32], max_length: usize) -> Vec<u8> {
+    let mut symbols = (0..frequencies.len()).filter(|&i| frequencies[i] > 0).collect::<Vec<_>>();
+    let mut i = 0;
+    while symbols.len() < 2 {
+        if !symbols.contains(&i) {
+            symbols.push(i);
+        }
+        i += 1;
+    }
+
+    // Build the Huffman tree, with the leaves first, and find the depth
+    // of each leaf.
+    let num_leaves = symbols.len();
+    let mut parent = vec![0; 2 * num_leaves - 1];
+    let mut heap = symbols.iter()
+        .enumerate()
+        .map(|(leaf, &symbol)| Reverse((frequencies[symbol] as u64, leaf)))
+        .collect::<BinaryHeap<_>>();
+    let mut next_node = num_leaves;
+    while heap.len() > 1 {
+        let Reverse((weight1, node1)) = heap.pop().unwrap();
+        let Reverse((weight2, node2)) = heap.pop().unwrap();
+        parent[node1] = next_node;
+        parent[node2] = next_node;
+        heap.push(Reverse((weight1 + weight2, next_node)));
+        next_node += 1;
+    }
+    let root = next_node - 1;
+    let mut depth = vec![0; 2 * num_leaves - 1];
+    for node in (0..root).rev() {
+        depth[node] = depth[parent[node]] + 1;
+    }
+
+    // Number of codes of each length, with the longest codes moved up
+    // to `max_length` bits the same way as in JPEG Figure K.3.
+    let max_depth = depth[..num_leaves].iter().cloned().max().unwrap();
+    let mut count = vec![0usize; max_depth.max(max_length) + 1];
+    for &depth in &depth[..num_leaves] {
+        count[depth] += 1;
+    }
+    for i in (max_length + 1..count.len()).rev() {
+        while count[i] > 0 {
+            let mut j = i - 2;
+            while count[j] == 0 {
+                j -= 1;
+            }
+            count[i] -= 2;
+            count[i - 1] += 1;
+            count[j + 1] += 2;
+            count[j] -= 1;
+        }
+    }
+
+    // The most frequent symbols get the shortest codes.
+    symbols.sort_by_key(|&symbol| Reverse(frequencies[symbol]));
+    let mut lengths = vec![0; frequencies.len()];
+    let mut symbols = symbols.into_iter();
+    for (length, &n) in count.iter().enumerate().take(max_length + 1) {
+        for symbol in symbols.by_ref().take(n) {
+            lengths[symbol] = length as u8;
+        }
+    }
+    lengths
+}
+
+/// The code lengths of the fixed Huffman codes for literals and lengths,
+/// and for distances. See RFC 1951 section 3.2.6.
+fn fixed_code_lengths() -> (Vec<u8>, Vec<u8>) {
+    let mut literal_lengths = vec![8; 288];
+    for length in &mut literal_lengths[144..256] {
+        *length = 9;
+    }
+    for length in &mut literal_lengths[256..280] {
+        *length = 7;
+    }
+    (literal_lengths, vec![5; NUM_DISTANCE_CODES])
+}
+
+/// The code lengths of a dynamic block, as code length symbols with
+/// their extra bits and the number of those. Runs are coded with symbols
+/// 16 to 18. See RFC 1951 section 3.2.7.
+fn code_length_symbols(lengths: &[u8]) -> Vec<(usize, u32, u8)> {
+    let mut symbols = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let length = lengths[i];
+        let run = lengths[i..].iter().take_while(|&&l| l == length).count();
+        let mut left = run;
+        if length == 0 {
+            while left >= 11 {
+                let n = left.min(138);
+                symbols.push((18, (n - 11) as u32, 7));
+                left -= n;
+            }
+            if left >= 3 {
+                symbols.push((17, (left - 3) as u32, 3));
+                left = 0;
+            }
+        } else {
+            symbols.push((length as usize, 0, 0));
+            left -= 1;
+            while left >= 3 {
+                let n = left.min(6);
+                symbols.push((16, (n - 3) as u32, 2));
+                left -= n;
+            }
+        }
+        for _ in 0..left {
+            symbols.push((length as usize, 0, 0));
+        }
+        i += run;
+    }
+    symbols
+}
+
+/// Number of bits the symbols of a block take with the given codes,
+/// including the end of the block.
+fn coded_size(symbols: &[Symbol], literal_lengths: &[u8], distance_lengths: &[u8]) -> usize {
+    let mut size = literal_lengths[END_OF_BLOCK] as usize;
+    for &symbol in symbols {
+        let (code, _, extra_bits) = symbol.length_code();
+        size += literal_lengths[code] as usize + extra_bits as usize;
+        if symbol.distance > 0 {
+            let (code, _, extra_bits) = symbol.distance_code();
+            size += distance_lengths[code] as usize + extra_bits as usize;
+        }
+    }
+    size
+}
+
+/// Write the symbols of a block, which code `data`, in whichever way
+/// takes the fewest bits.
+fn write_block(writer: &mut BitWriter, symbols: &[Symbol], data: &[u8], last: bool) {
+    let mut literal_frequencies = vec![0u32; NUM_LITERAL_LENGTH_CODES];
+    let mut distance_frequencies = vec![0u32; NUM_DISTANCE_CODES];
+    literal_frequencies[END_OF_BLOCK] = 1;
+    for &symbol in symbols {
+        literal_frequencies[symbol.length_code().0] += 1;
+        if symbol.distance > 0 {
+            distance_frequencies[symbol.distance_code().0] += 1;
+        }
+    }
+    let literal_lengths = code_lengths(&literal_frequencies, MAX_CODE_LENGTH);
+    let distance_lengths = code_lengths(&distance_frequencies, MAX_CODE_LENGTH);
+
+    // Trailing codes which are not used need not be written.
+    let num_literal_codes = 257.max(literal_lengths.iter().rposition(|&l| l > 0).unwrap() + 1);
+    let num_distance_codes = 1.max(distance_lengths.iter().rposition(|&l| l > 0).unwrap() + 1);
+    let mut all_lengths = literal_lengths[..num_literal_codes].to_vec();
+    all_lengths.extend_from_slice(&distance_lengths[..num_distance_codes]);
+    let length_symbols = code_length_symbols(&all_lengths);
+    let mut length_frequencies = vec![0u32; 19];
+    for &(symbol, _, _) in &length_symbols {
+        length_frequencies[symbol] += 1;
+    }
+    let length_lengths = code_lengths(&length_frequencies, MAX_CODE_LENGTH_CODE_LENGTH);
+    let num_length_codes =
+        4.max(CODE_LENGTH_ORDER.iter().rposition(|&i| length_lengths[i] > 0).unwrap() + 1);
+
+    let dynamic_size = 3 + 5 + 5 + 4 + 3 * num_length_codes +
+                       length_symbols.iter()
+        .map(|&(symbol, _, extra_bits)| length_lengths[symbol] as usize + extra_bits as usize)
+        .sum::<usize>() + coded_size(symbols, &literal_lengths, &distance_lengths);
+    let (fixed_literal_lengths, fixed_distance_lengths) = fixed_code_lengths();
+    let fixed_size = 3 + coded_size(symbols, &fixed_literal_lengths, &fixed_distance_lengths);
+    let num_stored = data.len().div_ceil(MAX_STORED).max(1);
+    let stored_size = num_stored * (3 + 7 + 32) + 8 * data.len();
+
+    if stored_size < dynamic_size.min(fixed_size) {
+        let mut chunks = data.chunks(MAX_STORED).peekable();
+        if data.is_empty() {
+            write_stored(writer, &[], last);
+        }
+        while let Some(chunk) = chunks.next() {
+            write_stored(writer, chunk, last && chunks.peek().is_none());
+        }
+        return;
+    }
+
+    let (literal_codes, distance_codes) = if dynamic_size < fixed_size {
+        writer.write_bits(last as u32 | (2 << 1), 3);
+        writer.write_bits((num_literal_codes - 257) as u32, 5);
+        writer.write_bits((num_distance_codes - 1) as u32, 5);
+        writer.write_bits((num_length_codes - 4) as u32, 4);
+        for &i in &CODE_LENGTH_ORDER[..num_length_codes] {
+            writer.write_bits(length_lengths[i] as u32, 3);
+        }
+        let length_codes = HuffmanCodes::new(&length_lengths);
+        for &(symbol, extra, extra_bits) in &length_symbols {
+            length_codes.write(writer, symbol);
+            writer.write_bits(extra, extra_bits);
+        }
+        (HuffmanCodes::new(&literal_lengths), HuffmanCodes::new(&distance_lengths))
+    } else {
+        writer.write_bits(last as u32 | (1 << 1), 3);
+        (HuffmanCodes::new(&fixed_literal_lengths), HuffmanCodes::new(&fixed_distance_lengths))
+    };
+    for &symbol in symbols {
+        let (code, extra, extra_bits) = symbol.length_code();
+        literal_codes.write(writer, code);
+        writer.write_bits(extra, extra_bits);
+        if symbol.distance > 0 {
+            let (code, extra, extra_bits) = symbol.distance_code();
+            distance_codes.write(writer, code);
+            writer.write_bits(extra, extra_bits);
+        }
+    }
+    literal_codes.write(writer, END_OF_BLOCK);
+}
+
+/// Write `data` as a block which is not compressed.
+fn write_stored(writer: &mut BitWriter, data: &[u8], last: bool) {
+    writer.write_bits(last as u32, 3);
+    writer.align();
+    let length = data.len() as u16;
+    writer.data.extend_from_slice(&length.to_le_bytes());
+    writer.data.extend_from_slice(&(!length).to_le_bytes());
+    writer.data.extend_from_slice(data);
+}
@@ -13,7 +13,7 @@ fn usize_square(n: usize) -> Option<usize> {
 }
 
 #[allow(non_upper_case_globals)]
-const Pi: f32 = PI as f32;
+const Pi: f32 = PI;
 
 pub fn discrete_cosine_transform(input: &[f32]) -> Vec<f32> {
     let alpha = |u| {
@@ -35,7 +35,7 @@ pub fn discrete_cosine_transform(input: &[f32]) -> Vec<f32> {
             for y in 0..d {
                 for x in 0..d {
                     let xy_index = y * d + x;
-                    let gxy = input[xy_index] as f32;
+                    let gxy = input[xy_index];
 
                     let yf = y as f32;
                     let xf = x as f32;
@@ -85,3 +85,46 @@ pub fn discrete_cosine_transform_inverse(input: &[f32]) -> Vec<f32> {
 
     vec
 }
+
+/// Inverse DCT of an 8x8 block of coefficients, giving `size` x `size`
+/// samples, like the scaled inverse DCTs of libjpeg. Below 8, only the
+/// lowest `size` x `size` frequencies are used, and above 8 the missing
+/// frequencies are zero. The samples have the same average as the full
+/// size block, so a `size` of 1 gives the DC coefficient over 8.
+pub fn discrete_cosine_transform_inverse_scaled(input: &[f32], size: usize) -> Vec<f32> {
+    if size == 8 {
+        return discrete_cosine_transform_inverse(input);
+    }
+    let alpha = |u| {
+        if u == 0 {
+            1f32 / 2f32.sqrt()
+        } else {
+            1f32
+        }
+    };
+    let num_frequencies = size.min(8);
+    // `cosines[x * num_frequencies + u]` is the basis function of
+    // frequency `u` at sample `x`.
+    let mut cosines = Vec::with_capacity(size * num_frequencies);
+    for x in 0..size {
+        for u in 0..num_frequencies {
+            let xf = x as f32;
+            let uf = u as f32;
+            cosines.push(alpha(u) * ((2f32 * xf + 1f32) * uf * Pi / (2 * size) as f32).cos());
+        }
+    }
+    let mut vec = Vec::with_capacity(size * size);
+    for y in 0..size {
+        for x in 0..size {
+            let mut sum = 0f32;
+            for v in 0..num_frequencies {
+                for u in 0..num_frequencies {
+                    sum += input[v * 8 + u] * cosines[x * num_frequencies + u] *
+                           cosines[y * num_frequencies + v];
+                }
+            }
+            vec.push(sum / 4f32);
+        }
+    }
+    vec
+}
@@ -0,0 +1,139 @@
+extern crate jpeg_rust;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use jpeg_rust::jpeg::JPEGImage;
+use jpeg_rust::jpeg::transform::{PartialMCUs, Transform};
+use jpeg_rust::jpeg::writer::JPEGSegmentWriter;
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_jpeg-rust")).args(args).output().unwrap()
+}
+
+fn input(name: &str) -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(name).to_str().unwrap().to_string()
+}
+
+/// A file name in a directory of its own for each test.
+fn output(test: &str, name: &str) -> PathBuf {
+    let directory = env::temp_dir().join(format!("jpeg-rust-cli-{}-{}", std::process::id(), test));
+    fs::create_dir_all(&directory).unwrap();
+    directory.join(name)
+}
+
+#[test]
+fn usage_errors_exit_with_2() {
+    for args in &[&[][..], &["frobnicate"], &["info"], &["decode", "--bogus", "a.jpg", "a.ppm"]] {
+        let result = run(args);
+        assert_eq!(result.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&result.stderr).contains("Usage:"));
+    }
+}
+
+#[test]
+fn failures_exit_with_1() {
+    let result = run(&["info", "does-not-exist.jpg"]);
+    assert_eq!(result.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&result.stderr).starts_with("jpeg-rust: Could not read"));
+
+    let ppm = output("failures", "a.ppm");
+    let result = run(&["decode", &input("README.md"), ppm.to_str().unwrap()]);
+    assert_eq!(result.status.code(), Some(1));
+    let result = run(&["extract-icc", &input("lena.jpeg"), ppm.to_str().unwrap()]);
+    assert_eq!(result.status.code(), Some(1));
+    fs::remove_dir_all(ppm.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn info_and_markers() {
+    let result = run(&["info", &input("lena.jpeg")]);
+    assert!(result.status.success());
+    let text = String::from_utf8(result.stdout).unwrap();
+    assert!(text.contains("512x512, 8 bits"));
+    assert!(text.contains("Markers: SOI APP0"));
+
+    let result = run(&["info", "--json", &input("lena.jpeg")]);
+    let json = String::from_utf8(result.stdout).unwrap();
+    assert!(json.contains("\"width\": 512"));
+
+    let result = run(&["validate", &input("lena.jpeg")]);
+    assert!(result.status.success());
+    assert_eq!(result.stdout, b"No problems found\n");
+
+    let result = run(&["dump-markers", &input("lena.jpeg")]);
+    let text = String::from_utf8(result.stdout).unwrap();
+    assert!(text.lines().next().unwrap().ends_with("SOI"));
+    assert!(text.lines().last().unwrap().ends_with("EOI"));
+}
+
+#[test]
+fn decode_transform_and_encode() {
+    let png = output("pipeline", "lena.png");
+    let rotated = output("pipeline", "rotated.jpg");
+    let ppm = output("pipeline", "rotated.ppm");
+    let encoded = output("pipeline", "encoded.jpg");
+    let lena = input("lena.jpeg");
+    let steps: &[&[&str]] = &[&["decode", "--scale", "4", &lena, png.to_str().unwrap()],
+                              &["transform", "--rotate", "90", "--progressive", &lena,
+                                rotated.to_str().unwrap()],
+                              &["decode", rotated.to_str().unwrap(), ppm.to_str().unwrap()],
+                              &["encode", "--quality", "50", ppm.to_str().unwrap(),
+                                encoded.to_str().unwrap()]];
+    for args in steps {
+        let result = run(args);
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    }
+    assert!(fs::read(&png).unwrap().starts_with(b"\x89PNG"));
+    assert!(fs::read(&ppm).unwrap().starts_with(b"P6\n512 512\n255\n"));
+    assert!(fs::read(&encoded).unwrap().starts_with(b"\xff\xd8\xff\xe0"));
+    fs::remove_dir_all(png.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn lenient_decode_warns_about_truncated_file() {
+    let truncated = output("lenient", "truncated.jpg");
+    let ppm = output("lenient", "truncated.ppm");
+    let data = fs::read(input("lena.jpeg")).unwrap();
+    fs::write(&truncated, &data[..data.len() / 2]).unwrap();
+    let result = run(&["decode", "--lenient", truncated.to_str().unwrap(), ppm.to_str().unwrap()]);
+    assert!(result.status.success());
+    let warnings = String::from_utf8(result.stderr).unwrap();
+    assert!(warnings.starts_with("jpeg-rust: warning: Scan 1: The data ends early"));
+    assert!(fs::read(&ppm).unwrap().starts_with(b"P6\n512 512\n255\n"));
+    fs::remove_dir_all(ppm.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn transform_crops_the_output() {
+    // Cropping before rotating would give a 32x64 image.
+    let cropped = output("crop", "cropped.jpg");
+    let result = run(&["transform", "--rotate", "90", "--crop", "64x32+16+0", &input("lena.jpeg"),
+                       cropped.to_str().unwrap()]);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    let data = fs::read(&cropped).unwrap();
+    let image = JPEGImage::parse(data.clone()).unwrap();
+    assert_eq!((image.width(), image.height()), (64, 32));
+
+    let mut writer = JPEGSegmentWriter::new(&fs::read(input("lena.jpeg")).unwrap()).unwrap();
+    writer.transform(Transform::Rotate90, PartialMCUs::Error).unwrap();
+    writer.crop(16, 0, 64, 32).unwrap();
+    assert!(writer.to_bytes() == data);
+    fs::remove_dir_all(cropped.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn transform_options_which_do_nothing_are_usage_errors() {
+    // Arithmetic coding has no Huffman tables to optimize, and only a
+    // transform can trim partial MCUs.
+    let lena = input("lena.jpeg");
+    for args in &[&["transform", "--optimize", "--arithmetic", &lena, "a.jpg"][..],
+                  &["transform", "--trim", &lena, "a.jpg"],
+                  &["transform", "--trim", "--crop", "16x16+0+0", &lena, "a.jpg"]] {
+        let result = run(args);
+        assert_eq!(result.status.code(), Some(2), "{:?}", args);
+        assert!(String::from_utf8_lossy(&result.stderr).contains("Usage:"));
+    }
+}
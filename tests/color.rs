@@ -0,0 +1,67 @@
+extern crate jpeg_rust;
+
+use jpeg_rust::jpeg::color::{ColorConversion, Matrix, Range};
+
+const CONVERSIONS: &[(Matrix, Range)] = &[(Matrix::BT601, Range::Full),
+                                          (Matrix::BT601, Range::Limited),
+                                          (Matrix::BT709, Range::Full),
+                                          (Matrix::BT709, Range::Limited)];
+
+#[test]
+fn gray_pixels_have_no_chroma() {
+    for &(matrix, range) in CONVERSIONS {
+        let conversion = ColorConversion::new(matrix, range);
+        for value in 0..=255 {
+            let (y, cb, cr) = conversion.to_y_cb_cr((value, value, value));
+            assert_eq!((cb, cr), (128, 128));
+            // Limited range has fewer values, so not all of them come back.
+            if range == Range::Full {
+                assert_eq!(conversion.to_rgb(y, cb, cr), (value, value, value));
+                assert_eq!(conversion.gray(y), value);
+            }
+        }
+    }
+}
+
+#[test]
+fn jfif_primaries() {
+    let conversion = ColorConversion::default();
+    assert_eq!(conversion.to_y_cb_cr((255, 0, 0)), (76, 85, 255));
+    assert_eq!(conversion.to_y_cb_cr((0, 255, 0)), (150, 44, 21));
+    assert_eq!(conversion.to_y_cb_cr((0, 0, 255)), (29, 255, 107));
+    assert_eq!(conversion.to_rgb(76, 85, 255), (254, 0, 0));
+}
+
+#[test]
+fn limited_range() {
+    for &matrix in &[Matrix::BT601, Matrix::BT709] {
+        let conversion = ColorConversion::new(matrix, Range::Limited);
+        assert_eq!(conversion.to_y_cb_cr((0, 0, 0)), (16, 128, 128));
+        assert_eq!(conversion.to_y_cb_cr((255, 255, 255)), (235, 128, 128));
+        assert_eq!(conversion.to_y_cb_cr((0, 0, 255)).1, 240);
+        assert_eq!(conversion.to_y_cb_cr((255, 0, 0)).2, 240);
+        // Values outside the range are clamped.
+        assert_eq!(conversion.to_rgb(0, 128, 128), (0, 0, 0));
+        assert_eq!(conversion.to_rgb(255, 128, 128), (255, 255, 255));
+    }
+}
+
+#[test]
+fn bt709_luma() {
+    let conversion = ColorConversion::new(Matrix::BT709, Range::Full);
+    assert_eq!(conversion.to_y_cb_cr((255, 0, 0)).0, 54);
+    assert_eq!(conversion.to_y_cb_cr((0, 255, 0)).0, 182);
+    assert_eq!(conversion.to_y_cb_cr((0, 0, 255)).0, 18);
+}
+
+#[test]
+fn luma_is_in_full_range() {
+    for &(matrix, range) in CONVERSIONS {
+        let conversion = ColorConversion::new(matrix, range);
+        for value in 0..=255 {
+            assert_eq!(conversion.luma((value, value, value)), value);
+        }
+        let red = if matrix == Matrix::BT601 { 76 } else { 54 };
+        assert_eq!(conversion.luma((255, 0, 0)), red);
+    }
+}
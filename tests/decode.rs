@@ -0,0 +1,569 @@
+extern crate jpeg_rust;
+
+use std::cell::Cell;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use jpeg_rust::jpeg::JPEGImage;
+use jpeg_rust::jpeg::color::{ColorConversion, Matrix, Range};
+use jpeg_rust::jpeg::compress::JPEGEncoder;
+use jpeg_rust::jpeg::decoder::CoefficientOrder;
+use jpeg_rust::jpeg::marker::{Marker, MarkerReader, SegmentKind};
+use jpeg_rust::jpeg::pixel::PixelFormat;
+use jpeg_rust::jpeg::stream::{DecodeStatus, JPEGPushDecoder, JPEGStreamDecoder};
+use jpeg_rust::jpeg::writer::JPEGSegmentWriter;
+
+fn read(name: &str) -> Vec<u8> {
+    fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(name)).unwrap()
+}
+
+/// The image with the coefficients of all components but the first
+/// cleared, so that its pixels are gray with the value of the luma.
+fn without_chroma(data: &[u8]) -> Vec<u8> {
+    let (_, mut components) = JPEGImage::read_coefficients(data, CoefficientOrder::Zigzag)
+        .unwrap();
+    for component in components.iter_mut().skip(1) {
+        for block in &mut component.blocks {
+            *block = [0; 64];
+        }
+    }
+    let mut writer = JPEGSegmentWriter::new(data).unwrap();
+    writer.replace_coefficients_with_optimal_tables(&components).unwrap();
+    writer.to_bytes()
+}
+
+#[test]
+fn luma_of_colour_image() {
+    let data = read("lena.jpeg");
+    let (image, luma) = JPEGImage::decode_luma(&data).unwrap();
+    assert_eq!(luma.len(), image.width() * image.height());
+    let gray = JPEGImage::parse(without_chroma(&data)).unwrap();
+    assert!(gray.image_data().unwrap().iter().map(|pixel| pixel.0).eq(luma.iter().cloned()));
+}
+
+#[test]
+fn luma_of_progressive_image_skips_chroma_scans() {
+    let data = read("lena.jpeg");
+    let mut writer = JPEGSegmentWriter::new(&data).unwrap();
+    writer.make_progressive().unwrap();
+    let progressive = writer.to_bytes();
+    assert_eq!(JPEGImage::decode_luma(&progressive).unwrap().1,
+               JPEGImage::decode_luma(&data).unwrap().1);
+}
+
+#[test]
+fn luma_of_gray_image() {
+    let data = read("working-jpegs/lena-bw.jpeg");
+    let (_, luma) = JPEGImage::decode_luma(&data).unwrap();
+    let image = JPEGImage::parse(data).unwrap();
+    assert!(image.image_data().unwrap().iter().map(|pixel| pixel.0).eq(luma.iter().cloned()));
+}
+
+#[test]
+fn scaled_luma_rows_are_gray() {
+    let data = read("lena.jpeg");
+    let mut decoder = JPEGStreamDecoder::new(&data[..]);
+    decoder.scale(4).unwrap();
+    decoder.luma_only().unwrap();
+    let gray = without_chroma(&data);
+    let mut gray_decoder = JPEGStreamDecoder::new(&gray[..]);
+    gray_decoder.scale(4).unwrap();
+    for (row, gray_row) in decoder.rows().zip(gray_decoder.rows()) {
+        let (row, gray_row) = (row.unwrap(), gray_row.unwrap());
+        assert!(row.pixels.iter().all(|&(r, g, b)| r == g && g == b));
+        assert_eq!(row.pixels, gray_row.pixels);
+    }
+}
+
+/// The image with a DRI segment of `restart_interval` MCUs, and its
+/// entropy coded data coded again with restart markers.
+fn with_restart_interval(data: &[u8], restart_interval: u16) -> Vec<u8> {
+    let mut restarted = data[..2].to_vec();
+    restarted.extend_from_slice(&[0xff, 0xdd, 0, 4]);
+    restarted.extend_from_slice(&restart_interval.to_be_bytes());
+    restarted.extend_from_slice(&data[2..]);
+    let (_, components) = JPEGImage::read_coefficients(data, CoefficientOrder::Zigzag)
+        .unwrap();
+    let mut writer = JPEGSegmentWriter::new(&restarted).unwrap();
+    writer.replace_coefficients_with_optimal_tables(&components).unwrap();
+    writer.to_bytes()
+}
+
+#[test]
+fn lenient_decoding_of_truncated_file_fills_with_gray() {
+    let data = read("lena.jpeg");
+    let original = JPEGImage::parse(data.clone()).unwrap();
+    let truncated = &data[..data.len() / 2];
+    let error = JPEGImage::parse(truncated.to_vec()).unwrap_err();
+    assert!(error.starts_with("The data ends early in MCU "), "{}", error);
+    let without_eoi = data[..data.len() - 2].to_vec();
+    assert_eq!(JPEGImage::parse(without_eoi).unwrap_err(), "The file ends without an EOI marker");
+
+    let image = JPEGImage::decode_lenient(truncated).unwrap();
+    assert_eq!(image.warnings().len(), 2);
+    assert!(image.warnings()[0].starts_with("Scan 1: The data ends early in MCU "));
+    assert_eq!(image.warnings()[1], "The file ends without an EOI marker");
+    let (pixels, width) = (image.image_data().unwrap(), image.width());
+    assert_eq!(pixels[..64 * width], original.image_data().unwrap()[..64 * width]);
+    assert!(pixels[(image.height() - 16) * width..].iter().all(|&pixel| pixel == (128, 128, 128)));
+}
+
+#[test]
+fn lenient_decoding_of_a_scan_which_does_not_start() {
+    let mut data = read("lena.jpeg");
+    let offset = MarkerReader::new(&data)
+        .map(|segment| segment.unwrap())
+        .find(|segment| segment.kind == SegmentKind::Marker(Marker::StartOfScan))
+        .unwrap()
+        .offset;
+    // The third component of the only scan is not in the frame.
+    assert_eq!(offset, 640);
+    data[offset + 9] = 0x43;
+    let error = JPEGImage::parse(data.clone()).unwrap_err();
+    assert_eq!(error, "Scan has component 67, which is not in the frame");
+
+    let image = JPEGImage::decode_lenient(&data).unwrap();
+    assert_eq!(image.warnings(), [error]);
+    assert!(image.image_data().is_none());
+}
+
+#[test]
+fn lenient_decoding_continues_at_restart_marker_after_corruption() {
+    let data = with_restart_interval(&read("lena.jpeg"), 32);
+    let original = JPEGImage::parse(data.clone()).unwrap();
+    assert_eq!(original.restart_interval(), 32);
+
+    // Overwrite the fifth restart interval, which is the MCU row of lines
+    // 32 to 39, with all-ones bits, which is not a Huffman code.
+    let mut corrupt = data.clone();
+    let rst3 = corrupt.windows(2).position(|bytes| bytes == [0xff, 0xd3]).unwrap();
+    let rst4 = corrupt.windows(2).position(|bytes| bytes == [0xff, 0xd4]).unwrap();
+    for pair in corrupt[rst3 + 2..rst4].chunks_mut(2) {
+        pair.copy_from_slice(&[0xff, 0][..pair.len()]);
+    }
+    assert!(JPEGImage::parse(corrupt.clone()).is_err());
+
+    let image = JPEGImage::decode_lenient(&corrupt).unwrap();
+    assert_eq!(image.warnings().len(), 1);
+    assert!(image.warnings()[0].starts_with("Scan 1: Invalid huffman code"));
+    assert!(image.warnings()[0].ends_with("in MCU 128, continuing at RST4 with MCU 160"));
+    let (pixels, width) = (image.image_data().unwrap(), image.width());
+    let original_pixels = original.image_data().unwrap();
+    assert_eq!(pixels[..24 * width], original_pixels[..24 * width]);
+    assert_eq!(pixels[48 * width..], original_pixels[48 * width..]);
+    assert!(pixels[34 * width..38 * width].iter().all(|&pixel| pixel == (128, 128, 128)));
+}
+
+/// The image with a height of `0` in its frame header, and a DNL segment
+/// after its scan which gives the height instead.
+fn with_dnl(data: &[u8]) -> Vec<u8> {
+    let segments = MarkerReader::new(data).collect::<Result<Vec<_>, _>>().unwrap();
+    let frame = segments.iter()
+        .find(|segment| segment.kind == SegmentKind::Marker(Marker::BaselineDCT))
+        .unwrap();
+    let end = segments.iter()
+        .find(|segment| segment.kind == SegmentKind::Marker(Marker::EndOfImage))
+        .unwrap();
+    let mut dnl = data[..end.offset].to_vec();
+    let height = [dnl[frame.offset + 5], dnl[frame.offset + 6]];
+    dnl[frame.offset + 5..frame.offset + 7].copy_from_slice(&[0, 0]);
+    dnl.extend_from_slice(&[0xff, 0xdc, 0, 4, height[0], height[1]]);
+    dnl.extend_from_slice(&data[end.offset..]);
+    dnl
+}
+
+#[test]
+fn height_from_dnl_segment() {
+    let data = read("lena.jpeg");
+    let original = JPEGImage::parse(data.clone()).unwrap();
+    let dnl = with_dnl(&data);
+
+    let mut decoder = JPEGStreamDecoder::new(&dnl[..]);
+    assert_eq!(decoder.read_header().unwrap(), (512, 0));
+    let image = decoder.decode().unwrap();
+    assert_eq!((image.width(), image.height()), (512, 512));
+    assert_eq!(image.image_data(), original.image_data());
+    assert_eq!(JPEGImage::parse(dnl.clone()).unwrap().image_data(), original.image_data());
+
+    let mut push = JPEGPushDecoder::new();
+    for chunk in dnl.chunks(1000) {
+        push.feed(chunk).unwrap();
+    }
+    assert_eq!(push.feed(&[]).unwrap(), DecodeStatus::Done);
+    assert_eq!(push.image().unwrap().image_data(), original.image_data());
+
+    let rows = JPEGStreamDecoder::new(&dnl[..]).rows().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(rows.last().map(|row| row.first_line + row.num_lines), Some(512));
+    let pixels = rows.into_iter().flat_map(|row| row.pixels).collect::<Vec<_>>();
+    assert_eq!(Some(&pixels), original.image_data());
+}
+
+#[test]
+fn dnl_segment_with_more_lines_than_the_scan() {
+    let mut dnl = with_dnl(&read("lena.jpeg"));
+    let end = dnl.len() - 2;
+    dnl[end - 2..end].copy_from_slice(&[0x04, 0]);
+    let error = JPEGImage::parse(dnl).unwrap_err();
+    assert!(error.starts_with("DNL segment defines 1024 lines"), "{}", error);
+}
+
+/// Feed `data` to a push decoder split at `splits`, and check it decodes
+/// the same image as `JPEGImage::parse`.
+fn feed_split(data: &[u8], splits: &[usize]) {
+    let mut push = JPEGPushDecoder::new();
+    let mut start = 0;
+    let mut statuses = Vec::new();
+    for &end in splits.iter().chain(Some(&data.len())) {
+        statuses.push(push.feed(&data[start..end]).unwrap());
+        start = end;
+    }
+    // Only the last piece has the EOI marker.
+    assert_eq!(statuses.pop(), Some(DecodeStatus::Done));
+    assert!(!statuses.contains(&DecodeStatus::Done));
+    let image = JPEGImage::parse(data.to_vec()).unwrap();
+    assert_eq!(push.image().unwrap().image_data(), image.image_data());
+}
+
+#[test]
+fn push_one_byte_at_a_time() {
+    let data = read("working-jpegs/lena-bw.jpeg");
+    let splits = (1..data.len()).collect::<Vec<_>>();
+    feed_split(&data, &splits);
+}
+
+#[test]
+fn push_split_inside_a_marker_segment() {
+    let data = read("lena.jpeg");
+    let segments = MarkerReader::new(&data).map(|s| s.unwrap()).collect::<Vec<_>>();
+    let splits = segments.iter()
+        .filter(|segment| segment.payload.len() > 4 && segment.kind != SegmentKind::EntropyCoded)
+        .map(|segment| segment.offset + 3)
+        .collect::<Vec<_>>();
+    // In the middle of the DQT, DHT, SOF and SOS segments, at least
+    assert!(splits.len() >= 4);
+    feed_split(&data, &splits);
+}
+
+#[test]
+fn push_split_inside_an_mcu() {
+    let data = read("lena.jpeg");
+    let scan = MarkerReader::new(&data)
+        .map(|s| s.unwrap())
+        .find(|segment| segment.kind == SegmentKind::EntropyCoded)
+        .unwrap();
+    // Split every few bytes, which is inside an MCU most of the time.
+    let splits = (scan.offset + 1..scan.offset + scan.payload.len())
+        .step_by(37)
+        .collect::<Vec<_>>();
+    feed_split(&data, &splits);
+}
+
+#[test]
+fn push_after_an_error() {
+    let mut data = read("lena.jpeg");
+    let scan = MarkerReader::new(&data)
+        .map(|s| s.unwrap())
+        .find(|segment| segment.kind == SegmentKind::EntropyCoded)
+        .unwrap()
+        .offset;
+    // A second frame header, which is too short
+    data[scan + 100..scan + 104].copy_from_slice(&[0xff, 0xc0, 0, 2]);
+    let mut push = JPEGPushDecoder::new();
+    let error = push.feed(&data[..scan + 200]).unwrap_err();
+    assert_eq!(push.feed(&data[scan + 200..]).unwrap_err(), error);
+    assert_eq!(push.feed(&[]).unwrap_err(), error);
+    assert!(push.image().is_none());
+}
+
+/// A reader which returns at most `chunk_size` bytes per call, like a
+/// slow socket.
+struct ChunkedReader<'a> {
+    data: &'a [u8],
+    chunk_size: usize,
+}
+
+impl<'a> Read for ChunkedReader<'a> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let length = self.chunk_size.min(buffer.len()).min(self.data.len());
+        buffer[..length].copy_from_slice(&self.data[..length]);
+        self.data = &self.data[length..];
+        Ok(length)
+    }
+}
+
+#[test]
+fn stream_with_short_reads() {
+    // The buffer never holds a whole MCU, so the entropy coded data is
+    // always decoded from a copy of it.
+    let baseline = read("lena.jpeg");
+    let mut writer = JPEGSegmentWriter::new(&baseline).unwrap();
+    writer.make_progressive().unwrap();
+    let progressive = writer.to_bytes();
+    for data in &[baseline, progressive] {
+        let expected = JPEGImage::parse(data.clone()).unwrap();
+        for &chunk_size in &[1, 7] {
+            let reader = ChunkedReader { data, chunk_size };
+            let image = JPEGStreamDecoder::new(reader).decode().unwrap();
+            assert_eq!(image.image_data(), expected.image_data());
+
+            // Rows stop the decoder in the middle of the copy.
+            let reader = ChunkedReader { data, chunk_size };
+            let pixels = JPEGStreamDecoder::new(reader)
+                .rows()
+                .map(|row| row.unwrap().pixels)
+                .collect::<Vec<_>>()
+                .concat();
+            assert_eq!(Some(&pixels), expected.image_data());
+        }
+    }
+}
+
+/// A reader which counts the bytes read from it.
+struct CountingReader<'a> {
+    data: &'a [u8],
+    count: &'a Cell<usize>,
+}
+
+impl<'a> Read for CountingReader<'a> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let length = self.data.read(buffer)?;
+        self.count.set(self.count.get() + length);
+        Ok(length)
+    }
+}
+
+#[test]
+fn rows_only_read_ahead_by_the_buffer() {
+    // A tall gray image of noise, so each row of 8 lines has about the
+    // same number of bytes.
+    let (width, height) = (64, 16384);
+    let mut seed = 1u32;
+    let luma = (0..width * height)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 24) as u8
+        })
+        .collect::<Vec<_>>();
+    let data = JPEGEncoder::new().encode_gray(&luma, width, height).unwrap();
+    let num_rows = height / 8;
+    assert!(data.len() > 8 * 64 * 1024);
+
+    let count = Cell::new(0);
+    let reader = CountingReader { data: &data, count: &count };
+    for (y, row) in JPEGStreamDecoder::new(reader).rows().enumerate() {
+        row.unwrap();
+        // What the rows so far take, and the 64 KiB buffer of the reader
+        let needed = data.len() * (y + 1) / num_rows;
+        assert!(count.get() <= needed + 2 * 64 * 1024,
+                "{} bytes read at row {} of {}",
+                count.get(),
+                y,
+                num_rows);
+    }
+    assert_eq!(count.get(), data.len());
+}
+
+const FORMATS: &[PixelFormat] = &[PixelFormat::RGB,
+                                  PixelFormat::RGBA,
+                                  PixelFormat::BGR,
+                                  PixelFormat::BGRA,
+                                  PixelFormat::ARGB,
+                                  PixelFormat::Gray8,
+                                  PixelFormat::GrayAlpha,
+                                  PixelFormat::RGB565];
+
+/// The bytes `format` has for a pixel with the given color and luma.
+fn pixel_bytes(format: PixelFormat, (r, g, b): (u8, u8, u8), luma: u8) -> Vec<u8> {
+    match format {
+        PixelFormat::RGB => vec![r, g, b],
+        PixelFormat::RGBA => vec![r, g, b, 0xff],
+        PixelFormat::BGR => vec![b, g, r],
+        PixelFormat::BGRA => vec![b, g, r, 0xff],
+        PixelFormat::ARGB => vec![0xff, r, g, b],
+        PixelFormat::Gray8 => vec![luma],
+        PixelFormat::GrayAlpha => vec![luma, 0xff],
+        PixelFormat::RGB565 => {
+            let value = ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3);
+            value.to_le_bytes().to_vec()
+        }
+    }
+}
+
+/// Check that `buffer` has the pixels of a `width` pixels wide image
+/// in `format`, lines `stride` bytes apart, and that the padding after
+/// each line is left as it was.
+fn check_buffer(buffer: &[u8],
+                format: PixelFormat,
+                stride: usize,
+                width: usize,
+                pixels: &[(u8, u8, u8)],
+                luma: &[u8]) {
+    let line_length = width * format.bytes_per_pixel();
+    for (y, line) in buffer.chunks(stride).enumerate() {
+        let expected = (y * width..(y + 1) * width)
+            .flat_map(|i| pixel_bytes(format, pixels[i], luma[i]))
+            .collect::<Vec<_>>();
+        assert!(line[..line_length] == expected[..], "{:?} line {}", format, y);
+        assert!(line[line_length..].iter().all(|&byte| byte == 0xaa), "{:?}", format);
+    }
+}
+
+#[test]
+fn decode_into_every_format_with_padded_lines() {
+    let data = read("lena.jpeg");
+    let image = JPEGImage::parse(data.clone()).unwrap();
+    let (width, height) = (image.width(), image.height());
+    let pixels = image.image_data().unwrap();
+    let (_, luma) = JPEGImage::decode_luma(&data).unwrap();
+    let conversion = ColorConversion::default();
+    let rgb_luma = pixels.iter().map(|&pixel| conversion.luma(pixel)).collect::<Vec<_>>();
+    for &format in FORMATS {
+        let stride = width * format.bytes_per_pixel() + 5;
+        let mut buffer = vec![0xaa; stride * height];
+        JPEGStreamDecoder::new(&data[..]).decode_into(&mut buffer, format, stride).unwrap();
+        // Gray formats get the luma of the image, not of its pixels.
+        check_buffer(&buffer, format, stride, width, pixels, &luma);
+
+        let mut buffer = vec![0xaa; stride * height];
+        image.decode_into(&mut buffer, format, stride).unwrap();
+        check_buffer(&buffer, format, stride, width, pixels, &rgb_luma);
+    }
+}
+
+#[test]
+fn gray_formats_use_the_color_conversion() {
+    let data = read("lena.jpeg");
+    let conversion = ColorConversion::new(Matrix::BT709, Range::Limited);
+    let mut decoder = JPEGStreamDecoder::new(&data[..]);
+    decoder.color_conversion(conversion).unwrap();
+    let (width, height) = decoder.read_header().unwrap();
+    let mut luma = vec![0; width * height];
+    decoder.decode_into(&mut luma, PixelFormat::Gray8, width).unwrap();
+    // Y in limited range is stretched to the full range.
+    let (_, y) = JPEGImage::decode_luma(&data).unwrap();
+    assert!(luma.iter().zip(&y).all(|(&luma, &y)| luma == conversion.gray(y)));
+    assert!(luma != y);
+
+    let mut decoder = JPEGStreamDecoder::new(&data[..]);
+    decoder.color_conversion(conversion).unwrap();
+    let image = decoder.decode().unwrap();
+    let mut buffer = vec![0; width * height];
+    image.decode_into(&mut buffer, PixelFormat::Gray8, width).unwrap();
+    let pixels = image.image_data().unwrap();
+    assert!(buffer.iter().zip(pixels).all(|(&gray, &pixel)| gray == conversion.luma(pixel)));
+    assert!(buffer != y);
+}
+
+/// Decode `data` at `scale / 8` of its size.
+fn decode_scaled(data: &[u8], scale: usize) -> JPEGImage {
+    let mut decoder = JPEGStreamDecoder::new(data);
+    decoder.scale(scale).unwrap();
+    decoder.decode().unwrap()
+}
+
+#[test]
+fn scaled_sizes() {
+    for &name in &["lena.jpeg", "2x2-chroma.jpeg"] {
+        let data = read(name);
+        let full = JPEGImage::parse(data.clone()).unwrap();
+        for &scale in &[1, 3, 6, 13, 16] {
+            let image = decode_scaled(&data, scale);
+            assert_eq!((image.width(), image.height()),
+                       ((full.width() * scale).div_ceil(8), (full.height() * scale).div_ceil(8)));
+            assert_eq!(image.image_data().unwrap().len(), image.width() * image.height());
+        }
+        assert_eq!(decode_scaled(&data, 8).image_data(), full.image_data());
+    }
+}
+
+#[test]
+fn scaled_luma_is_close_to_a_downsampled_one() {
+    let data = read("lena.jpeg");
+    let (image, full) = JPEGImage::decode_luma(&data).unwrap();
+    let full_width = image.width();
+    for &scale in &[1, 2, 4] {
+        let mut decoder = JPEGStreamDecoder::new(&data[..]);
+        decoder.scale(scale).unwrap();
+        let (image, luma) = decoder.decode_luma().unwrap();
+        let (width, factor) = (image.width(), 8 / scale);
+        let mut total_difference = 0;
+        let mut max_difference = 0;
+        for (i, &value) in luma.iter().enumerate() {
+            let (x, y) = (i % width * factor, i / width * factor);
+            // The average of the `factor` x `factor` pixels it covers
+            let sum = (y..y + factor)
+                .flat_map(|y| &full[y * full_width + x..y * full_width + x + factor])
+                .map(|&value| value as i32)
+                .sum::<i32>();
+            let area = (factor * factor) as i32;
+            let difference = (value as i32 - (sum + area / 2) / area).abs();
+            max_difference = max_difference.max(difference);
+            total_difference += difference;
+        }
+        let mean = total_difference as f64 / luma.len() as f64;
+        // At 1/8, each pixel is the DC coefficient of its block, which is
+        // the average. Larger sizes keep only the low frequencies, which
+        // differ from the average at edges.
+        let max_allowed = if scale == 1 { 1 } else { 24 };
+        assert!(max_difference <= max_allowed, "scale {}: {}", scale, max_difference);
+        assert!(mean < 1.5, "scale {}: mean difference {}", scale, mean);
+    }
+}
+
+/// The `width` x `height` rectangle at `(x, y)` of `image`.
+fn crop(image: &JPEGImage, x: usize, y: usize, width: usize, height: usize) -> Vec<(u8, u8, u8)> {
+    let pixels = image.image_data().unwrap();
+    (y..y + height)
+        .flat_map(|line| &pixels[line * image.width() + x..line * image.width() + x + width])
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn region_is_a_crop_of_the_image() {
+    let data = read("lena.jpeg");
+    // Restart intervals of 3 MCUs, which don't line up with the MCU rows
+    // of 32 MCUs, so that some intervals are partly in the region.
+    for data in &[data.clone(), with_restart_interval(&data, 3)] {
+        let full = JPEGImage::parse(data.clone()).unwrap();
+        // Aligned to MCUs, inside one MCU, unaligned across several, at
+        // the bottom right corner, and the whole image
+        for &(x, y, width, height) in &[(16, 8, 32, 16),
+                                        (3, 2, 5, 4),
+                                        (101, 250, 77, 139),
+                                        (500, 505, 12, 7),
+                                        (0, 0, 512, 512)] {
+            let region = JPEGImage::decode_region(data, x, y, width, height).unwrap();
+            assert_eq!((region.width(), region.height()), (width, height));
+            assert!(region.image_data().unwrap()[..] == crop(&full, x, y, width, height)[..],
+                    "{:?}",
+                    (x, y, width, height));
+        }
+    }
+}
+
+#[test]
+fn region_of_a_scaled_image() {
+    let data = with_restart_interval(&read("lena.jpeg"), 5);
+    let full = decode_scaled(&data, 4);
+    let mut decoder = JPEGStreamDecoder::new(&data[..]);
+    decoder.scale(4).unwrap();
+    let region = decoder.decode_region(30, 40, 100, 50).unwrap();
+    assert!(region.image_data().unwrap()[..] == crop(&full, 30, 40, 100, 50)[..]);
+}
+
+#[test]
+fn region_outside_the_image() {
+    let data = read("lena.jpeg");
+    for &(x, y, width, height) in &[(500, 0, 13, 1), (0, 500, 1, 13), (0, 0, 0, 1)] {
+        let error = JPEGImage::decode_region(&data, x, y, width, height).unwrap_err();
+        assert_eq!(error,
+                   format!("Region of {}x{} pixels at ({}, {}) is not inside the image of \
+                            512x512 pixels",
+                           width,
+                           height,
+                           x,
+                           y));
+    }
+}
@@ -0,0 +1,84 @@
+extern crate jpeg_rust;
+
+use std::fs;
+use std::path::Path;
+
+use jpeg_rust::jpeg::JPEGImage;
+use jpeg_rust::jpeg::compress::{ChromaSubsampling, JPEGEncoder};
+
+fn read(name: &str) -> Vec<u8> {
+    fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(name)).unwrap()
+}
+
+/// Peak signal to noise ratio of the components of two images, in dB.
+fn psnr(a: &[(u8, u8, u8)], b: &[(u8, u8, u8)]) -> f64 {
+    let squared_error = a.iter()
+        .zip(b)
+        .flat_map(|(a, b)| vec![(a.0, b.0), (a.1, b.1), (a.2, b.2)])
+        .map(|(a, b)| (a as f64 - b as f64).powi(2))
+        .sum::<f64>();
+    let mean = squared_error / (3 * a.len()) as f64;
+    10.0 * (255.0 * 255.0 / mean).log10()
+}
+
+fn lena() -> JPEGImage {
+    JPEGImage::parse(read("lena.jpeg")).unwrap()
+}
+
+#[test]
+fn encoded_image_is_close_to_the_original() {
+    let image = lena();
+    let pixels = image.image_data().unwrap();
+    for &(subsampling, quality, min_psnr) in &[(ChromaSubsampling::YCbCr420, 75, 30.0),
+                                               (ChromaSubsampling::YCbCr422, 90, 32.0),
+                                               (ChromaSubsampling::YCbCr444, 100, 38.0)] {
+        let mut encoder = JPEGEncoder::new();
+        encoder.chroma_subsampling(subsampling);
+        encoder.quality(quality).unwrap();
+        let data = encoder.encode(pixels, image.width(), image.height()).unwrap();
+        let encoded = JPEGImage::parse(data).unwrap();
+        assert_eq!((encoded.width(), encoded.height()), (image.width(), image.height()));
+        assert!(psnr(pixels, encoded.image_data().unwrap()) > min_psnr);
+    }
+}
+
+#[test]
+fn higher_quality_gives_larger_files() {
+    let image = lena();
+    let sizes = [10, 50, 95]
+        .iter()
+        .map(|&quality| {
+            let mut encoder = JPEGEncoder::new();
+            encoder.quality(quality).unwrap();
+            encoder.encode(image.image_data().unwrap(), image.width(), image.height())
+                .unwrap()
+                .len()
+        })
+        .collect::<Vec<_>>();
+    assert!(sizes[0] < sizes[1] && sizes[1] < sizes[2]);
+    assert!(JPEGEncoder::new().quality(0).is_err());
+    assert!(JPEGEncoder::new().quality(101).is_err());
+}
+
+#[test]
+fn progressive_image_has_the_same_pixels() {
+    let image = lena();
+    let pixels = image.image_data().unwrap();
+    let baseline = JPEGEncoder::new().encode(pixels, image.width(), image.height()).unwrap();
+    let mut encoder = JPEGEncoder::new();
+    encoder.progressive();
+    let progressive = encoder.encode(pixels, image.width(), image.height()).unwrap();
+    assert_eq!(JPEGImage::parse(baseline).unwrap().image_data(),
+               JPEGImage::parse(progressive).unwrap().image_data());
+}
+
+#[test]
+fn gray_image_with_partial_blocks() {
+    let (width, height) = (37, 21);
+    let luma = (0..width * height).map(|i| (i % width * 6) as u8).collect::<Vec<_>>();
+    let data = JPEGEncoder::new().encode_gray(&luma, width, height).unwrap();
+    let (image, decoded) = JPEGImage::decode_luma(&data).unwrap();
+    assert_eq!((image.width(), image.height()), (width, height));
+    assert!(luma.iter().zip(&decoded).all(|(&a, &b)| (a as i32 - b as i32).abs() < 16));
+    assert!(JPEGEncoder::new().encode_gray(&luma, width, height + 1).is_err());
+}
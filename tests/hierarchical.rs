@@ -0,0 +1,97 @@
+extern crate jpeg_rust;
+
+use std::fs;
+use std::path::Path;
+
+use jpeg_rust::jpeg::JPEGImage;
+use jpeg_rust::jpeg::marker::{Marker, MarkerReader, SegmentKind};
+use jpeg_rust::jpeg::stream::JPEGStreamDecoder;
+
+fn read(name: &str) -> Vec<u8> {
+    fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(name)).unwrap()
+}
+
+/// The gray samples the test images were coded from.
+fn source() -> Vec<u8> {
+    let data = read("working-jpegs/hierarchical-source.pgm");
+    let header = b"P5\n37 23\n255\n";
+    assert!(data.starts_with(header));
+    data[header.len()..].to_vec()
+}
+
+/// The largest difference between the pixels of the image in `name` and
+/// the source samples.
+fn max_difference(name: &str) -> u8 {
+    let image = JPEGImage::parse(read(name)).unwrap();
+    assert_eq!((image.width(), image.height()), (37, 23));
+    image.image_data()
+        .unwrap()
+        .iter()
+        .zip(source())
+        .map(|(&(r, g, b), sample)| {
+            assert!(r == g && g == b);
+            (r as i32 - sample as i32).unsigned_abs() as u8
+        })
+        .max()
+        .unwrap()
+}
+
+/// The offset of the payload of the first `marker` segment in `data`.
+fn payload_offset(data: &[u8], marker: Marker) -> usize {
+    MarkerReader::new(data)
+        .map(|segment| segment.unwrap())
+        .find(|segment| segment.kind == SegmentKind::Marker(marker))
+        .map(|segment| segment.offset + 4)
+        .unwrap()
+}
+
+#[test]
+fn lossless_image_is_the_source() {
+    assert_eq!(max_difference("working-jpegs/lossless.jpg"), 0);
+    // Predictor 7, with a restart interval of three lines
+    assert_eq!(max_difference("working-jpegs/lossless-restart.jpg"), 0);
+}
+
+#[test]
+fn hierarchical_lossless_image_is_the_source() {
+    // A lossless frame of half the size, and a differential frame which
+    // is upsampled from it.
+    assert_eq!(max_difference("working-jpegs/hierarchical-lossless.jpg"), 0);
+}
+
+#[test]
+fn hierarchical_dct_image_is_close_to_the_source() {
+    // A DCT frame of a quarter of the width and half the height, and two
+    // differential frames, the first of which is upsampled horizontally.
+    assert!(max_difference("working-jpegs/hierarchical-dct.jpg") <= 2);
+}
+
+#[test]
+fn point_transform_must_be_less_than_the_precision() {
+    let mut data = read("working-jpegs/lossless.jpg");
+    let offset = payload_offset(&data, Marker::StartOfScan);
+    // The last byte of the scan header has the point transform.
+    data[offset + 5] = 8;
+    let error = JPEGImage::parse(data).unwrap_err();
+    assert_eq!(error, "Invalid point transform 8 in lossless scan of 8-bit samples");
+}
+
+#[test]
+fn last_frame_must_have_the_size_of_the_dhp_segment() {
+    let mut data = read("working-jpegs/hierarchical-lossless.jpg");
+    let offset = payload_offset(&data, Marker::DefineHierarchicalProgression);
+    // The number of lines is after the sample precision.
+    data[offset + 2] = 24;
+    let error = JPEGImage::parse(data).unwrap_err();
+    assert_eq!(error, "Component 1 is 37x23, but the DHP segment makes it 37x24");
+}
+
+#[test]
+fn rows_of_a_lossless_image_are_single_lines() {
+    let data = read("working-jpegs/lossless-restart.jpg");
+    let rows = JPEGStreamDecoder::new(&data[..]).rows().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(rows.len(), 23);
+    assert!(rows.iter().enumerate().all(|(y, row)| row.first_line == y && row.num_lines == 1));
+    let pixels = rows.into_iter().flat_map(|row| row.pixels).collect::<Vec<_>>();
+    assert_eq!(Some(&pixels), JPEGImage::parse(data).unwrap().image_data());
+}
@@ -0,0 +1,109 @@
+extern crate jpeg_rust;
+
+use jpeg_rust::jpeg::marker::{Marker, MarkerReader, SegmentKind};
+
+/// The kind, offset and payload of a segment
+type Segment = (SegmentKind, usize, Vec<u8>);
+
+/// The segments of `data`, up to the first error.
+fn segments(data: &[u8]) -> (Vec<Segment>, Option<String>) {
+    let mut segments = Vec::new();
+    let mut reader = MarkerReader::new(data);
+    for segment in &mut reader {
+        match segment {
+            Ok(segment) => segments.push((segment.kind, segment.offset, segment.payload.to_vec())),
+            Err(error) => {
+                // The reader stops after an error.
+                assert!(reader.next().is_none());
+                return (segments, Some(error));
+            }
+        }
+    }
+    (segments, None)
+}
+
+#[test]
+fn every_code_round_trips() {
+    for code in 0x01..=0xfe {
+        assert_eq!(Marker::from_code(code).code(), code);
+    }
+    assert_eq!(Marker::from_code(0x01), Marker::Temporary);
+    assert_eq!(Marker::from_code(0x02), Marker::Reserved(0x02));
+    assert_eq!(Marker::from_code(0xbf), Marker::Reserved(0xbf));
+    assert_eq!(Marker::from_code(0xc3), Marker::Lossless);
+    assert_eq!(Marker::from_code(0xd3), Marker::Restart(3));
+    assert_eq!(Marker::from_code(0xe1), Marker::ApplicationSegment(1));
+    assert_eq!(Marker::from_code(0xfd), Marker::JPEGExtension(13));
+}
+
+#[test]
+fn only_tem_rst_soi_and_eoi_stand_alone() {
+    for code in 0x01..=0xfe {
+        let stands_alone = code == 0x01 || (0xd0..=0xd9).contains(&code);
+        assert_eq!(Marker::from_code(code).has_length(), !stands_alone, "{:02x}", code);
+    }
+}
+
+#[test]
+fn segments_of_a_file() {
+    let data = [0xff, 0xd8,
+                // Fill bytes, and a comment
+                0xff, 0xff, 0xff, 0xfe, 0, 4, b'h', b'i',
+                0xff, 0xda, 0, 5, 1, 2, 3,
+                // A stuffed 0xff in the entropy coded data, which a
+                // restart marker splits in two
+                0x12, 0xff, 0x00, 0x34,
+                0xff, 0xd0,
+                0x56,
+                0xff, 0xd9,
+                b'x', 0xff, 0xd8];
+    let (found, error) = segments(&data);
+    assert_eq!(error, None);
+    assert_eq!(found,
+               [(SegmentKind::Marker(Marker::StartOfImage), 0, vec![]),
+                (SegmentKind::Fill, 2, vec![0xff, 0xff]),
+                (SegmentKind::Marker(Marker::Comment), 4, b"hi".to_vec()),
+                (SegmentKind::Marker(Marker::StartOfScan), 10, vec![1, 2, 3]),
+                (SegmentKind::EntropyCoded, 17, vec![0x12, 0xff, 0x00, 0x34]),
+                (SegmentKind::Marker(Marker::Restart(0)), 21, vec![]),
+                (SegmentKind::EntropyCoded, 23, vec![0x56]),
+                (SegmentKind::Marker(Marker::EndOfImage), 24, vec![]),
+                // Not read as markers, even if it has some
+                (SegmentKind::Trailer, 26, vec![b'x', 0xff, 0xd8])]);
+}
+
+#[test]
+fn data_outside_a_scan() {
+    let (found, error) = segments(&[0xff, 0xd8, 0x12]);
+    assert_eq!(found.len(), 1);
+    assert_eq!(error.unwrap(), "Expected a marker at byte 2, found 12");
+    // A `0xff` at the end can't be a marker either.
+    let (_, error) = segments(&[0xff, 0xd8, 0xff]);
+    assert_eq!(error.unwrap(), "Expected a marker at byte 2, found ff");
+}
+
+#[test]
+fn truncated_length() {
+    let (found, error) = segments(&[0xff, 0xd8, 0xff, 0xdb, 0]);
+    assert_eq!(found.len(), 1);
+    assert_eq!(error.unwrap(), "QuantizationTable marker at byte 2 has no length");
+}
+
+#[test]
+fn invalid_lengths() {
+    let (_, error) = segments(&[0xff, 0xd8, 0xff, 0xfe, 0, 1, 0]);
+    assert_eq!(error.unwrap(), "Comment marker at byte 2 has invalid length 1");
+    let (_, error) = segments(&[0xff, 0xd8, 0xff, 0xfe, 0, 8, 0]);
+    assert_eq!(error.unwrap(), "Comment marker at byte 2 has invalid length 8");
+}
+
+#[test]
+fn lossless_frames() {
+    for code in 0x01..=0xfe {
+        let marker = Marker::from_code(code);
+        assert_eq!(marker.is_lossless(),
+                   marker.is_start_of_frame() && code & 3 == 3,
+                   "{:02x}",
+                   code);
+    }
+}
@@ -0,0 +1,169 @@
+extern crate jpeg_rust;
+
+use std::fs;
+use std::path::Path;
+
+use jpeg_rust::jpeg::JPEGImage;
+use jpeg_rust::jpeg::icc;
+use jpeg_rust::jpeg::marker::Marker;
+use jpeg_rust::jpeg::transform::{PartialMCUs, Transform};
+use jpeg_rust::jpeg::writer::{JPEGSegmentWriter, Segment};
+
+fn read(name: &str) -> Vec<u8> {
+    fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(name)).unwrap()
+}
+
+/// An EXIF segment with an empty IFD0, and IFD1 pointing to `thumbnail`.
+fn exif_with_thumbnail(thumbnail: &[u8]) -> Vec<u8> {
+    let mut data = b"Exif\0\0MM\0\x2a\0\0\0\x08".to_vec();
+    // IFD0, with no entries and IFD1 at offset 14
+    data.extend_from_slice(&[0, 0, 0, 0, 0, 14]);
+    // IFD1, with the offset and length of the thumbnail, which follows
+    data.extend_from_slice(&[0, 2]);
+    data.extend_from_slice(&[0x02, 0x01, 0, 4, 0, 0, 0, 1, 0, 0, 0, 44]);
+    data.extend_from_slice(&[0x02, 0x02, 0, 4, 0, 0, 0, 1, 0, 0, 0, thumbnail.len() as u8]);
+    data.extend_from_slice(&[0, 0, 0, 0]);
+    data.extend_from_slice(thumbnail);
+    data
+}
+
+/// An EXIF segment whose IFD0 has an orientation of 6 and points to a
+/// GPS IFD with the latitude reference.
+fn exif_with_gps() -> Vec<u8> {
+    let mut data = b"Exif\0\0MM\0\x2a\0\0\0\x08".to_vec();
+    // IFD0, with the GPS IFD at offset 38
+    data.extend_from_slice(&[0, 2]);
+    data.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+    data.extend_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 38]);
+    data.extend_from_slice(&[0, 0, 0, 0]);
+    // The GPS IFD, with "N" as the latitude reference
+    data.extend_from_slice(&[0, 1]);
+    data.extend_from_slice(&[0, 1, 0, 2, 0, 0, 0, 2, b'N', 0, 0, 0]);
+    data.extend_from_slice(&[0, 0, 0, 0]);
+    data
+}
+
+/// The data of the first APP1 segment.
+fn app1(writer: &JPEGSegmentWriter) -> &[u8] {
+    writer.segments()
+        .iter()
+        .find_map(|segment| match *segment {
+            Segment::MarkerSegment(Marker::ApplicationSegment(1), ref data) => Some(&data[..]),
+            _ => None,
+        })
+        .unwrap()
+}
+
+#[test]
+fn strip_gps() {
+    let mut writer = JPEGSegmentWriter::new(&read("lena.jpeg")).unwrap();
+    writer.insert_application_segment(1, exif_with_gps()).unwrap();
+    assert_eq!(writer.strip_gps(), Ok(true));
+    assert_eq!(writer.strip_gps(), Ok(false));
+
+    // IFD0 only has the orientation left, and the GPS IFD is zeroed out.
+    let tiff = &app1(&writer)[6..];
+    assert_eq!(tiff[8..10], [0, 1]);
+    assert_eq!(tiff[10..22], [0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+    assert_eq!(tiff[22..26], [0, 0, 0, 0]);
+    assert!(tiff[38..].iter().all(|&byte| byte == 0));
+    // The image itself is not touched.
+    assert_eq!(JPEGImage::parse(writer.to_bytes()).unwrap().image_data(),
+               JPEGImage::parse(read("lena.jpeg")).unwrap().image_data());
+}
+
+#[test]
+fn set_and_add_comments() {
+    let mut writer = JPEGSegmentWriter::new(&read("lena.jpeg")).unwrap();
+    assert_eq!(writer.comments(), ["Handmade Software, Inc. Image Alchemy v1.9\n"]);
+    writer.add_comment("first").unwrap();
+    writer.add_comment("second").unwrap();
+    assert_eq!(writer.comments()[1..], ["first", "second"]);
+    writer.set_comment("only").unwrap();
+    assert_eq!(writer.comments(), ["only"]);
+
+    // Comments go after the other metadata, before the tables.
+    let markers = writer.segments()
+        .iter()
+        .take(3)
+        .map(|segment| match *segment {
+            Segment::Marker(marker) | Segment::MarkerSegment(marker, _) => marker,
+            _ => panic!("{:?}", segment),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(markers, [Marker::StartOfImage, Marker::ApplicationSegment(0), Marker::Comment]);
+    assert_eq!(JPEGImage::parse(writer.to_bytes()).unwrap().comment(), Some("only"));
+
+    let long = "x".repeat(0x10000);
+    assert!(writer.set_comment(&long).is_err());
+    assert!(writer.add_comment(&long).is_err());
+    assert_eq!(writer.comments(), ["only"]);
+}
+
+#[test]
+fn application_segments_only_go_up_to_15() {
+    let mut writer = JPEGSegmentWriter::new(&read("lena.jpeg")).unwrap();
+    assert!(writer.insert_application_segment(16, Vec::new()).is_err());
+    assert!(writer.replace_application_segment(16, b"", Vec::new()).is_err());
+    assert!(writer.remove_application_segments(16, b"").is_err());
+    assert!(writer.remove_application_segments(15, b"").is_ok());
+}
+
+#[test]
+fn transform_fails_with_broken_exif() {
+    let mut writer = JPEGSegmentWriter::new(&read("lena.jpeg")).unwrap();
+    writer.insert_application_segment(1, b"Exif\0\0XX".to_vec()).unwrap();
+    let original = writer.to_bytes();
+    assert!(writer.transform(Transform::Rotate180, PartialMCUs::Trim).is_err());
+    assert_eq!(writer.to_bytes(), original);
+}
+
+#[test]
+fn exif_thumbnail() {
+    let mut writer = JPEGSegmentWriter::new(&read("lena.jpeg")).unwrap();
+    assert_eq!(writer.exif_thumbnail(), Ok(None));
+    writer.insert_application_segment(1, exif_with_thumbnail(b"\xff\xd8thumb\xff\xd9")).unwrap();
+    assert_eq!(writer.exif_thumbnail(), Ok(Some(&b"\xff\xd8thumb\xff\xd9"[..])));
+}
+
+#[test]
+fn icc_profile_chunks_are_joined_in_order() {
+    let mut writer = JPEGSegmentWriter::new(&read("lena.jpeg")).unwrap();
+    assert_eq!(writer.icc_profile(), Ok(None));
+    for &(number, chunk) in &[(2, &b"world"[..]), (1, &b"hello "[..])] {
+        let mut data = icc::ICC_IDENTIFIER.to_vec();
+        data.extend_from_slice(&[number, 2]);
+        data.extend_from_slice(chunk);
+        writer.insert_application_segment(2, data).unwrap();
+    }
+    assert_eq!(writer.icc_profile(), Ok(Some(b"hello world".to_vec())));
+}
+
+#[test]
+fn missing_icc_chunk() {
+    let mut chunk = icc::ICC_IDENTIFIER.to_vec();
+    chunk.extend_from_slice(&[1, 2, 0]);
+    assert!(icc::join_chunks(vec![&chunk[..]]).is_err());
+    chunk[icc::ICC_IDENTIFIER.len()] = 3;
+    assert!(icc::join_chunks(vec![&chunk[..]]).is_err());
+}
+
+/// A writer for lena.jpeg with an EXIF segment whose IFD0 has one entry
+/// with the given tag and type, and no values.
+fn with_empty_exif_entry(tag: u16, field_type: u8) -> JPEGSegmentWriter {
+    let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+    exif.extend_from_slice(&[(tag >> 8) as u8, tag as u8, 0, field_type, 0, 0, 0, 0]);
+    exif.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut writer = JPEGSegmentWriter::new(&read("lena.jpeg")).unwrap();
+    writer.insert_application_segment(1, exif).unwrap();
+    writer
+}
+
+#[test]
+fn exif_entries_without_a_value() {
+    // An orientation of no bytes, and a GPS pointer of no longs
+    let mut writer = with_empty_exif_entry(0x0112, 7);
+    assert_eq!(writer.reset_orientation().unwrap_err(), "TIFF tag 0112 has no value");
+    let mut writer = with_empty_exif_entry(0x8825, 4);
+    assert_eq!(writer.strip_gps().unwrap_err(), "TIFF tag 8825 has no value");
+}
@@ -0,0 +1,191 @@
+extern crate jpeg_rust;
+
+use std::fs;
+use std::path::Path;
+
+use jpeg_rust::jpeg::JPEGImage;
+use jpeg_rust::jpeg::icc;
+use jpeg_rust::jpeg::marker::Marker;
+use jpeg_rust::jpeg::mpf::MPFImageType;
+use jpeg_rust::jpeg::writer::{JPEGSegmentWriter, Segment};
+
+fn read(name: &str) -> Vec<u8> {
+    fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(name)).unwrap()
+}
+
+/// `primary` with an APP2 segment after SOI whose MP index lists it and
+/// `secondary`, which is put after its EOI. The MP header is in little
+/// or big endian byte order.
+fn with_mpf(primary: &[u8], secondary: &[u8], little_endian: bool) -> Vec<u8> {
+    let u16_bytes = |n: u16| if little_endian { n.to_le_bytes() } else { n.to_be_bytes() };
+    let u32_bytes = |n: u32| if little_endian { n.to_le_bytes() } else { n.to_be_bytes() };
+    // SOI, the APP2 marker and length, and "MPF\0" come before the MP
+    // header, which the offsets are relative to.
+    let header_offset = 2 + 4 + 4;
+    let primary_size = primary.len() + 4 + 4 + 8 + 42 + 32;
+
+    let mut header = if little_endian { b"II\x2a\0".to_vec() } else { b"MM\0\x2a".to_vec() };
+    header.extend_from_slice(&u32_bytes(8));
+    // The MP index IFD, with the version, number of images and entries,
+    // which follow the IFD at offset 50.
+    header.extend_from_slice(&u16_bytes(3));
+    for &(tag, kind, count, value) in &[(0xb000, 7, 4, u32::from_be_bytes(*b"0100")),
+                                        (0xb001, 4, 1, 2),
+                                        (0xb002, 7, 32, 50)] {
+        header.extend_from_slice(&u16_bytes(tag));
+        header.extend_from_slice(&u16_bytes(kind));
+        header.extend_from_slice(&u32_bytes(count));
+        if tag == 0xb000 {
+            header.extend_from_slice(&value.to_be_bytes());
+        } else {
+            header.extend_from_slice(&u32_bytes(value));
+        }
+    }
+    header.extend_from_slice(&u32_bytes(0));
+    // A representative baseline primary image, and a large thumbnail
+    for &(attribute, size, offset) in
+        &[(0x2003_0000, primary_size, 0),
+          (0x0001_0001, secondary.len(), primary_size - header_offset)] {
+        header.extend_from_slice(&u32_bytes(attribute));
+        header.extend_from_slice(&u32_bytes(size as u32));
+        header.extend_from_slice(&u32_bytes(offset as u32));
+        header.extend_from_slice(&u32_bytes(0));
+    }
+
+    let mut data = primary[..2].to_vec();
+    data.extend_from_slice(&[0xff, 0xe2]);
+    data.extend_from_slice(&(2 + 4 + header.len() as u16).to_be_bytes());
+    data.extend_from_slice(b"MPF\0");
+    data.extend_from_slice(&header);
+    data.extend_from_slice(&primary[2..]);
+    assert_eq!(data.len(), primary_size);
+    data.extend_from_slice(secondary);
+    data
+}
+
+#[test]
+fn index_lists_primary_and_secondary_image() {
+    let (primary, secondary) = (read("lena.jpeg"), read("2x2-chroma.jpeg"));
+    for &little_endian in &[false, true] {
+        let image = JPEGImage::parse(with_mpf(&primary, &secondary, little_endian)).unwrap();
+        let index = image.mpf_index().unwrap();
+        assert_eq!(index.version, b"0100");
+        assert_eq!(image.num_embedded_images(), 2);
+
+        let entry = &index.entries[0];
+        assert_eq!(entry.image_type, MPFImageType::BaselinePrimary);
+        assert!(entry.is_representative && !entry.is_dependent_child);
+        assert_eq!((entry.offset, entry.size), (0, primary.len() + 90));
+        let entry = &index.entries[1];
+        assert_eq!(entry.image_type, MPFImageType::LargeThumbnailVGA);
+        assert!(!entry.is_representative);
+        assert_eq!((entry.offset, entry.size), (primary.len() + 90, secondary.len()));
+    }
+}
+
+#[test]
+fn secondary_image_after_eoi_is_extracted() {
+    let (primary, secondary) = (read("lena.jpeg"), read("2x2-chroma.jpeg"));
+    let image = JPEGImage::parse(with_mpf(&primary, &secondary, false)).unwrap();
+    // Parsing stops at EOI, so the primary image is decoded as it is.
+    assert_eq!(image.image_data(), JPEGImage::parse(primary).unwrap().image_data());
+
+    assert_eq!(image.embedded_image_data(1).unwrap(), &secondary[..]);
+    let embedded = image.embedded_image(1).unwrap();
+    assert_eq!(embedded.image_data(), JPEGImage::parse(secondary).unwrap().image_data());
+    assert!(image.embedded_image_data(2).is_err());
+}
+
+#[test]
+fn secondary_image_past_the_end_of_the_file() {
+    let (primary, secondary) = (read("lena.jpeg"), read("2x2-chroma.jpeg"));
+    let mut data = with_mpf(&primary, &secondary, true);
+    data.truncate(data.len() - 1);
+    let image = JPEGImage::parse(data).unwrap();
+    let error = image.embedded_image_data(1).unwrap_err();
+    assert!(error.starts_with("Embedded image 1 ends at byte"));
+}
+
+#[test]
+fn broken_index_is_a_warning() {
+    let primary = read("lena.jpeg");
+    let original = JPEGImage::parse(primary.clone()).unwrap();
+    let mut data = with_mpf(&primary, &read("2x2-chroma.jpeg"), false);
+    // The number of images is UNDEFINED with no bytes. Its entry is the
+    // second of the IFD at offset 8 of the MP header at byte 10.
+    data[34..36].copy_from_slice(&[0, 7]);
+    data[36..40].copy_from_slice(&[0, 0, 0, 0]);
+    let image = JPEGImage::parse(data).unwrap();
+    assert!(image.mpf_index().is_none());
+    assert_eq!(image.warnings(), ["Ignoring the MPF index: TIFF tag b001 has no value"]);
+    assert_eq!(image.image_data(), original.image_data());
+
+    // Not even a TIFF header
+    let mut data = primary[..2].to_vec();
+    data.extend_from_slice(&[0xff, 0xe2, 0, 14]);
+    data.extend_from_slice(b"MPF\0XXXXXXXX");
+    data.extend_from_slice(&primary[2..]);
+    let image = JPEGImage::parse(data).unwrap();
+    assert!(image.mpf_index().is_none());
+    assert_eq!(image.warnings(), ["Ignoring the MPF index: Unknown TIFF byte order: 58 58"]);
+    assert_eq!(image.image_data(), original.image_data());
+}
+
+/// `image` with an EXIF segment whose IFD0 points to a GPS IFD with the
+/// latitude reference.
+fn with_gps(image: &[u8]) -> Vec<u8> {
+    let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08".to_vec();
+    // IFD0, with the GPS IFD at offset 26
+    exif.extend_from_slice(&[0, 1]);
+    exif.extend_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 26]);
+    exif.extend_from_slice(&[0, 0, 0, 0]);
+    // The GPS IFD, with "N" as the latitude reference
+    exif.extend_from_slice(&[0, 1]);
+    exif.extend_from_slice(&[0, 1, 0, 2, 0, 0, 0, 2, b'N', 0, 0, 0]);
+    exif.extend_from_slice(&[0, 0, 0, 0]);
+    let mut writer = JPEGSegmentWriter::new(image).unwrap();
+    writer.insert_application_segment(1, exif).unwrap();
+    writer.to_bytes()
+}
+
+#[test]
+fn strip_gps_in_secondary_image() {
+    let (primary, secondary) = (read("lena.jpeg"), with_gps(&read("2x2-chroma.jpeg")));
+    let mut writer = JPEGSegmentWriter::new(&with_mpf(&primary, &secondary, true)).unwrap();
+    assert_eq!(writer.strip_gps(), Ok(true));
+    assert_eq!(writer.strip_gps(), Ok(false));
+
+    // Nothing is moved, so the index still finds the secondary image.
+    let image = JPEGImage::parse(writer.to_bytes()).unwrap();
+    let stripped = image.embedded_image_data(1).unwrap();
+    assert_eq!(stripped.len(), secondary.len());
+    assert_eq!(JPEGSegmentWriter::new(stripped).unwrap().strip_gps(), Ok(false));
+    assert_eq!(image.embedded_image(1).unwrap().image_data(),
+               JPEGImage::parse(secondary).unwrap().image_data());
+}
+
+#[test]
+fn remove_all_metadata_keeps_icc_and_drops_secondary_images() {
+    let (primary, secondary) = (read("lena.jpeg"), read("2x2-chroma.jpeg"));
+    let mut writer = JPEGSegmentWriter::new(&with_mpf(&primary, &secondary, false)).unwrap();
+    let mut icc_profile = icc::ICC_IDENTIFIER.to_vec();
+    icc_profile.extend_from_slice(&[1, 1]);
+    icc_profile.extend_from_slice(b"profile");
+    writer.insert_application_segment(2, icc_profile).unwrap();
+
+    writer.remove_all_metadata();
+    assert_eq!(writer.icc_profile(), Ok(Some(b"profile".to_vec())));
+    let is_left = |segment: &Segment| match *segment {
+        Segment::MarkerSegment(Marker::Comment, _) |
+        Segment::Trailer(_) => true,
+        Segment::MarkerSegment(Marker::ApplicationSegment(2), ref data) => {
+            !data.starts_with(icc::ICC_IDENTIFIER)
+        }
+        _ => false,
+    };
+    assert!(!writer.segments().iter().any(is_left));
+
+    let image = JPEGImage::parse(writer.to_bytes()).unwrap();
+    assert!(image.mpf_index().is_none());
+    assert_eq!(image.image_data(), JPEGImage::parse(primary).unwrap().image_data());
+}
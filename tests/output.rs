@@ -0,0 +1,302 @@
+extern crate jpeg_rust;
+
+use std::path::Path;
+
+use jpeg_rust::output::{self, ColorType, Format, Image};
+use jpeg_rust::output::zlib;
+
+fn u32_be(data: &[u8]) -> u32 {
+    (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
+}
+
+#[test]
+fn formats_from_extensions() {
+    assert_eq!(Format::from_path(Path::new("a/b.PNG")), Ok(Format::PNG));
+    assert_eq!(Format::from_path(Path::new("b.pam")), Ok(Format::PAM));
+    assert!(Format::from_path(Path::new("b.gif")).is_err());
+    assert!(Format::from_path(Path::new("b")).is_err());
+}
+
+#[test]
+fn pixels_are_converted_to_what_the_format_holds() {
+    assert_eq!(Format::PPM.color_type_for(ColorType::Gray), Some(ColorType::RGB));
+    assert_eq!(Format::PGM.color_type_for(ColorType::RGB), Some(ColorType::Gray));
+    assert_eq!(Format::TGA.color_type_for(ColorType::GrayAlpha), Some(ColorType::RGBA));
+    assert_eq!(Format::PNG.color_type_for(ColorType::CMYK), None);
+
+    let image = Image::new(&[0, 128, 255], 3, 1, ColorType::Gray).unwrap();
+    let ppm = output::encode(&image, Format::PPM).unwrap();
+    assert_eq!(ppm, b"P6\n3 1\n255\n\x00\x00\x00\x80\x80\x80\xff\xff\xff".to_vec());
+    assert!(output::encode(&Image::new(&[0; 4], 1, 1, ColorType::CMYK).unwrap(), Format::BMP)
+        .is_err());
+}
+
+#[test]
+fn image_size_is_checked() {
+    assert!(Image::new(&[0; 6], 2, 1, ColorType::RGB).is_ok());
+    assert!(Image::new(&[0; 5], 2, 1, ColorType::RGB).is_err());
+    assert!(Image::new(&[], 0, 1, ColorType::Gray).is_err());
+}
+
+#[test]
+fn pam_tuple_types() {
+    let image = Image::new(&[1, 2, 3, 4], 1, 1, ColorType::CMYK).unwrap();
+    let pam = output::encode(&image, Format::PAM).unwrap();
+    assert_eq!(pam,
+               b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 4\nMAXVAL 255\nTUPLTYPE CMYK\nENDHDR\n\x01\x02\x03\x04"
+                   .to_vec());
+}
+
+#[test]
+fn bmp_lines_are_padded_and_bottom_up() {
+    let pixels = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+    let image = Image::new(&pixels, 2, 2, ColorType::RGB).unwrap();
+    let bmp = output::encode(&image, Format::BMP).unwrap();
+    assert_eq!(&bmp[..2], b"BM");
+    assert_eq!(bmp.len(), 54 + 2 * 8);
+    assert_eq!(&bmp[54..], &[9, 8, 7, 12, 11, 10, 0, 0, 3, 2, 1, 6, 5, 4, 0, 0]);
+}
+
+#[test]
+fn tga_is_top_down_bgra() {
+    let image = Image::new(&[1, 2, 3, 4], 1, 1, ColorType::RGBA).unwrap();
+    let tga = output::encode(&image, Format::TGA).unwrap();
+    assert_eq!(tga[2], 2);
+    assert_eq!(tga[16], 32);
+    assert_eq!(tga[17], 0x28);
+    assert_eq!(&tga[18..22], &[3, 2, 1, 4]);
+    assert!(tga.ends_with(b"TRUEVISION-XFILE.\0"));
+}
+
+#[test]
+fn png_chunks() {
+    let pixels: Vec<u8> = (0..64 * 48 * 3).map(|n| (n % 251) as u8).collect();
+    let image = Image::new(&pixels, 64, 48, ColorType::RGB).unwrap();
+    let png = output::encode(&image, Format::PNG).unwrap();
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+
+    let mut names = Vec::new();
+    let mut position = 8;
+    while position < png.len() {
+        let length = u32_be(&png[position..]) as usize;
+        names.push(png[position + 4..position + 8].to_vec());
+        position += 12 + length;
+    }
+    assert_eq!(position, png.len());
+    assert_eq!(names.first().unwrap(), b"IHDR");
+    assert_eq!(names.last().unwrap(), b"IEND");
+    assert!(names.iter().any(|name| name == b"IDAT"));
+    assert_eq!(&png[16..26], &[0, 0, 0, 64, 0, 0, 0, 48, 8, 2]);
+    // The CRC of IEND, which has no data, is always the same.
+    assert_eq!(&png[png.len() - 4..], &[0xae, 0x42, 0x60, 0x82]);
+}
+
+#[test]
+fn zlib_stream() {
+    assert_eq!(zlib::adler32(b"Wikipedia"), 0x11e6_0398);
+    assert_eq!(zlib::compress(b""), vec![0x78, 0x9c, 3, 0, 0, 0, 0, 1]);
+
+    let data: Vec<u8> = b"abcabcabd".iter().cycle().take(90000).cloned().collect();
+    let compressed = zlib::compress(&data);
+    assert!(compressed.len() < 1000);
+    assert_eq!(&compressed[..2], &[0x78, 0x9c]);
+    assert_eq!(u32_be(&compressed[compressed.len() - 4..]), zlib::adler32(&data));
+}
+
+/// Reads bits from the least significant bit of each byte on.
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, length: usize) -> usize {
+        let mut value = 0;
+        for i in 0..length {
+            let byte = self.data[self.position / 8];
+            value |= ((byte >> (self.position % 8)) as usize & 1) << i;
+            self.position += 1;
+        }
+        value
+    }
+
+    fn align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length and
+/// the symbols in the order of their codes. See RFC 1951 section 3.2.2.
+struct HuffmanDecoder {
+    counts: [usize; 16],
+    symbols: Vec<usize>,
+}
+
+impl HuffmanDecoder {
+    fn new(lengths: &[usize]) -> HuffmanDecoder {
+        let mut counts = [0; 16];
+        let mut symbols = Vec::new();
+        for (length, count) in counts.iter_mut().enumerate().skip(1) {
+            for (symbol, _) in lengths.iter().enumerate().filter(|&(_, &l)| l == length) {
+                *count += 1;
+                symbols.push(symbol);
+            }
+        }
+        HuffmanDecoder { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> usize {
+        // The first code of each length, and the index of its symbol
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1);
+            if code - first < count {
+                return self.symbols[index + code - first];
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        panic!("Invalid Huffman code at bit {}", reader.position);
+    }
+}
+
+/// The smallest value of each length or distance code, with its number
+/// of extra bits, where the first `flat` codes have none and every
+/// `step` codes after that have one more. See RFC 1951 section 3.2.5.
+fn code_bases(num_codes: usize, first: usize, flat: usize, step: usize) -> Vec<(usize, usize)> {
+    let mut bases = Vec::new();
+    let mut base = first;
+    for code in 0..num_codes {
+        let extra_bits = if code < flat { 0 } else { (code - flat) / step + 1 };
+        bases.push((base, extra_bits));
+        base += 1 << extra_bits;
+    }
+    bases
+}
+
+/// Decode the symbols of a compressed block into `output`.
+fn inflate_block(reader: &mut BitReader,
+                 output: &mut Vec<u8>,
+                 literals: &HuffmanDecoder,
+                 distances: &HuffmanDecoder) {
+    let mut lengths = code_bases(28, 3, 8, 4);
+    // The longest length has a code of its own.
+    lengths.push((258, 0));
+    let distance_bases = code_bases(30, 1, 4, 2);
+    loop {
+        let symbol = literals.decode(reader);
+        if symbol < 256 {
+            output.push(symbol as u8);
+            continue;
+        } else if symbol == 256 {
+            return;
+        }
+        let (base, extra_bits) = lengths[symbol - 257];
+        let length = base + reader.bits(extra_bits);
+        let (base, extra_bits) = distance_bases[distances.decode(reader)];
+        let distance = base + reader.bits(extra_bits);
+        assert!(distance <= output.len().min(32768));
+        for _ in 0..length {
+            let byte = output[output.len() - distance];
+            output.push(byte);
+        }
+    }
+}
+
+/// Decompress a zlib stream, checking its header and checksum.
+fn inflate(stream: &[u8]) -> Vec<u8> {
+    assert_eq!(&stream[..2], &[0x78, 0x9c]);
+    let mut reader = BitReader {
+        data: &stream[2..],
+        position: 0,
+    };
+    let mut output = Vec::new();
+    loop {
+        let last = reader.bits(1) == 1;
+        match reader.bits(2) {
+            0 => {
+                reader.align();
+                let (data, start) = (reader.data, reader.position / 8);
+                let u16_le = |i: usize| data[i] as usize | (data[i + 1] as usize) << 8;
+                let length = u16_le(start);
+                assert_eq!(length ^ u16_le(start + 2), 0xffff);
+                output.extend_from_slice(&data[start + 4..start + 4 + length]);
+                reader.position = (start + 4 + length) * 8;
+            }
+            1 => {
+                let mut literal_lengths = vec![8; 288];
+                literal_lengths[144..256].iter_mut().for_each(|length| *length = 9);
+                literal_lengths[256..280].iter_mut().for_each(|length| *length = 7);
+                inflate_block(&mut reader,
+                              &mut output,
+                              &HuffmanDecoder::new(&literal_lengths),
+                              &HuffmanDecoder::new(&[5; 30]));
+            }
+            2 => {
+                let num_literal_codes = reader.bits(5) + 257;
+                let num_distance_codes = reader.bits(5) + 1;
+                let num_length_codes = reader.bits(4) + 4;
+                let mut length_lengths = [0; 19];
+                for &i in &[16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15]
+                              [..num_length_codes] {
+                    length_lengths[i] = reader.bits(3);
+                }
+                let length_codes = HuffmanDecoder::new(&length_lengths);
+                let mut lengths = Vec::new();
+                while lengths.len() < num_literal_codes + num_distance_codes {
+                    let (length, repeat) = match length_codes.decode(&mut reader) {
+                        16 => (*lengths.last().unwrap(), 3 + reader.bits(2)),
+                        17 => (0, 3 + reader.bits(3)),
+                        18 => (0, 11 + reader.bits(7)),
+                        length => (length, 1),
+                    };
+                    lengths.extend(std::iter::repeat_n(length, repeat));
+                }
+                assert_eq!(lengths.len(), num_literal_codes + num_distance_codes);
+                inflate_block(&mut reader,
+                              &mut output,
+                              &HuffmanDecoder::new(&lengths[..num_literal_codes]),
+                              &HuffmanDecoder::new(&lengths[num_literal_codes..]));
+            }
+            kind => panic!("Invalid block type {}", kind),
+        }
+        if last {
+            break;
+        }
+    }
+    reader.align();
+    let end = 2 + reader.position / 8;
+    assert_eq!(stream.len(), end + 4);
+    assert_eq!(u32_be(&stream[end..]), zlib::adler32(&output));
+    output
+}
+
+#[test]
+fn zlib_round_trip() {
+    let mut seed = 1u32;
+    let mut random = || {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (seed >> 16) as u8
+    };
+    // Stored blocks, of which there is more than one
+    let noise = (0..100_000).map(|_| random()).collect::<Vec<u8>>();
+    // Long and short runs, and repeats far back in the window
+    let mut runs = Vec::new();
+    while runs.len() < 200_000 {
+        let (byte, length) = (random(), random() as usize + 1);
+        runs.extend(std::iter::repeat_n(byte, length));
+        if byte < 16 && runs.len() > 40_000 {
+            let start = runs.len() - 32768;
+            runs.extend_from_within(start..start + 300);
+        }
+    }
+    // Short enough for the fixed codes
+    let text = b"deflate deflates, and inflate inflates".to_vec();
+    let rows = (0..70_000).map(|n| (n % 251) as u8 ^ (n / 3000) as u8).collect::<Vec<_>>();
+    for data in &[Vec::new(), noise, runs, text, rows] {
+        let compressed = zlib::compress(data);
+        assert!(inflate(&compressed) == *data, "{} bytes", data.len());
+    }
+}
@@ -0,0 +1,79 @@
+extern crate jpeg_rust;
+
+use std::fs;
+use std::path::Path;
+
+use jpeg_rust::jpeg::exif::{ExifIFD, ExifValue};
+use jpeg_rust::jpeg::marker::Marker;
+use jpeg_rust::jpeg::report::Report;
+use jpeg_rust::jpeg::writer::JPEGSegmentWriter;
+
+fn read(name: &str) -> Vec<u8> {
+    fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(name)).unwrap()
+}
+
+#[test]
+fn headers_and_tables() {
+    let data = read("lena.jpeg");
+    let report = Report::new(&data).unwrap();
+    assert_eq!(report.file_size, data.len());
+    assert_eq!(report.jfif.as_ref().unwrap().version, (1, 1));
+    assert_eq!(report.comments.len(), 1);
+
+    assert_eq!(report.frames.len(), 1);
+    assert_eq!(report.frames[0].frame_type, Marker::BaselineDCT);
+    assert_eq!((report.frames[0].samples_per_line, report.frames[0].num_lines), (512, 512));
+
+    assert_eq!(report.quantization_tables.len(), 2);
+    assert!(report.quantization_tables.iter().all(|table| table.bits == 8));
+    assert_eq!(report.huffman_tables.len(), 4);
+    for table in &report.huffman_tables {
+        let codes = table.code_lengths.iter().map(|&n| n as usize).sum::<usize>();
+        assert_eq!(codes, table.values.len());
+    }
+
+    assert_eq!(report.scans.len(), 1);
+    assert_eq!(report.scans[0].header.scan_components.len(), 3);
+    let scan_end = report.scans[0].offset + 2 + 12 + report.scans[0].data_length;
+    assert_eq!(report.segments.last().unwrap().marker, Marker::EndOfImage);
+    assert_eq!(report.segments.last().unwrap().offset, scan_end);
+    assert_eq!(report.trailer_length, 0);
+}
+
+#[test]
+fn exif_fields() {
+    // Big endian, with IFD0 holding the orientation and a GPS IFD which
+    // holds the latitude reference.
+    let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08".to_vec();
+    exif.extend_from_slice(&[0, 2]);
+    exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+    exif.extend_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 38]);
+    exif.extend_from_slice(&[0, 0, 0, 0]);
+    exif.extend_from_slice(&[0, 1]);
+    exif.extend_from_slice(&[0x00, 0x01, 0, 2, 0, 0, 0, 2, b'N', 0, 0, 0]);
+    exif.extend_from_slice(&[0, 0, 0, 0]);
+
+    let mut writer = JPEGSegmentWriter::new(&read("lena.jpeg")).unwrap();
+    writer.insert_application_segment(1, exif).unwrap();
+    let report = Report::new(&writer.to_bytes()).unwrap();
+    let fields = report.exif.unwrap().unwrap();
+    assert_eq!(fields.len(), 3);
+    assert_eq!(fields[0].name(), Some("Orientation"));
+    assert_eq!(fields[0].value, ExifValue::Integers(vec![6]));
+    assert_eq!(fields[2].ifd, ExifIFD::GPS);
+    assert_eq!(fields[2].value, ExifValue::Text("N".to_string()));
+}
+
+#[test]
+fn json() {
+    let mut writer = JPEGSegmentWriter::new(&read("lena.jpeg")).unwrap();
+    writer.set_comment("say \"hi\"\n").unwrap();
+    let json = Report::new(&writer.to_bytes()).unwrap().to_json();
+    assert!(json.starts_with("{\n  \"file_size\": "));
+    assert!(json.contains("\"comments\": [\"say \\\"hi\\\"\\n\"]"));
+    let dc_lengths = "[0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0]";
+    assert!(json.contains(&format!("\"codes_per_length\": {}", dc_lengths)));
+    assert!(json.contains("\"spectral_selection\": [0, 63]"));
+    assert!(json.contains("\"exif\": null"));
+    assert!(json.ends_with("}\n"));
+}
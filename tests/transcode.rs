@@ -0,0 +1,190 @@
+extern crate jpeg_rust;
+
+use std::fs;
+use std::path::Path;
+
+use jpeg_rust::jpeg::JPEGImage;
+use jpeg_rust::jpeg::decoder::{Block, CoefficientOrder, ZIGZAG_INDICES};
+use jpeg_rust::jpeg::marker::Marker;
+use jpeg_rust::jpeg::writer::{JPEGSegmentWriter, Segment};
+
+fn read(name: &str) -> Vec<u8> {
+    fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(name)).unwrap()
+}
+
+fn coefficients(data: &[u8]) -> Vec<Vec<Block>> {
+    let (_, components) = JPEGImage::read_coefficients(data, CoefficientOrder::Natural).unwrap();
+    components.into_iter().map(|c| c.blocks).collect()
+}
+
+fn assert_same_coefficients(a: &[u8], b: &[u8]) {
+    let (a, b) = (coefficients(a), coefficients(b));
+    assert_eq!(a.len(), b.len());
+    for (a, b) in a.iter().zip(&b) {
+        assert_eq!(a.len(), b.len());
+        assert!(a.iter().zip(b).all(|(a, b)| a[..] == b[..]));
+    }
+}
+
+fn frame_type(writer: &JPEGSegmentWriter) -> Marker {
+    writer.segments()
+        .iter()
+        .filter_map(|segment| match *segment {
+            Segment::MarkerSegment(marker, _) if marker.is_start_of_frame() => Some(marker),
+            _ => None,
+        })
+        .next()
+        .unwrap()
+}
+
+fn num_scans(writer: &JPEGSegmentWriter) -> usize {
+    writer.segments()
+        .iter()
+        .filter(|segment| matches!(**segment, Segment::MarkerSegment(Marker::StartOfScan, _)))
+        .count()
+}
+
+const FILES: &[&str] = &["lena.jpeg", "2x2-chroma.jpeg", "working-jpegs/lena-bw.jpeg"];
+
+#[test]
+fn baseline_to_progressive_keeps_coefficients() {
+    for name in FILES {
+        let data = read(name);
+        let mut writer = JPEGSegmentWriter::new(&data).unwrap();
+        writer.make_progressive().unwrap();
+        assert_eq!(frame_type(&writer), Marker::ProgressiveDCT);
+        assert!(num_scans(&writer) > 1);
+        assert_same_coefficients(&data, &writer.to_bytes());
+    }
+}
+
+#[test]
+fn progressive_to_baseline_keeps_coefficients() {
+    for name in FILES {
+        let data = read(name);
+        let mut writer = JPEGSegmentWriter::new(&data).unwrap();
+        writer.make_progressive().unwrap();
+        let progressive = writer.to_bytes();
+        writer.make_sequential().unwrap();
+        assert_eq!(frame_type(&writer), Marker::BaselineDCT);
+        assert_eq!(num_scans(&writer), 1);
+        assert_same_coefficients(&progressive, &writer.to_bytes());
+        assert_same_coefficients(&data, &writer.to_bytes());
+    }
+}
+
+#[test]
+fn optimized_tables_keep_coefficients_and_size() {
+    for name in FILES {
+        let data = read(name);
+        let mut writer = JPEGSegmentWriter::new(&data).unwrap();
+        writer.optimize_huffman_tables().unwrap();
+        let optimized = writer.to_bytes();
+        assert!(optimized.len() <= data.len());
+        assert_same_coefficients(&data, &optimized);
+
+        // Optimizing again finds the same tables.
+        writer.optimize_huffman_tables().unwrap();
+        assert_eq!(writer.to_bytes(), optimized);
+    }
+}
+
+#[test]
+fn round_trip_through_progressive_is_optimized_baseline() {
+    for name in FILES {
+        let data = read(name);
+        let mut optimized = JPEGSegmentWriter::new(&data).unwrap();
+        optimized.optimize_huffman_tables().unwrap();
+        let mut writer = JPEGSegmentWriter::new(&data).unwrap();
+        writer.make_progressive().unwrap();
+        writer.make_sequential().unwrap();
+        assert_eq!(writer.to_bytes(), optimized.to_bytes());
+    }
+}
+
+#[test]
+fn rewriting_with_the_same_tables_gives_the_same_file() {
+    for name in FILES {
+        let data = read(name);
+        let (_, components) = JPEGImage::read_coefficients(&data, CoefficientOrder::Zigzag)
+            .unwrap();
+        let mut writer = JPEGSegmentWriter::new(&data).unwrap();
+        writer.replace_coefficients(&components).unwrap();
+        assert_eq!(writer.to_bytes(), data);
+    }
+}
+
+#[test]
+fn huffman_to_arithmetic_keeps_coefficients() {
+    for name in FILES {
+        let data = read(name);
+        let mut writer = JPEGSegmentWriter::new(&data).unwrap();
+        writer.make_arithmetic().unwrap();
+        assert_eq!(frame_type(&writer), Marker::ExtendedSequentialDCTArithmetic);
+        assert_same_coefficients(&data, &writer.to_bytes());
+
+        writer.make_progressive().unwrap();
+        writer.make_arithmetic().unwrap();
+        assert_eq!(frame_type(&writer), Marker::ProgressiveDCTArithmetic);
+        assert_same_coefficients(&data, &writer.to_bytes());
+    }
+}
+
+#[test]
+fn round_trip_through_arithmetic_is_optimized_huffman() {
+    for name in FILES {
+        let data = read(name);
+        let mut optimized = JPEGSegmentWriter::new(&data).unwrap();
+        optimized.optimize_huffman_tables().unwrap();
+        let mut writer = JPEGSegmentWriter::new(&data).unwrap();
+        writer.make_arithmetic().unwrap();
+        writer.make_huffman().unwrap();
+        assert_eq!(writer.to_bytes(), optimized.to_bytes());
+    }
+}
+
+#[test]
+fn component_coefficients_of_a_420_image() {
+    // 750x595 with MCUs of 16x16, so 47x38 MCUs. Luma has 2x2 blocks in
+    // each of them, and the rows are padded to 76 for the partial MCUs.
+    let data = read("2x2-chroma.jpeg");
+    let (_, natural) = JPEGImage::read_coefficients(&data, CoefficientOrder::Natural).unwrap();
+    let (_, zigzag) = JPEGImage::read_coefficients(&data, CoefficientOrder::Zigzag).unwrap();
+    let sizes = natural.iter()
+        .map(|c| {
+            ((c.horizontal_sampling_factor, c.vertical_sampling_factor),
+             (c.width_in_blocks, c.height_in_blocks),
+             (c.blocks_per_line, c.blocks.len() / c.blocks_per_line))
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(sizes,
+               [((2, 2), (94, 75), (94, 76)), ((1, 1), (47, 38), (47, 38)),
+                ((1, 1), (47, 38), (47, 38))]);
+
+    // Cropped to 728 pixels, which is 91 luma blocks in 46 MCUs
+    let mut writer = JPEGSegmentWriter::new(&data).unwrap();
+    writer.crop(0, 0, 728, 595).unwrap();
+    let (_, cropped) = JPEGImage::read_coefficients(&writer.to_bytes(), CoefficientOrder::Natural)
+        .unwrap();
+    assert_eq!((cropped[0].width_in_blocks, cropped[0].blocks_per_line), (91, 92));
+    assert_eq!((cropped[1].width_in_blocks, cropped[1].blocks_per_line), (46, 46));
+
+    // The tables are in the order of the coefficients, so the zigzag
+    // ones are as in the DQT segments.
+    let ids = natural.iter().map(|c| c.quantization_id).collect::<Vec<_>>();
+    assert_eq!(ids, [0, 1, 1]);
+    assert_eq!(zigzag[0].quantization_table[..8], [5, 3, 4, 4, 4, 3, 5, 4]);
+    assert_eq!(zigzag[1].quantization_table[..8], [5, 5, 5, 7, 6, 7, 14, 8]);
+    assert_eq!(natural[0].quantization_table[..8], [5, 3, 3, 5, 7, 12, 15, 18]);
+    for (natural, zigzag) in natural.iter().zip(&zigzag) {
+        assert_eq!(zigzag.order, CoefficientOrder::Zigzag);
+        for (k, &n) in ZIGZAG_INDICES.iter().enumerate() {
+            assert_eq!(natural.quantization_table[n], zigzag.quantization_table[k]);
+            assert!(natural.blocks.iter().zip(&zigzag.blocks).all(|(a, b)| a[n] == b[k]));
+        }
+    }
+
+    // The first AC coefficient of the first column of the top left block
+    assert_eq!(natural[0].blocks[0][8], -4);
+    assert_eq!(zigzag[0].blocks[0][2], -4);
+}
@@ -0,0 +1,173 @@
+extern crate jpeg_rust;
+
+use std::fs;
+use std::path::Path;
+
+use jpeg_rust::jpeg::JPEGImage;
+use jpeg_rust::jpeg::marker::Marker;
+use jpeg_rust::jpeg::transform::{PartialMCUs, Transform};
+use jpeg_rust::jpeg::writer::{JPEGSegmentWriter, Segment};
+
+fn read(name: &str) -> Vec<u8> {
+    fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(name)).unwrap()
+}
+
+const TRANSFORMS: &[Transform] = &[Transform::FlipHorizontal,
+                                   Transform::FlipVertical,
+                                   Transform::Transpose,
+                                   Transform::Transverse,
+                                   Transform::Rotate90,
+                                   Transform::Rotate180,
+                                   Transform::Rotate270];
+
+fn transformed(data: &[u8],
+               transform: Transform,
+               partial_mcus: PartialMCUs)
+               -> Result<JPEGImage, String> {
+    let mut writer = JPEGSegmentWriter::new(data)?;
+    writer.transform(transform, partial_mcus)?;
+    JPEGImage::parse(writer.to_bytes())
+}
+
+/// The pixel of a `width` x `height` image which `transform` moves to
+/// `(x, y)`.
+fn source_pixel(transform: Transform,
+                (width, height): (usize, usize),
+                (x, y): (usize, usize))
+                -> (usize, usize) {
+    match transform {
+        Transform::FlipHorizontal => (width - 1 - x, y),
+        Transform::FlipVertical => (x, height - 1 - y),
+        Transform::Transpose => (y, x),
+        Transform::Transverse => (width - 1 - y, height - 1 - x),
+        Transform::Rotate90 => (y, height - 1 - x),
+        Transform::Rotate180 => (width - 1 - x, height - 1 - y),
+        Transform::Rotate270 => (width - 1 - y, x),
+    }
+}
+
+/// Check that each pixel of `image` is the one `transform` moves there
+/// from `source`. The inverse DCT of the moved coefficients may round
+/// differently, so they can be off by one.
+fn assert_moved(source: &JPEGImage, image: &JPEGImage, transform: Transform) {
+    let size = (source.width(), source.height());
+    let source_pixels = source.image_data().unwrap();
+    for (i, pixel) in image.image_data().unwrap().iter().enumerate() {
+        let (x, y) = source_pixel(transform, size, (i % image.width(), i / image.width()));
+        let expected = source_pixels[y * source.width() + x];
+        let close = |a: u8, b: u8| (a as i32 - b as i32).abs() <= 1;
+        assert!(close(pixel.0, expected.0) && close(pixel.1, expected.1) &&
+                close(pixel.2, expected.2),
+                "{:?} at {:?}: {:?} is not {:?}",
+                transform,
+                (x, y),
+                pixel,
+                expected);
+    }
+}
+
+#[test]
+fn pixels_are_moved() {
+    // 4:2:2, so the chroma sampling factors are swapped by transposing
+    let data = read("lena.jpeg");
+    let source = JPEGImage::parse(data.clone()).unwrap();
+    for &transform in TRANSFORMS {
+        let image = transformed(&data, transform, PartialMCUs::Error).unwrap();
+        assert_moved(&source, &image, transform);
+    }
+}
+
+#[test]
+fn partial_mcus_are_trimmed_or_fail() {
+    // 750x595 with MCUs of 16x16, so the right and bottom MCUs are partial
+    let data = read("2x2-chroma.jpeg");
+    let mut trimmed: Vec<((usize, usize), JPEGImage)> = Vec::new();
+    for &transform in TRANSFORMS {
+        // Only transposing keeps the right and bottom edges where they are.
+        let error = transformed(&data, transform, PartialMCUs::Error).err();
+        assert_eq!(error.is_none(), transform == Transform::Transpose, "{:?}", transform);
+
+        let image = transformed(&data, transform, PartialMCUs::Trim).unwrap();
+        let (width, height) = match transform {
+            Transform::FlipHorizontal => (736, 595),
+            Transform::FlipVertical => (750, 592),
+            Transform::Transpose => (595, 750),
+            Transform::Transverse => (592, 736),
+            Transform::Rotate90 => (592, 750),
+            Transform::Rotate180 => (736, 592),
+            Transform::Rotate270 => (595, 736),
+        };
+        assert_eq!((image.width(), image.height()), (width, height), "{:?}", transform);
+        // The trimmed source, which the transform moves as a whole
+        let size = if transform.transposes() { (height, width) } else { (width, height) };
+        if !trimmed.iter().any(|&(trimmed_size, _)| trimmed_size == size) {
+            let mut writer = JPEGSegmentWriter::new(&data).unwrap();
+            writer.crop(0, 0, size.0, size.1).unwrap();
+            trimmed.push((size, JPEGImage::parse(writer.to_bytes()).unwrap()));
+        }
+        let source = &trimmed.iter().find(|&&(trimmed_size, _)| trimmed_size == size).unwrap().1;
+        assert_moved(source, &image, transform);
+    }
+}
+
+#[test]
+fn exif_orientation_is_reset() {
+    // IFD0 with an orientation of 6, whose value is at byte 24
+    let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08".to_vec();
+    exif.extend_from_slice(&[0, 1]);
+    exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+    exif.extend_from_slice(&[0, 0, 0, 0]);
+    let mut writer = JPEGSegmentWriter::new(&read("lena.jpeg")).unwrap();
+    writer.insert_application_segment(1, exif.clone()).unwrap();
+
+    writer.transform(Transform::Rotate90, PartialMCUs::Error).unwrap();
+    exif[24..26].copy_from_slice(&[0, 1]);
+    let app1 = writer.segments().iter().find_map(|segment| match *segment {
+        Segment::MarkerSegment(Marker::ApplicationSegment(1), ref data) => Some(data.clone()),
+        _ => None,
+    });
+    assert_eq!(app1, Some(exif));
+}
+
+/// The image in `data` cropped to the given rectangle.
+fn cropped(data: &[u8], (x, y, width, height): (usize, usize, usize, usize)) -> JPEGImage {
+    let mut writer = JPEGSegmentWriter::new(data).unwrap();
+    writer.crop(x, y, width, height).unwrap();
+    JPEGImage::parse(writer.to_bytes()).unwrap()
+}
+
+#[test]
+fn crop_keeps_the_pixels() {
+    // MCUs of 16x8 and of 16x16 pixels. The last rectangle reaches the
+    // partial MCUs at the right and bottom edges.
+    for &(name, rectangle) in &[("lena.jpeg", (32, 16, 100, 77)),
+                                ("lena.jpeg", (0, 0, 512, 512)),
+                                ("2x2-chroma.jpeg", (16, 32, 734, 563))] {
+        let data = read(name);
+        let source = JPEGImage::parse(data.clone()).unwrap();
+        let image = cropped(&data, rectangle);
+        let (x, y, width, height) = rectangle;
+        assert_eq!((image.width(), image.height()), (width, height));
+        let pixels = source.image_data().unwrap();
+        let expected = (y..y + height)
+            .flat_map(|line| &pixels[line * source.width() + x..line * source.width() + x + width])
+            .cloned()
+            .collect::<Vec<_>>();
+        assert!(image.image_data().unwrap()[..] == expected[..], "{} {:?}", name, rectangle);
+    }
+}
+
+#[test]
+fn crop_must_start_on_an_mcu_and_be_inside_the_image() {
+    let mut writer = JPEGSegmentWriter::new(&read("lena.jpeg")).unwrap();
+    let original = writer.to_bytes();
+    assert_eq!(writer.crop(8, 8, 16, 16).unwrap_err(),
+               "Crop at (8, 8) is not on the boundary of an MCU of 16x8 pixels");
+    assert_eq!(writer.crop(16, 4, 16, 16).unwrap_err(),
+               "Crop at (16, 4) is not on the boundary of an MCU of 16x8 pixels");
+    assert_eq!(writer.crop(496, 0, 17, 16).unwrap_err(),
+               "Crop of 17x16 at (496, 0) is outside the image of 512x512");
+    assert_eq!(writer.crop(0, 0, 0, 16).unwrap_err(),
+               "Crop of 0x16 at (0, 0) is outside the image of 512x512");
+    assert_eq!(writer.to_bytes(), original);
+}
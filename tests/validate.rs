@@ -0,0 +1,97 @@
+extern crate jpeg_rust;
+
+use std::fs;
+use std::path::Path;
+
+use jpeg_rust::jpeg::marker::{Marker, MarkerReader, SegmentKind};
+use jpeg_rust::jpeg::validate::{validate, Severity, Violation};
+
+fn read(name: &str) -> Vec<u8> {
+    fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(name)).unwrap()
+}
+
+/// The offset and length of the first segment with `marker`.
+fn find(data: &[u8], marker: Marker) -> (usize, usize) {
+    MarkerReader::new(data)
+        .map(Result::unwrap)
+        .find(|segment| segment.kind == SegmentKind::Marker(marker))
+        .map(|segment| (segment.offset, segment.encoded_length()))
+        .unwrap()
+}
+
+fn errors(violations: &[Violation]) -> Vec<String> {
+    violations.iter()
+        .filter(|violation| violation.severity == Severity::Error)
+        .map(|violation| violation.message.clone())
+        .collect()
+}
+
+#[test]
+fn valid_files() {
+    assert_eq!(validate(&read("lena.jpeg")), vec![]);
+    assert_eq!(validate(&read("2x2-chroma.jpeg")), vec![]);
+}
+
+#[test]
+fn structure() {
+    let data = read("lena.jpeg");
+    assert_eq!(errors(&validate(&data[2..])), vec!["File does not start with SOI"]);
+
+    let truncated = &data[..data.len() / 2];
+    let violations = validate(truncated);
+    assert_eq!(errors(&violations), vec!["File ends without EOI"]);
+    assert_eq!(violations[0].offset, truncated.len());
+
+    let mut trailing = data.clone();
+    trailing.extend_from_slice(b"garbage");
+    let violations = validate(&trailing);
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0].severity, Severity::Warning);
+    assert_eq!(violations[0].offset, data.len());
+}
+
+#[test]
+fn undefined_tables() {
+    let data = read("lena.jpeg");
+    let (offset, length) = find(&data, Marker::DefineHuffmanTable);
+    let mut removed = data[..offset].to_vec();
+    removed.extend_from_slice(&data[offset + length..]);
+    let errors = errors(&validate(&removed));
+    assert_eq!(errors.len(), 6);
+    assert_eq!(errors[0], "Huffman DC table 0 of component 1 is not defined");
+}
+
+#[test]
+fn overflowing_huffman_table() {
+    let mut data = read("lena.jpeg");
+    let (offset, _) = find(&data, Marker::DefineHuffmanTable);
+    // One code of length 1 instead of length 2 leaves no room for the
+    // five codes of length 3.
+    data[offset + 5] = 1;
+    data[offset + 6] = 0;
+    assert_eq!(errors(&validate(&data)),
+               vec!["Huffman DC table 0 has too many codes of length 3"]);
+}
+
+#[test]
+fn baseline_limits() {
+    let data = read("lena.jpeg");
+    let (frame, _) = find(&data, Marker::BaselineDCT);
+    let mut tables = vec![0xff, 0xdb, 0, 131, 0x12];
+    tables.extend((0..64).flat_map(|_| vec![0, 1]));
+    tables.extend_from_slice(&[0xff, 0xc4, 0, 20, 0x02, 0, 1]);
+    tables.extend_from_slice(&[0; 14]);
+    tables.push(0);
+    let mut extended = data[..frame].to_vec();
+    extended.extend_from_slice(&tables);
+    extended.extend_from_slice(&data[frame..]);
+    assert_eq!(errors(&validate(&extended)),
+               vec!["Quantization table 2 has 16-bit values, but the samples have 8 bits",
+                    "Baseline frames have only 2 Huffman DC tables, but table 2 is defined"]);
+
+    // Components of 4x4, 1x1 and 1x1 blocks make 18 blocks in an MCU.
+    let mut sampled = data.clone();
+    sampled[frame + 11] = 0x44;
+    assert_eq!(errors(&validate(&sampled)),
+               vec!["Sampling factors of the scan components add up to 18, more than 10"]);
+}